@@ -0,0 +1,320 @@
+use crate::sql::models::AttachmentRecord;
+use crate::store::{SpordStore, StoreError};
+use crate::CONFIG;
+use chrono::Utc;
+use rand::distributions::{Alphanumeric, DistString};
+use sha2::{Digest, Sha256};
+use std::path::{Path, PathBuf};
+use tokio::io::AsyncWriteExt;
+
+// Bytes kept from the start of an upload to sniff its type
+const SNIFF_BYTES: usize = 8192;
+const MAX_FILENAME_CHARS: usize = 200;
+
+lazy_static! {
+    // Storing and removing contents is serialized, so a delete can't remove a
+    // file that an upload of the same content has just decided to reuse
+    static ref CONTENT_LOCK: tokio::sync::Mutex<()> = tokio::sync::Mutex::new(());
+}
+
+#[derive(Debug, Error)]
+pub enum AttachmentError {
+    #[error("File is larger than {0} bytes")]
+    TooLarge(u64),
+
+    #[error("Attachments of this spord would exceed {0} bytes")]
+    SpordFull(u64),
+
+    #[error("Files of type {0} can't be attached")]
+    TypeNotAllowed(String),
+
+    #[error("Attachment(IO({0:?}))")]
+    Io(#[from] std::io::Error),
+
+    #[error("Attachment({0})")]
+    Store(#[from] StoreError),
+}
+type Result<T> = std::result::Result<T, AttachmentError>;
+
+// Where the content with this hash is kept, spread over subdirectories by the
+// first two hex digits
+fn content_path(dir: &Path, sha256: &str) -> PathBuf {
+    dir.join(&sha256[..2]).join(sha256)
+}
+
+// The type of a file from its first bytes. Text is anything that is valid
+// utf-8 without NUL bytes
+pub fn sniff(head: &[u8]) -> String {
+    if let Some(kind) = infer::get(head) {
+        return kind.mime_type().to_string();
+    }
+
+    // the head may end in the middle of a character
+    let is_text = match std::str::from_utf8(head) {
+        Ok(_) => true,
+        Err(e) => e.error_len().is_none(),
+    } && !head.contains(&0);
+    if is_text && !head.is_empty() {
+        "text/plain".to_string()
+    } else {
+        "application/octet-stream".to_string()
+    }
+}
+
+// The last path component of a client supplied file name, without control
+// characters and quotes
+pub fn clean_filename(filename: &str) -> String {
+    let name = filename
+        .rsplit(['/', '\\'])
+        .next()
+        .unwrap_or_default()
+        .chars()
+        .filter(|c| !c.is_control() && *c != '"')
+        .take(MAX_FILENAME_CHARS)
+        .collect::<String>();
+    let name = name.trim();
+
+    if name.is_empty() || name == "." || name == ".." {
+        "attachment".to_string()
+    } else {
+        name.to_string()
+    }
+}
+
+// An upload being written to a temporary file and hashed on the way. The
+// file is removed again unless it is stored
+pub struct IncomingFile {
+    dir: PathBuf,
+    file: tokio::fs::File,
+    path: Option<PathBuf>,
+    hasher: Sha256,
+    size: u64,
+    head: Vec<u8>,
+}
+impl IncomingFile {
+    pub async fn create() -> Result<IncomingFile> {
+        IncomingFile::create_in(PathBuf::from(&CONFIG.attachments.dir)).await
+    }
+
+    // An upload for the attachments kept in dir rather than attachments.dir
+    async fn create_in(dir: PathBuf) -> Result<IncomingFile> {
+        let temp_dir = dir.join("tmp");
+        tokio::fs::create_dir_all(&temp_dir).await?;
+        let path = temp_dir.join(Alphanumeric.sample_string(&mut rand::thread_rng(), 24));
+        let file = tokio::fs::File::create(&path).await?;
+
+        Ok(IncomingFile {
+            dir,
+            file,
+            path: Some(path),
+            hasher: Sha256::new(),
+            size: 0,
+            head: Vec::new(),
+        })
+    }
+
+    pub async fn write(&mut self, chunk: &[u8]) -> Result<()> {
+        self.size += chunk.len() as u64;
+        if self.size > CONFIG.attachments.max_file_bytes {
+            return Err(AttachmentError::TooLarge(CONFIG.attachments.max_file_bytes));
+        }
+
+        if self.head.len() < SNIFF_BYTES {
+            let wanted = (SNIFF_BYTES - self.head.len()).min(chunk.len());
+            self.head.extend_from_slice(&chunk[..wanted]);
+        }
+        self.hasher.update(chunk);
+        self.file.write_all(chunk).await?;
+
+        Ok(())
+    }
+}
+impl Drop for IncomingFile {
+    fn drop(&mut self) {
+        if let Some(ref path) = self.path {
+            if let Err(e) = std::fs::remove_file(path) {
+                warn!("Could not remove upload {}: {}", path.display(), e);
+            }
+        }
+    }
+}
+
+// Keeps an upload with a spord. The content is only written when no other
+// attachment has the same content yet
+pub async fn store(
+    spords: &dyn SpordStore,
+    spord_id: i32,
+    filename: &str,
+    mut incoming: IncomingFile,
+    username: Option<String>,
+) -> Result<AttachmentRecord> {
+    incoming.file.flush().await?;
+    incoming.file.sync_all().await?;
+
+    let mime_type = sniff(&incoming.head);
+    if !CONFIG.attachments.allowed_types.contains(&mime_type) {
+        return Err(AttachmentError::TypeNotAllowed(mime_type));
+    }
+    let sha256 = format!("{:x}", incoming.hasher.clone().finalize());
+
+    let _lock = CONTENT_LOCK.lock().await;
+
+    let used = spords.attachments_size(spord_id).await? as u64;
+    if used + incoming.size > CONFIG.attachments.max_spord_bytes {
+        return Err(AttachmentError::SpordFull(
+            CONFIG.attachments.max_spord_bytes,
+        ));
+    }
+
+    let path = content_path(&incoming.dir, &sha256);
+    if !tokio::fs::try_exists(&path).await? {
+        tokio::fs::create_dir_all(path.parent().unwrap()).await?;
+        if let Some(temp_path) = incoming.path.take() {
+            tokio::fs::rename(&temp_path, &path).await?;
+        }
+    }
+
+    let mut attachment = AttachmentRecord {
+        id: 0,
+        spord_id,
+        filename: clean_filename(filename),
+        mime_type,
+        size: incoming.size as i64,
+        sha256,
+        username,
+        created: Utc::now(),
+    };
+    attachment.id = spords.add_attachment(attachment.clone()).await?;
+
+    Ok(attachment)
+}
+
+pub async fn read(attachment: &AttachmentRecord) -> Result<Vec<u8>> {
+    let dir = Path::new(&CONFIG.attachments.dir);
+    Ok(tokio::fs::read(content_path(dir, &attachment.sha256)).await?)
+}
+
+// Removes an attachment, and its content once nothing else refers to it
+pub async fn delete(spords: &dyn SpordStore, attachment: &AttachmentRecord) -> Result<()> {
+    delete_from(Path::new(&CONFIG.attachments.dir), spords, attachment).await
+}
+
+async fn delete_from(
+    dir: &Path,
+    spords: &dyn SpordStore,
+    attachment: &AttachmentRecord,
+) -> Result<()> {
+    let _lock = CONTENT_LOCK.lock().await;
+
+    spords.delete_attachment(attachment.id).await?;
+    if !spords.attachment_content_used(&attachment.sha256).await? {
+        let path = content_path(dir, &attachment.sha256);
+        if let Err(e) = tokio::fs::remove_file(&path).await {
+            warn!(
+                "Could not remove attachment content {}: {}",
+                path.display(),
+                e
+            );
+        }
+    }
+
+    Ok(())
+}
+
+// Removes every attachment of a spord, when the spord itself goes
+pub async fn delete_all(spords: &dyn SpordStore, spord_id: i32) -> Result<()> {
+    for attachment in spords.attachments(spord_id).await? {
+        delete(spords, &attachment).await?;
+    }
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::store::memory::MemoryStore;
+    use crate::testing;
+    use std::prelude::v1::test;
+
+    #[test]
+    fn sniff_uses_the_content() {
+        assert_eq!(sniff(b"%PDF-1.7\n%\xe2\xe3"), "application/pdf");
+        assert_eq!(sniff(b"\x89PNG\r\n\x1a\n\0\0\0\rIHDR"), "image/png");
+        assert_eq!(sniff("part for M\u{fc}ller\n".as_bytes()), "text/plain");
+        // cut off in the middle of the \u{fc}
+        assert_eq!(sniff(&"M\u{fc}".as_bytes()[..2]), "text/plain");
+        assert_eq!(sniff(b"text\0with a NUL"), "application/octet-stream");
+        assert_eq!(sniff(b"\xff\xfe\xfd"), "application/octet-stream");
+        assert_eq!(sniff(b""), "application/octet-stream");
+    }
+
+    #[test]
+    fn clean_filename_keeps_only_the_name() {
+        for (filename, expected) in [
+            ("invoice.pdf", "invoice.pdf"),
+            ("../../etc/passwd", "passwd"),
+            ("C:\\Users\\dave\\scan 1.png", "scan 1.png"),
+            ("quote\"d\r\n.txt", "quoted.txt"),
+            ("  spaced.txt  ", "spaced.txt"),
+            ("dir/", "attachment"),
+            ("..", "attachment"),
+            ("", "attachment"),
+        ] {
+            assert_eq!(clean_filename(filename), expected, "{:?}", filename);
+        }
+        assert_eq!(
+            clean_filename(&"x".repeat(500)).chars().count(),
+            MAX_FILENAME_CHARS
+        );
+    }
+
+    async fn upload(dir: &Path, content: &[u8]) -> IncomingFile {
+        let mut incoming = IncomingFile::create_in(dir.to_path_buf()).await.unwrap();
+        incoming.write(content).await.unwrap();
+        incoming
+    }
+
+    #[actix_web::test]
+    async fn same_content_is_stored_once() {
+        testing::config();
+        let dir = tempfile::tempdir().unwrap();
+        let spords = MemoryStore::new();
+        let spord = SpordStore::create(&spords, testing::spord("Dave", "Fan belt"))
+            .await
+            .unwrap() as i32;
+
+        let first = store(
+            &spords,
+            spord,
+            "a.txt",
+            upload(dir.path(), b"receipt").await,
+            None,
+        )
+        .await
+        .unwrap();
+        let second = store(
+            &spords,
+            spord,
+            "b.txt",
+            upload(dir.path(), b"receipt").await,
+            None,
+        )
+        .await
+        .unwrap();
+        assert_eq!(first.sha256, second.sha256);
+        assert_ne!(first.id, second.id);
+        let path = content_path(dir.path(), &first.sha256);
+        assert_eq!(std::fs::read(&path).unwrap(), b"receipt");
+        // nothing is left behind in tmp, the second upload was thrown away
+        assert_eq!(
+            std::fs::read_dir(dir.path().join("tmp")).unwrap().count(),
+            0
+        );
+
+        delete_from(dir.path(), &spords, &first).await.unwrap();
+        assert!(path.exists());
+        delete_from(dir.path(), &spords, &second).await.unwrap();
+        assert!(!path.exists());
+    }
+}
@@ -1,13 +1,58 @@
-use crate::config;
-use clap::{Arg, ArgMatches, Command};
+use crate::common;
+use crate::config::{self, SqlDriver};
+use crate::constants;
+use crate::sql;
+use crate::sql::lockout::AttemptKind;
+use crate::sql::models::TokenScope;
+use crate::sql::Pool;
+use crate::store::postgres::{self, PostgresStore};
+use crate::store::{LocationStore, SpordStore, UserStore, WebhookStore};
+use crate::CONFIG;
+use anyhow::Context;
+use chrono::{Duration, Utc};
+use clap::{Arg, ArgAction, ArgMatches, Command};
+use std::path::{Path, PathBuf};
+
+mod spord;
+
+const DATE_FORMAT: &str = "%Y-%m-%d %H:%M";
 
 fn gen_clap() -> Command {
-    let app = command!()
+    command!()
+        .subcommand(spord::gen_command())
+        .arg(
+            Arg::new("config")
+                .long("config")
+                .required(false)
+                .value_name("PATH")
+                .env("SPORD_CONFIG")
+                .value_parser(value_parser!(PathBuf))
+                .help("Config file to use, .toml or .json. Defaults to config.toml in the data directory, or config.json when only that exists"),
+        )
+        .arg(
+            Arg::new("data-dir")
+                .long("data-dir")
+                .required(false)
+                .value_name("DIR")
+                .env("SPORD_DATA_DIR")
+                .value_parser(value_parser!(PathBuf))
+                .help("Directory relative paths in the config are resolved against, defaults to the directory of the executable"),
+        )
         .arg(
             Arg::new("write-config")
                 .long("write-config")
                 .required(false)
-                .help("Write a default configuration"),
+                .num_args(0..=1)
+                .value_name("PATH")
+                .value_parser(value_parser!(PathBuf))
+                .help("Write a commented default configuration to config.toml, or PATH (.toml or .json)"),
+        )
+        .arg(
+            Arg::new("check-config")
+                .long("check-config")
+                .required(false)
+                .action(ArgAction::SetTrue)
+                .help("Check the config, including SPORD_ environment overrides, and exit"),
         )
         .arg(
             Arg::new("create-user")
@@ -15,34 +60,532 @@ fn gen_clap() -> Command {
                 .required(false)
                 .num_args(0..=1)
                 .help("Create a new user in the database"),
+        )
+        .arg(
+            Arg::new("unlock-user")
+                .long("unlock-user")
+                .required(false)
+                .value_name("USERNAME")
+                .help("Clear failed logins and lockout for a user"),
+        )
+        .arg(
+            Arg::new("list-sessions")
+                .long("list-sessions")
+                .required(false)
+                .action(ArgAction::SetTrue)
+                .help("List active sessions (requires session.database_store)"),
+        )
+        .arg(
+            Arg::new("revoke-session")
+                .long("revoke-session")
+                .required(false)
+                .value_name("ID")
+                .value_parser(value_parser!(i64))
+                .help("Log out the session with this id"),
+        )
+        .arg(
+            Arg::new("revoke-user-sessions")
+                .long("revoke-user-sessions")
+                .required(false)
+                .value_name("USERNAME")
+                .help("Log out every session of a user"),
+        )
+        .arg(
+            Arg::new("create-token")
+                .long("create-token")
+                .required(false)
+                .value_name("NAME")
+                .help(
+                    "Create an api token, see --token-user, --token-location, --token-scope and --token-expires-days",
+                ),
+        )
+        .arg(
+            Arg::new("token-user")
+                .long("token-user")
+                .required(false)
+                .value_name("USERNAME")
+                .requires("create-token")
+                .help("User owning the new token, leave out for a service token"),
+        )
+        .arg(
+            Arg::new("token-location")
+                .long("token-location")
+                .required(false)
+                .value_name("LOCATION")
+                .requires("create-token")
+                .conflicts_with("token-user")
+                .help("Limit the new service token to one store, it sees every store otherwise. Service tokens never get manager rights"),
+        )
+        .arg(
+            Arg::new("token-scope")
+                .long("token-scope")
+                .required(false)
+                .value_parser(["read", "write"])
+                .default_value("read")
+                .requires("create-token")
+                .help("What the new token may do"),
+        )
+        .arg(
+            Arg::new("token-expires-days")
+                .long("token-expires-days")
+                .required(false)
+                .value_name("DAYS")
+                .value_parser(value_parser!(i64))
+                .requires("create-token")
+                .help("Expire the new token after this many days"),
+        )
+        .arg(
+            Arg::new("list-tokens")
+                .long("list-tokens")
+                .required(false)
+                .action(ArgAction::SetTrue)
+                .help("List api tokens"),
+        )
+        .arg(
+            Arg::new("revoke-token")
+                .long("revoke-token")
+                .required(false)
+                .value_name("ID")
+                .value_parser(value_parser!(i64))
+                .help("Delete the api token with this id"),
+        )
+        .arg(
+            Arg::new("backup")
+                .long("backup")
+                .required(false)
+                .action(ArgAction::SetTrue)
+                .help("Back up the sqlite database into backup.dir, safe while the server runs"),
+        )
+        .arg(
+            Arg::new("restore")
+                .long("restore")
+                .required(false)
+                .value_name("FILE")
+                .value_parser(value_parser!(PathBuf))
+                .help("Replace the sqlite database with a backup, stop the server first"),
+        )
+        .arg(
+            Arg::new("copy-to-postgres")
+                .long("copy-to-postgres")
+                .required(false)
+                .num_args(0..=1)
+                .value_name("URL")
+                .help("Copy users, spords and webhooks from the sqlite database into an empty postgres database, defaults to sql.postgres_url. Attachment files stay in attachments.dir"),
+        )
+        .arg(
+            Arg::new("list-users")
+                .long("list-users")
+                .required(false)
+                .action(ArgAction::SetTrue)
+                .help("List users with their store"),
+        )
+        .arg(
+            Arg::new("create-location")
+                .long("create-location")
+                .required(false)
+                .value_name("NAME")
+                .help("Add a store location"),
+        )
+        .arg(
+            Arg::new("list-locations")
+                .long("list-locations")
+                .required(false)
+                .action(ArgAction::SetTrue)
+                .help("List store locations"),
+        )
+        .arg(
+            Arg::new("set-user-location")
+                .long("set-user-location")
+                .required(false)
+                .num_args(2)
+                .value_names(["USERNAME", "LOCATION"])
+                .help("Set the home store of a user, LOCATION \"none\" clears it"),
+        )
+        .arg(
+            Arg::new("set-manager")
+                .long("set-manager")
+                .required(false)
+                .num_args(2)
+                .value_names(["USERNAME", "true|false"])
+                .help("Let a user see and manage every store"),
+        )
+}
+
+pub fn parse() -> ArgMatches {
+    gen_clap().get_matches()
+}
+
+// The config files named on the command line, or their defaults
+pub struct ConfigPaths {
+    // the config to read
+    pub config: PathBuf,
+    // where --write-config writes the template, when it was given
+    pub write_config: Option<PathBuf>,
+}
+
+// Changes into the data directory and returns the config files to use. A
+// relative --config or --write-config is taken relative to where we were
+// started from
+pub fn enter_data_dir(matches: &ArgMatches) -> anyhow::Result<ConfigPaths> {
+    let config_path = match matches.get_one::<PathBuf>("config") {
+        Some(path) => Some(std::path::absolute(path)?),
+        None => None,
+    };
+    let write_config_path = match matches.get_one::<PathBuf>("write-config") {
+        Some(path) => Some(std::path::absolute(path)?),
+        None => None,
+    };
+
+    match matches.get_one::<PathBuf>("data-dir") {
+        Some(dir) => std::env::set_current_dir(dir)
+            .with_context(|| format!("Could not enter data directory {}", dir.display()))?,
+        None => common::set_cwd_to_exe()?,
+    }
+
+    let write_config = matches.contains_id("write-config").then(|| {
+        // a commented toml template unless a json file was asked for
+        write_config_path
+            .or_else(|| config_path.clone())
+            .unwrap_or_else(|| PathBuf::from(constants::CONFIG_TOML_LOCATION))
+    });
+
+    // a config.json is only read when there is one, a first start writes the
+    // toml template
+    let config = config_path.unwrap_or_else(|| {
+        if !Path::new(constants::CONFIG_TOML_LOCATION).exists()
+            && Path::new(constants::CONFIG_LOCATION).exists()
+        {
+            PathBuf::from(constants::CONFIG_LOCATION)
+        } else {
+            PathBuf::from(constants::CONFIG_TOML_LOCATION)
+        }
+    });
+
+    Ok(ConfigPaths {
+        config,
+        write_config,
+    })
+}
+
+// Commands that work without a valid config
+pub fn handle_config_commands(matches: &ArgMatches, paths: &ConfigPaths) -> anyhow::Result<()> {
+    let config_path = &paths.config;
+    if let Some(path) = &paths.write_config {
+        if path.exists() {
+            eprintln!("{} already exists, not overwriting it", path.display());
+            std::process::exit(1);
+        }
+        config::write_default_config(path)?;
+        println!("Wrote default config to {}!", path.display());
+        std::process::exit(0);
+    }
+
+    if matches.get_flag("check-config") {
+        match config::read_config(config_path) {
+            Ok(_) => {
+                println!("Config {} is valid", config_path.display());
+                std::process::exit(0);
+            }
+            Err(e) => {
+                eprintln!("{}", e);
+                std::process::exit(1);
+            }
+        }
+    }
+
+    Ok(())
+}
+
+// Backups copy the sqlite file, which holds nothing in use with postgres
+fn sqlite_driver(flag: &str) -> anyhow::Result<()> {
+    if CONFIG.sql.driver == SqlDriver::Postgres {
+        anyhow::bail!(
+            "{} only works with sql.driver sqlite, back up postgres with its own tools like pg_dump",
+            flag
         );
-    app
+    }
+    Ok(())
 }
 
-async fn handle_matches(matches: ArgMatches) -> anyhow::Result<()> {
-    if matches.contains_id("write-config") {
-        config::write_default_config(crate::constants::CONFIG_LOCATION)?;
-        println!("Wrote default config!");
+async fn handle_matches(
+    matches: ArgMatches,
+    pool: &Pool,
+    spords: &dyn SpordStore,
+    users: &dyn UserStore,
+    locations: &dyn LocationStore,
+    webhooks: &dyn WebhookStore,
+) -> anyhow::Result<()> {
+    if let Some(("spord", matches)) = matches.subcommand() {
+        spord::handle(matches, spords, locations, webhooks).await?;
         std::process::exit(0);
     }
 
     if matches.contains_id("create-user") {
-        let username = if let Some(username) = matches.get_one::<String>("create-user") {
-            Some(username.to_owned())
+        let username = matches.get_one::<String>("create-user").cloned();
+
+        create_user_console(users, username).await?;
+    }
+
+    if let Some(username) = matches.get_one::<String>("unlock-user") {
+        if users
+            .clear_login_failures(AttemptKind::Username, username)
+            .await?
+        {
+            println!("Unlocked user {}!", username);
+        } else {
+            println!("User {} was not locked", username);
+        }
+        std::process::exit(0);
+    }
+
+    if matches.get_flag("list-sessions") {
+        println!(
+            "{:>6}  {:<20} {:<20} {:<20} Expires",
+            "Id", "User", "Created", "Last seen"
+        );
+        for session in users.sessions().await? {
+            println!(
+                "{:>6}  {:<20} {:<20} {:<20} {}",
+                session.id,
+                session.username.unwrap_or_default(),
+                session.created.format(DATE_FORMAT).to_string(),
+                session.last_seen.format(DATE_FORMAT).to_string(),
+                session.expires.format(DATE_FORMAT),
+            );
+        }
+        std::process::exit(0);
+    }
+
+    if let Some(id) = matches.get_one::<i64>("revoke-session") {
+        if users.revoke_session(*id).await? {
+            println!("Revoked session {}!", id);
+        } else {
+            println!("No session with id {}", id);
+        }
+        std::process::exit(0);
+    }
+
+    if let Some(username) = matches.get_one::<String>("revoke-user-sessions") {
+        let count = users.revoke_user_sessions(username).await?;
+        println!("Revoked {} sessions of {}", count, username);
+        std::process::exit(0);
+    }
+
+    if let Some(name) = matches.get_one::<String>("create-token") {
+        let username = matches.get_one::<String>("token-user");
+        let scope = TokenScope::from_sql(matches.get_one::<String>("token-scope").unwrap());
+        let expires = matches
+            .get_one::<i64>("token-expires-days")
+            .map(|days| Utc::now() + Duration::days(*days));
+        let location = match matches.get_one::<String>("token-location") {
+            Some(location_name) => match locations.find(location_name).await? {
+                Some(location) => Some(location.id),
+                None => anyhow::bail!("No location named {}", location_name),
+            },
+            None => None,
+        };
+
+        let (id, token) = users
+            .create_token(name, username.map(|u| u.as_str()), scope, expires, location)
+            .await?;
+        println!("Created token {} ({}):", id, name);
+        println!("{}", token);
+        println!("It will not be shown again!");
+        std::process::exit(0);
+    }
+
+    if matches.get_flag("list-tokens") {
+        let locations = locations.list().await?;
+        println!(
+            "{:>6}  {:<20} {:<20} {:<16} {:<6} {:<20} {:<20} Last used",
+            "Id", "Name", "User", "Store", "Scope", "Created", "Expires"
+        );
+        for token in users.tokens().await? {
+            let format_date = |date: Option<chrono::DateTime<Utc>>| {
+                date.map(|date| date.format(DATE_FORMAT).to_string())
+                    .unwrap_or_else(|| "never".to_string())
+            };
+            // user tokens see what their user sees
+            let store = match (&token.username, token.location) {
+                (Some(_), _) => "",
+                (None, None) => "<all>",
+                (None, Some(id)) => locations
+                    .iter()
+                    .find(|location| location.id == id)
+                    .map(|location| location.name.as_str())
+                    .unwrap_or("?"),
+            };
+            println!(
+                "{:>6}  {:<20} {:<20} {:<16} {:<6} {:<20} {:<20} {}",
+                token.id,
+                token.name,
+                token.username.as_deref().unwrap_or("<service>"),
+                store,
+                token.scope.as_sql(),
+                token.created.format(DATE_FORMAT).to_string(),
+                format_date(token.expires),
+                format_date(token.last_used),
+            );
+        }
+        std::process::exit(0);
+    }
+
+    if let Some(id) = matches.get_one::<i64>("revoke-token") {
+        if users.revoke_token(*id, None).await? {
+            println!("Revoked token {}!", id);
         } else {
+            println!("No token with id {}", id);
+        }
+        std::process::exit(0);
+    }
+
+    if matches.get_flag("backup") {
+        sqlite_driver("--backup")?;
+        let path =
+            sql::backup::create(pool, Path::new(&CONFIG.backup.dir), CONFIG.backup.keep).await?;
+        println!("Wrote backup {}", path.display());
+        std::process::exit(0);
+    }
+
+    if matches.contains_id("copy-to-postgres") {
+        let url =
+            match matches.get_one::<String>("copy-to-postgres") {
+                Some(url) => url.as_str(),
+                None => CONFIG.sql.postgres_url.as_deref().ok_or_else(|| {
+                    anyhow::anyhow!("No URL given and sql.postgres_url is not set")
+                })?,
+            };
+
+        let target = PostgresStore::connect(url, CONFIG.sql.postgres_ca_file.as_deref(), 1).await?;
+        let copied = postgres::copy_from_sqlite(pool, &target).await?;
+        println!(
+            "Copied {} locations, {} users, {} api tokens, {} sessions, {} saved filters, {} \
+                spords, {} history entries, {} notes, {} attachments, {} webhooks and {} \
+                webhook deliveries from {}",
+            copied.locations,
+            copied.users,
+            copied.tokens,
+            copied.sessions,
+            copied.filters,
+            copied.spords,
+            copied.events,
+            copied.notes,
+            copied.attachments,
+            copied.webhooks,
+            copied.deliveries,
+            CONFIG.sql.location
+        );
+        println!("Set sql.driver to \"postgres\" to use it");
+        std::process::exit(0);
+    }
+
+    if let Some(source) = matches.get_one::<PathBuf>("restore") {
+        sqlite_driver("--restore")?;
+        let saved = sql::backup::restore(pool, source, Path::new(&CONFIG.backup.dir)).await?;
+        println!("Restored {}!", source.display());
+        println!("The previous database was saved as {}", saved.display());
+        std::process::exit(0);
+    }
+
+    if matches.get_flag("list-users") {
+        let locations = locations.list().await?;
+        println!("{:<20} {:<8} {:<20} Manager", "User", "Enabled", "Location");
+        for user in users.list().await? {
+            let location = locations
+                .iter()
+                .find(|location| Some(location.id) == user.location)
+                .map(|location| location.name.as_str())
+                .unwrap_or("");
+            println!(
+                "{:<20} {:<8} {:<20} {}",
+                user.username, user.enabled, location, user.manager
+            );
+        }
+        std::process::exit(0);
+    }
+
+    if let Some(name) = matches.get_one::<String>("create-location") {
+        let id = locations.create(name).await?;
+        println!("Created location {} ({})", name, id);
+        std::process::exit(0);
+    }
+
+    if matches.get_flag("list-locations") {
+        println!("{:>6}  Name", "Id");
+        for location in locations.list().await? {
+            println!("{:>6}  {}", location.id, location.name);
+        }
+        std::process::exit(0);
+    }
+
+    if let Some(mut values) = matches.get_many::<String>("set-user-location") {
+        let username = values.next().unwrap();
+        let location_name = values.next().unwrap();
+
+        let location = if location_name == "none" {
             None
+        } else {
+            match locations.find(location_name).await? {
+                Some(location) => Some(location.id),
+                None => anyhow::bail!("No location named {}", location_name),
+            }
         };
 
-        crate::sql::user_create_console(None, username).await?;
+        if users.set_location(username, location).await? {
+            println!("Set location of {} to {}", username, location_name);
+        } else {
+            println!("No user named {}", username);
+        }
+        std::process::exit(0);
+    }
+
+    if let Some(mut values) = matches.get_many::<String>("set-manager") {
+        let username = values.next().unwrap();
+        let manager: bool = values.next().unwrap().parse()?;
+
+        if users.set_manager(username, manager).await? {
+            println!("Set manager of {} to {}", username, manager);
+        } else {
+            println!("No user named {}", username);
+        }
+        std::process::exit(0);
     }
 
     Ok(())
 }
 
-pub async fn handle_cli() -> anyhow::Result<()> {
-    let matches = gen_clap().get_matches();
+async fn create_user_console(
+    users: &dyn UserStore,
+    username: Option<String>,
+) -> anyhow::Result<()> {
+    let username = if let Some(username) = username {
+        username
+    } else {
+        crate::prompt_user_input("Username: ")?
+    };
+
+    let password1 = rpassword::prompt_password("Password: ")?;
+    let password2 = rpassword::prompt_password("Password again: ")?;
+
+    if password1 == password2 {
+        users.create(&username, &password1).await?;
+        println!("User {} created!", username);
+    } else {
+        panic!("Passwords do not match! Please try again");
+    }
+
+    Ok(())
+}
 
-    handle_matches(matches).await?;
+pub async fn handle_cli(
+    matches: ArgMatches,
+    pool: &Pool,
+    spords: &dyn SpordStore,
+    users: &dyn UserStore,
+    locations: &dyn LocationStore,
+    webhooks: &dyn WebhookStore,
+) -> anyhow::Result<()> {
+    handle_matches(matches, pool, spords, users, locations, webhooks).await?;
 
     Ok(())
 }
@@ -0,0 +1,563 @@
+use super::DATE_FORMAT;
+use crate::attachments;
+use crate::reports::csv_text;
+use crate::retention;
+use crate::sql::models::{
+    self, LocationRecord, SpordEventKind, SpordRecord, SpordState, WebhookEvent,
+};
+use crate::sql::{LocationFilter, SpordFilter};
+use crate::store::{LocationStore, SpordStore, WebhookStore};
+use crate::webhooks;
+use chrono::Utc;
+use clap::{Arg, ArgAction, ArgMatches, Command};
+use serde_json::json;
+use std::io::Write;
+use std::path::PathBuf;
+
+pub fn gen_command() -> Command {
+    let state_names: Vec<&str> = SpordState::ALL.iter().map(|state| state.name()).collect();
+
+    Command::new("spord")
+        .about("Manage spords without the web interface")
+        .subcommand_required(true)
+        .subcommand(
+            Command::new("list")
+                .about("List spords")
+                .args(filter_args(&state_names))
+                .arg(format_arg(&["table", "json"])),
+        )
+        .subcommand(
+            Command::new("show")
+                .about("Show one spord with its history")
+                .arg(id_arg())
+                .arg(format_arg(&["table", "json"])),
+        )
+        .subcommand(
+            Command::new("create")
+                .about("Create a spord")
+                .arg(
+                    Arg::new("customer")
+                        .long("customer")
+                        .required(true)
+                        .help("Customer name"),
+                )
+                .arg(Arg::new("part").long("part").required(true))
+                .args(detail_args())
+                .arg(
+                    Arg::new("state")
+                        .long("state")
+                        .value_parser(state_names.clone())
+                        .default_value("pending"),
+                )
+                .arg(
+                    Arg::new("location")
+                        .long("location")
+                        .value_name("NAME")
+                        .help("Store the spord belongs to"),
+                )
+                .arg(format_arg(&["table", "json"])),
+        )
+        .subcommand(
+            Command::new("set-state")
+                .about("Change the state of a spord")
+                .arg(id_arg())
+                .arg(
+                    Arg::new("state")
+                        .required(true)
+                        .value_parser(state_names.clone()),
+                ),
+        )
+        .subcommand(
+            Command::new("update")
+                .about("Change details of a spord, an empty value clears optional fields")
+                .arg(id_arg())
+                .arg(Arg::new("customer").long("customer").help("Customer name"))
+                .arg(Arg::new("part").long("part"))
+                .args(detail_args())
+                .arg(format_arg(&["table", "json"])),
+        )
+        .subcommand(
+            Command::new("archive")
+                // delete archives too, purge is the only way to delete for good
+                .visible_alias("delete")
+                .about("Hide a spord from the lists, keeping everything")
+                .arg(id_arg()),
+        )
+        .subcommand(
+            Command::new("restore")
+                .about("Bring an archived spord back")
+                .arg(id_arg()),
+        )
+        .subcommand(
+            Command::new("purge")
+                .about("Delete a spord for good with its history and attachments, archive hides it instead")
+                .arg(id_arg())
+                .arg(
+                    Arg::new("yes")
+                        .long("yes")
+                        .action(ArgAction::SetTrue)
+                        .help("Confirm the spord should be deleted for good"),
+                ),
+        )
+        .subcommand(
+            Command::new("retention")
+                .about("Archive and purge spords as set in the retention section now"),
+        )
+        .subcommand(
+            Command::new("export")
+                .about("Export spords for spreadsheets or other tools")
+                .args(filter_args(&state_names))
+                .arg(format_arg(&["csv", "json"]))
+                .arg(
+                    Arg::new("output")
+                        .long("output")
+                        .short('o')
+                        .value_name("FILE")
+                        .value_parser(value_parser!(PathBuf))
+                        .help("Write to FILE instead of stdout"),
+                ),
+        )
+}
+
+fn id_arg() -> Arg {
+    Arg::new("id")
+        .required(true)
+        .value_name("ID")
+        .value_parser(value_parser!(i32))
+}
+
+fn format_arg(formats: &[&'static str]) -> Arg {
+    Arg::new("format")
+        .long("format")
+        .value_parser(formats.to_vec())
+        .default_value(formats[0])
+}
+
+// Same filters as the spord list of the api
+fn filter_args(state_names: &[&'static str]) -> Vec<Arg> {
+    vec![
+        Arg::new("location")
+            .long("location")
+            .value_name("NAME")
+            .help("Only spords of this store, \"none\" for spords without one"),
+        Arg::new("state")
+            .long("state")
+            .value_parser(state_names.to_vec()),
+        Arg::new("search")
+            .long("search")
+            .value_name("TEXT")
+            .help("Text to look for in customer details and part"),
+        Arg::new("tag")
+            .long("tag")
+            .value_name("TAGS")
+            .help("Only spords with all of these comma separated tags"),
+        Arg::new("archived")
+            .long("archived")
+            .action(ArgAction::SetTrue)
+            .help("Archived spords instead of the active ones"),
+    ]
+}
+
+fn detail_args() -> Vec<Arg> {
+    vec![
+        Arg::new("phone").long("phone"),
+        Arg::new("email").long("email"),
+        Arg::new("vendor").long("vendor"),
+        Arg::new("tags")
+            .long("tags")
+            .value_name("TAGS")
+            .help("Comma separated, replaces the tags of the spord"),
+        Arg::new("note")
+            .long("note")
+            .value_name("TEXT")
+            .help("Add a note to the spord"),
+    ]
+}
+
+pub async fn handle(
+    matches: &ArgMatches,
+    spords: &dyn SpordStore,
+    locations: &dyn LocationStore,
+    webhooks: &dyn WebhookStore,
+) -> anyhow::Result<()> {
+    let locations = locations.list().await?;
+
+    match matches.subcommand() {
+        Some(("list", matches)) => {
+            let spords = spords.list(read_filter(matches, &locations)?).await?;
+            match format(matches) {
+                "json" => println!("{}", serde_json::to_string_pretty(&spords)?),
+                _ => print_table(&spords, &locations),
+            }
+        }
+        Some(("show", matches)) => {
+            let spord = get(matches, spords).await?;
+            let events = spords.events(spord.id).await?;
+            let notes = spords.notes(spord.id).await?;
+            match format(matches) {
+                "json" => println!(
+                    "{}",
+                    serde_json::to_string_pretty(
+                        &json!({ "spord": spord, "events": events, "notes": notes })
+                    )?
+                ),
+                _ => {
+                    print_details(&spord, &locations);
+                    for note in notes {
+                        println!(
+                            "{:<16}{} by {}{}: {}",
+                            "Note:",
+                            note.created.format(DATE_FORMAT),
+                            note.username.as_deref().unwrap_or("-"),
+                            match note.edited {
+                                Some(edited) => format!(" (edited {})", edited.format(DATE_FORMAT)),
+                                None => String::new(),
+                            },
+                            note.text,
+                        );
+                    }
+                    for event in events {
+                        let change = match event.kind {
+                            SpordEventKind::State => format!(
+                                " {} -> {}",
+                                state_name(event.old_value),
+                                state_name(event.new_value)
+                            ),
+                            SpordEventKind::Archive | SpordEventKind::Restore => String::new(),
+                            _ => format!(
+                                " {} -> {}",
+                                location_name(event.old_value, &locations),
+                                location_name(event.new_value, &locations)
+                            ),
+                        };
+                        println!(
+                            "{:<16}{} {:?}{} by {}",
+                            "History:",
+                            event.time.format(DATE_FORMAT),
+                            event.kind,
+                            change,
+                            event.username.as_deref().unwrap_or("-"),
+                        );
+                    }
+                }
+            }
+        }
+        Some(("create", matches)) => {
+            let location = match matches.get_one::<String>("location") {
+                Some(name) => Some(find_location(name, &locations)?),
+                None => None,
+            };
+            let state = read_state(matches).unwrap_or(SpordState::Pending);
+
+            let mut spord = SpordRecord {
+                id: 0,
+                customer_name: matches.get_one::<String>("customer").unwrap().clone(),
+                customer_phone: read_optional(matches, "phone").flatten(),
+                customer_email: read_optional(matches, "email").flatten(),
+                part: matches.get_one::<String>("part").unwrap().clone(),
+                vendor: read_optional(matches, "vendor").flatten(),
+                received_date: (state == SpordState::Received).then(Utc::now),
+                state,
+                creation_date: Utc::now(),
+                location: location.flatten(),
+                tags: matches
+                    .get_one::<String>("tags")
+                    .map(|tags| models::parse_tags(tags))
+                    .unwrap_or_default(),
+                archived: None,
+                anonymized: None,
+            };
+            spord.id = spords.create(spord.clone()).await? as i32;
+            add_note(matches, spords, spord.id).await?;
+            webhooks::notify(webhooks, WebhookEvent::Created, &spord, None, None).await;
+
+            match format(matches) {
+                "json" => println!("{}", serde_json::to_string_pretty(&spord)?),
+                _ => println!("Created spord {}", spord.id),
+            }
+        }
+        Some(("set-state", matches)) => {
+            let mut spord = get(matches, spords).await?;
+            let previous = spord.state.clone();
+            spord.state = read_state(matches).unwrap();
+            if spord.state == SpordState::Received && spord.received_date.is_none() {
+                spord.received_date = Some(Utc::now());
+            }
+
+            spords.update(spord.clone(), None).await?;
+            if spord.state != previous {
+                webhooks::notify(
+                    webhooks,
+                    WebhookEvent::StateChanged,
+                    &spord,
+                    None,
+                    Some(json!({ "state": previous })),
+                )
+                .await;
+            }
+            println!("Spord {} is now {}", spord.id, spord.state.name());
+        }
+        Some(("update", matches)) => {
+            let mut spord = get(matches, spords).await?;
+            if let Some(customer) = matches.get_one::<String>("customer") {
+                spord.customer_name = customer.clone();
+            }
+            if let Some(part) = matches.get_one::<String>("part") {
+                spord.part = part.clone();
+            }
+            if let Some(phone) = read_optional(matches, "phone") {
+                spord.customer_phone = phone;
+            }
+            if let Some(email) = read_optional(matches, "email") {
+                spord.customer_email = email;
+            }
+            if let Some(vendor) = read_optional(matches, "vendor") {
+                spord.vendor = vendor;
+            }
+            if let Some(tags) = matches.get_one::<String>("tags") {
+                spord.tags = models::parse_tags(tags);
+            }
+
+            spords.update(spord.clone(), None).await?;
+            add_note(matches, spords, spord.id).await?;
+            match format(matches) {
+                "json" => println!("{}", serde_json::to_string_pretty(&spord)?),
+                _ => println!("Updated spord {}", spord.id),
+            }
+        }
+        Some(("archive", matches)) => {
+            let spord = get(matches, spords).await?;
+            if !spords.archive(spord.id, None).await? {
+                anyhow::bail!("Spord {} is already archived", spord.id);
+            }
+            if let Some(archived) = spords.get(spord.id).await? {
+                webhooks::notify(webhooks, WebhookEvent::Archived, &archived, None, None).await;
+            }
+            println!("Archived spord {}", spord.id);
+        }
+        Some(("restore", matches)) => {
+            let spord = get(matches, spords).await?;
+            if !spords.restore(spord.id, None).await? {
+                anyhow::bail!("Spord {} is not archived", spord.id);
+            }
+            println!("Restored spord {}", spord.id);
+        }
+        Some(("retention", _)) => {
+            let summary = retention::run(spords, webhooks).await?;
+            println!(
+                "Archived {} and purged {} spords",
+                summary.archived, summary.purged
+            );
+            if summary.failed > 0 {
+                anyhow::bail!("{} spords could not be purged", summary.failed);
+            }
+        }
+        Some(("purge", matches)) => {
+            let spord = get(matches, spords).await?;
+            if !matches.get_flag("yes") {
+                anyhow::bail!(
+                    "Purging deletes spord {} for good, pass --yes to confirm or use archive",
+                    spord.id
+                );
+            }
+            attachments::delete_all(spords, spord.id).await?;
+            spords.delete(spord.id).await?;
+            webhooks::notify(webhooks, WebhookEvent::Deleted, &spord, None, None).await;
+            println!("Purged spord {}", spord.id);
+        }
+        Some(("export", matches)) => {
+            let spords = spords.list(read_filter(matches, &locations)?).await?;
+            let mut out: Box<dyn Write> = match matches.get_one::<PathBuf>("output") {
+                Some(path) => Box::new(std::fs::File::create(path)?),
+                None => Box::new(std::io::stdout()),
+            };
+
+            match format(matches) {
+                "json" => serde_json::to_writer_pretty(&mut out, &spords)?,
+                _ => write_csv(&mut out, &spords, &locations)?,
+            }
+            out.flush()?;
+        }
+        _ => unreachable!("clap requires a subcommand"),
+    }
+
+    Ok(())
+}
+
+// Adds the --note text, if one was given
+async fn add_note(matches: &ArgMatches, spords: &dyn SpordStore, id: i32) -> anyhow::Result<()> {
+    if let Some(text) = read_optional(matches, "note").flatten() {
+        spords.add_note(id, None, text).await?;
+    }
+
+    Ok(())
+}
+
+fn format(matches: &ArgMatches) -> &str {
+    matches.get_one::<String>("format").unwrap()
+}
+
+fn read_state(matches: &ArgMatches) -> Option<SpordState> {
+    matches
+        .get_one::<String>("state")
+        .and_then(|name| SpordState::from_name(name))
+}
+
+// None when the option was not given, Some(None) when it was given empty
+fn read_optional(matches: &ArgMatches, id: &str) -> Option<Option<String>> {
+    matches
+        .get_one::<String>(id)
+        .map(|value| Some(value.clone()).filter(|value| !value.is_empty()))
+}
+
+fn read_filter(matches: &ArgMatches, locations: &[LocationRecord]) -> anyhow::Result<SpordFilter> {
+    let location = match matches.get_one::<String>("location") {
+        Some(name) => Some(LocationFilter(find_location(name, locations)?)),
+        None => None,
+    };
+
+    Ok(SpordFilter {
+        location,
+        state: read_state(matches),
+        search: matches.get_one::<String>("search").cloned(),
+        tags: matches
+            .get_one::<String>("tag")
+            .map(|tags| models::parse_tags(tags))
+            .unwrap_or_default(),
+        archived: matches.get_flag("archived"),
+    })
+}
+
+// "none" stands for no store
+fn find_location(name: &str, locations: &[LocationRecord]) -> anyhow::Result<Option<i64>> {
+    if name == "none" {
+        return Ok(None);
+    }
+    match locations.iter().find(|location| location.name == name) {
+        Some(location) => Ok(Some(location.id)),
+        None => anyhow::bail!("No location named {}", name),
+    }
+}
+
+fn location_name(id: Option<i64>, locations: &[LocationRecord]) -> String {
+    locations
+        .iter()
+        .find(|location| Some(location.id) == id)
+        .map(|location| location.name.clone())
+        .unwrap_or_default()
+}
+
+fn state_name(value: Option<i64>) -> String {
+    value
+        .map(|value| SpordState::from_sql(value as i32).name().to_string())
+        .unwrap_or_default()
+}
+
+async fn get(matches: &ArgMatches, spords: &dyn SpordStore) -> anyhow::Result<SpordRecord> {
+    let id = *matches.get_one::<i32>("id").unwrap();
+    match spords.get(id).await? {
+        Some(spord) => Ok(spord),
+        None => anyhow::bail!("No spord with id {}", id),
+    }
+}
+
+fn print_table(spords: &[SpordRecord], locations: &[LocationRecord]) {
+    println!(
+        "{:>6}  {:<24} {:<16} {:<9} {:<16} {:<17} Received",
+        "Id", "Customer", "Part", "State", "Location", "Created"
+    );
+    for spord in spords {
+        println!(
+            "{:>6}  {:<24} {:<16} {:<9} {:<16} {:<17} {}",
+            spord.id,
+            spord.customer_name,
+            spord.part,
+            spord.state.name(),
+            location_name(spord.location, locations),
+            spord.creation_date.format(DATE_FORMAT).to_string(),
+            spord
+                .received_date
+                .map(|date| date.format(DATE_FORMAT).to_string())
+                .unwrap_or_default(),
+        );
+    }
+}
+
+fn print_details(spord: &SpordRecord, locations: &[LocationRecord]) {
+    let fields = [
+        ("Id:", spord.id.to_string()),
+        ("Customer:", spord.customer_name.clone()),
+        ("Phone:", spord.customer_phone.clone().unwrap_or_default()),
+        ("Email:", spord.customer_email.clone().unwrap_or_default()),
+        ("Part:", spord.part.clone()),
+        ("Vendor:", spord.vendor.clone().unwrap_or_default()),
+        ("State:", spord.state.name().to_string()),
+        ("Location:", location_name(spord.location, locations)),
+        ("Tags:", spord.tags.join(", ")),
+        (
+            "Created:",
+            spord.creation_date.format(DATE_FORMAT).to_string(),
+        ),
+        (
+            "Received:",
+            spord
+                .received_date
+                .map(|date| date.format(DATE_FORMAT).to_string())
+                .unwrap_or_default(),
+        ),
+        (
+            "Archived:",
+            spord
+                .archived
+                .map(|date| date.format(DATE_FORMAT).to_string())
+                .unwrap_or_default(),
+        ),
+        (
+            "Anonymized:",
+            spord
+                .anonymized
+                .map(|date| date.format(DATE_FORMAT).to_string())
+                .unwrap_or_default(),
+        ),
+    ];
+    for (name, value) in fields {
+        println!("{:<16}{}", name, value);
+    }
+}
+
+fn write_csv(
+    out: &mut dyn Write,
+    spords: &[SpordRecord],
+    locations: &[LocationRecord],
+) -> anyhow::Result<()> {
+    let mut writer = csv::Writer::from_writer(out);
+    writer.write_record([
+        "id", "customer", "phone", "email", "part", "vendor", "state", "location", "created",
+        "received", "tags", "archived",
+    ])?;
+    for spord in spords {
+        writer.write_record([
+            spord.id.to_string(),
+            csv_text(&spord.customer_name),
+            csv_text(spord.customer_phone.as_deref().unwrap_or_default()),
+            csv_text(spord.customer_email.as_deref().unwrap_or_default()),
+            csv_text(&spord.part),
+            csv_text(spord.vendor.as_deref().unwrap_or_default()),
+            spord.state.name().to_string(),
+            csv_text(&location_name(spord.location, locations)),
+            spord.creation_date.to_rfc3339(),
+            spord
+                .received_date
+                .map(|date| date.to_rfc3339())
+                .unwrap_or_default(),
+            csv_text(&spord.tags.join(",")),
+            spord
+                .archived
+                .map(|date| date.to_rfc3339())
+                .unwrap_or_default(),
+        ])?;
+    }
+    writer.flush()?;
+
+    Ok(())
+}
@@ -1,9 +1,11 @@
-use crate::constants::CONFIG_LOCATION;
 use serde::{Deserialize, Serialize};
 use std::{
     fs::File,
     io::Write,
+    net::{IpAddr, ToSocketAddrs},
     path::{Path, PathBuf},
+    str::FromStr,
+    sync::OnceLock,
 };
 
 #[derive(Debug, Error)]
@@ -13,6 +15,18 @@ pub enum ConfigError {
 
     #[error("Config(Json({0}))")]
     Json(#[from] serde_json::Error),
+
+    #[error("Could not read config {}: {}", .0.display(), .1)]
+    Read(PathBuf, std::io::Error),
+
+    #[error("Could not parse config {}: {}", .0.display(), .1)]
+    Parse(PathBuf, String),
+
+    #[error("Invalid value in {0}: {1}")]
+    Env(String, serde_json::Error),
+
+    #[error("Invalid config {}:\n  - {}", .0.display(), .1)]
+    Invalid(PathBuf, String),
 }
 type Result<T> = std::result::Result<T, ConfigError>;
 
@@ -21,6 +35,24 @@ pub struct Config {
     pub log: LogConfig,
     pub sql: SqlConfig,
     pub web: WebConfig,
+    #[serde(default)]
+    pub login: LoginConfig,
+    #[serde(default)]
+    pub session: SessionConfig,
+    #[serde(default)]
+    pub webhooks: WebhookConfig,
+    #[serde(default)]
+    pub backup: BackupConfig,
+    #[serde(default)]
+    pub spords: SpordConfig,
+    #[serde(default)]
+    pub metrics: MetricsConfig,
+    #[serde(default)]
+    pub reports: ReportsConfig,
+    #[serde(default)]
+    pub attachments: AttachmentConfig,
+    #[serde(default)]
+    pub retention: RetentionConfig,
 }
 
 #[derive(Debug, Serialize, Deserialize)]
@@ -29,11 +61,56 @@ pub struct LogConfig {
     pub dir: Option<String>,
     pub stdout: bool,
     pub fileskept: Option<usize>,
+    #[serde(default)]
+    pub format: LogFormat,
+}
+
+#[derive(Debug, Clone, Copy, Default, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum LogFormat {
+    // one human readable line per record
+    #[default]
+    Text,
+    // one json object per line, with the record's key-values as fields
+    Json,
 }
 
 #[derive(Debug, Serialize, Deserialize)]
+#[serde(default)]
 pub struct SqlConfig {
+    // where spords, users, locations, tokens, sessions, failed logins, saved
+    // filters, webhooks and attachment details are kept
+    pub driver: SqlDriver,
     pub location: String,
+    // connection string used by the postgres driver, sslmode=require in it
+    // refuses to connect without TLS
+    pub postgres_url: Option<String>,
+    // PEM file with the CA that signed the postgres server certificate, when
+    // it isn't signed by one of the public roots
+    pub postgres_ca_file: Option<String>,
+    // connections shared by all requests
+    pub pool_size: usize,
+    // how long a query waits for a locked database before failing
+    pub busy_timeout_ms: u64,
+}
+impl Default for SqlConfig {
+    fn default() -> Self {
+        SqlConfig {
+            driver: SqlDriver::Sqlite,
+            location: "spord-tracker.db".to_string(),
+            postgres_url: None,
+            postgres_ca_file: None,
+            pool_size: 4,
+            busy_timeout_ms: 5000,
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum SqlDriver {
+    Sqlite,
+    Postgres,
 }
 
 #[derive(Debug, Serialize, Deserialize)]
@@ -42,49 +119,505 @@ pub struct WebConfig {
     pub https: Option<bool>,
     pub key_file: Option<String>,
     pub privkey_file: Option<String>,
+    // plain http address that redirects everything to https
+    pub redirect_listen: Option<String>,
+    // reverse proxies whose Forwarded or X-Forwarded-For header gives the
+    // client address, for counting failed logins per address
+    #[serde(default)]
+    pub trusted_proxies: Vec<IpAddr>,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(default)]
+pub struct LoginConfig {
+    // failed attempts for one username before it gets locked
+    pub max_user_failures: u32,
+    // failed attempts from one ip address before it gets locked
+    pub max_ip_failures: u32,
+    // first lockout length, doubled for every further failure
+    pub lockout_seconds: i64,
+    pub max_lockout_seconds: i64,
+    // failures older than this are forgotten
+    pub failure_window_seconds: i64,
+}
+impl Default for LoginConfig {
+    fn default() -> Self {
+        LoginConfig {
+            max_user_failures: 5,
+            max_ip_failures: 20,
+            lockout_seconds: 30,
+            max_lockout_seconds: 3600,
+            failure_window_seconds: 86400,
+        }
+    }
 }
 
-pub fn read_config() -> Result<Config> {
-    let path = get_config_location();
+#[derive(Debug, Serialize, Deserialize)]
+#[serde(default)]
+pub struct SessionConfig {
+    // key used to sign session cookies, generated on first start
+    pub key_file: String,
+    // log out after this long without a request
+    pub idle_timeout_seconds: Option<u64>,
+    // log out this long after logging in, no matter what
+    pub absolute_timeout_seconds: Option<u64>,
+    // keep sessions in the database of sql.driver instead of the cookie, so
+    // they can be listed and revoked, sqlite_store is its old name
+    #[serde(alias = "sqlite_store")]
+    pub database_store: bool,
+}
+impl Default for SessionConfig {
+    fn default() -> Self {
+        SessionConfig {
+            key_file: "session.key".to_string(),
+            idle_timeout_seconds: Some(2 * 3600),
+            absolute_timeout_seconds: Some(12 * 3600),
+            database_store: false,
+        }
+    }
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+#[serde(default)]
+pub struct WebhookConfig {
+    // how often the outbox is checked for due deliveries
+    pub poll_seconds: u64,
+    pub timeout_seconds: u64,
+    // first retry delay, doubled for every further attempt
+    pub retry_seconds: i64,
+    // give up on a delivery after this many attempts
+    pub max_attempts: u32,
+    // finished deliveries are dropped from the log after this long, 0 keeps them
+    pub keep_days: u32,
+}
+impl Default for WebhookConfig {
+    fn default() -> Self {
+        WebhookConfig {
+            poll_seconds: 5,
+            timeout_seconds: 10,
+            retry_seconds: 30,
+            max_attempts: 8,
+            keep_days: 30,
+        }
+    }
+}
 
-    let content = std::fs::read_to_string(path)?;
+#[derive(Debug, Serialize, Deserialize)]
+#[serde(default)]
+pub struct BackupConfig {
+    // where --backup and scheduled backups of the sqlite database go
+    pub dir: String,
+    // number of backups kept, older ones are deleted
+    pub keep: usize,
+    // take a backup this often while the server runs, 0 turns it off
+    pub interval_hours: u64,
+}
+impl Default for BackupConfig {
+    fn default() -> Self {
+        BackupConfig {
+            dir: "backups".to_string(),
+            keep: 7,
+            interval_hours: 0,
+        }
+    }
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+#[serde(default)]
+pub struct SpordConfig {
+    // spords still pending or ordered this long after creation are overdue
+    pub overdue_days: u32,
+    // let authors and managers change notes, earlier texts are kept. Notes
+    // can only be added otherwise
+    pub edit_notes: bool,
+}
+impl Default for SpordConfig {
+    fn default() -> Self {
+        SpordConfig {
+            overdue_days: 14,
+            edit_notes: false,
+        }
+    }
+}
+
+#[derive(Debug, Default, Serialize, Deserialize)]
+#[serde(default)]
+pub struct MetricsConfig {
+    // serve prometheus metrics on /metrics
+    pub enabled: bool,
+    // serve /metrics on this address only, instead of next to the web ui
+    pub listen: Option<String>,
+    // when set, scrapers have to send it as a bearer token
+    pub token: Option<String>,
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+#[serde(default)]
+pub struct ReportsConfig {
+    // parts are grouped by this many leading characters
+    pub part_prefix_length: usize,
+    // reports cover spords created this many months back, 0 for all of them
+    pub months: u32,
+}
+impl Default for ReportsConfig {
+    fn default() -> Self {
+        ReportsConfig {
+            part_prefix_length: 3,
+            months: 12,
+        }
+    }
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+#[serde(default)]
+pub struct AttachmentConfig {
+    // uploaded files, stored once per content under their sha256. Servers
+    // sharing a postgres database need to share this directory too
+    pub dir: String,
+    // largest single upload
+    pub max_file_bytes: u64,
+    // all attachments of one spord together
+    pub max_spord_bytes: u64,
+    // types uploads may have, as sniffed from their content
+    pub allowed_types: Vec<String>,
+}
+impl Default for AttachmentConfig {
+    fn default() -> Self {
+        AttachmentConfig {
+            dir: "attachments".to_string(),
+            max_file_bytes: 10 * 1024 * 1024,
+            max_spord_bytes: 50 * 1024 * 1024,
+            allowed_types: [
+                "application/pdf",
+                "image/jpeg",
+                "image/png",
+                "image/gif",
+                "image/webp",
+                "image/heif",
+                "text/plain",
+            ]
+            .map(String::from)
+            .to_vec(),
+        }
+    }
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+#[serde(default)]
+pub struct RetentionConfig {
+    // picked up spords are archived this many days after pickup, 0 turns it off
+    pub archive_after_days: u32,
+    // archived spords are purged this many days after archiving, 0 keeps them
+    pub purge_after_days: u32,
+    pub purge_mode: PurgeMode,
+    // how often the server archives and purges, 0 leaves it to the command line
+    pub interval_hours: u64,
+}
+impl Default for RetentionConfig {
+    fn default() -> Self {
+        RetentionConfig {
+            archive_after_days: 90,
+            purge_after_days: 0,
+            purge_mode: PurgeMode::Anonymize,
+            interval_hours: 24,
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum PurgeMode {
+    // delete the spord with its history, notes and attachments
+    Delete,
+    // keep the spord and its history for the reports, without customer
+    // details, notes and attachments
+    Anonymize,
+}
+
+// Commented template for --write-config, every option with its default
+pub const TOML_TEMPLATE: &str = include_str!("../config-template.toml");
+
+// Config files ending in .toml are toml, anything else is json
+pub fn is_toml(path: &Path) -> bool {
+    path.extension()
+        .is_some_and(|extension| extension == "toml")
+}
 
-    let config: Config = serde_json::from_str(&content)?;
+// Prefix of environment variables overriding config fields, e.g.
+// SPORD_WEB_LISTEN or SPORD_SESSION_IDLE_TIMEOUT_SECONDS
+pub const ENV_PREFIX: &str = "SPORD_";
+
+// Longest lockout login.max_lockout_seconds may ask for
+const MAX_LOCKOUT_SECONDS: i64 = 365 * 86400;
+
+static LOADED: OnceLock<Config> = OnceLock::new();
+
+// The config set by `set` at startup
+pub fn get() -> &'static Config {
+    LOADED.get().expect("config is loaded before it is used")
+}
+
+pub fn set(config: Config) {
+    if LOADED.set(config).is_err() {
+        panic!("config loaded twice");
+    }
+}
+
+// Reads the config file, applies environment overrides and validates it
+pub fn read_config(path: &Path) -> Result<Config> {
+    let content =
+        std::fs::read_to_string(path).map_err(|e| ConfigError::Read(path.to_path_buf(), e))?;
+
+    let parsed = if is_toml(path) {
+        toml::from_str::<Config>(&content).map_err(|e| e.to_string())
+    } else {
+        serde_json::from_str::<Config>(&content).map_err(|e| e.to_string())
+    };
+    let config = parsed.map_err(|e| ConfigError::Parse(path.to_path_buf(), e))?;
+
+    // serialize once so fields left out of the file can be overridden too
+    let mut value = serde_json::to_value(&config)?;
+    apply_env_overrides(&mut value, std::env::vars())?;
+
+    let config: Config = serde_json::from_value(value)?;
+    let problems = config.validate();
+    if !problems.is_empty() {
+        return Err(ConfigError::Invalid(
+            path.to_path_buf(),
+            problems.join("\n  - "),
+        ));
+    }
 
     Ok(config)
 }
 
-pub fn get_config_location() -> PathBuf {
-    Path::new(crate::constants::CONFIG_LOCATION).to_path_buf()
+// Replaces every field that has a SPORD_<SECTION>_<FIELD> variable set
+fn apply_env_overrides(
+    value: &mut serde_json::Value,
+    vars: impl Iterator<Item = (String, String)>,
+) -> Result<()> {
+    use serde_json::Value;
+
+    let vars: Vec<(String, String)> = vars
+        .filter(|(name, _)| name.starts_with(ENV_PREFIX))
+        .collect();
+
+    let mut fields = Vec::new();
+    if let Value::Object(sections) = &*value {
+        for (section, fields_value) in sections {
+            if let Value::Object(section_fields) = fields_value {
+                fields.extend(
+                    section_fields
+                        .keys()
+                        .map(|field| (section.clone(), field.clone())),
+                );
+            }
+        }
+    }
+
+    for (section, field) in fields {
+        let var_name = format!("{}{}_{}", ENV_PREFIX, section, field).to_uppercase();
+        let Some((_, raw)) = vars.iter().find(|(name, _)| *name == var_name) else {
+            continue;
+        };
+
+        // numbers, booleans and null are given as json, anything else is
+        // taken as a string. Optional strings that look like numbers fall
+        // back to a string when the number does not fit
+        let mut candidates = Vec::new();
+        if !value[&section][&field].is_string() {
+            if let Ok(parsed) = serde_json::from_str::<Value>(raw) {
+                candidates.push(parsed);
+            }
+        }
+        candidates.push(Value::String(raw.clone()));
+
+        let mut last_error = None;
+        for candidate in candidates {
+            let mut attempt = value.clone();
+            attempt[&section][&field] = candidate;
+            match serde_json::from_value::<Config>(attempt.clone()) {
+                Ok(_) => {
+                    *value = attempt;
+                    last_error = None;
+                    break;
+                }
+                Err(e) => last_error = Some(e),
+            }
+        }
+        if let Some(e) = last_error {
+            return Err(ConfigError::Env(var_name, e));
+        }
+    }
+
+    Ok(())
+}
+
+impl Config {
+    // Everything that would only fail later at startup, as readable messages
+    pub fn validate(&self) -> Vec<String> {
+        let mut problems = Vec::new();
+
+        for directive in self.log.level.split(',') {
+            let level = directive
+                .rsplit_once('=')
+                .map(|(_, level)| level)
+                .unwrap_or(directive)
+                .trim();
+            if log::LevelFilter::from_str(level).is_err() {
+                problems.push(format!(
+                    "log.level: unknown log level \"{}\", use off, error, warn, info, debug or trace",
+                    level
+                ));
+            }
+        }
+
+        let mut listen_addresses = vec![("web.listen", Some(&self.web.listen))];
+        listen_addresses.push(("web.redirect_listen", self.web.redirect_listen.as_ref()));
+        listen_addresses.push(("metrics.listen", self.metrics.listen.as_ref()));
+        for (field, address) in listen_addresses {
+            let Some(address) = address else {
+                continue;
+            };
+            if let Err(e) = address.to_socket_addrs() {
+                problems.push(format!(
+                    "{}: \"{}\" is not a valid listen address ({})",
+                    field, address, e
+                ));
+            }
+        }
+
+        if self.web.https.unwrap_or(false) {
+            let cert_files = [
+                ("web.key_file", &self.web.key_file),
+                ("web.privkey_file", &self.web.privkey_file),
+            ];
+            for (field, file) in cert_files {
+                match file {
+                    None => problems.push(format!("{}: required when web.https is on", field)),
+                    Some(file) if !Path::new(file).is_file() => {
+                        problems.push(format!("{}: file \"{}\" does not exist", field, file))
+                    }
+                    Some(_) => {}
+                }
+            }
+        } else if self.web.redirect_listen.is_some() {
+            problems.push("web.redirect_listen: only used when web.https is on".to_string());
+        }
+
+        if self.sql.location.is_empty() {
+            problems.push("sql.location: must not be empty".to_string());
+        }
+        if self.sql.driver == SqlDriver::Postgres
+            && self.sql.postgres_url.as_deref().unwrap_or("").is_empty()
+        {
+            problems.push("sql.postgres_url: required when sql.driver is postgres".to_string());
+        }
+        if let Some(file) = &self.sql.postgres_ca_file {
+            if !Path::new(file).is_file() {
+                problems.push(format!(
+                    "sql.postgres_ca_file: file \"{}\" does not exist",
+                    file
+                ));
+            }
+        }
+        if self.sql.pool_size == 0 {
+            problems.push("sql.pool_size: must be at least 1".to_string());
+        }
+        if self.backup.keep == 0 {
+            problems.push("backup.keep: must be at least 1".to_string());
+        }
+        if self.spords.overdue_days == 0 {
+            problems.push("spords.overdue_days: must be at least 1".to_string());
+        }
+        if self.reports.part_prefix_length == 0 {
+            problems.push("reports.part_prefix_length: must be at least 1".to_string());
+        }
+        if self.webhooks.poll_seconds == 0 {
+            problems.push("webhooks.poll_seconds: must be at least 1".to_string());
+        }
+        if self.login.max_user_failures == 0 {
+            problems.push("login.max_user_failures: must be at least 1".to_string());
+        }
+        if self.login.max_ip_failures == 0 {
+            problems.push("login.max_ip_failures: must be at least 1".to_string());
+        }
+        if self.login.lockout_seconds < 1 {
+            problems.push("login.lockout_seconds: must be at least 1".to_string());
+        }
+        if self.login.failure_window_seconds < 1 {
+            problems.push("login.failure_window_seconds: must be at least 1".to_string());
+        }
+        if self.login.max_lockout_seconds > MAX_LOCKOUT_SECONDS {
+            problems.push(format!(
+                "login.max_lockout_seconds: must be at most {} (a year)",
+                MAX_LOCKOUT_SECONDS
+            ));
+        } else if self.login.max_lockout_seconds < self.login.lockout_seconds {
+            problems.push(
+                "login.max_lockout_seconds: must be at least login.lockout_seconds".to_string(),
+            );
+        }
+        if self.attachments.dir.is_empty() {
+            problems.push("attachments.dir: must not be empty".to_string());
+        }
+        if self.attachments.max_file_bytes == 0 {
+            problems.push("attachments.max_file_bytes: must be at least 1".to_string());
+        }
+        if self.attachments.max_spord_bytes < self.attachments.max_file_bytes {
+            problems.push(
+                "attachments.max_spord_bytes: must be at least attachments.max_file_bytes"
+                    .to_string(),
+            );
+        }
+
+        problems
+    }
 }
 
-// Checks if the config exists, and returns false if it didn't
-pub fn check_config_exists() -> Result<bool> {
-    if !std::fs::exists(CONFIG_LOCATION)? {
-        write_default_config(CONFIG_LOCATION)?;
+// Writes a default config if there is none yet, returns false if it did
+pub fn check_config_exists(path: &Path) -> Result<bool> {
+    if !std::fs::exists(path)? {
+        write_default_config(path)?;
         Ok(false)
     } else {
         Ok(true)
     }
 }
 
-pub fn write_default_config(path: &str) -> Result<()> {
+pub fn write_default_config(path: &Path) -> Result<()> {
+    if is_toml(path) {
+        let mut file = File::create(path)?;
+        file.write_all(TOML_TEMPLATE.as_bytes())?;
+        return Ok(());
+    }
+
     let config = Config {
         log: LogConfig {
             level: "info".to_string(),
             dir: None,
             stdout: true,
             fileskept: None,
+            format: LogFormat::Text,
         },
-        sql: SqlConfig {
-            location: "spord-tracker.db".to_string(),
-        },
+        sql: SqlConfig::default(),
         web: WebConfig {
             listen: "127.0.0.1:8080".to_string(),
             https: Some(false),
             key_file: Some("key.crt".to_string()),
             privkey_file: Some("privkey.key".to_string()),
+            redirect_listen: None,
+            trusted_proxies: vec![],
         },
+        login: LoginConfig::default(),
+        session: SessionConfig::default(),
+        webhooks: WebhookConfig::default(),
+        backup: BackupConfig::default(),
+        spords: SpordConfig::default(),
+        metrics: MetricsConfig::default(),
+        reports: ReportsConfig::default(),
+        attachments: AttachmentConfig::default(),
+        retention: RetentionConfig::default(),
     };
 
     let config_content = serde_json::to_string_pretty(&config)?;
@@ -93,3 +626,199 @@ pub fn write_default_config(path: &str) -> Result<()> {
 
     Ok(())
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::prelude::v1::test;
+
+    // edits a valid config so it has one problem
+    type Change = fn(&mut Config);
+
+    fn template() -> Config {
+        toml::from_str(TOML_TEMPLATE).unwrap()
+    }
+
+    fn vars(vars: &[(&str, &str)]) -> impl Iterator<Item = (String, String)> {
+        vars.iter()
+            .map(|(name, value)| (name.to_string(), value.to_string()))
+            .collect::<Vec<_>>()
+            .into_iter()
+    }
+
+    fn with_env(env: &[(&str, &str)]) -> Result<Config> {
+        let mut value = serde_json::to_value(template())?;
+        apply_env_overrides(&mut value, vars(env))?;
+        Ok(serde_json::from_value(value)?)
+    }
+
+    #[test]
+    fn env_overrides_nested_fields() {
+        let config = with_env(&[
+            ("SPORD_SESSION_IDLE_TIMEOUT_SECONDS", "60"),
+            ("SPORD_SESSION_ABSOLUTE_TIMEOUT_SECONDS", "null"),
+            ("SPORD_SQL_DRIVER", "postgres"),
+            ("SPORD_SQL_POOL_SIZE", "9"),
+            ("SPORD_WEB_LISTEN", "0.0.0.0:80"),
+            // optional strings that look like numbers stay strings
+            ("SPORD_SQL_POSTGRES_URL", "5432"),
+            ("SPORD_WEB_TRUSTED_PROXIES", "[\"10.0.0.1\"]"),
+            ("SPORD_UNKNOWN_FIELD", "1"),
+            ("OTHER_WEB_LISTEN", "nowhere"),
+        ])
+        .unwrap();
+
+        assert_eq!(config.session.idle_timeout_seconds, Some(60));
+        assert_eq!(config.session.absolute_timeout_seconds, None);
+        assert_eq!(config.sql.driver, SqlDriver::Postgres);
+        assert_eq!(config.sql.pool_size, 9);
+        assert_eq!(config.web.listen, "0.0.0.0:80");
+        assert_eq!(config.sql.postgres_url.as_deref(), Some("5432"));
+        assert_eq!(
+            config.web.trusted_proxies,
+            ["10.0.0.1".parse::<IpAddr>().unwrap()]
+        );
+    }
+
+    #[test]
+    fn env_override_of_the_wrong_type_names_the_variable() {
+        for (name, value) in [
+            ("SPORD_SQL_POOL_SIZE", "many"),
+            ("SPORD_SQL_POOL_SIZE", "-1"),
+            ("SPORD_SQL_DRIVER", "oracle"),
+            ("SPORD_WEB_HTTPS", "maybe"),
+        ] {
+            match with_env(&[(name, value)]) {
+                Err(ConfigError::Env(var_name, _)) => assert_eq!(var_name, name),
+                other => panic!("{}={} gave {:?}", name, value, other.map(|_| ())),
+            }
+        }
+    }
+
+    #[test]
+    fn template_is_valid() {
+        assert_eq!(template().validate(), Vec::<String>::new());
+    }
+
+    #[test]
+    fn validate_reports_each_problem() {
+        let cases: &[(Change, &str)] = &[
+            (
+                |config| config.log.level = "info,spord_tracker=loud".to_string(),
+                "log.level: unknown log level \"loud\"",
+            ),
+            (
+                |config| config.web.listen = "nowhere".to_string(),
+                "web.listen: \"nowhere\" is not a valid listen address",
+            ),
+            (
+                |config| config.metrics.listen = Some("nowhere".to_string()),
+                "metrics.listen: \"nowhere\" is not a valid listen address",
+            ),
+            (
+                |config| config.web.redirect_listen = Some("127.0.0.1:80".to_string()),
+                "web.redirect_listen: only used when web.https is on",
+            ),
+            (
+                |config| {
+                    config.web.https = Some(true);
+                    config.web.key_file = None;
+                    config.web.privkey_file = Some("Cargo.toml".to_string());
+                },
+                "web.key_file: required when web.https is on",
+            ),
+            (
+                |config| {
+                    config.web.https = Some(true);
+                    config.web.key_file = Some("Cargo.toml".to_string());
+                    config.web.privkey_file = Some("missing.key".to_string());
+                },
+                "web.privkey_file: file \"missing.key\" does not exist",
+            ),
+            (
+                |config| config.sql.location = String::new(),
+                "sql.location: must not be empty",
+            ),
+            (
+                |config| config.sql.driver = SqlDriver::Postgres,
+                "sql.postgres_url: required when sql.driver is postgres",
+            ),
+            (
+                |config| config.sql.postgres_ca_file = Some("missing.pem".to_string()),
+                "sql.postgres_ca_file: file \"missing.pem\" does not exist",
+            ),
+            (
+                |config| config.sql.pool_size = 0,
+                "sql.pool_size: must be at least 1",
+            ),
+            (
+                |config| config.backup.keep = 0,
+                "backup.keep: must be at least 1",
+            ),
+            (
+                |config| config.spords.overdue_days = 0,
+                "spords.overdue_days: must be at least 1",
+            ),
+            (
+                |config| config.reports.part_prefix_length = 0,
+                "reports.part_prefix_length: must be at least 1",
+            ),
+            (
+                |config| config.webhooks.poll_seconds = 0,
+                "webhooks.poll_seconds: must be at least 1",
+            ),
+            (
+                |config| config.login.max_user_failures = 0,
+                "login.max_user_failures: must be at least 1",
+            ),
+            (
+                |config| config.login.max_ip_failures = 0,
+                "login.max_ip_failures: must be at least 1",
+            ),
+            (
+                |config| {
+                    config.login.lockout_seconds = 0;
+                    config.login.max_lockout_seconds = 0;
+                },
+                "login.lockout_seconds: must be at least 1",
+            ),
+            (
+                |config| config.login.failure_window_seconds = -1,
+                "login.failure_window_seconds: must be at least 1",
+            ),
+            (
+                |config| config.login.max_lockout_seconds = i64::MAX,
+                "login.max_lockout_seconds: must be at most 31536000",
+            ),
+            (
+                |config| config.login.max_lockout_seconds = config.login.lockout_seconds - 1,
+                "login.max_lockout_seconds: must be at least login.lockout_seconds",
+            ),
+            (
+                |config| config.attachments.dir = String::new(),
+                "attachments.dir: must not be empty",
+            ),
+            (
+                |config| config.attachments.max_file_bytes = 0,
+                "attachments.max_file_bytes: must be at least 1",
+            ),
+            (
+                |config| config.attachments.max_spord_bytes = config.attachments.max_file_bytes - 1,
+                "attachments.max_spord_bytes: must be at least attachments.max_file_bytes",
+            ),
+        ];
+
+        for (change, expected) in cases {
+            let mut config = template();
+            change(&mut config);
+            let problems = config.validate();
+            assert_eq!(problems.len(), 1, "{:?}", problems);
+            assert!(
+                problems[0].starts_with(expected),
+                "{:?} does not start with {:?}",
+                problems[0],
+                expected
+            );
+        }
+    }
+}
@@ -1 +1,4 @@
 pub const CONFIG_LOCATION: &str = "config.json";
+// written on first start, CONFIG_LOCATION is only read when it exists and
+// this doesn't
+pub const CONFIG_TOML_LOCATION: &str = "config.toml";
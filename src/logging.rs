@@ -1,20 +1,47 @@
-use flexi_logger::{Age, Cleanup, Criterion, DeferredNow, Naming};
+use flexi_logger::{Age, Cleanup, Criterion, DeferredNow, LogSpecification, LoggerHandle, Naming};
+use log::kv::{self, VisitSource};
 use log::Record;
+use serde_json::{Map, Value};
+use std::path::PathBuf;
+use std::sync::{Mutex, OnceLock};
 
+use crate::config::LogFormat;
 use crate::{Result, CONFIG};
 
-pub fn setup() -> Result<()> {
-    use flexi_logger::{Duplicate, FileSpec, Logger};
+tokio::task_local! {
+    // id of the web request being handled, set by web::request_id. Records
+    // logged from a conn() closure run on the sqlite thread and go without it
+    pub static REQUEST_ID: String;
+}
+
+// Kept for the whole process, dropping it would stop the logger
+static HANDLE: OnceLock<LoggerHandle> = OnceLock::new();
+// The spec last applied, the handle has no way to read it back
+static SPEC: Mutex<String> = Mutex::new(String::new());
 
-    let log_dir = if let Some(ref dir) = CONFIG.log.dir {
+// The request id of the current task, if it is handling a web request
+pub fn request_id() -> Option<String> {
+    REQUEST_ID.try_with(|id| id.clone()).ok()
+}
+
+// Where log files go, log.dir or "logs"
+pub fn log_dir() -> String {
+    if let Some(ref dir) = CONFIG.log.dir {
         dir.to_string()
     } else {
         String::from("logs")
-    };
+    }
+}
+
+pub fn setup() -> Result<()> {
+    use flexi_logger::{Duplicate, FileSpec, Logger};
 
     let mut log = Logger::try_with_str(&CONFIG.log.level)?
-        .log_to_file(FileSpec::default().directory(log_dir))
-        .format(my_format);
+        .log_to_file(FileSpec::default().directory(log_dir()))
+        .format(match CONFIG.log.format {
+            LogFormat::Text => my_format,
+            LogFormat::Json => json_format,
+        });
 
     if CONFIG.log.stdout {
         log = log.duplicate_to_stderr(Duplicate::All);
@@ -33,11 +60,78 @@ pub fn setup() -> Result<()> {
         log = log.rotate(Criterion::Age(Age::Day), Naming::Timestamps, Cleanup::Never);
     }
 
-    let _log_handle = log.start()?;
+    let handle = log.start()?;
+    *SPEC.lock().unwrap() = CONFIG.log.level.clone();
+    if HANDLE.set(handle).is_err() {
+        anyhow::bail!("logging set up twice");
+    }
+
+    Ok(())
+}
+
+pub fn current_spec() -> String {
+    SPEC.lock().unwrap().clone()
+}
+
+// Replaces the log spec of the running process, e.g.
+// "info, spord_tracker::sql=debug". Not written back to the config
+pub fn set_spec(spec: &str) -> Result<()> {
+    let handle = HANDLE
+        .get()
+        .ok_or_else(|| anyhow::anyhow!("logging is not set up"))?;
+    let parsed = LogSpecification::parse(spec)?;
+    handle.set_new_spec(parsed);
+    *SPEC.lock().unwrap() = spec.to_string();
 
     Ok(())
 }
 
+// Flushes and closes the log files, nothing is logged afterwards
+pub fn shutdown() {
+    if let Some(handle) = HANDLE.get() {
+        handle.flush();
+        handle.shutdown();
+    }
+}
+
+// Applies log.level from the config file on every SIGUSR1, so the level can be
+// changed by editing the config and running `kill -USR1 <pid>`
+#[cfg(unix)]
+pub async fn reload_on_sigusr1(config_path: PathBuf) {
+    use tokio::signal::unix::{signal, SignalKind};
+
+    let mut signals = match signal(SignalKind::user_defined1()) {
+        Ok(signals) => signals,
+        Err(e) => {
+            error!(
+                "Could not listen for SIGUSR1, log level reload disabled: {}",
+                e
+            );
+            return;
+        }
+    };
+
+    while signals.recv().await.is_some() {
+        let level = crate::config::read_config(&config_path)
+            .map_err(anyhow::Error::from)
+            .and_then(|config| {
+                set_spec(&config.log.level)?;
+                Ok(config.log.level)
+            });
+        match level {
+            Ok(level) => info!(
+                "Log level set to \"{}\" from {}",
+                level,
+                config_path.display()
+            ),
+            Err(e) => error!("Could not reload the log level, keeping the old one: {}", e),
+        }
+    }
+}
+
+#[cfg(not(unix))]
+pub async fn reload_on_sigusr1(_config_path: PathBuf) {}
+
 fn my_format(
     w: &mut dyn std::io::Write,
     now: &mut DeferredNow,
@@ -51,5 +145,132 @@ fn my_format(
         record.module_path().unwrap_or("<unnamed>"),
         record.line().unwrap_or(0),
         record.args(),
-    )
+    )?;
+    if let Some(request_id) = request_id() {
+        write!(w, " [request {}]", request_id)?;
+    }
+
+    Ok(())
+}
+
+// Collects a record's key-values, keeping numbers and booleans typed
+struct JsonFields<'a>(&'a mut Map<String, Value>);
+impl<'kvs> VisitSource<'kvs> for JsonFields<'_> {
+    fn visit_pair(
+        &mut self,
+        key: kv::Key<'kvs>,
+        value: kv::Value<'kvs>,
+    ) -> std::result::Result<(), kv::Error> {
+        let value = if let Some(number) = value.to_i64() {
+            Value::from(number)
+        } else if let Some(number) = value.to_u64() {
+            Value::from(number)
+        } else if let Some(boolean) = value.to_bool() {
+            Value::from(boolean)
+        } else {
+            Value::from(value.to_string())
+        };
+        self.0.insert(key.to_string(), value);
+
+        Ok(())
+    }
+}
+
+// One json object per line for log shippers
+fn json_format(
+    w: &mut dyn std::io::Write,
+    now: &mut DeferredNow,
+    record: &Record,
+) -> std::io::Result<()> {
+    // fixed fields first and in this order, a map would sort them
+    let mut fields: Vec<(String, Value)> = vec![
+        (
+            "timestamp".to_string(),
+            Value::from(
+                now.now()
+                    .to_rfc3339_opts(chrono::SecondsFormat::Millis, false),
+            ),
+        ),
+        ("level".to_string(), Value::from(record.level().as_str())),
+        (
+            "module".to_string(),
+            Value::from(record.module_path().unwrap_or("<unnamed>")),
+        ),
+        ("line".to_string(), Value::from(record.line().unwrap_or(0))),
+        (
+            "message".to_string(),
+            Value::from(record.args().to_string()),
+        ),
+    ];
+    if let Some(request_id) = request_id() {
+        fields.push(("request_id".to_string(), Value::from(request_id)));
+    }
+
+    // key-values can't overwrite the fields above
+    let mut extra = Map::new();
+    if let Err(e) = record.key_values().visit(&mut JsonFields(&mut extra)) {
+        extra.insert("kv_error".to_string(), Value::from(e.to_string()));
+    }
+    for (key, value) in extra {
+        if !fields.iter().any(|(name, _)| *name == key) {
+            fields.push((key, value));
+        }
+    }
+
+    write!(w, "{{")?;
+    for (i, (key, value)) in fields.iter().enumerate() {
+        let separator = if i == 0 { "" } else { "," };
+        write!(w, "{}{}:{}", separator, Value::from(key.as_str()), value)?;
+    }
+    write!(w, "}}")
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::prelude::v1::test;
+
+    fn json_line(record: &Record) -> Value {
+        let mut line = vec![];
+        json_format(&mut line, &mut DeferredNow::new(), record).unwrap();
+        let line = String::from_utf8(line).unwrap();
+        assert!(!line.contains('\n'));
+        serde_json::from_str(&line).unwrap()
+    }
+
+    #[test]
+    fn json_lines_carry_the_key_values() {
+        let kvs = [
+            ("username", kv::Value::from("dave \"d\"")),
+            ("spord_id", kv::Value::from(7i64)),
+            ("manager", kv::Value::from(true)),
+            // can't replace a fixed field
+            ("level", kv::Value::from("quiet")),
+        ];
+        let line = REQUEST_ID.sync_scope("req-1".to_string(), || {
+            json_line(
+                &Record::builder()
+                    .args(format_args!("archived spord {}", 7))
+                    .level(log::Level::Warn)
+                    .module_path(Some("spord_tracker::web"))
+                    .line(Some(12))
+                    .key_values(&kvs)
+                    .build(),
+            )
+        });
+
+        assert_eq!(line["level"], "WARN");
+        assert_eq!(line["module"], "spord_tracker::web");
+        assert_eq!(line["line"], 12);
+        assert_eq!(line["message"], "archived spord 7");
+        assert_eq!(line["request_id"], "req-1");
+        assert_eq!(line["username"], "dave \"d\"");
+        assert_eq!(line["spord_id"], 7);
+        assert_eq!(line["manager"], true);
+        assert!(line["timestamp"].is_string());
+
+        // outside of a request there is no request id
+        let line = json_line(&Record::builder().args(format_args!("started")).build());
+        assert!(line.get("request_id").is_none());
+    }
 }
@@ -1,5 +1,3 @@
-use chrono::Utc;
-
 #[macro_use]
 extern crate thiserror;
 #[macro_use]
@@ -8,60 +6,79 @@ extern crate lazy_static;
 extern crate log;
 #[macro_use]
 extern crate clap;
+// this also makes #[test] actix_web's, which needs an async fn. Tests that
+// aren't async import std::prelude::v1::test for the plain one
 #[macro_use]
 extern crate actix_web;
 
+mod attachments;
 mod cli;
 mod common;
 mod config;
 mod constants;
 mod logging;
+mod metrics;
+mod reports;
+mod retention;
 mod sql;
+mod store;
+#[cfg(test)]
+mod testing;
 mod web;
+mod webhooks;
 
 lazy_static! {
-    pub static ref CONFIG: config::Config = config::read_config().unwrap();
+    pub static ref CONFIG: &'static config::Config = config::get();
 }
 type Result<T> = anyhow::Result<T>;
 
+// used by common::winservice, which is turned off for now
+#[allow(dead_code)]
 const SERVICE_NAME: &str = "SPORD TRACKER";
 
 #[actix_web::main]
 async fn main() -> Result<()> {
-    common::set_cwd_to_exe()?;
-
-    if !config::check_config_exists()? {
-        println!("Wrote default config to file, please edit it if necessary!");
+    let matches = cli::parse();
+    let paths = cli::enter_data_dir(&matches)?;
+    cli::handle_config_commands(&matches, &paths)?;
+    let config_path = paths.config;
+
+    if !config::check_config_exists(&config_path)? {
+        println!(
+            "Wrote default config to {}, please edit it if necessary!",
+            config_path.display()
+        );
         return Ok(());
     }
+    match config::read_config(&config_path) {
+        Ok(config) => config::set(config),
+        Err(e) => {
+            eprintln!("{}", e);
+            std::process::exit(1);
+        }
+    }
 
-    sql::check_initialized().await?;
-
-    cli::handle_cli().await?;
-    logging::setup()?;
+    let pool = sql::check_initialized().await?;
 
-    web::start().await?;
+    let (spords, users, locations, webhooks) = store::open(&pool).await?;
 
-    Ok(())
-}
+    cli::handle_cli(
+        matches,
+        &pool,
+        spords.as_ref(),
+        users.as_ref(),
+        locations.as_ref(),
+        webhooks.as_ref(),
+    )
+    .await?;
+    logging::setup()?;
 
-async fn test_spord_creation() -> Result<()> {
-    let mut spord = sql::models::SpordRecord {
-        id: 0,
-        customer_name: "bob".to_string(),
-        customer_email: Some("hello@example.com".to_string()),
-        customer_phone: None,
-        part: "TRA9780".to_string(),
-        state: sql::models::SpordState::Ordered,
-        creation_date: Utc::now(),
-        received_date: None,
-        comments: Some("test field".to_string()),
-    };
-    info!("spord: {:?}", spord);
-    sql::spord_create(None, spord.clone()).await?;
+    actix_web::rt::spawn(logging::reload_on_sigusr1(config_path));
+    let result = web::start(pool, spords, users, locations, webhooks).await;
+    info!("Server stopped");
+    logging::shutdown();
+    result?;
 
-    spord.customer_phone = Some("test phone number!".to_string());
-    sql::spord_update(None, spord).await?;
     Ok(())
 }
 
@@ -0,0 +1,49 @@
+use prometheus::{
+    register_histogram_vec, register_int_counter_vec, register_int_gauge, register_int_gauge_vec,
+    HistogramVec, IntCounterVec, IntGauge, IntGaugeVec, TextEncoder,
+};
+
+lazy_static! {
+    pub static ref HTTP_REQUESTS: IntCounterVec = register_int_counter_vec!(
+        "spord_tracker_http_requests_total",
+        "HTTP requests by route and status",
+        &["method", "route", "status"]
+    )
+    .unwrap();
+    pub static ref HTTP_DURATION: HistogramVec = register_histogram_vec!(
+        "spord_tracker_http_request_duration_seconds",
+        "Time taken to answer HTTP requests",
+        &["method", "route", "status"]
+    )
+    .unwrap();
+    pub static ref LOGINS: IntCounterVec = register_int_counter_vec!(
+        "spord_tracker_logins_total",
+        "Web logins by result: success, failure or locked",
+        &["result"]
+    )
+    .unwrap();
+    pub static ref SQL_DURATION: HistogramVec = register_histogram_vec!(
+        "spord_tracker_sql_query_duration_seconds",
+        "Time taken by spord and user store queries",
+        &["backend", "query"],
+        vec![0.0005, 0.001, 0.0025, 0.005, 0.01, 0.025, 0.05, 0.1, 0.25, 0.5, 1.0]
+    )
+    .unwrap();
+    pub static ref SPORDS: IntGaugeVec =
+        register_int_gauge_vec!("spord_tracker_spords", "Spords by state", &["state"]).unwrap();
+    pub static ref OVERDUE: IntGauge = register_int_gauge!(
+        "spord_tracker_spords_overdue",
+        "Spords still pending or ordered after spords.overdue_days"
+    )
+    .unwrap();
+}
+
+// Every registered metric in the prometheus text format
+pub fn render() -> String {
+    TextEncoder::new()
+        .encode_to_string(&prometheus::gather())
+        .unwrap_or_else(|e| {
+            error!("Could not encode metrics: {}", e);
+            String::new()
+        })
+}
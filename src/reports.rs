@@ -0,0 +1,279 @@
+use crate::sql::models::SpordTurnaround;
+use serde::{Deserialize, Serialize};
+use std::collections::BTreeMap;
+use std::io::Write;
+
+const SECONDS_PER_DAY: f64 = 86400.0;
+
+// What the turnaround reports are broken down by
+#[derive(Debug, Default, PartialEq, Clone, Copy, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum ReportGroup {
+    #[default]
+    Vendor,
+    // the first reports.part_prefix_length characters of the part
+    PartPrefix,
+    // month the spord was created in
+    Month,
+}
+impl ReportGroup {
+    pub const ALL: [ReportGroup; 3] = [Self::Vendor, Self::PartPrefix, Self::Month];
+    pub fn name(&self) -> &'static str {
+        match self {
+            Self::Vendor => "vendor",
+            Self::PartPrefix => "part_prefix",
+            Self::Month => "month",
+        }
+    }
+    pub fn title(&self) -> &'static str {
+        match self {
+            Self::Vendor => "Vendor",
+            Self::PartPrefix => "Part prefix",
+            Self::Month => "Month",
+        }
+    }
+
+    fn key(&self, spord: &SpordTurnaround, part_prefix_length: usize) -> String {
+        match self {
+            Self::Vendor => spord
+                .vendor
+                .as_deref()
+                .map(str::trim)
+                .filter(|vendor| !vendor.is_empty())
+                .unwrap_or("(no vendor)")
+                .to_string(),
+            Self::PartPrefix => {
+                let prefix: String = spord.part.trim().chars().take(part_prefix_length).collect();
+                if prefix.is_empty() {
+                    "(no part)".to_string()
+                } else {
+                    prefix.to_uppercase()
+                }
+            }
+            Self::Month => spord.creation_date.format("%Y-%m").to_string(),
+        }
+    }
+}
+
+// Average, median and 90th percentile of a set of durations, in days. Empty
+// when nothing reached the step yet
+#[derive(Debug, Clone, Default, Serialize)]
+pub struct DurationStats {
+    pub count: usize,
+    pub average: Option<f64>,
+    pub median: Option<f64>,
+    pub p90: Option<f64>,
+}
+impl DurationStats {
+    fn from_seconds(mut seconds: Vec<i64>) -> DurationStats {
+        if seconds.is_empty() {
+            return DurationStats::default();
+        }
+        seconds.sort_unstable();
+
+        let total: i64 = seconds.iter().sum();
+        DurationStats {
+            count: seconds.len(),
+            average: Some(total as f64 / seconds.len() as f64 / SECONDS_PER_DAY),
+            median: percentile(&seconds, 50.0),
+            p90: percentile(&seconds, 90.0),
+        }
+    }
+}
+
+// Linear interpolation between the closest ranks, in days. seconds is sorted,
+// None when it is empty
+fn percentile(seconds: &[i64], percent: f64) -> Option<f64> {
+    let last = seconds.len().checked_sub(1)?;
+    let position = percent / 100.0 * last as f64;
+    let lower = seconds[position.floor() as usize] as f64;
+    let upper = seconds[position.ceil() as usize] as f64;
+
+    Some((lower + (upper - lower) * position.fract()) / SECONDS_PER_DAY)
+}
+
+#[derive(Debug, Clone, Serialize)]
+pub struct ReportRow {
+    // vendor, part prefix or month
+    pub group: String,
+    pub spords: usize,
+    pub created_to_received: DurationStats,
+    pub received_to_picked_up: DurationStats,
+}
+
+// One row per vendor, part prefix or month, sorted by it. Durations that come
+// out negative, like a received date before the creation, are left out
+pub fn build(
+    spords: &[SpordTurnaround],
+    group: ReportGroup,
+    part_prefix_length: usize,
+) -> Vec<ReportRow> {
+    let mut groups: BTreeMap<String, (usize, Vec<i64>, Vec<i64>)> = BTreeMap::new();
+    for spord in spords {
+        let (count, to_received, to_picked_up) = groups
+            .entry(group.key(spord, part_prefix_length))
+            .or_default();
+        *count += 1;
+
+        let Some(received_date) = spord.received_date else {
+            continue;
+        };
+        let waited = (received_date - spord.creation_date).num_seconds();
+        if waited >= 0 {
+            to_received.push(waited);
+        }
+        if let Some(picked_up_date) = spord.picked_up_date {
+            let waited = (picked_up_date - received_date).num_seconds();
+            if waited >= 0 {
+                to_picked_up.push(waited);
+            }
+        }
+    }
+
+    groups
+        .into_iter()
+        .map(|(key, (count, to_received, to_picked_up))| ReportRow {
+            group: key,
+            spords: count,
+            created_to_received: DurationStats::from_seconds(to_received),
+            received_to_picked_up: DurationStats::from_seconds(to_picked_up),
+        })
+        .collect()
+}
+
+// Days with one decimal, empty when there is no value
+pub fn format_days(days: Option<f64>) -> String {
+    days.map(|days| format!("{:.1}", days)).unwrap_or_default()
+}
+
+// A text cell for a CSV export. Spreadsheets run cells starting with one of
+// these as a formula, so customer or user supplied text gets a ' in front
+pub fn csv_text(value: &str) -> String {
+    if value.starts_with(['=', '+', '-', '@', '\t', '\r']) {
+        format!("'{}", value)
+    } else {
+        value.to_string()
+    }
+}
+
+pub fn write_csv(
+    out: &mut dyn Write,
+    group: ReportGroup,
+    rows: &[ReportRow],
+) -> anyhow::Result<()> {
+    let mut writer = csv::Writer::from_writer(out);
+    writer.write_record([
+        group.name(),
+        "spords",
+        "received",
+        "received_average_days",
+        "received_median_days",
+        "received_p90_days",
+        "picked_up",
+        "picked_up_average_days",
+        "picked_up_median_days",
+        "picked_up_p90_days",
+    ])?;
+    for row in rows {
+        let (received, picked_up) = (&row.created_to_received, &row.received_to_picked_up);
+        writer.write_record([
+            csv_text(&row.group),
+            row.spords.to_string(),
+            received.count.to_string(),
+            format_days(received.average),
+            format_days(received.median),
+            format_days(received.p90),
+            picked_up.count.to_string(),
+            format_days(picked_up.average),
+            format_days(picked_up.median),
+            format_days(picked_up.p90),
+        ])?;
+    }
+    writer.flush()?;
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use chrono::{DateTime, Duration, TimeZone, Utc};
+    use std::prelude::v1::test;
+
+    const DAY: i64 = 86400;
+
+    fn spord(
+        vendor: Option<&str>,
+        received_days: Option<i64>,
+        picked_up_days: Option<i64>,
+    ) -> SpordTurnaround {
+        let created: DateTime<Utc> = Utc.with_ymd_and_hms(2024, 3, 1, 0, 0, 0).unwrap();
+        SpordTurnaround {
+            vendor: vendor.map(str::to_string),
+            part: "tra123".to_string(),
+            creation_date: created,
+            received_date: received_days.map(|days| created + Duration::days(days)),
+            picked_up_date: picked_up_days.map(|days| created + Duration::days(days)),
+        }
+    }
+
+    #[test]
+    fn percentile_interpolates_between_ranks() {
+        assert_eq!(percentile(&[], 50.0), None);
+        assert_eq!(percentile(&[2 * DAY], 50.0), Some(2.0));
+        assert_eq!(percentile(&[2 * DAY], 90.0), Some(2.0));
+        assert_eq!(percentile(&[DAY, 2 * DAY], 50.0), Some(1.5));
+        let days: Vec<i64> = (1..=11).map(|days| days * DAY).collect();
+        assert_eq!(percentile(&days, 50.0), Some(6.0));
+        assert_eq!(percentile(&days, 90.0), Some(10.0));
+        assert_eq!(percentile(&days[..10], 90.0), Some(9.1));
+    }
+
+    #[test]
+    fn build_groups_and_times_each_step() {
+        let spords = [
+            spord(Some("Trek"), Some(2), Some(3)),
+            spord(Some(" Trek "), Some(4), None),
+            spord(Some("Trek"), None, None),
+            // received before it was created, left out of the durations
+            spord(None, Some(-1), Some(1)),
+        ];
+
+        let rows = build(&spords, ReportGroup::Vendor, 3);
+        let groups: Vec<_> = rows.iter().map(|row| row.group.as_str()).collect();
+        assert_eq!(groups, ["(no vendor)", "Trek"]);
+
+        let trek = &rows[1];
+        assert_eq!(trek.spords, 3);
+        assert_eq!(trek.created_to_received.count, 2);
+        assert_eq!(trek.created_to_received.average, Some(3.0));
+        assert_eq!(trek.received_to_picked_up.count, 1);
+        assert_eq!(trek.received_to_picked_up.median, Some(1.0));
+
+        let none = &rows[0];
+        assert_eq!(none.spords, 1);
+        assert_eq!(none.created_to_received.count, 0);
+        assert_eq!(none.created_to_received.median, None);
+        assert_eq!(none.received_to_picked_up.count, 1);
+
+        let rows = build(&spords, ReportGroup::PartPrefix, 3);
+        assert_eq!(rows.len(), 1);
+        assert_eq!(rows[0].group, "TRA");
+        assert_eq!(rows[0].spords, 4);
+    }
+
+    #[test]
+    fn csv_cells_never_start_a_formula() {
+        for formula in ["=1+1", "+1", "-1", "@SUM(A1)", "\tx", "\rx"] {
+            assert_eq!(csv_text(formula), format!("'{}", formula));
+        }
+        assert_eq!(csv_text("Trek"), "Trek");
+        assert_eq!(csv_text(""), "");
+
+        let rows = build(&[spord(Some("=cmd()"), None, None)], ReportGroup::Vendor, 3);
+        let mut out = vec![];
+        write_csv(&mut out, ReportGroup::Vendor, &rows).unwrap();
+        let out = String::from_utf8(out).unwrap();
+        assert!(out.lines().nth(1).unwrap().starts_with("'=cmd(),1,0,"));
+    }
+}
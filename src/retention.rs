@@ -0,0 +1,118 @@
+use crate::attachments::{self, AttachmentError};
+use crate::config::PurgeMode;
+use crate::sql::models::{SpordRecord, WebhookEvent};
+use crate::sql::SpordFilter;
+use crate::store::{SpordStore, StoreError, WebhookStore};
+use crate::webhooks;
+use crate::CONFIG;
+use chrono::{Duration, Utc};
+use std::sync::Arc;
+
+#[derive(Debug, Error)]
+pub enum RetentionError {
+    #[error("Retention({0})")]
+    Store(#[from] StoreError),
+
+    #[error("Retention({0})")]
+    Attachment(#[from] AttachmentError),
+}
+type Result<T> = std::result::Result<T, RetentionError>;
+
+// What one pass of the retention policy did
+#[derive(Debug, Default)]
+pub struct RetentionSummary {
+    pub archived: usize,
+    // deleted or anonymized, depending on retention.purge_mode
+    pub purged: usize,
+    // spords that could not be purged, they are tried again on the next pass
+    pub failed: usize,
+}
+
+// Archives the spords picked up more than retention.archive_after_days ago and
+// purges the ones archived more than retention.purge_after_days ago
+pub async fn run(spords: &dyn SpordStore, webhooks: &dyn WebhookStore) -> Result<RetentionSummary> {
+    let mut summary = RetentionSummary::default();
+
+    if CONFIG.retention.archive_after_days > 0 {
+        let picked_up_before =
+            Utc::now() - Duration::days(CONFIG.retention.archive_after_days as i64);
+        for id in spords.archive_picked_up(picked_up_before).await? {
+            info!(spord_id = id; "Archived spord {}, it was picked up", id);
+            summary.archived += 1;
+            match spords.get(id).await {
+                Ok(Some(spord)) => {
+                    webhooks::notify(webhooks, WebhookEvent::Archived, &spord, None, None).await
+                }
+                Ok(None) => {}
+                Err(e) => error!(spord_id = id; "Could not load archived spord {}: {}", id, e),
+            }
+        }
+    }
+
+    if CONFIG.retention.purge_after_days > 0 {
+        let archived_before = Utc::now() - Duration::days(CONFIG.retention.purge_after_days as i64);
+        let archived = SpordFilter {
+            archived: true,
+            ..SpordFilter::default()
+        };
+        let expired = spords.list(archived).await?.into_iter().filter(|spord| {
+            spord
+                .archived
+                .is_some_and(|archived| archived < archived_before)
+                && spord.anonymized.is_none()
+        });
+
+        for spord in expired {
+            match purge(spords, webhooks, &spord).await {
+                Ok(()) => summary.purged += 1,
+                Err(e) => {
+                    error!(spord_id = spord.id; "Purging archived spord {} failed: {}", spord.id, e);
+                    summary.failed += 1;
+                }
+            }
+        }
+    }
+
+    Ok(summary)
+}
+
+// The attachments go first, so a spord whose files could not be removed is
+// still there for the next pass to try again
+async fn purge(
+    spords: &dyn SpordStore,
+    webhooks: &dyn WebhookStore,
+    spord: &SpordRecord,
+) -> Result<()> {
+    attachments::delete_all(spords, spord.id).await?;
+    match CONFIG.retention.purge_mode {
+        PurgeMode::Delete => {
+            spords.delete(spord.id).await?;
+            webhooks::notify(webhooks, WebhookEvent::Deleted, spord, None, None).await;
+            info!(spord_id = spord.id; "Deleted archived spord {}", spord.id);
+        }
+        PurgeMode::Anonymize => {
+            spords.anonymize(spord.id).await?;
+            info!(spord_id = spord.id; "Anonymized archived spord {}", spord.id);
+        }
+    }
+
+    Ok(())
+}
+
+// Applies the retention policy right away and then every
+// retention.interval_hours, for as long as the server runs
+pub async fn run_schedule(spords: Arc<dyn SpordStore>, webhooks: Arc<dyn WebhookStore>) {
+    let interval = std::time::Duration::from_secs(CONFIG.retention.interval_hours * 3600);
+
+    loop {
+        match run(spords.as_ref(), webhooks.as_ref()).await {
+            Ok(summary) => debug!(
+                "Retention archived {}, purged {} and failed to purge {} spords",
+                summary.archived, summary.purged, summary.failed
+            ),
+            Err(e) => error!("Applying the retention policy failed: {}", e),
+        }
+
+        actix_web::rt::time::sleep(interval).await;
+    }
+}
@@ -0,0 +1,120 @@
+use super::Pool;
+use super::Result;
+use crate::sql::models::AttachmentRecord;
+use async_sqlite::rusqlite::OptionalExtension;
+use chrono::DateTime;
+
+const ATTACHMENT_COLUMNS: &str = "id, spord, filename, mime_type, size, sha256, username, created";
+
+fn attachment_from_row(
+    row: &async_sqlite::rusqlite::Row,
+) -> async_sqlite::rusqlite::Result<AttachmentRecord> {
+    Ok(AttachmentRecord {
+        id: row.get(0)?,
+        spord_id: row.get(1)?,
+        filename: row.get(2)?,
+        mime_type: row.get(3)?,
+        size: row.get(4)?,
+        sha256: row.get(5)?,
+        username: row.get(6)?,
+        created: DateTime::from_timestamp(row.get(7)?, 0).unwrap_or_default(),
+    })
+}
+
+pub async fn create(pool: &Pool, attachment: AttachmentRecord) -> Result<i64> {
+    let id = pool
+        .conn(move |conn| {
+            conn.execute(
+                "INSERT INTO attachments
+                    (spord, filename, mime_type, size, sha256, username, created)
+                    VALUES(?1, ?2, ?3, ?4, ?5, ?6, ?7)",
+                (
+                    attachment.spord_id,
+                    &attachment.filename,
+                    &attachment.mime_type,
+                    attachment.size,
+                    &attachment.sha256,
+                    &attachment.username,
+                    attachment.created.timestamp(),
+                ),
+            )?;
+            Ok(conn.last_insert_rowid())
+        })
+        .await?;
+
+    Ok(id)
+}
+
+// Attachments of one spord, oldest first
+pub async fn list(pool: &Pool, spord_id: i32) -> Result<Vec<AttachmentRecord>> {
+    let attachments = pool
+        .conn(move |conn| {
+            let mut stmt = conn.prepare(&format!(
+                "SELECT {} FROM attachments WHERE spord=?1 ORDER BY created, id",
+                ATTACHMENT_COLUMNS
+            ))?;
+            let attachment_iter = stmt.query_map([spord_id], attachment_from_row)?;
+            attachment_iter.collect()
+        })
+        .await?;
+
+    Ok(attachments)
+}
+
+// Only finds the attachment through the spord it belongs to
+pub async fn get(pool: &Pool, spord_id: i32, id: i64) -> Result<Option<AttachmentRecord>> {
+    let attachment = pool
+        .conn(move |conn| {
+            conn.query_row(
+                &format!(
+                    "SELECT {} FROM attachments WHERE id=?1 AND spord=?2",
+                    ATTACHMENT_COLUMNS
+                ),
+                (id, spord_id),
+                attachment_from_row,
+            )
+            .optional()
+        })
+        .await?;
+
+    Ok(attachment)
+}
+
+// Bytes attached to a spord so far
+pub async fn total_size(pool: &Pool, spord_id: i32) -> Result<i64> {
+    let total = pool
+        .conn(move |conn| {
+            conn.query_row(
+                "SELECT COALESCE(SUM(size), 0) FROM attachments WHERE spord=?1",
+                [spord_id],
+                |row| row.get(0),
+            )
+        })
+        .await?;
+
+    Ok(total)
+}
+
+// Whether any attachment still has this content
+pub async fn content_used(pool: &Pool, sha256: &str) -> Result<bool> {
+    let sqlsha256 = sha256.to_owned();
+    let used = pool
+        .conn(move |conn| {
+            conn.query_row(
+                "SELECT EXISTS(SELECT 1 FROM attachments WHERE sha256=?1)",
+                [&sqlsha256],
+                |row| row.get(0),
+            )
+        })
+        .await?;
+
+    Ok(used)
+}
+
+pub async fn delete(pool: &Pool, id: i64) -> Result<bool> {
+    let deleted = pool
+        .conn(move |conn| conn.execute("DELETE FROM attachments WHERE id=?1", [id]))
+        .await?;
+
+    Ok(deleted > 0)
+}
@@ -0,0 +1,196 @@
+use super::Pool;
+use super::{migrate, Result, SqlError};
+use crate::CONFIG;
+use async_sqlite::rusqlite::backup::Backup;
+use async_sqlite::rusqlite::{Connection, DatabaseName, OpenFlags};
+use chrono::Utc;
+use std::path::{Path, PathBuf};
+
+// Backups are named <prefix><timestamp>.db, only files named like that are
+// pruned by the retention count
+const BACKUP_PREFIX: &str = "backup-";
+const PRE_RESTORE_PREFIX: &str = "pre-restore-";
+const TIMESTAMP_FORMAT: &str = "%Y%m%d-%H%M%S";
+
+// Copies the live database into a new timestamped file in dir using the online
+// backup api, checks the copy and removes all but the newest `keep` backups
+pub async fn create(pool: &Pool, dir: &Path, keep: usize) -> Result<PathBuf> {
+    let path = copy_to(pool, dir, BACKUP_PREFIX).await?;
+    prune(dir, keep)?;
+
+    Ok(path)
+}
+
+async fn copy_to(pool: &Pool, dir: &Path, prefix: &str) -> Result<PathBuf> {
+    std::fs::create_dir_all(dir)?;
+    let path = dir.join(format!(
+        "{}{}.db",
+        prefix,
+        Utc::now().format(TIMESTAMP_FORMAT)
+    ));
+    if path.exists() {
+        return Err(SqlError::Backup(format!(
+            "{} already exists",
+            path.display()
+        )));
+    }
+
+    let destination = path.clone();
+    pool.conn(move |conn| {
+        let mut copy = Connection::open(destination)?;
+        Backup::new(conn, &mut copy)?.run_to_completion(
+            100,
+            std::time::Duration::from_millis(50),
+            None,
+        )?;
+        // the copy inherits wal mode, switch back so it is one self
+        // contained file without -wal and -shm next to it
+        copy.pragma_update(None, "journal_mode", "DELETE")?;
+        Ok(())
+    })
+    .await?;
+
+    if let Err(e) = check_integrity(&path) {
+        // a broken backup is worse than none, it would be trusted later
+        std::fs::remove_file(&path)?;
+        return Err(e);
+    }
+
+    Ok(path)
+}
+
+// Runs PRAGMA integrity_check on a database file
+pub fn check_integrity(path: &Path) -> Result<()> {
+    let conn = Connection::open_with_flags(path, OpenFlags::SQLITE_OPEN_READ_ONLY)
+        .map_err(async_sqlite::Error::from)?;
+    let result: String = conn
+        .query_row("PRAGMA integrity_check", (), |row| row.get(0))
+        .map_err(async_sqlite::Error::from)?;
+
+    if result == "ok" {
+        Ok(())
+    } else {
+        Err(SqlError::Backup(format!(
+            "integrity check of {} failed: {}",
+            path.display(),
+            result
+        )))
+    }
+}
+
+// Deletes the oldest backups in dir so that `keep` are left
+fn prune(dir: &Path, keep: usize) -> Result<()> {
+    let mut backups = list(dir)?;
+    if backups.len() <= keep {
+        return Ok(());
+    }
+
+    backups.truncate(backups.len() - keep);
+    for path in backups {
+        info!("Removing old backup {}", path.display());
+        std::fs::remove_file(path)?;
+    }
+
+    Ok(())
+}
+
+// Backups in dir, oldest first
+pub fn list(dir: &Path) -> Result<Vec<PathBuf>> {
+    if !dir.exists() {
+        return Ok(vec![]);
+    }
+
+    let mut backups = vec![];
+    for entry in std::fs::read_dir(dir)? {
+        let path = entry?.path();
+        let is_backup = path
+            .file_name()
+            .and_then(|name| name.to_str())
+            .is_some_and(|name| name.starts_with(BACKUP_PREFIX) && name.ends_with(".db"));
+        if is_backup {
+            backups.push(path);
+        }
+    }
+    // the timestamp sorts the same as the file name
+    backups.sort();
+
+    Ok(backups)
+}
+
+// Replaces the live database with a backup. The backup is checked first and the
+// current database is saved as pre-restore-<timestamp>.db in dir, so a restore
+// can be undone. Older backups are migrated to the current schema
+pub async fn restore(pool: &Pool, source: &Path, dir: &Path) -> Result<PathBuf> {
+    check_integrity(source)?;
+    let saved = copy_to(pool, dir, PRE_RESTORE_PREFIX).await?;
+
+    let source = source.to_path_buf();
+    pool.conn_mut(move |conn| conn.restore(DatabaseName::Main, source, None::<fn(_)>))
+        .await?;
+    migrate(pool).await?;
+
+    Ok(saved)
+}
+
+// Takes a backup every backup.interval_hours while the server runs
+pub async fn run_schedule(pool: Pool) {
+    let interval = std::time::Duration::from_secs(CONFIG.backup.interval_hours * 3600);
+    let dir = PathBuf::from(&CONFIG.backup.dir);
+
+    loop {
+        actix_web::rt::time::sleep(interval).await;
+
+        match create(&pool, &dir, CONFIG.backup.keep).await {
+            Ok(path) => info!("Wrote scheduled backup {}", path.display()),
+            Err(e) => error!("Scheduled backup failed: {}", e),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::sql::locations;
+    use crate::testing;
+
+    #[actix_web::test]
+    async fn restores_what_was_backed_up() {
+        let (pool, dir) = testing::pool().await;
+        let backups = dir.path().join("backups");
+        locations::create(&pool, "North").await.unwrap();
+
+        let backup = create(&pool, &backups, 7).await.unwrap();
+        assert_eq!(list(&backups).unwrap(), std::slice::from_ref(&backup));
+        check_integrity(&backup).unwrap();
+
+        locations::create(&pool, "South").await.unwrap();
+        let saved = restore(&pool, &backup, &backups).await.unwrap();
+        let names: Vec<_> = locations::list(&pool)
+            .await
+            .unwrap()
+            .into_iter()
+            .map(|location| location.name)
+            .collect();
+        assert_eq!(names, ["North"]);
+
+        // the database before the restore is kept, but not counted as a backup
+        assert!(saved.exists());
+        assert_eq!(list(&backups).unwrap(), [backup]);
+    }
+
+    #[actix_web::test]
+    async fn keeps_only_the_newest_backups() {
+        let (pool, dir) = testing::pool().await;
+        let backups = dir.path().join("backups");
+        std::fs::create_dir_all(&backups).unwrap();
+        for old in ["backup-20200101-000000.db", "backup-20200102-000000.db"] {
+            std::fs::write(backups.join(old), "").unwrap();
+        }
+
+        let backup = create(&pool, &backups, 2).await.unwrap();
+        assert_eq!(
+            list(&backups).unwrap(),
+            [backups.join("backup-20200102-000000.db"), backup]
+        );
+    }
+}
@@ -0,0 +1,86 @@
+use super::Pool;
+use super::Result;
+use crate::sql::models::{self, SavedFilterRecord, SpordState};
+use chrono::{DateTime, Utc};
+
+fn filter_from_row(
+    row: &async_sqlite::rusqlite::Row,
+) -> async_sqlite::rusqlite::Result<SavedFilterRecord> {
+    Ok(SavedFilterRecord {
+        id: row.get(0)?,
+        username: row.get(1)?,
+        name: row.get(2)?,
+        state: row.get::<_, Option<i32>>(3)?.map(SpordState::from_sql),
+        tags: models::parse_tags(&row.get::<_, String>(4)?),
+        search: row.get(5)?,
+        created: DateTime::from_timestamp(row.get(6)?, 0).unwrap_or_default(),
+    })
+}
+
+// Saves a filter for a user. A filter with the same name is replaced
+pub async fn save(
+    pool: &Pool,
+    username: &str,
+    name: &str,
+    state: Option<SpordState>,
+    tags: &[String],
+    search: Option<String>,
+) -> Result<i64> {
+    let sqlusername = username.to_owned();
+    let sqlname = name.to_owned();
+    let sqltags = tags.join(",");
+    let id = pool
+        .conn(move |conn| {
+            conn.query_row(
+                "INSERT INTO saved_filters (username, name, state, tags, search, created)
+                    VALUES(?1, ?2, ?3, ?4, ?5, ?6)
+                    ON CONFLICT (username, name) DO UPDATE SET state=excluded.state,
+                        tags=excluded.tags, search=excluded.search, created=excluded.created
+                    RETURNING id",
+                (
+                    &sqlusername,
+                    &sqlname,
+                    state.map(|state| state.as_sql()),
+                    &sqltags,
+                    &search,
+                    Utc::now().timestamp(),
+                ),
+                |row| row.get(0),
+            )
+        })
+        .await?;
+
+    Ok(id)
+}
+
+// Filters of one user, by name
+pub async fn list(pool: &Pool, username: &str) -> Result<Vec<SavedFilterRecord>> {
+    let sqlusername = username.to_owned();
+    let filters = pool
+        .conn(move |conn| {
+            let mut stmt = conn.prepare(
+                "SELECT id, username, name, state, tags, search, created FROM saved_filters
+                    WHERE username=?1 ORDER BY name",
+            )?;
+            let filter_iter = stmt.query_map([&sqlusername], filter_from_row)?;
+            filter_iter.collect()
+        })
+        .await?;
+
+    Ok(filters)
+}
+
+// Only deletes filters of this user, returns false if there was none
+pub async fn delete(pool: &Pool, username: &str, id: i64) -> Result<bool> {
+    let sqlusername = username.to_owned();
+    let deleted = pool
+        .conn(move |conn| {
+            conn.execute(
+                "DELETE FROM saved_filters WHERE id=?1 AND username=?2",
+                (id, &sqlusername),
+            )
+        })
+        .await?;
+
+    Ok(deleted > 0)
+}
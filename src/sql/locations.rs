@@ -0,0 +1,149 @@
+use super::Pool;
+use super::Result;
+use crate::sql::models::{LocationRecord, SpordEventKind};
+use async_sqlite::rusqlite::OptionalExtension;
+use chrono::Utc;
+
+pub async fn create(pool: &Pool, name: &str) -> Result<i64> {
+    let sqlname = name.to_owned();
+    let id = pool
+        .conn(move |conn| {
+            conn.execute("INSERT INTO locations (name) VALUES(?1)", [&sqlname])?;
+            Ok(conn.last_insert_rowid())
+        })
+        .await?;
+
+    Ok(id)
+}
+
+pub async fn list(pool: &Pool) -> Result<Vec<LocationRecord>> {
+    let locations = pool
+        .conn(|conn| {
+            let mut stmt = conn.prepare("SELECT id, name FROM locations ORDER BY name")?;
+            let location_iter = stmt.query_map([], |row| {
+                Ok(LocationRecord {
+                    id: row.get(0)?,
+                    name: row.get(1)?,
+                })
+            })?;
+            location_iter.collect()
+        })
+        .await?;
+
+    Ok(locations)
+}
+
+pub async fn find(pool: &Pool, name: &str) -> Result<Option<LocationRecord>> {
+    let sqlname = name.to_owned();
+    let location = pool
+        .conn(move |conn| {
+            conn.query_row(
+                "SELECT id, name FROM locations WHERE name=?1",
+                [&sqlname],
+                |row| {
+                    Ok(LocationRecord {
+                        id: row.get(0)?,
+                        name: row.get(1)?,
+                    })
+                },
+            )
+            .optional()
+        })
+        .await?;
+
+    Ok(location)
+}
+
+pub async fn get(pool: &Pool, id: i64) -> Result<Option<LocationRecord>> {
+    let location = pool
+        .conn(move |conn| {
+            conn.query_row("SELECT id, name FROM locations WHERE id=?1", [id], |row| {
+                Ok(LocationRecord {
+                    id: row.get(0)?,
+                    name: row.get(1)?,
+                })
+            })
+            .optional()
+        })
+        .await?;
+
+    Ok(location)
+}
+
+// Returns false if there is no such user
+pub async fn set_user_location(pool: &Pool, username: &str, location: Option<i64>) -> Result<bool> {
+    let sqlusername = username.to_owned();
+    let updated = pool
+        .conn(move |conn| {
+            conn.execute(
+                "UPDATE auth SET location=?1 WHERE username=?2",
+                (location, &sqlusername),
+            )
+        })
+        .await?;
+
+    Ok(updated > 0)
+}
+
+// Returns false if there is no such user
+pub async fn set_user_manager(pool: &Pool, username: &str, manager: bool) -> Result<bool> {
+    let sqlusername = username.to_owned();
+    let updated = pool
+        .conn(move |conn| {
+            conn.execute(
+                "UPDATE auth SET manager=?1 WHERE username=?2",
+                (manager, &sqlusername),
+            )
+        })
+        .await?;
+
+    Ok(updated > 0)
+}
+
+// Moves a spord to another store and records the transfer. Returns false if
+// there is no spord with this id
+pub async fn transfer_spord(
+    pool: &Pool,
+    spord_id: i32,
+    location: i64,
+    username: Option<String>,
+) -> Result<bool> {
+    let transferred = pool
+        .conn_mut(move |conn| {
+            let tx = conn.transaction()?;
+
+            let old_location: Option<Option<i64>> = tx
+                .query_row(
+                    "SELECT location FROM spords WHERE id=?1",
+                    [spord_id],
+                    |row| row.get(0),
+                )
+                .optional()?;
+            let Some(old_location) = old_location else {
+                return Ok(false);
+            };
+
+            tx.execute(
+                "UPDATE spords SET location=?1 WHERE id=?2",
+                (location, spord_id),
+            )?;
+            tx.execute(
+                "INSERT INTO spord_events (spord, kind, old_value, new_value, username, time)
+                    VALUES(?1, ?2, ?3, ?4, ?5, ?6)",
+                (
+                    spord_id,
+                    SpordEventKind::Transfer.as_sql(),
+                    old_location,
+                    location,
+                    &username,
+                    Utc::now().timestamp(),
+                ),
+            )?;
+            tx.commit()?;
+
+            Ok(true)
+        })
+        .await?;
+
+    Ok(transferred)
+}
@@ -0,0 +1,288 @@
+use super::Pool;
+use super::Result;
+use crate::config::LoginConfig;
+use async_sqlite::rusqlite::OptionalExtension;
+use chrono::{DateTime, Utc};
+
+#[derive(Debug, PartialEq, Clone, Copy)]
+pub enum AttemptKind {
+    Username,
+    Ip,
+}
+impl AttemptKind {
+    pub fn as_sql(&self) -> &'static str {
+        match self {
+            Self::Username => "user",
+            Self::Ip => "ip",
+        }
+    }
+}
+
+// Returns when the lock on this username/ip runs out, if it is currently locked
+pub async fn locked_until(
+    pool: &Pool,
+    kind: AttemptKind,
+    key: &str,
+) -> Result<Option<DateTime<Utc>>> {
+    let sqlkey = key.to_owned();
+    let locked_until: Option<Option<i64>> = pool
+        .conn(move |conn| {
+            conn.query_row(
+                "SELECT locked_until FROM login_attempts WHERE kind=?1 AND key=?2",
+                (kind.as_sql(), &sqlkey),
+                |row| row.get(0),
+            )
+            .optional()
+        })
+        .await?;
+
+    let now = Utc::now();
+    Ok(locked_until
+        .flatten()
+        .and_then(|timestamp| DateTime::from_timestamp(timestamp, 0))
+        .filter(|until| *until > now))
+}
+
+// How long the failures'th failure in a row locks for, if at all. Every failure
+// past max_failures doubles the lockout, up to max_lockout_seconds
+fn lockout_seconds(config: &LoginConfig, max_failures: u32, failures: u32) -> Option<i64> {
+    if failures < max_failures {
+        return None;
+    }
+
+    let doublings = (failures - max_failures).min(32);
+    Some(
+        config
+            .lockout_seconds
+            .saturating_mul(1 << doublings)
+            .min(config.max_lockout_seconds),
+    )
+}
+
+// Counts one more failed login at now, given the failures and last failure
+// recorded so far. Returns the new count and until when the username/ip is
+// locked, if the count is over the limit for its kind
+pub fn failed_again(
+    config: &LoginConfig,
+    kind: AttemptKind,
+    previous: Option<(u32, i64)>,
+    now: i64,
+) -> (u32, Option<i64>) {
+    let max_failures = match kind {
+        AttemptKind::Username => config.max_user_failures,
+        AttemptKind::Ip => config.max_ip_failures,
+    };
+    let window_start = now.saturating_sub(config.failure_window_seconds);
+
+    let failures = match previous {
+        Some((failures, last_failure)) if last_failure >= window_start => {
+            failures.saturating_add(1)
+        }
+        _ => 1,
+    };
+    // a lockout past what a DateTime holds would read back as no lockout at all
+    let locked_until = lockout_seconds(config, max_failures, failures).map(|seconds| {
+        now.saturating_add(seconds)
+            .min(DateTime::<Utc>::MAX_UTC.timestamp())
+    });
+
+    (failures, locked_until)
+}
+
+// Records a failed login, and locks the username/ip once it has failed too many
+// times. Rows whose failures and lockout have both run out are pruned here, so
+// made up usernames don't pile up.
+pub async fn record_failure(
+    pool: &Pool,
+    config: &LoginConfig,
+    kind: AttemptKind,
+    key: &str,
+) -> Result<Option<DateTime<Utc>>> {
+    let config = config.clone();
+
+    let sqlkey = key.to_owned();
+    let locked_until: Option<i64> = pool
+        .conn(move |conn| {
+            let now = Utc::now().timestamp();
+            let window_start = now.saturating_sub(config.failure_window_seconds);
+
+            let previous: Option<(u32, i64)> = conn
+                .query_row(
+                    "SELECT failures, last_failure FROM login_attempts WHERE kind=?1 AND key=?2",
+                    (kind.as_sql(), &sqlkey),
+                    |row| Ok((row.get(0)?, row.get(1)?)),
+                )
+                .optional()?;
+            let (failures, locked_until) = failed_again(&config, kind, previous, now);
+
+            conn.execute(
+                "INSERT OR REPLACE INTO login_attempts
+                    (kind, key, failures, last_failure, locked_until)
+                    VALUES(?1, ?2, ?3, ?4, ?5)",
+                (kind.as_sql(), &sqlkey, failures, now, locked_until),
+            )?;
+            conn.execute(
+                "DELETE FROM login_attempts WHERE last_failure<?1
+                    AND (locked_until IS NULL OR locked_until<=?2)",
+                (window_start, now),
+            )?;
+
+            Ok(locked_until)
+        })
+        .await?;
+
+    Ok(locked_until.and_then(|timestamp| DateTime::from_timestamp(timestamp, 0)))
+}
+
+// Forgets all failures for this username/ip, returns false if there were none
+pub async fn clear(pool: &Pool, kind: AttemptKind, key: &str) -> Result<bool> {
+    let sqlkey = key.to_owned();
+    let deleted = pool
+        .conn(move |conn| {
+            conn.execute(
+                "DELETE FROM login_attempts WHERE kind=?1 AND key=?2",
+                (kind.as_sql(), &sqlkey),
+            )
+        })
+        .await?;
+
+    Ok(deleted > 0)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{sql, testing};
+    use std::prelude::v1::test;
+
+    #[test]
+    fn no_lockout_below_threshold() {
+        let config = LoginConfig::default();
+        for failures in 0..config.max_user_failures {
+            assert_eq!(
+                lockout_seconds(&config, config.max_user_failures, failures),
+                None
+            );
+        }
+    }
+
+    #[test]
+    fn lockout_doubles_up_to_max() {
+        let config = LoginConfig {
+            lockout_seconds: 30,
+            max_lockout_seconds: 3600,
+            ..LoginConfig::default()
+        };
+        let lockouts: Vec<_> = (5..=13)
+            .map(|failures| lockout_seconds(&config, 5, failures))
+            .collect();
+        assert_eq!(
+            lockouts,
+            [30, 60, 120, 240, 480, 960, 1920, 3600, 3600].map(Some)
+        );
+    }
+
+    #[test]
+    fn lockout_does_not_overflow() {
+        let config = LoginConfig {
+            lockout_seconds: i64::MAX / 2,
+            max_lockout_seconds: i64::MAX,
+            ..LoginConfig::default()
+        };
+        assert_eq!(lockout_seconds(&config, 1, u32::MAX), Some(i64::MAX));
+
+        let now = Utc::now().timestamp();
+        let (_, locked_until) = failed_again(&config, AttemptKind::Ip, Some((u32::MAX, now)), now);
+        assert_eq!(locked_until, Some(DateTime::<Utc>::MAX_UTC.timestamp()));
+    }
+
+    fn strict() -> LoginConfig {
+        LoginConfig {
+            max_user_failures: 3,
+            ..LoginConfig::default()
+        }
+    }
+
+    #[actix_web::test]
+    async fn locks_after_max_failures() {
+        let (pool, _dir) = testing::pool().await;
+        let config = strict();
+        for _ in 1..config.max_user_failures {
+            let locked = record_failure(&pool, &config, AttemptKind::Username, "dave")
+                .await
+                .unwrap();
+            assert_eq!(locked, None);
+        }
+        assert!(locked_until(&pool, AttemptKind::Username, "dave")
+            .await
+            .unwrap()
+            .is_none());
+
+        let locked = record_failure(&pool, &config, AttemptKind::Username, "dave")
+            .await
+            .unwrap()
+            .unwrap();
+        assert!(locked > Utc::now());
+        assert_eq!(
+            locked_until(&pool, AttemptKind::Username, "dave")
+                .await
+                .unwrap(),
+            Some(locked)
+        );
+        // other usernames and the ip of the same name aren't locked with it
+        assert!(locked_until(&pool, AttemptKind::Username, "erin")
+            .await
+            .unwrap()
+            .is_none());
+        assert!(locked_until(&pool, AttemptKind::Ip, "dave")
+            .await
+            .unwrap()
+            .is_none());
+    }
+
+    #[actix_web::test]
+    async fn lock_survives_reopening() {
+        let (pool, dir) = testing::pool().await;
+        let config = strict();
+        let mut locked = None;
+        for _ in 0..config.max_user_failures {
+            locked = record_failure(&pool, &config, AttemptKind::Username, "dave")
+                .await
+                .unwrap();
+        }
+        assert!(locked.is_some());
+        drop(pool);
+
+        let pool = sql::open(&dir.path().join("spord-tracker.db"))
+            .await
+            .unwrap();
+        assert_eq!(
+            locked_until(&pool, AttemptKind::Username, "dave")
+                .await
+                .unwrap(),
+            locked
+        );
+    }
+
+    #[actix_web::test]
+    async fn clear_removes_the_lock() {
+        let (pool, _dir) = testing::pool().await;
+        let config = strict();
+        for _ in 0..config.max_user_failures {
+            record_failure(&pool, &config, AttemptKind::Username, "dave")
+                .await
+                .unwrap();
+        }
+        assert!(clear(&pool, AttemptKind::Username, "dave").await.unwrap());
+        assert!(!clear(&pool, AttemptKind::Username, "dave").await.unwrap());
+        assert!(locked_until(&pool, AttemptKind::Username, "dave")
+            .await
+            .unwrap()
+            .is_none());
+        // counting starts over after a clear
+        let locked = record_failure(&pool, &config, AttemptKind::Username, "dave")
+            .await
+            .unwrap();
+        assert_eq!(locked, None);
+    }
+}
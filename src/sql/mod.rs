@@ -1,9 +1,24 @@
 use crate::CONFIG;
-use async_sqlite::{Client, ClientBuilder, JournalMode, Pool, PoolBuilder};
-use chrono::DateTime;
-use models::{SpordRecord, SpordState};
-
+use async_sqlite::rusqlite::OptionalExtension;
+use chrono::{DateTime, Utc};
+use models::{
+    Dashboard, SpordEventKind, SpordEventRecord, SpordRecord, SpordState, SpordStats,
+    SpordTurnaround, UserRecord,
+};
+
+pub mod attachments;
+pub mod backup;
+pub mod filters;
+pub mod locations;
+pub mod lockout;
 pub mod models;
+pub mod notes;
+mod pool;
+pub mod sessions;
+pub mod tokens;
+pub mod webhooks;
+
+pub use pool::Pool;
 
 #[derive(Debug, Error)]
 pub enum SqlError {
@@ -15,58 +30,225 @@ pub enum SqlError {
 
     #[error("Sql(Bcrypt({0:?}))")]
     Bcrypt(#[from] bcrypt::BcryptError),
+
+    #[error("Backup failed: {0}")]
+    Backup(String),
 }
 type Result<T> = std::result::Result<T, SqlError>;
 
-pub async fn open_connection() -> Result<Client> {
-    let client = ClientBuilder::new()
-        .path(&CONFIG.sql.location)
-        .journal_mode(JournalMode::Wal)
-        .open()
-        .await?;
+// Schema changes made after the initial tables, applied in order. The index of
+// the last applied migration + 1 is kept in PRAGMA user_version.
+const MIGRATIONS: &[&str] = &[
+    // 1: failed login tracking
+    "CREATE TABLE login_attempts (
+        kind TEXT NOT NULL,
+        key TEXT NOT NULL,
+        failures INTEGER NOT NULL,
+        last_failure INTEGER NOT NULL,
+        locked_until INTEGER,
+        PRIMARY KEY (kind, key)
+    );",
+    // 2: server side sessions
+    "CREATE TABLE sessions (
+        id INTEGER PRIMARY KEY AUTOINCREMENT,
+        key TEXT NOT NULL UNIQUE,
+        username TEXT,
+        state TEXT NOT NULL,
+        created INTEGER NOT NULL,
+        last_seen INTEGER NOT NULL,
+        expires INTEGER NOT NULL
+    );",
+    // 3: api tokens, username is NULL for service tokens
+    "CREATE TABLE api_tokens (
+        id INTEGER PRIMARY KEY AUTOINCREMENT,
+        name TEXT NOT NULL,
+        username TEXT,
+        token_hash TEXT NOT NULL UNIQUE,
+        scope TEXT NOT NULL,
+        created INTEGER NOT NULL,
+        expires INTEGER,
+        last_used INTEGER
+    );",
+    // 4: stores, with a home store per user and a store per spord
+    "CREATE TABLE locations (
+        id INTEGER PRIMARY KEY AUTOINCREMENT,
+        name TEXT NOT NULL UNIQUE
+    );
+    ALTER TABLE spords ADD COLUMN location INTEGER REFERENCES locations(id);
+    ALTER TABLE auth ADD COLUMN location INTEGER REFERENCES locations(id);
+    ALTER TABLE auth ADD COLUMN manager BOOL NOT NULL DEFAULT 0;
+    CREATE TABLE spord_events (
+        id INTEGER PRIMARY KEY AUTOINCREMENT,
+        spord INTEGER NOT NULL REFERENCES spords(id),
+        kind TEXT NOT NULL,
+        old_value INTEGER,
+        new_value INTEGER,
+        username TEXT,
+        time INTEGER NOT NULL
+    );",
+    // 5: outbound webhooks, with every event queued as a delivery
+    "CREATE TABLE webhooks (
+        id INTEGER PRIMARY KEY AUTOINCREMENT,
+        url TEXT NOT NULL,
+        secret TEXT NOT NULL,
+        events TEXT NOT NULL,
+        enabled BOOL NOT NULL,
+        created INTEGER NOT NULL
+    );
+    CREATE TABLE webhook_deliveries (
+        id INTEGER PRIMARY KEY AUTOINCREMENT,
+        webhook INTEGER NOT NULL REFERENCES webhooks(id),
+        event TEXT NOT NULL,
+        payload TEXT NOT NULL,
+        state TEXT NOT NULL,
+        attempts INTEGER NOT NULL,
+        next_attempt INTEGER,
+        last_status INTEGER,
+        last_error TEXT,
+        created INTEGER NOT NULL,
+        updated INTEGER NOT NULL
+    );
+    CREATE INDEX webhook_deliveries_due ON webhook_deliveries (state, next_attempt);",
+    // 6: vendors, and state changes in the history for the turnaround reports
+    "ALTER TABLE spords ADD COLUMN vendor TEXT;
+    CREATE INDEX spord_events_spord ON spord_events (spord, kind);",
+    // 7: files attached to spords
+    "CREATE TABLE attachments (
+        id INTEGER PRIMARY KEY AUTOINCREMENT,
+        spord INTEGER NOT NULL REFERENCES spords(id),
+        filename TEXT NOT NULL,
+        mime_type TEXT NOT NULL,
+        size INTEGER NOT NULL,
+        sha256 TEXT NOT NULL,
+        username TEXT,
+        created INTEGER NOT NULL
+    );
+    CREATE INDEX attachments_spord ON attachments (spord);
+    CREATE INDEX attachments_sha256 ON attachments (sha256);",
+    // 8: notes replace the comments field, every comment becomes the first
+    // note of its spord. Earlier texts of edited notes go to the revisions
+    "CREATE TABLE spord_notes (
+        id INTEGER PRIMARY KEY AUTOINCREMENT,
+        spord INTEGER NOT NULL REFERENCES spords(id),
+        username TEXT,
+        text TEXT NOT NULL,
+        created INTEGER NOT NULL,
+        edited INTEGER,
+        edited_by TEXT
+    );
+    CREATE INDEX spord_notes_spord ON spord_notes (spord);
+    CREATE TABLE spord_note_revisions (
+        id INTEGER PRIMARY KEY AUTOINCREMENT,
+        note INTEGER NOT NULL REFERENCES spord_notes(id),
+        text TEXT NOT NULL,
+        username TEXT,
+        time INTEGER NOT NULL
+    );
+    CREATE INDEX spord_note_revisions_note ON spord_note_revisions (note);
+    INSERT INTO spord_notes (spord, text, created)
+        SELECT id, comments, created FROM spords WHERE TRIM(COALESCE(comments, '')) != ''
+        ORDER BY id;
+    ALTER TABLE spords DROP COLUMN comments;",
+    // 9: free-form tags on spords, and list filters saved by each user
+    "CREATE TABLE tags (
+        id INTEGER PRIMARY KEY AUTOINCREMENT,
+        name TEXT NOT NULL UNIQUE
+    );
+    CREATE TABLE spord_tags (
+        spord INTEGER NOT NULL REFERENCES spords(id),
+        tag INTEGER NOT NULL REFERENCES tags(id),
+        PRIMARY KEY (spord, tag)
+    );
+    CREATE INDEX spord_tags_tag ON spord_tags (tag);
+    CREATE TABLE saved_filters (
+        id INTEGER PRIMARY KEY AUTOINCREMENT,
+        username TEXT NOT NULL,
+        name TEXT NOT NULL,
+        state INTEGER,
+        tags TEXT NOT NULL,
+        search TEXT,
+        created INTEGER NOT NULL,
+        UNIQUE (username, name)
+    );",
+    // 10: archiving instead of deleting, and the retention policy which
+    // anonymizes archived spords after a while
+    "ALTER TABLE spords ADD COLUMN archived INTEGER;
+    ALTER TABLE spords ADD COLUMN anonymized INTEGER;
+    CREATE INDEX spords_archived ON spords (archived);",
+    // 11: service tokens limited to one store, NULL for every store
+    "ALTER TABLE api_tokens ADD COLUMN location INTEGER REFERENCES locations(id);",
+];
+
+// Opens the pool shared by the whole process, creating and migrating the
+// database as needed
+pub async fn check_initialized() -> Result<Pool> {
+    open(CONFIG.sql.location.as_ref()).await
+}
 
-    Ok(client)
+// Opens a pool on the database at path, creating and migrating it as needed
+pub async fn open(path: &std::path::Path) -> Result<Pool> {
+    let exists = std::fs::exists(path)?;
+    let pool = Pool::open(
+        path,
+        CONFIG.sql.pool_size,
+        std::time::Duration::from_millis(CONFIG.sql.busy_timeout_ms),
+    )
+    .await?;
+
+    if !exists {
+        initialize(&pool).await?;
+    }
+    migrate(&pool).await?;
+
+    Ok(pool)
 }
 
-async fn open_if_needed(client_opt: Option<Client>) -> Result<Client> {
-    let client = if let Some(client) = client_opt {
-        client
-    } else {
-        open_connection().await?
-    };
+// The number of applied migrations, and how many this build knows about
+pub async fn schema_version(pool: &Pool) -> Result<(usize, usize)> {
+    let version = pool
+        .conn(|conn| conn.query_row("PRAGMA user_version", (), |row| row.get(0)))
+        .await?;
 
-    Ok(client)
+    Ok((version, MIGRATIONS.len()))
 }
 
-pub async fn check_initialized() -> Result<()> {
-    if !std::fs::exists(&CONFIG.sql.location)? {
-        initialize(None).await?;
-    }
+async fn migrate(pool: &Pool) -> Result<()> {
+    pool.conn(|conn| {
+        let version: usize = conn.query_row("PRAGMA user_version", (), |row| row.get(0))?;
+
+        for (i, migration) in MIGRATIONS.iter().enumerate().skip(version) {
+            info!("Applying database migration {}", i + 1);
+            conn.execute_batch(&format!(
+                "BEGIN; {} PRAGMA user_version = {}; COMMIT;",
+                migration,
+                i + 1
+            ))?;
+        }
+
+        Ok(())
+    })
+    .await?;
 
     Ok(())
 }
 
-async fn initialize(client_opt: Option<Client>) -> Result<()> {
-    let client = open_if_needed(client_opt).await?;
-
-    client
-        .conn(|conn| {
-            conn.execute(
-                "CREATE TABLE auth (
+async fn initialize(pool: &Pool) -> Result<()> {
+    pool.conn(|conn| {
+        conn.execute(
+            "CREATE TABLE auth (
             username TEXT PRIMARY KEY,
             password TEXT NOT NULL,
             enabled BOOL NOT NULL,
             lastlogin INTEGER
             )",
-                (),
-            )
-        })
-        .await?;
-
-    client
-        .conn(|conn| {
-            conn.execute(
-                "CREATE TABLE spords (
+            (),
+        )
+    })
+    .await?;
+
+    pool.conn(|conn| {
+        conn.execute(
+            "CREATE TABLE spords (
                     id INTEGER PRIMARY KEY AUTOINCREMENT,
                     name TEXT NOT NULL,
                     phone TEXT,
@@ -77,32 +259,31 @@ async fn initialize(client_opt: Option<Client>) -> Result<()> {
                     received INTEGER,
                     comments TEXT
                 )",
-                (),
-            )
-        })
-        .await?;
+            (),
+        )
+    })
+    .await?;
 
     Ok(())
 }
 
-pub async fn user_login(
-    client_opt: Option<Client>,
-    username: &str,
-    password: &str,
-) -> Result<bool> {
-    let client = open_if_needed(client_opt).await?;
-
+pub async fn user_login(pool: &Pool, username: &str, password: &str) -> Result<bool> {
     let sqlusername = username.to_owned();
-    let sqlpswd: String = client
+    let sqlpswd: Option<String> = pool
         .conn(move |conn| {
             conn.query_row(
-                "SELECT password FROM auth WHERE username=?",
+                "SELECT password FROM auth WHERE username=? AND enabled",
                 [&sqlusername],
                 |row| row.get(0),
             )
+            .optional()
         })
         .await?;
 
+    let Some(sqlpswd) = sqlpswd else {
+        return Ok(false);
+    };
+
     if bcrypt::verify(password, &sqlpswd)? {
         //TODO: update last logged in state
 
@@ -112,57 +293,114 @@ pub async fn user_login(
     }
 }
 
-pub async fn user_create(client_opt: Option<Client>, username: &str, password: &str) -> Result<()> {
-    let client = open_if_needed(client_opt).await?;
-
+pub async fn user_create(pool: &Pool, username: &str, password: &str) -> Result<()> {
     let sqlpswd = bcrypt::hash(password, bcrypt::DEFAULT_COST)?;
     let sqlusername = username.to_owned();
 
-    client
+    pool.conn(move |conn| {
+        conn.execute(
+            "INSERT INTO auth (username, password, enabled) VALUES(?1, ?2, ?3)",
+            (&sqlusername, &sqlpswd, &true),
+        )
+    })
+    .await?;
+
+    Ok(())
+}
+
+fn user_from_row(row: &async_sqlite::rusqlite::Row) -> async_sqlite::rusqlite::Result<UserRecord> {
+    Ok(UserRecord {
+        username: row.get(0)?,
+        enabled: row.get(1)?,
+        location: row.get(2)?,
+        manager: row.get(3)?,
+    })
+}
+
+pub async fn user_get(pool: &Pool, username: &str) -> Result<Option<UserRecord>> {
+    let sqlusername = username.to_owned();
+    let user = pool
         .conn(move |conn| {
-            conn.execute(
-                "INSERT INTO auth (username, password, enabled) VALUES(?1, ?2, ?3)",
-                (&sqlusername, &sqlpswd, &true),
+            conn.query_row(
+                "SELECT username, enabled, location, manager FROM auth WHERE username=?1",
+                [&sqlusername],
+                user_from_row,
             )
+            .optional()
         })
         .await?;
 
-    Ok(())
+    Ok(user)
 }
 
-// Used by the cli to create users
-pub async fn user_create_console(
-    client_opt: Option<Client>,
-    username: Option<String>,
-) -> Result<()> {
-    let username = if let Some(username) = username {
-        username
-    } else {
-        crate::prompt_user_input("Username: ")?
-    };
+pub async fn user_get_all(pool: &Pool) -> Result<Vec<UserRecord>> {
+    let users = pool
+        .conn(|conn| {
+            let mut stmt = conn.prepare(
+                "SELECT username, enabled, location, manager FROM auth ORDER BY username",
+            )?;
+            let user_iter = stmt.query_map([], user_from_row)?;
+            user_iter.collect()
+        })
+        .await?;
 
-    let password1 = rpassword::prompt_password("Password: ")?;
-    let password2 = rpassword::prompt_password("Password again: ")?;
+    Ok(users)
+}
 
-    if password1 == password2 {
-        user_create(client_opt, &username, &password1).await?;
-        println!("User {} created!", username);
-    } else {
-        panic!("Passwords do not match! Please try again");
+// Every user with its password hash and last login, for copying the
+// database to another backend
+pub async fn user_export(pool: &Pool) -> Result<Vec<(UserRecord, String, Option<i64>)>> {
+    let users = pool
+        .conn(|conn| {
+            let mut stmt = conn.prepare(
+                "SELECT username, enabled, location, manager, password, lastlogin
+                    FROM auth ORDER BY username",
+            )?;
+            let user_iter = stmt.query_map([], |row| {
+                Ok((user_from_row(row)?, row.get(4)?, row.get(5)?))
+            })?;
+            user_iter.collect()
+        })
+        .await?;
+
+    Ok(users)
+}
+
+// Replaces the tags of a spord, creating new ones and dropping the ones no
+// spord uses anymore
+fn set_tags(
+    tx: &async_sqlite::rusqlite::Transaction,
+    spord_id: i64,
+    tags: &[String],
+) -> async_sqlite::rusqlite::Result<()> {
+    tx.execute("DELETE FROM spord_tags WHERE spord=?1", [spord_id])?;
+    for tag in tags {
+        tx.execute(
+            "INSERT INTO tags (name) VALUES(?1) ON CONFLICT (name) DO NOTHING",
+            [tag],
+        )?;
+        tx.execute(
+            "INSERT INTO spord_tags (spord, tag) SELECT ?1, id FROM tags WHERE name=?2",
+            (spord_id, tag),
+        )?;
     }
+    tx.execute(
+        "DELETE FROM tags WHERE id NOT IN (SELECT tag FROM spord_tags)",
+        (),
+    )?;
 
     Ok(())
 }
 
-pub async fn spord_create(client_opt: Option<Client>, spord: SpordRecord) -> Result<()> {
-    let client = open_if_needed(client_opt).await?;
-
-    client
-        .conn(move |conn| {
-            conn.execute(
+// Inserts a new spord, returning its id
+pub async fn spord_create(pool: &Pool, spord: SpordRecord) -> Result<i64> {
+    let id = pool
+        .conn_mut(move |conn| {
+            let tx = conn.transaction()?;
+            tx.execute(
                 "INSERT INTO spords 
-        (name, phone, email, part, state, created, received, comments)
-        VALUES(?1, ?2, ?3, ?4, ?5, ?6, ?7, ?8)",
+        (name, phone, email, part, state, created, received, location, vendor)
+        VALUES(?1, ?2, ?3, ?4, ?5, ?6, ?7, ?8, ?9)",
                 (
                     &spord.customer_name,
                     &spord.customer_phone,
@@ -171,58 +409,596 @@ pub async fn spord_create(client_opt: Option<Client>, spord: SpordRecord) -> Res
                     spord.state.as_sql(),
                     spord.creation_date.timestamp(),
                     spord.received_date_unix(),
-                    &spord.comments,
+                    spord.location,
+                    &spord.vendor,
                 ),
-            )
+            )?;
+            let id = tx.last_insert_rowid();
+            set_tags(&tx, id, &spord.tags)?;
+            tx.commit()?;
+
+            Ok(id)
         })
         .await?;
 
-    Ok(())
+    Ok(id)
 }
 
-pub async fn spord_update(client_opt: Option<Client>, spord: SpordRecord) -> Result<()> {
-    let client = open_if_needed(client_opt).await?;
+// Returns false if there is no spord with this id. The location is left alone,
+// moving a spord to another store goes through locations::transfer_spord. The
+// tags are replaced and a change of state is recorded in the history
+pub async fn spord_update(
+    pool: &Pool,
+    spord: SpordRecord,
+    username: Option<String>,
+) -> Result<bool> {
+    let updated = pool
+        .conn_mut(move |conn| {
+            let tx = conn.transaction()?;
 
-    client.conn(move |conn| {
+            let old_state: Option<i32> = tx
+                .query_row("SELECT state FROM spords WHERE id=?1", [spord.id], |row| {
+                    row.get(0)
+                })
+                .optional()?;
+            let Some(old_state) = old_state else {
+                return Ok(false);
+            };
+
+            tx.execute(
+                "UPDATE spords SET name=?1, phone=?2, email=?3, part=?4, state=?5, created=?6,
+                    received=?7, vendor=?8 WHERE id=?9",
+                (
+                    &spord.customer_name,
+                    &spord.customer_phone,
+                    &spord.customer_email,
+                    &spord.part,
+                    spord.state.as_sql(),
+                    spord.creation_date.timestamp(),
+                    spord.received_date_unix(),
+                    &spord.vendor,
+                    spord.id,
+                ),
+            )?;
+            set_tags(&tx, spord.id as i64, &spord.tags)?;
+            if old_state != spord.state.as_sql() {
+                tx.execute(
+                    "INSERT INTO spord_events (spord, kind, old_value, new_value, username, time)
+                        VALUES(?1, ?2, ?3, ?4, ?5, ?6)",
+                    (
+                        spord.id,
+                        SpordEventKind::State.as_sql(),
+                        old_state,
+                        spord.state.as_sql(),
+                        &username,
+                        Utc::now().timestamp(),
+                    ),
+                )?;
+            }
+            tx.commit()?;
 
-        conn.execute("UPDATE spords SET name=?1, phone=?2, email=?3, part=?4, state=?5, created=?6, received=?7, comments=?8 WHERE id=?9;", 
-        (&spord.customer_name, &spord.customer_phone, &spord.customer_email
-            , &spord.part, spord.state.as_sql(), spord.creation_date.timestamp(), spord.received_date_unix(), &spord.comments, spord.id))
-    }).await?;
-    Ok(())
+            Ok(true)
+        })
+        .await?;
+
+    Ok(updated)
 }
 
-pub async fn spord_get_all(client_opt: Option<Client>) -> Result<Vec<SpordRecord>> {
-    let client = open_if_needed(client_opt).await?;
+const SPORD_COLUMNS: &str = "id, name, phone, email, part, state, created, received, location, \
+    vendor, (SELECT GROUP_CONCAT(tags.name) FROM spord_tags JOIN tags ON tags.id = spord_tags.tag \
+        WHERE spord_tags.spord = spords.id), archived, anonymized";
+
+fn spord_from_row(
+    row: &async_sqlite::rusqlite::Row,
+) -> async_sqlite::rusqlite::Result<SpordRecord> {
+    Ok(SpordRecord {
+        id: row.get(0)?,
+        customer_name: row.get(1)?,
+        customer_phone: row.get(2)?,
+        customer_email: row.get(3)?,
+        part: row.get(4)?,
+        state: SpordState::from_sql(row.get(5)?),
+        creation_date: DateTime::from_timestamp(row.get(6)?, 0).unwrap(),
+        received_date: row
+            .get::<_, Option<i64>>(7)?
+            .and_then(|received| DateTime::from_timestamp(received, 0)),
+        location: row.get(8)?,
+        vendor: row.get(9)?,
+        tags: models::clean_tags(
+            row.get::<_, Option<String>>(10)?
+                .unwrap_or_default()
+                .split(','),
+        ),
+        archived: row
+            .get::<_, Option<i64>>(11)?
+            .and_then(|archived| DateTime::from_timestamp(archived, 0)),
+        anonymized: row
+            .get::<_, Option<i64>>(12)?
+            .and_then(|anonymized| DateTime::from_timestamp(anonymized, 0)),
+    })
+}
+
+pub async fn spord_get(pool: &Pool, id: i32) -> Result<Option<SpordRecord>> {
+    let spord = pool
+        .conn(move |conn| {
+            conn.query_row(
+                &format!("SELECT {} FROM spords WHERE id=?1", SPORD_COLUMNS),
+                [id],
+                spord_from_row,
+            )
+            .optional()
+        })
+        .await?;
+
+    Ok(spord)
+}
+
+// Restricts a spord listing to one store, None being spords without a store
+#[derive(Debug, Clone, Copy)]
+pub struct LocationFilter(pub Option<i64>);
+
+// What a spord listing is narrowed down to, the default shows everything
+#[derive(Debug, Clone, Default)]
+pub struct SpordFilter {
+    // one store, or every store when None
+    pub location: Option<LocationFilter>,
+    pub state: Option<SpordState>,
+    // matched against customer name, phone, email and part
+    pub search: Option<String>,
+    // spords having every one of these tags
+    pub tags: Vec<String>,
+    // archived spords instead of the active ones
+    pub archived: bool,
+}
 
-    let myspords = client
+pub async fn spord_get_all(pool: &Pool, filter: SpordFilter) -> Result<Vec<SpordRecord>> {
+    let myspords = pool
+        .conn(move |conn| {
+            let mut stmt = conn.prepare(&format!(
+                "SELECT {} FROM spords WHERE (?1 OR location IS ?2)
+                    AND (?3 IS NULL OR state = ?3)
+                    AND (?4 IS NULL OR name LIKE ?4 ESCAPE '\\' OR phone LIKE ?4 ESCAPE '\\'
+                        OR email LIKE ?4 ESCAPE '\\' OR part LIKE ?4 ESCAPE '\\')
+                    AND (SELECT COUNT(*) FROM spord_tags JOIN tags ON tags.id = spord_tags.tag
+                        WHERE spord_tags.spord = spords.id
+                        AND tags.name IN (SELECT value FROM json_each(?5))) = ?6
+                    AND (archived IS NOT NULL) = ?7
+                    ORDER BY id",
+                SPORD_COLUMNS
+            ))?;
+
+            let all_locations = filter.location.is_none();
+            let location = filter
+                .location
+                .and_then(|LocationFilter(location)| location);
+            let state = filter.state.map(|state| state.as_sql());
+            let search = filter.search.map(|search| {
+                let escaped = search
+                    .replace('\\', "\\\\")
+                    .replace('%', "\\%")
+                    .replace('_', "\\_");
+                format!("%{}%", escaped)
+            });
+            let tag_count = filter.tags.len();
+            let tags = serde_json::to_string(&filter.tags).unwrap_or_default();
+            let spord_iter = stmt.query_map(
+                (
+                    all_locations,
+                    location,
+                    state,
+                    search,
+                    tags,
+                    tag_count,
+                    filter.archived,
+                ),
+                spord_from_row,
+            )?;
+            Ok(spord_iter.collect::<Vec<_>>())
+        })
+        .await?;
+
+    // logged here rather than on the pool thread, to keep the request id
+    let mut spords = vec![];
+    for spord in myspords {
+        match spord {
+            Ok(spord) => spords.push(spord),
+            Err(e) => warn!("Error fetch spord record: {:?}", e),
+        }
+    }
+
+    Ok(spords)
+}
+
+// Every spord, archived or not, for copying the database to another backend.
+// Unlike spord_get_all a row that can't be read is an error, a copy must not
+// leave spords behind
+pub async fn spord_export(pool: &Pool) -> Result<Vec<SpordRecord>> {
+    let spords = pool
         .conn(|conn| {
-            let mut stmt = conn.prepare("SELECT * FROM spords")?;
+            let mut stmt =
+                conn.prepare(&format!("SELECT {} FROM spords ORDER BY id", SPORD_COLUMNS))?;
+            let spord_iter = stmt.query_map([], spord_from_row)?;
+            spord_iter.collect()
+        })
+        .await?;
+
+    Ok(spords)
+}
+
+// Spords per state, and how many pending or ordered ones were created before
+// overdue_before. Archived spords are not counted
+pub async fn spord_stats(pool: &Pool, overdue_before: DateTime<Utc>) -> Result<SpordStats> {
+    let stats = pool
+        .conn(move |conn| {
+            let mut stmt = conn.prepare(
+                "SELECT state, COUNT(*) FROM spords WHERE archived IS NULL GROUP BY state",
+            )?;
+            let counts = stmt
+                .query_map([], |row| {
+                    Ok((SpordState::from_sql(row.get(0)?), row.get::<_, i64>(1)?))
+                })?
+                .collect::<async_sqlite::rusqlite::Result<Vec<_>>>()?;
+
+            let overdue = conn.query_row(
+                "SELECT COUNT(*) FROM spords
+                    WHERE archived IS NULL AND state IN (?1, ?2) AND created < ?3",
+                (
+                    SpordState::Pending.as_sql(),
+                    SpordState::Ordered.as_sql(),
+                    overdue_before.timestamp(),
+                ),
+                |row| row.get(0),
+            )?;
+
+            Ok(SpordStats {
+                by_state: count_by_state(counts),
+                overdue,
+            })
+        })
+        .await?;
+
+    Ok(stats)
+}
+
+// Numbers for the index page, restricted to one store when location is given.
+// Open spords are the pending and ordered ones, archived spords are left out
+pub async fn spord_dashboard(
+    pool: &Pool,
+    location: Option<LocationFilter>,
+    created_since: DateTime<Utc>,
+    overdue_before: DateTime<Utc>,
+    oldest_limit: usize,
+) -> Result<Dashboard> {
+    let dashboard = pool
+        .conn(move |conn| {
+            let all_locations = location.is_none();
+            let location = location.and_then(|LocationFilter(location)| location);
+            let open = (SpordState::Pending.as_sql(), SpordState::Ordered.as_sql());
+
+            let mut stmt = conn.prepare(
+                "SELECT state, COUNT(*) FROM spords WHERE (?1 OR location IS ?2)
+                    AND archived IS NULL GROUP BY state",
+            )?;
+            let counts = stmt
+                .query_map((all_locations, location), |row| {
+                    Ok((SpordState::from_sql(row.get(0)?), row.get::<_, i64>(1)?))
+                })?
+                .collect::<async_sqlite::rusqlite::Result<Vec<_>>>()?;
+
+            let (created, awaiting_pickup, overdue) = conn.query_row(
+                "SELECT
+                    COALESCE(SUM(CASE WHEN created >= ?3 THEN 1 ELSE 0 END), 0),
+                    COALESCE(SUM(CASE WHEN state = ?4 THEN 1 ELSE 0 END), 0),
+                    COALESCE(SUM(CASE WHEN state IN (?5, ?6) AND created < ?7
+                        THEN 1 ELSE 0 END), 0)
+                    FROM spords WHERE (?1 OR location IS ?2) AND archived IS NULL",
+                (
+                    all_locations,
+                    location,
+                    created_since.timestamp(),
+                    SpordState::Received.as_sql(),
+                    open.0,
+                    open.1,
+                    overdue_before.timestamp(),
+                ),
+                |row| Ok((row.get(0)?, row.get(1)?, row.get(2)?)),
+            )?;
+
+            let mut stmt = conn.prepare(&format!(
+                "SELECT {} FROM spords WHERE (?1 OR location IS ?2) AND state IN (?3, ?4)
+                    AND archived IS NULL ORDER BY created, id LIMIT ?5",
+                SPORD_COLUMNS
+            ))?;
+            let oldest_open = stmt
+                .query_map(
+                    (all_locations, location, open.0, open.1, oldest_limit),
+                    spord_from_row,
+                )?
+                .collect::<async_sqlite::rusqlite::Result<Vec<_>>>()?;
+
+            Ok(Dashboard {
+                by_state: count_by_state(counts),
+                created_since: created,
+                awaiting_pickup,
+                overdue,
+                oldest_open,
+            })
+        })
+        .await?;
+
+    Ok(dashboard)
+}
+
+// Creation, received and picked up times of the spords created since
+// created_since, restricted to one store when location is given. A missing
+// received date is taken from the history. Archived spords are included, so
+// the reports don't change when picked up spords get archived
+pub async fn spord_turnaround(
+    pool: &Pool,
+    location: Option<LocationFilter>,
+    created_since: Option<DateTime<Utc>>,
+) -> Result<Vec<SpordTurnaround>> {
+    let turnaround = pool
+        .conn(move |conn| {
+            let all_locations = location.is_none();
+            let location = location.and_then(|LocationFilter(location)| location);
+            let created_since = created_since.map(|since| since.timestamp());
+
+            let mut stmt = conn.prepare(
+                "SELECT vendor, part, created,
+                    COALESCE(received, (SELECT MIN(time) FROM spord_events
+                        WHERE spord = spords.id AND kind = ?4 AND new_value = ?5)),
+                    (SELECT MIN(time) FROM spord_events
+                        WHERE spord = spords.id AND kind = ?4 AND new_value = ?6)
+                    FROM spords WHERE (?1 OR location IS ?2) AND (?3 IS NULL OR created >= ?3)
+                    ORDER BY created, id",
+            )?;
+            let rows = stmt.query_map(
+                (
+                    all_locations,
+                    location,
+                    created_since,
+                    SpordEventKind::State.as_sql(),
+                    SpordState::Received.as_sql(),
+                    SpordState::PickedUp.as_sql(),
+                ),
+                |row| {
+                    Ok(SpordTurnaround {
+                        vendor: row.get(0)?,
+                        part: row.get::<_, Option<String>>(1)?.unwrap_or_default(),
+                        creation_date: DateTime::from_timestamp(row.get(2)?, 0).unwrap_or_default(),
+                        received_date: row
+                            .get::<_, Option<i64>>(3)?
+                            .and_then(|received| DateTime::from_timestamp(received, 0)),
+                        picked_up_date: row
+                            .get::<_, Option<i64>>(4)?
+                            .and_then(|picked_up| DateTime::from_timestamp(picked_up, 0)),
+                    })
+                },
+            )?;
+            rows.collect::<async_sqlite::rusqlite::Result<Vec<_>>>()
+        })
+        .await?;
+
+    Ok(turnaround)
+}
+
+// One entry per state in SpordState::ALL order. Unknown state numbers all map
+// to Other, so their counts are added up
+pub fn count_by_state(counts: Vec<(SpordState, i64)>) -> Vec<(SpordState, i64)> {
+    SpordState::ALL
+        .into_iter()
+        .map(|state| {
+            let count = counts
+                .iter()
+                .filter(|(counted, _)| *counted == state)
+                .map(|(_, count)| count)
+                .sum();
+            (state, count)
+        })
+        .collect()
+}
 
-            let spord_iter = stmt.query_map([], |row| {
-                Ok(SpordRecord {
+// Deletes a spord with its history, notes and tags, returns false if there was none
+pub async fn spord_delete(pool: &Pool, id: i32) -> Result<bool> {
+    let deleted = pool
+        .conn_mut(move |conn| {
+            let tx = conn.transaction()?;
+            tx.execute("DELETE FROM spord_events WHERE spord=?1", [id])?;
+            tx.execute(
+                "DELETE FROM spord_note_revisions
+                    WHERE note IN (SELECT id FROM spord_notes WHERE spord=?1)",
+                [id],
+            )?;
+            tx.execute("DELETE FROM spord_notes WHERE spord=?1", [id])?;
+            set_tags(&tx, id as i64, &[])?;
+            let deleted = tx.execute("DELETE FROM spords WHERE id=?1", [id])?;
+            tx.commit()?;
+            Ok(deleted > 0)
+        })
+        .await?;
+
+    Ok(deleted)
+}
+
+// Sets or clears the archived time of a spord and records it in the history.
+// Returns false if the spord does not exist or is already in that state
+fn set_archived(
+    tx: &async_sqlite::rusqlite::Transaction,
+    id: i32,
+    archived: Option<i64>,
+    username: &Option<String>,
+) -> async_sqlite::rusqlite::Result<bool> {
+    let (changed, kind) = match archived {
+        Some(archived) => (
+            tx.execute(
+                "UPDATE spords SET archived=?1 WHERE id=?2 AND archived IS NULL",
+                (archived, id),
+            )?,
+            SpordEventKind::Archive,
+        ),
+        None => (
+            tx.execute(
+                "UPDATE spords SET archived=NULL WHERE id=?1 AND archived IS NOT NULL",
+                [id],
+            )?,
+            SpordEventKind::Restore,
+        ),
+    };
+    if changed == 0 {
+        return Ok(false);
+    }
+
+    tx.execute(
+        "INSERT INTO spord_events (spord, kind, username, time) VALUES(?1, ?2, ?3, ?4)",
+        (id, kind.as_sql(), username, Utc::now().timestamp()),
+    )?;
+
+    Ok(true)
+}
+
+// Hides a spord from the listings, returns false if there is no spord with
+// this id that isn't archived yet
+pub async fn spord_archive(pool: &Pool, id: i32, username: Option<String>) -> Result<bool> {
+    let archived = pool
+        .conn_mut(move |conn| {
+            let tx = conn.transaction()?;
+            let archived = set_archived(&tx, id, Some(Utc::now().timestamp()), &username)?;
+            tx.commit()?;
+            Ok(archived)
+        })
+        .await?;
+
+    Ok(archived)
+}
+
+// Brings an archived spord back, returns false if there is no archived spord
+// with this id
+pub async fn spord_restore(pool: &Pool, id: i32, username: Option<String>) -> Result<bool> {
+    let restored = pool
+        .conn_mut(move |conn| {
+            let tx = conn.transaction()?;
+            let restored = set_archived(&tx, id, None, &username)?;
+            tx.commit()?;
+            Ok(restored)
+        })
+        .await?;
+
+    Ok(restored)
+}
+
+// Archives the picked up spords whose last change to picked up in the history
+// is before picked_up_before. Spords without one count from their received or
+// creation date, restored spords from when they were restored. Returns the ids
+// of the archived spords
+pub async fn spord_archive_picked_up(
+    pool: &Pool,
+    picked_up_before: DateTime<Utc>,
+) -> Result<Vec<i32>> {
+    let ids = pool
+        .conn_mut(move |conn| {
+            let tx = conn.transaction()?;
+            let ids = {
+                let mut stmt = tx.prepare(
+                    "SELECT id FROM spords WHERE archived IS NULL AND state = ?1
+                        AND MAX(COALESCE((SELECT MAX(time) FROM spord_events
+                                WHERE spord = spords.id AND kind = ?2 AND new_value = ?1),
+                                received, created),
+                            COALESCE((SELECT MAX(time) FROM spord_events
+                                WHERE spord = spords.id AND kind = ?3), 0)) < ?4
+                        ORDER BY id",
+                )?;
+                let id_iter = stmt.query_map(
+                    (
+                        SpordState::PickedUp.as_sql(),
+                        SpordEventKind::State.as_sql(),
+                        SpordEventKind::Restore.as_sql(),
+                        picked_up_before.timestamp(),
+                    ),
+                    |row| row.get(0),
+                )?;
+                id_iter.collect::<async_sqlite::rusqlite::Result<Vec<i32>>>()?
+            };
+
+            let now = Utc::now().timestamp();
+            for id in &ids {
+                set_archived(&tx, *id, Some(now), &None)?;
+            }
+            tx.commit()?;
+
+            Ok(ids)
+        })
+        .await?;
+
+    Ok(ids)
+}
+
+// Removes the customer details and notes of an archived spord, keeping the
+// rest and its history for the reports. Returns false if there is no archived
+// spord with this id that still has them
+pub async fn spord_anonymize(pool: &Pool, id: i32) -> Result<bool> {
+    let anonymized = pool
+        .conn_mut(move |conn| {
+            let tx = conn.transaction()?;
+            let changed = tx.execute(
+                "UPDATE spords SET name='', phone=NULL, email=NULL, anonymized=?1
+                    WHERE id=?2 AND archived IS NOT NULL AND anonymized IS NULL",
+                (Utc::now().timestamp(), id),
+            )?;
+            if changed == 0 {
+                return Ok(false);
+            }
+            tx.execute(
+                "DELETE FROM spord_note_revisions
+                    WHERE note IN (SELECT id FROM spord_notes WHERE spord=?1)",
+                [id],
+            )?;
+            tx.execute("DELETE FROM spord_notes WHERE spord=?1", [id])?;
+            tx.commit()?;
+
+            Ok(true)
+        })
+        .await?;
+
+    Ok(anonymized)
+}
+
+pub async fn spord_events_get(pool: &Pool, spord_id: i32) -> Result<Vec<SpordEventRecord>> {
+    let events = pool
+        .conn(move |conn| {
+            let mut stmt = conn.prepare(
+                "SELECT id, spord, kind, old_value, new_value, username, time
+                    FROM spord_events WHERE spord=?1 ORDER BY time, id",
+            )?;
+            let event_iter = stmt.query_map([spord_id], |row| {
+                Ok(SpordEventRecord {
                     id: row.get(0)?,
-                    customer_name: row.get(1)?,
-                    customer_phone: row.get(2)?,
-                    customer_email: row.get(3)?,
-                    part: row.get(4)?,
-                    state: SpordState::from_sql(row.get(5)?),
-                    creation_date: DateTime::from_timestamp(row.get(6)?, 0).unwrap(),
-                    received_date: None,
-                    comments: row.get(8)?,
+                    spord_id: row.get(1)?,
+                    kind: SpordEventKind::from_sql(&row.get::<_, String>(2)?),
+                    old_value: row.get(3)?,
+                    new_value: row.get(4)?,
+                    username: row.get(5)?,
+                    time: DateTime::from_timestamp(row.get(6)?, 0).unwrap_or_default(),
                 })
             })?;
-            let mut spords = vec![];
-            for spord in spord_iter {
-                if spord.is_ok() {
-                    spords.push(spord.unwrap());
-                } else {
-                    warn!("Error fetch spord record: {:?}", spord.unwrap_err());
-                }
-            }
-            Ok(spords)
+            event_iter.collect()
+        })
+        .await?;
+
+    Ok(events)
+}
+
+// Every tag some spord has, sorted
+pub async fn tags_get_all(pool: &Pool) -> Result<Vec<String>> {
+    let tags = pool
+        .conn(|conn| {
+            let mut stmt = conn.prepare(
+                "SELECT name FROM tags WHERE id IN (SELECT tag FROM spord_tags) ORDER BY name",
+            )?;
+            let tag_iter = stmt.query_map([], |row| row.get(0))?;
+            tag_iter.collect()
         })
         .await?;
 
-    Ok(myspords)
+    Ok(tags)
 }
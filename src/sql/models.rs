@@ -1,10 +1,12 @@
-use chrono::{DateTime, Local, Utc};
+use chrono::{DateTime, Utc};
+use serde::{Deserialize, Serialize};
 
-#[derive(Debug, PartialEq, Clone)]
+#[derive(Debug, PartialEq, Clone, Serialize, Deserialize)]
 pub enum SpordState {
     Pending,
     Ordered,
     Received,
+    PickedUp,
     Other,
 }
 impl SpordState {
@@ -13,6 +15,7 @@ impl SpordState {
             Self::Pending => 1,
             Self::Ordered => 2,
             Self::Received => 3,
+            Self::PickedUp => 4,
             Self::Other => 0,
         }
     }
@@ -21,24 +24,60 @@ impl SpordState {
             1 => Self::Pending,
             2 => Self::Ordered,
             3 => Self::Received,
+            4 => Self::PickedUp,
             _ => Self::Other,
         }
     }
+    pub const ALL: [SpordState; 5] = [
+        Self::Pending,
+        Self::Ordered,
+        Self::Received,
+        Self::PickedUp,
+        Self::Other,
+    ];
+    // lowercase name for the command line
+    pub fn name(&self) -> &'static str {
+        match self {
+            Self::Pending => "pending",
+            Self::Ordered => "ordered",
+            Self::Received => "received",
+            Self::PickedUp => "picked_up",
+            Self::Other => "other",
+        }
+    }
+    pub fn from_name(name: &str) -> Option<SpordState> {
+        Self::ALL
+            .into_iter()
+            .find(|state| state.name().eq_ignore_ascii_case(name))
+    }
 }
 
-#[derive(Debug, Clone)]
+#[derive(Debug, Clone, Serialize, Deserialize)]
 pub struct SpordRecord {
     pub id: i32,
     pub customer_name: String,
     pub customer_phone: Option<String>,
     pub customer_email: Option<String>,
     pub part: String,
+    // where the part is ordered from
+    #[serde(default)]
+    pub vendor: Option<String>,
     pub state: SpordState,
     pub creation_date: DateTime<Utc>,
     pub received_date: Option<DateTime<Utc>>,
-    pub comments: Option<String>,
+    pub location: Option<i64>,
+    // sorted, as cleaned up by clean_tags
+    #[serde(default)]
+    pub tags: Vec<String>,
+    // archived spords are left out of listings until they are restored
+    #[serde(default)]
+    pub archived: Option<DateTime<Utc>>,
+    // when the retention policy removed the customer details and notes
+    #[serde(default)]
+    pub anonymized: Option<DateTime<Utc>>,
 }
 impl SpordRecord {
+    #[allow(clippy::manual_map)]
     pub fn received_date_unix(&self) -> Option<i64> {
         if let Some(received_date) = self.received_date {
             Some(received_date.timestamp())
@@ -47,3 +86,333 @@ impl SpordRecord {
         }
     }
 }
+
+const MAX_TAG_CHARS: usize = 40;
+
+// Lowercase tags with single spaces, sorted and without duplicates. Commas
+// separate tags, so they are dropped
+pub fn clean_tags<'a>(tags: impl IntoIterator<Item = &'a str>) -> Vec<String> {
+    let mut tags: Vec<String> = tags
+        .into_iter()
+        .map(|tag| {
+            tag.replace(',', " ")
+                .split_whitespace()
+                .collect::<Vec<_>>()
+                .join(" ")
+                .to_lowercase()
+                .chars()
+                .take(MAX_TAG_CHARS)
+                .collect::<String>()
+        })
+        .filter(|tag| !tag.is_empty())
+        .collect();
+    tags.sort();
+    tags.dedup();
+    tags
+}
+
+// Tags typed as one comma separated text
+pub fn parse_tags(tags: &str) -> Vec<String> {
+    clean_tags(tags.split(','))
+}
+
+// Aggregate numbers over all spords
+#[derive(Debug, Clone, Default, Serialize)]
+pub struct SpordStats {
+    // every state, including those without spords
+    pub by_state: Vec<(SpordState, i64)>,
+    // still pending or ordered after spords.overdue_days
+    pub overdue: i64,
+}
+
+// What the index page shows, for one store or all of them
+#[derive(Debug, Clone, Default, Serialize)]
+pub struct Dashboard {
+    pub by_state: Vec<(SpordState, i64)>,
+    pub created_since: i64,
+    // received in the store, waiting for the customer
+    pub awaiting_pickup: i64,
+    pub overdue: i64,
+    // pending or ordered, oldest first
+    pub oldest_open: Vec<SpordRecord>,
+}
+
+// When a spord reached each step, for the turnaround reports
+#[derive(Debug, Clone)]
+pub struct SpordTurnaround {
+    pub vendor: Option<String>,
+    pub part: String,
+    pub creation_date: DateTime<Utc>,
+    // received_date, or the first change to received in the history
+    pub received_date: Option<DateTime<Utc>>,
+    // the first change to picked up in the history
+    pub picked_up_date: Option<DateTime<Utc>>,
+}
+
+#[derive(Debug, PartialEq, Clone, Copy, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum SpordEventKind {
+    // old_value and new_value are location ids
+    Transfer,
+    // old_value and new_value are SpordState::as_sql numbers
+    State,
+    // archived by hand or by the retention policy, without values
+    Archive,
+    Restore,
+    Other,
+}
+impl SpordEventKind {
+    pub fn as_sql(&self) -> &'static str {
+        match self {
+            Self::Transfer => "transfer",
+            Self::State => "state",
+            Self::Archive => "archive",
+            Self::Restore => "restore",
+            Self::Other => "other",
+        }
+    }
+    pub fn from_sql(kind: &str) -> SpordEventKind {
+        match kind {
+            "transfer" => Self::Transfer,
+            "state" => Self::State,
+            "archive" => Self::Archive,
+            "restore" => Self::Restore,
+            _ => Self::Other,
+        }
+    }
+}
+
+#[derive(Debug, Clone, Serialize)]
+pub struct SpordEventRecord {
+    pub id: i64,
+    pub spord_id: i32,
+    pub kind: SpordEventKind,
+    pub old_value: Option<i64>,
+    pub new_value: Option<i64>,
+    pub username: Option<String>,
+    pub time: DateTime<Utc>,
+}
+
+// A note on a spord. Notes are only appended, unless spords.edit_notes
+// allows changing them, in which case every earlier text is kept
+#[derive(Debug, Clone, Serialize)]
+pub struct NoteRecord {
+    pub id: i64,
+    pub spord_id: i32,
+    // None for notes made from the command line or the old comments field
+    pub username: Option<String>,
+    pub text: String,
+    pub created: DateTime<Utc>,
+    pub edited: Option<DateTime<Utc>>,
+    pub edited_by: Option<String>,
+    // earlier texts, oldest first
+    pub revisions: Vec<NoteRevision>,
+}
+
+// A text a note had before it was edited, with who wrote it and when
+#[derive(Debug, Clone, Serialize)]
+pub struct NoteRevision {
+    pub text: String,
+    pub username: Option<String>,
+    pub time: DateTime<Utc>,
+}
+
+// A file kept with a spord. The content lives in attachments.dir under its
+// sha256, shared by every attachment with the same content
+#[derive(Debug, Clone, Serialize)]
+pub struct AttachmentRecord {
+    pub id: i64,
+    pub spord_id: i32,
+    pub filename: String,
+    // sniffed from the content
+    pub mime_type: String,
+    pub size: i64,
+    pub sha256: String,
+    pub username: Option<String>,
+    pub created: DateTime<Utc>,
+}
+
+// A spord list filter a user saved under a name, shown in the navbar
+#[derive(Debug, Clone, Serialize)]
+pub struct SavedFilterRecord {
+    pub id: i64,
+    pub username: String,
+    pub name: String,
+    pub state: Option<SpordState>,
+    pub tags: Vec<String>,
+    pub search: Option<String>,
+    pub created: DateTime<Utc>,
+}
+impl SavedFilterRecord {
+    // Query string of the spord list showing this filter
+    pub fn query(&self) -> String {
+        let mut query = vec![];
+        if let Some(ref state) = self.state {
+            query.push(("state", state.name().to_string()));
+        }
+        if !self.tags.is_empty() {
+            query.push(("tags", self.tags.join(",")));
+        }
+        if let Some(ref search) = self.search {
+            query.push(("q", search.clone()));
+        }
+        serde_urlencoded::to_string(query).unwrap_or_default()
+    }
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct LocationRecord {
+    pub id: i64,
+    pub name: String,
+}
+
+#[derive(Debug, Clone)]
+pub struct UserRecord {
+    pub username: String,
+    pub enabled: bool,
+    // home store, whose spords are shown by default
+    pub location: Option<i64>,
+    // managers see and manage every store
+    pub manager: bool,
+}
+
+#[derive(Debug, Clone)]
+pub struct SessionRecord {
+    pub id: i64,
+    pub username: Option<String>,
+    pub created: DateTime<Utc>,
+    pub last_seen: DateTime<Utc>,
+    pub expires: DateTime<Utc>,
+}
+
+#[derive(Debug, PartialEq, Clone, Copy, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum TokenScope {
+    Read,
+    Write,
+}
+impl TokenScope {
+    pub fn as_sql(&self) -> &'static str {
+        match self {
+            Self::Read => "read",
+            Self::Write => "write",
+        }
+    }
+    pub fn from_sql(scope: &str) -> TokenScope {
+        match scope {
+            "write" => Self::Write,
+            _ => Self::Read,
+        }
+    }
+}
+
+#[derive(Debug, Clone, Serialize)]
+pub struct ApiTokenRecord {
+    pub id: i64,
+    pub name: String,
+    // None for service tokens
+    pub username: Option<String>,
+    pub scope: TokenScope,
+    pub created: DateTime<Utc>,
+    pub expires: Option<DateTime<Utc>>,
+    pub last_used: Option<DateTime<Utc>>,
+    // the only store a service token sees, None for every store. User tokens
+    // see what their user sees
+    pub location: Option<i64>,
+}
+
+#[derive(Debug, PartialEq, Clone, Copy, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum WebhookEvent {
+    Created,
+    StateChanged,
+    Transferred,
+    // by hand or by the retention policy
+    Archived,
+    Deleted,
+    // sent by the test button only
+    Ping,
+}
+impl WebhookEvent {
+    pub const SUBSCRIBABLE: [WebhookEvent; 5] = [
+        Self::Created,
+        Self::StateChanged,
+        Self::Transferred,
+        Self::Archived,
+        Self::Deleted,
+    ];
+
+    pub fn as_sql(&self) -> &'static str {
+        match self {
+            Self::Created => "created",
+            Self::StateChanged => "state_changed",
+            Self::Transferred => "transferred",
+            Self::Archived => "archived",
+            Self::Deleted => "deleted",
+            Self::Ping => "ping",
+        }
+    }
+    pub fn from_sql(event: &str) -> Option<WebhookEvent> {
+        match event {
+            "created" => Some(Self::Created),
+            "state_changed" => Some(Self::StateChanged),
+            "transferred" => Some(Self::Transferred),
+            "archived" => Some(Self::Archived),
+            "deleted" => Some(Self::Deleted),
+            "ping" => Some(Self::Ping),
+            _ => None,
+        }
+    }
+}
+
+#[derive(Debug, Clone, Serialize)]
+pub struct WebhookRecord {
+    pub id: i64,
+    pub url: String,
+    // never shown after the webhook was created
+    #[serde(skip_serializing)]
+    pub secret: String,
+    pub events: Vec<WebhookEvent>,
+    pub enabled: bool,
+    pub created: DateTime<Utc>,
+}
+
+#[derive(Debug, PartialEq, Clone, Copy, Serialize)]
+#[serde(rename_all = "lowercase")]
+pub enum DeliveryState {
+    Pending,
+    Delivered,
+    // gave up after too many attempts
+    Failed,
+}
+impl DeliveryState {
+    pub fn as_sql(&self) -> &'static str {
+        match self {
+            Self::Pending => "pending",
+            Self::Delivered => "delivered",
+            Self::Failed => "failed",
+        }
+    }
+    pub fn from_sql(state: &str) -> DeliveryState {
+        match state {
+            "delivered" => Self::Delivered,
+            "failed" => Self::Failed,
+            _ => Self::Pending,
+        }
+    }
+}
+
+#[derive(Debug, Clone, Serialize)]
+pub struct WebhookDeliveryRecord {
+    pub id: i64,
+    pub webhook_id: i64,
+    pub event: String,
+    pub payload: String,
+    pub state: DeliveryState,
+    pub attempts: u32,
+    pub next_attempt: Option<DateTime<Utc>>,
+    pub last_status: Option<u16>,
+    pub last_error: Option<String>,
+    pub created: DateTime<Utc>,
+    pub updated: DateTime<Utc>,
+}
@@ -0,0 +1,124 @@
+use super::Pool;
+use super::Result;
+use crate::sql::models::{NoteRecord, NoteRevision};
+use async_sqlite::rusqlite::OptionalExtension;
+use chrono::{DateTime, Utc};
+
+// Notes of one spord oldest first, each with its earlier texts
+pub async fn list(pool: &Pool, spord_id: i32) -> Result<Vec<NoteRecord>> {
+    let notes = pool
+        .conn(move |conn| {
+            let mut stmt = conn.prepare(
+                "SELECT id, spord, username, text, created, edited, edited_by
+                    FROM spord_notes WHERE spord=?1 ORDER BY created, id",
+            )?;
+            let mut notes = stmt
+                .query_map([spord_id], |row| {
+                    Ok(NoteRecord {
+                        id: row.get(0)?,
+                        spord_id: row.get(1)?,
+                        username: row.get(2)?,
+                        text: row.get(3)?,
+                        created: DateTime::from_timestamp(row.get(4)?, 0).unwrap_or_default(),
+                        edited: row
+                            .get::<_, Option<i64>>(5)?
+                            .and_then(|edited| DateTime::from_timestamp(edited, 0)),
+                        edited_by: row.get(6)?,
+                        revisions: vec![],
+                    })
+                })?
+                .collect::<async_sqlite::rusqlite::Result<Vec<_>>>()?;
+
+            let mut stmt = conn.prepare(
+                "SELECT spord_note_revisions.note, spord_note_revisions.text,
+                    spord_note_revisions.username, spord_note_revisions.time
+                    FROM spord_note_revisions
+                    JOIN spord_notes ON spord_notes.id = spord_note_revisions.note
+                    WHERE spord_notes.spord=?1 ORDER BY spord_note_revisions.id",
+            )?;
+            let revisions = stmt.query_map([spord_id], |row| {
+                Ok((
+                    row.get::<_, i64>(0)?,
+                    NoteRevision {
+                        text: row.get(1)?,
+                        username: row.get(2)?,
+                        time: DateTime::from_timestamp(row.get(3)?, 0).unwrap_or_default(),
+                    },
+                ))
+            })?;
+            for revision in revisions {
+                let (note_id, revision) = revision?;
+                if let Some(note) = notes.iter_mut().find(|note| note.id == note_id) {
+                    note.revisions.push(revision);
+                }
+            }
+
+            Ok(notes)
+        })
+        .await?;
+
+    Ok(notes)
+}
+
+// Appends a note to a spord, returning its id
+pub async fn add(
+    pool: &Pool,
+    spord_id: i32,
+    username: Option<String>,
+    text: String,
+) -> Result<i64> {
+    let id = pool
+        .conn(move |conn| {
+            conn.execute(
+                "INSERT INTO spord_notes (spord, username, text, created) VALUES(?1, ?2, ?3, ?4)",
+                (spord_id, &username, &text, Utc::now().timestamp()),
+            )?;
+            Ok(conn.last_insert_rowid())
+        })
+        .await?;
+
+    Ok(id)
+}
+
+// Replaces the text of a note, moving the current one with its author and time
+// to the revisions. Returns false if the spord has no such note
+pub async fn edit(
+    pool: &Pool,
+    spord_id: i32,
+    id: i64,
+    username: Option<String>,
+    text: String,
+) -> Result<bool> {
+    let edited = pool
+        .conn_mut(move |conn| {
+            let tx = conn.transaction()?;
+
+            let current: Option<(String, Option<String>, i64)> = tx
+                .query_row(
+                    "SELECT text, CASE WHEN edited IS NULL THEN username ELSE edited_by END,
+                        COALESCE(edited, created) FROM spord_notes WHERE id=?1 AND spord=?2",
+                    (id, spord_id),
+                    |row| Ok((row.get(0)?, row.get(1)?, row.get(2)?)),
+                )
+                .optional()?;
+            let Some((old_text, old_username, old_time)) = current else {
+                return Ok(false);
+            };
+
+            tx.execute(
+                "INSERT INTO spord_note_revisions (note, text, username, time)
+                    VALUES(?1, ?2, ?3, ?4)",
+                (id, &old_text, &old_username, old_time),
+            )?;
+            tx.execute(
+                "UPDATE spord_notes SET text=?1, edited=?2, edited_by=?3 WHERE id=?4",
+                (&text, Utc::now().timestamp(), &username, id),
+            )?;
+            tx.commit()?;
+
+            Ok(true)
+        })
+        .await?;
+
+    Ok(edited)
+}
@@ -0,0 +1,57 @@
+use async_sqlite::rusqlite::{self, Connection};
+use async_sqlite::{Client, ClientBuilder, Error, JournalMode};
+use std::path::Path;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::Arc;
+use std::time::Duration;
+
+// A fixed set of sqlite connections handed out in turn, like async_sqlite's
+// Pool. Every connection is set up right after it is opened, so settings like
+// the busy timeout don't depend on how connections are picked later
+#[derive(Clone)]
+pub struct Pool {
+    clients: Arc<Vec<Client>>,
+    next: Arc<AtomicUsize>,
+}
+impl Pool {
+    pub async fn open(path: &Path, size: usize, busy_timeout: Duration) -> Result<Pool, Error> {
+        let mut clients = Vec::with_capacity(size.max(1));
+        for _ in 0..size.max(1) {
+            let client = ClientBuilder::new()
+                .path(path)
+                .journal_mode(JournalMode::Wal)
+                .open()
+                .await?;
+            client
+                .conn(move |conn| conn.busy_timeout(busy_timeout))
+                .await?;
+            clients.push(client);
+        }
+
+        Ok(Pool {
+            clients: Arc::new(clients),
+            next: Arc::new(AtomicUsize::new(0)),
+        })
+    }
+
+    fn client(&self) -> &Client {
+        let next = self.next.fetch_add(1, Ordering::Relaxed);
+        &self.clients[next % self.clients.len()]
+    }
+
+    pub async fn conn<F, T>(&self, func: F) -> Result<T, Error>
+    where
+        F: FnOnce(&Connection) -> Result<T, rusqlite::Error> + Send + 'static,
+        T: Send + 'static,
+    {
+        self.client().conn(func).await
+    }
+
+    pub async fn conn_mut<F, T>(&self, func: F) -> Result<T, Error>
+    where
+        F: FnOnce(&mut Connection) -> Result<T, rusqlite::Error> + Send + 'static,
+        T: Send + 'static,
+    {
+        self.client().conn_mut(func).await
+    }
+}
@@ -0,0 +1,134 @@
+use super::Pool;
+use super::Result;
+use crate::sql::models::SessionRecord;
+use async_sqlite::rusqlite::OptionalExtension;
+use chrono::{DateTime, Utc};
+
+// Returns the stored session state, unless the session is missing or expired
+pub async fn load(pool: &Pool, key: &str) -> Result<Option<String>> {
+    let sqlkey = key.to_owned();
+    let state = pool
+        .conn(move |conn| {
+            conn.query_row(
+                "SELECT state FROM sessions WHERE key=?1 AND expires>?2",
+                (&sqlkey, Utc::now().timestamp()),
+                |row| row.get(0),
+            )
+            .optional()
+        })
+        .await?;
+
+    Ok(state)
+}
+
+pub async fn save(
+    pool: &Pool,
+    key: &str,
+    username: Option<String>,
+    state: String,
+    expires: DateTime<Utc>,
+) -> Result<()> {
+    let sqlkey = key.to_owned();
+    pool.conn(move |conn| {
+        let now = Utc::now().timestamp();
+        conn.execute(
+            "INSERT INTO sessions (key, username, state, created, last_seen, expires)
+                    VALUES(?1, ?2, ?3, ?4, ?4, ?5)
+                    ON CONFLICT(key) DO UPDATE SET username=excluded.username,
+                    state=excluded.state, last_seen=excluded.last_seen, expires=excluded.expires",
+            (&sqlkey, &username, &state, now, expires.timestamp()),
+        )?;
+        conn.execute("DELETE FROM sessions WHERE expires<=?1", [now])
+    })
+    .await?;
+
+    Ok(())
+}
+
+pub async fn set_expiry(pool: &Pool, key: &str, expires: DateTime<Utc>) -> Result<()> {
+    let sqlkey = key.to_owned();
+    pool.conn(move |conn| {
+        conn.execute(
+            "UPDATE sessions SET last_seen=?1, expires=?2 WHERE key=?3",
+            (Utc::now().timestamp(), expires.timestamp(), &sqlkey),
+        )
+    })
+    .await?;
+
+    Ok(())
+}
+
+pub async fn delete(pool: &Pool, key: &str) -> Result<()> {
+    let sqlkey = key.to_owned();
+    pool.conn(move |conn| conn.execute("DELETE FROM sessions WHERE key=?1", [&sqlkey]))
+        .await?;
+
+    Ok(())
+}
+
+fn session_from_row(
+    row: &async_sqlite::rusqlite::Row,
+) -> async_sqlite::rusqlite::Result<SessionRecord> {
+    Ok(SessionRecord {
+        id: row.get(0)?,
+        username: row.get(1)?,
+        created: DateTime::from_timestamp(row.get(2)?, 0).unwrap_or_default(),
+        last_seen: DateTime::from_timestamp(row.get(3)?, 0).unwrap_or_default(),
+        expires: DateTime::from_timestamp(row.get(4)?, 0).unwrap_or_default(),
+    })
+}
+
+pub async fn list(pool: &Pool) -> Result<Vec<SessionRecord>> {
+    let sessions = pool
+        .conn(|conn| {
+            let mut stmt = conn.prepare(
+                "SELECT id, username, created, last_seen, expires FROM sessions
+                    WHERE expires>?1 ORDER BY last_seen DESC",
+            )?;
+
+            let session_iter = stmt.query_map([Utc::now().timestamp()], session_from_row)?;
+            session_iter.collect()
+        })
+        .await?;
+
+    Ok(sessions)
+}
+
+// Every unexpired session with its key and state, for copying the database to
+// another backend
+pub async fn export(pool: &Pool) -> Result<Vec<(SessionRecord, String, String)>> {
+    let sessions = pool
+        .conn(|conn| {
+            let mut stmt = conn.prepare(
+                "SELECT id, username, created, last_seen, expires, key, state FROM sessions
+                    WHERE expires>?1 ORDER BY id",
+            )?;
+
+            let session_iter = stmt.query_map([Utc::now().timestamp()], |row| {
+                Ok((session_from_row(row)?, row.get(5)?, row.get(6)?))
+            })?;
+            session_iter.collect()
+        })
+        .await?;
+
+    Ok(sessions)
+}
+
+// Returns false if there was no session with this id
+pub async fn revoke(pool: &Pool, id: i64) -> Result<bool> {
+    let deleted = pool
+        .conn(move |conn| conn.execute("DELETE FROM sessions WHERE id=?1", [id]))
+        .await?;
+
+    Ok(deleted > 0)
+}
+
+// Returns how many sessions were revoked
+pub async fn revoke_user(pool: &Pool, username: &str) -> Result<usize> {
+    let sqlusername = username.to_owned();
+    let deleted = pool
+        .conn(move |conn| conn.execute("DELETE FROM sessions WHERE username=?1", [&sqlusername]))
+        .await?;
+
+    Ok(deleted)
+}
@@ -0,0 +1,161 @@
+use super::Pool;
+use super::Result;
+use crate::sql::models::{ApiTokenRecord, TokenScope};
+use async_sqlite::rusqlite::OptionalExtension;
+use chrono::{DateTime, Utc};
+use rand::distributions::{Alphanumeric, DistString};
+use sha2::{Digest, Sha256};
+
+const TOKEN_PREFIX: &str = "spt_";
+const TOKEN_COLUMNS: &str = "id, name, username, scope, created, expires, last_used, location";
+
+// Tokens are long and random, so a plain sha256 is enough and keeps lookups cheap
+pub fn hash_token(token: &str) -> String {
+    format!("{:x}", Sha256::digest(token.as_bytes()))
+}
+
+// A new plain token and the hash that is stored in its place
+pub fn generate() -> (String, String) {
+    let token = format!(
+        "{}{}",
+        TOKEN_PREFIX,
+        Alphanumeric.sample_string(&mut rand::thread_rng(), 40)
+    );
+    let token_hash = hash_token(&token);
+
+    (token, token_hash)
+}
+
+fn token_from_row(
+    row: &async_sqlite::rusqlite::Row,
+) -> async_sqlite::rusqlite::Result<ApiTokenRecord> {
+    let timestamp =
+        |timestamp: Option<i64>| timestamp.and_then(|ts| DateTime::from_timestamp(ts, 0));
+
+    Ok(ApiTokenRecord {
+        id: row.get(0)?,
+        name: row.get(1)?,
+        username: row.get(2)?,
+        scope: TokenScope::from_sql(&row.get::<_, String>(3)?),
+        created: DateTime::from_timestamp(row.get(4)?, 0).unwrap_or_default(),
+        expires: timestamp(row.get(5)?),
+        last_used: timestamp(row.get(6)?),
+        location: row.get(7)?,
+    })
+}
+
+// Creates a token and returns its id and the plain token, which is not stored
+// anywhere and can only be shown this once. location only applies to service
+// tokens
+pub async fn create(
+    pool: &Pool,
+    name: &str,
+    username: Option<&str>,
+    scope: TokenScope,
+    expires: Option<DateTime<Utc>>,
+    location: Option<i64>,
+) -> Result<(i64, String)> {
+    let (token, token_hash) = generate();
+    let sqlname = name.to_owned();
+    let sqlusername = username.map(|username| username.to_owned());
+
+    let id = pool
+        .conn(move |conn| {
+            conn.execute(
+                "INSERT INTO api_tokens
+                    (name, username, token_hash, scope, created, expires, location)
+                    VALUES(?1, ?2, ?3, ?4, ?5, ?6, ?7)",
+                (
+                    &sqlname,
+                    &sqlusername,
+                    &token_hash,
+                    scope.as_sql(),
+                    Utc::now().timestamp(),
+                    expires.map(|expires| expires.timestamp()),
+                    location.filter(|_| sqlusername.is_none()),
+                ),
+            )?;
+            Ok(conn.last_insert_rowid())
+        })
+        .await?;
+
+    Ok((id, token))
+}
+
+// Looks up an unexpired token and marks it as used
+pub async fn authenticate(pool: &Pool, token: &str) -> Result<Option<ApiTokenRecord>> {
+    let token_hash = hash_token(token);
+    let record = pool
+        .conn(move |conn| {
+            let now = Utc::now().timestamp();
+            let record = conn
+                .query_row(
+                    &format!(
+                        "SELECT {} FROM api_tokens
+                            WHERE token_hash=?1 AND (expires IS NULL OR expires>?2)",
+                        TOKEN_COLUMNS
+                    ),
+                    (&token_hash, now),
+                    token_from_row,
+                )
+                .optional()?;
+
+            if let Some(ref record) = record {
+                conn.execute(
+                    "UPDATE api_tokens SET last_used=?1 WHERE id=?2",
+                    (now, record.id),
+                )?;
+            }
+            Ok(record)
+        })
+        .await?;
+
+    Ok(record)
+}
+
+pub async fn list(pool: &Pool) -> Result<Vec<ApiTokenRecord>> {
+    let tokens = pool
+        .conn(|conn| {
+            let mut stmt = conn.prepare(&format!(
+                "SELECT {} FROM api_tokens ORDER BY id",
+                TOKEN_COLUMNS
+            ))?;
+            let token_iter = stmt.query_map([], token_from_row)?;
+            token_iter.collect()
+        })
+        .await?;
+
+    Ok(tokens)
+}
+
+// Every token with its hash, for copying the database to another backend
+pub async fn export(pool: &Pool) -> Result<Vec<(ApiTokenRecord, String)>> {
+    let tokens = pool
+        .conn(|conn| {
+            let mut stmt = conn.prepare(&format!(
+                "SELECT {}, token_hash FROM api_tokens ORDER BY id",
+                TOKEN_COLUMNS
+            ))?;
+            let token_iter = stmt.query_map([], |row| Ok((token_from_row(row)?, row.get(8)?)))?;
+            token_iter.collect()
+        })
+        .await?;
+
+    Ok(tokens)
+}
+
+// Only revokes tokens of this user when one is given, returns false if there
+// was no such token
+pub async fn revoke(pool: &Pool, id: i64, username: Option<&str>) -> Result<bool> {
+    let sqlusername = username.map(|username| username.to_owned());
+    let deleted = pool
+        .conn(move |conn| {
+            conn.execute(
+                "DELETE FROM api_tokens WHERE id=?1 AND (?2 IS NULL OR username=?2)",
+                (id, &sqlusername),
+            )
+        })
+        .await?;
+
+    Ok(deleted > 0)
+}
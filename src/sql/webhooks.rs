@@ -0,0 +1,299 @@
+use super::Pool;
+use super::Result;
+use crate::sql::models::{DeliveryState, WebhookDeliveryRecord, WebhookEvent, WebhookRecord};
+use async_sqlite::rusqlite::OptionalExtension;
+use chrono::{DateTime, Utc};
+
+const WEBHOOK_COLUMNS: &str = "id, url, secret, events, enabled, created";
+const DELIVERY_COLUMNS: &str = "id, webhook, event, payload, state, attempts, next_attempt, \
+    last_status, last_error, created, updated";
+
+// Subscribed events are kept as one comma separated column
+pub fn events_to_sql(events: &[WebhookEvent]) -> String {
+    events
+        .iter()
+        .map(|event| event.as_sql())
+        .collect::<Vec<_>>()
+        .join(",")
+}
+
+pub fn events_from_sql(events: &str) -> Vec<WebhookEvent> {
+    events
+        .split(',')
+        .filter_map(WebhookEvent::from_sql)
+        .collect()
+}
+
+fn webhook_from_row(
+    row: &async_sqlite::rusqlite::Row,
+) -> async_sqlite::rusqlite::Result<WebhookRecord> {
+    Ok(WebhookRecord {
+        id: row.get(0)?,
+        url: row.get(1)?,
+        secret: row.get(2)?,
+        events: events_from_sql(&row.get::<_, String>(3)?),
+        enabled: row.get(4)?,
+        created: DateTime::from_timestamp(row.get(5)?, 0).unwrap_or_default(),
+    })
+}
+
+fn delivery_from_row(
+    row: &async_sqlite::rusqlite::Row,
+) -> async_sqlite::rusqlite::Result<WebhookDeliveryRecord> {
+    let timestamp = |timestamp: i64| DateTime::from_timestamp(timestamp, 0).unwrap_or_default();
+
+    Ok(WebhookDeliveryRecord {
+        id: row.get(0)?,
+        webhook_id: row.get(1)?,
+        event: row.get(2)?,
+        payload: row.get(3)?,
+        state: DeliveryState::from_sql(&row.get::<_, String>(4)?),
+        attempts: row.get(5)?,
+        next_attempt: row.get::<_, Option<i64>>(6)?.map(timestamp),
+        last_status: row.get(7)?,
+        last_error: row.get(8)?,
+        created: timestamp(row.get(9)?),
+        updated: timestamp(row.get(10)?),
+    })
+}
+
+pub async fn create(pool: &Pool, url: &str, secret: &str, events: &[WebhookEvent]) -> Result<i64> {
+    let sqlurl = url.to_owned();
+    let sqlsecret = secret.to_owned();
+    let sqlevents = events_to_sql(events);
+    let id = pool
+        .conn(move |conn| {
+            conn.execute(
+                "INSERT INTO webhooks (url, secret, events, enabled, created)
+                    VALUES(?1, ?2, ?3, ?4, ?5)",
+                (
+                    &sqlurl,
+                    &sqlsecret,
+                    &sqlevents,
+                    true,
+                    Utc::now().timestamp(),
+                ),
+            )?;
+            Ok(conn.last_insert_rowid())
+        })
+        .await?;
+
+    Ok(id)
+}
+
+pub async fn list(pool: &Pool) -> Result<Vec<WebhookRecord>> {
+    let webhooks = pool
+        .conn(|conn| {
+            let mut stmt = conn.prepare(&format!(
+                "SELECT {} FROM webhooks ORDER BY id",
+                WEBHOOK_COLUMNS
+            ))?;
+            let webhook_iter = stmt.query_map([], webhook_from_row)?;
+            webhook_iter.collect()
+        })
+        .await?;
+
+    Ok(webhooks)
+}
+
+pub async fn get(pool: &Pool, id: i64) -> Result<Option<WebhookRecord>> {
+    let webhook = pool
+        .conn(move |conn| {
+            conn.query_row(
+                &format!("SELECT {} FROM webhooks WHERE id=?1", WEBHOOK_COLUMNS),
+                [id],
+                webhook_from_row,
+            )
+            .optional()
+        })
+        .await?;
+
+    Ok(webhook)
+}
+
+// Returns false if there is no webhook with this id
+pub async fn set_enabled(pool: &Pool, id: i64, enabled: bool) -> Result<bool> {
+    let updated = pool
+        .conn(move |conn| conn.execute("UPDATE webhooks SET enabled=?1 WHERE id=?2", (enabled, id)))
+        .await?;
+
+    Ok(updated > 0)
+}
+
+// Deletes a webhook together with its delivery log
+pub async fn delete(pool: &Pool, id: i64) -> Result<bool> {
+    let deleted = pool
+        .conn_mut(move |conn| {
+            let tx = conn.transaction()?;
+            tx.execute("DELETE FROM webhook_deliveries WHERE webhook=?1", [id])?;
+            let deleted = tx.execute("DELETE FROM webhooks WHERE id=?1", [id])?;
+            tx.commit()?;
+            Ok(deleted)
+        })
+        .await?;
+
+    Ok(deleted > 0)
+}
+
+// Queues a delivery of this event for every enabled webhook subscribed to it,
+// or only for the given webhook. Returns how many deliveries were queued
+pub async fn enqueue(
+    pool: &Pool,
+    event: WebhookEvent,
+    payload: String,
+    only_webhook: Option<i64>,
+) -> Result<usize> {
+    let queued = pool
+        .conn(move |conn| {
+            let now = Utc::now().timestamp();
+            let mut stmt = conn.prepare(&format!(
+                "SELECT {} FROM webhooks WHERE enabled AND (?1 IS NULL OR id=?1)",
+                WEBHOOK_COLUMNS
+            ))?;
+            let webhooks = stmt
+                .query_map([only_webhook], webhook_from_row)?
+                .collect::<async_sqlite::rusqlite::Result<Vec<_>>>()?;
+
+            let mut queued = 0;
+            for webhook in webhooks {
+                if only_webhook.is_none() && !webhook.events.contains(&event) {
+                    continue;
+                }
+                conn.execute(
+                    "INSERT INTO webhook_deliveries
+                        (webhook, event, payload, state, attempts, next_attempt, created, updated)
+                        VALUES(?1, ?2, ?3, ?4, 0, ?5, ?5, ?5)",
+                    (
+                        webhook.id,
+                        event.as_sql(),
+                        &payload,
+                        DeliveryState::Pending.as_sql(),
+                        now,
+                    ),
+                )?;
+                queued += 1;
+            }
+            Ok(queued)
+        })
+        .await?;
+
+    Ok(queued)
+}
+
+// Pending deliveries whose next attempt is due, oldest first, moved on to
+// claim_until. Deliveries of disabled webhooks wait until they are enabled again
+pub async fn due(
+    pool: &Pool,
+    limit: u32,
+    claim_until: DateTime<Utc>,
+) -> Result<Vec<(WebhookDeliveryRecord, WebhookRecord)>> {
+    let due = pool
+        .conn_mut(move |conn| {
+            let tx = conn.transaction()?;
+            let deliveries = {
+                let mut stmt = tx.prepare(&format!(
+                    "SELECT {} FROM webhook_deliveries
+                        WHERE state=?1 AND next_attempt<=?2
+                            AND webhook IN (SELECT id FROM webhooks WHERE enabled)
+                        ORDER BY next_attempt LIMIT ?3",
+                    DELIVERY_COLUMNS
+                ))?;
+                let rows = stmt.query_map(
+                    (
+                        DeliveryState::Pending.as_sql(),
+                        Utc::now().timestamp(),
+                        limit,
+                    ),
+                    delivery_from_row,
+                )?;
+                rows.collect::<async_sqlite::rusqlite::Result<Vec<_>>>()?
+            };
+
+            let mut due = vec![];
+            for delivery in deliveries {
+                tx.execute(
+                    "UPDATE webhook_deliveries SET next_attempt=?1 WHERE id=?2",
+                    (claim_until.timestamp(), delivery.id),
+                )?;
+                let webhook = tx
+                    .query_row(
+                        &format!("SELECT {} FROM webhooks WHERE id=?1", WEBHOOK_COLUMNS),
+                        [delivery.webhook_id],
+                        webhook_from_row,
+                    )
+                    .optional()?;
+                if let Some(webhook) = webhook {
+                    due.push((delivery, webhook));
+                }
+            }
+            tx.commit()?;
+            Ok(due)
+        })
+        .await?;
+
+    Ok(due)
+}
+
+// Records the outcome of one delivery attempt. next_attempt is None when the
+// delivery succeeded or has been given up
+pub async fn record_attempt(
+    pool: &Pool,
+    id: i64,
+    state: DeliveryState,
+    status: Option<u16>,
+    error: Option<String>,
+    next_attempt: Option<DateTime<Utc>>,
+) -> Result<()> {
+    pool.conn(move |conn| {
+        conn.execute(
+            "UPDATE webhook_deliveries SET state=?1, attempts=attempts+1, next_attempt=?2,
+                    last_status=?3, last_error=?4, updated=?5 WHERE id=?6",
+            (
+                state.as_sql(),
+                next_attempt.map(|next| next.timestamp()),
+                status,
+                &error,
+                Utc::now().timestamp(),
+                id,
+            ),
+        )
+    })
+    .await?;
+
+    Ok(())
+}
+
+// Drops delivered and failed deliveries last updated before the given time,
+// returns how many were dropped
+pub async fn prune(pool: &Pool, finished_before: DateTime<Utc>) -> Result<usize> {
+    let deleted = pool
+        .conn(move |conn| {
+            conn.execute(
+                "DELETE FROM webhook_deliveries WHERE state!=?1 AND updated<?2",
+                (DeliveryState::Pending.as_sql(), finished_before.timestamp()),
+            )
+        })
+        .await?;
+
+    Ok(deleted)
+}
+
+// Newest deliveries of one webhook
+pub async fn deliveries(
+    pool: &Pool,
+    webhook_id: i64,
+    limit: u32,
+) -> Result<Vec<WebhookDeliveryRecord>> {
+    let deliveries = pool
+        .conn(move |conn| {
+            let mut stmt = conn.prepare(&format!(
+                "SELECT {} FROM webhook_deliveries WHERE webhook=?1 ORDER BY id DESC LIMIT ?2",
+                DELIVERY_COLUMNS
+            ))?;
+            let delivery_iter = stmt.query_map((webhook_id, limit), delivery_from_row)?;
+            delivery_iter.collect()
+        })
+        .await?;
+
+    Ok(deliveries)
+}
@@ -0,0 +1,1125 @@
+use super::{LocationStore, Result, SpordStore, UserStore, WebhookStore};
+use crate::config::LoginConfig;
+use crate::sql;
+use crate::sql::lockout::{self, AttemptKind};
+use crate::sql::models::{
+    ApiTokenRecord, AttachmentRecord, Dashboard, DeliveryState, LocationRecord, NoteRecord,
+    NoteRevision, SavedFilterRecord, SessionRecord, SpordEventKind, SpordEventRecord, SpordRecord,
+    SpordState, SpordStats, SpordTurnaround, TokenScope, UserRecord, WebhookDeliveryRecord,
+    WebhookEvent, WebhookRecord,
+};
+use crate::sql::{LocationFilter, SpordFilter};
+use async_trait::async_trait;
+use chrono::{DateTime, Utc};
+use std::collections::{BTreeMap, BTreeSet};
+use std::sync::Mutex;
+
+// Keeps everything in memory and forgets it on drop, so handlers can be tested
+// without a database file
+#[derive(Default)]
+pub struct MemoryStore {
+    state: Mutex<State>,
+}
+
+#[derive(Default)]
+struct State {
+    spords: BTreeMap<i32, SpordRecord>,
+    events: Vec<SpordEventRecord>,
+    notes: Vec<NoteRecord>,
+    attachments: Vec<AttachmentRecord>,
+    // username -> (bcrypt hash, user)
+    users: BTreeMap<String, (String, UserRecord)>,
+    locations: Vec<LocationRecord>,
+    // (token hash, token)
+    tokens: Vec<(String, ApiTokenRecord)>,
+    // (key, serialized state, session)
+    sessions: Vec<(String, String, SessionRecord)>,
+    // (kind, key) -> (failures, last failure, locked until)
+    login_attempts: BTreeMap<(&'static str, String), (u32, i64, Option<i64>)>,
+    saved_filters: Vec<SavedFilterRecord>,
+    webhooks: Vec<WebhookRecord>,
+    deliveries: Vec<WebhookDeliveryRecord>,
+}
+
+impl State {
+    // Sets or clears the archived time and records it in the history, like
+    // sql::set_archived
+    fn set_archived(
+        &mut self,
+        id: i32,
+        archived: Option<DateTime<Utc>>,
+        username: Option<String>,
+    ) -> bool {
+        let Some(spord) = self.spords.get_mut(&id) else {
+            return false;
+        };
+        if spord.archived.is_some() == archived.is_some() {
+            return false;
+        }
+        spord.archived = archived;
+
+        let event_id = self.events.len() as i64 + 1;
+        self.events.push(SpordEventRecord {
+            id: event_id,
+            spord_id: id,
+            kind: match archived {
+                Some(_) => SpordEventKind::Archive,
+                None => SpordEventKind::Restore,
+            },
+            old_value: None,
+            new_value: None,
+            username,
+            time: Utc::now(),
+        });
+
+        true
+    }
+}
+
+impl MemoryStore {
+    pub fn new() -> MemoryStore {
+        MemoryStore::default()
+    }
+
+    // Adds a user with every field given, create() only covers the defaults
+    pub fn insert_user(&self, user: UserRecord, password: &str) -> Result<()> {
+        let hash = bcrypt::hash(password, 4)?;
+        let mut state = self.state.lock().unwrap();
+        state.users.insert(user.username.clone(), (hash, user));
+
+        Ok(())
+    }
+}
+
+// The databases hand tags back sorted and without duplicates
+fn sort_tags(spord: &mut SpordRecord) {
+    spord.tags.sort();
+    spord.tags.dedup();
+}
+
+// Case insensitive substring match, like LIKE '%search%' in sqlite
+fn matches_search(spord: &SpordRecord, search: &str) -> bool {
+    let search = search.to_lowercase();
+    [
+        Some(&spord.customer_name),
+        spord.customer_phone.as_ref(),
+        spord.customer_email.as_ref(),
+        Some(&spord.part),
+    ]
+    .into_iter()
+    .flatten()
+    .any(|field| field.to_lowercase().contains(&search))
+}
+
+#[async_trait]
+impl SpordStore for MemoryStore {
+    async fn create(&self, mut spord: SpordRecord) -> Result<i64> {
+        let mut state = self.state.lock().unwrap();
+        let id = state.spords.keys().next_back().copied().unwrap_or(0) + 1;
+        spord.id = id;
+        sort_tags(&mut spord);
+        state.spords.insert(id, spord);
+
+        Ok(id as i64)
+    }
+
+    async fn update(&self, mut spord: SpordRecord, username: Option<String>) -> Result<bool> {
+        sort_tags(&mut spord);
+        let mut state = self.state.lock().unwrap();
+        let Some(existing) = state.spords.get_mut(&spord.id) else {
+            return Ok(false);
+        };
+        let (location, archived, anonymized) =
+            (existing.location, existing.archived, existing.anonymized);
+        let (id, old_state, new_state) = (spord.id, existing.state.clone(), spord.state.clone());
+        *existing = SpordRecord {
+            location,
+            archived,
+            anonymized,
+            ..spord
+        };
+
+        if old_state != new_state {
+            let event_id = state.events.len() as i64 + 1;
+            state.events.push(SpordEventRecord {
+                id: event_id,
+                spord_id: id,
+                kind: SpordEventKind::State,
+                old_value: Some(old_state.as_sql() as i64),
+                new_value: Some(new_state.as_sql() as i64),
+                username,
+                time: Utc::now(),
+            });
+        }
+
+        Ok(true)
+    }
+
+    async fn get(&self, id: i32) -> Result<Option<SpordRecord>> {
+        Ok(self.state.lock().unwrap().spords.get(&id).cloned())
+    }
+
+    async fn list(&self, filter: SpordFilter) -> Result<Vec<SpordRecord>> {
+        let state = self.state.lock().unwrap();
+        let spords = state
+            .spords
+            .values()
+            .filter(|spord| match filter.location {
+                Some(LocationFilter(location)) => spord.location == location,
+                None => true,
+            })
+            .filter(|spord| {
+                filter
+                    .state
+                    .as_ref()
+                    .is_none_or(|state| spord.state == *state)
+            })
+            .filter(|spord| {
+                filter
+                    .search
+                    .as_deref()
+                    .is_none_or(|search| matches_search(spord, search))
+            })
+            .filter(|spord| filter.tags.iter().all(|tag| spord.tags.contains(tag)))
+            .filter(|spord| spord.archived.is_some() == filter.archived)
+            .cloned()
+            .collect();
+
+        Ok(spords)
+    }
+
+    async fn delete(&self, id: i32) -> Result<bool> {
+        let mut state = self.state.lock().unwrap();
+        state.events.retain(|event| event.spord_id != id);
+        state.notes.retain(|note| note.spord_id != id);
+
+        Ok(state.spords.remove(&id).is_some())
+    }
+
+    async fn archive(&self, id: i32, username: Option<String>) -> Result<bool> {
+        let mut state = self.state.lock().unwrap();
+
+        Ok(state.set_archived(id, Some(Utc::now()), username))
+    }
+
+    async fn restore(&self, id: i32, username: Option<String>) -> Result<bool> {
+        let mut state = self.state.lock().unwrap();
+
+        Ok(state.set_archived(id, None, username))
+    }
+
+    async fn archive_picked_up(&self, picked_up_before: DateTime<Utc>) -> Result<Vec<i32>> {
+        let mut state = self.state.lock().unwrap();
+        // the last change to picked up in the history, or the last restore
+        let picked_up = |state: &State, spord: &SpordRecord| {
+            let events = state
+                .events
+                .iter()
+                .filter(|event| event.spord_id == spord.id);
+            let picked_up = events
+                .clone()
+                .filter(|event| event.kind == SpordEventKind::State)
+                .filter(|event| event.new_value == Some(SpordState::PickedUp.as_sql() as i64))
+                .map(|event| event.time)
+                .max()
+                .or(spord.received_date)
+                .unwrap_or(spord.creation_date);
+            let restored = events
+                .filter(|event| event.kind == SpordEventKind::Restore)
+                .map(|event| event.time)
+                .max();
+            restored.map_or(picked_up, |restored| restored.max(picked_up))
+        };
+
+        let ids: Vec<i32> = state
+            .spords
+            .values()
+            .filter(|spord| spord.archived.is_none() && spord.state == SpordState::PickedUp)
+            .filter(|spord| picked_up(&state, spord) < picked_up_before)
+            .map(|spord| spord.id)
+            .collect();
+        let now = Utc::now();
+        for id in &ids {
+            state.set_archived(*id, Some(now), None);
+        }
+
+        Ok(ids)
+    }
+
+    async fn anonymize(&self, id: i32) -> Result<bool> {
+        let mut state = self.state.lock().unwrap();
+        let Some(spord) = state.spords.get_mut(&id) else {
+            return Ok(false);
+        };
+        if spord.archived.is_none() || spord.anonymized.is_some() {
+            return Ok(false);
+        }
+        spord.customer_name = String::new();
+        spord.customer_phone = None;
+        spord.customer_email = None;
+        spord.anonymized = Some(Utc::now());
+        state.notes.retain(|note| note.spord_id != id);
+
+        Ok(true)
+    }
+
+    async fn tags(&self) -> Result<Vec<String>> {
+        let state = self.state.lock().unwrap();
+        let tags: BTreeSet<String> = state
+            .spords
+            .values()
+            .flat_map(|spord| spord.tags.iter().cloned())
+            .collect();
+
+        Ok(tags.into_iter().collect())
+    }
+
+    async fn events(&self, spord_id: i32) -> Result<Vec<SpordEventRecord>> {
+        let state = self.state.lock().unwrap();
+        let events = state
+            .events
+            .iter()
+            .filter(|event| event.spord_id == spord_id)
+            .cloned()
+            .collect();
+
+        Ok(events)
+    }
+
+    async fn notes(&self, spord_id: i32) -> Result<Vec<NoteRecord>> {
+        let state = self.state.lock().unwrap();
+        let notes = state
+            .notes
+            .iter()
+            .filter(|note| note.spord_id == spord_id)
+            .cloned()
+            .collect();
+
+        Ok(notes)
+    }
+
+    async fn add_note(&self, spord_id: i32, username: Option<String>, text: String) -> Result<i64> {
+        let mut state = self.state.lock().unwrap();
+        let id = state.notes.len() as i64 + 1;
+        state.notes.push(NoteRecord {
+            id,
+            spord_id,
+            username,
+            text,
+            created: Utc::now(),
+            edited: None,
+            edited_by: None,
+            revisions: vec![],
+        });
+
+        Ok(id)
+    }
+
+    async fn edit_note(
+        &self,
+        spord_id: i32,
+        id: i64,
+        username: Option<String>,
+        text: String,
+    ) -> Result<bool> {
+        let mut state = self.state.lock().unwrap();
+        let Some(note) = state
+            .notes
+            .iter_mut()
+            .find(|note| note.id == id && note.spord_id == spord_id)
+        else {
+            return Ok(false);
+        };
+
+        let (old_username, old_time) = match note.edited {
+            Some(edited) => (note.edited_by.clone(), edited),
+            None => (note.username.clone(), note.created),
+        };
+        let old_text = std::mem::replace(&mut note.text, text);
+        note.revisions.push(NoteRevision {
+            text: old_text,
+            username: old_username,
+            time: old_time,
+        });
+        note.edited = Some(Utc::now());
+        note.edited_by = username;
+
+        Ok(true)
+    }
+
+    async fn attachments(&self, spord_id: i32) -> Result<Vec<AttachmentRecord>> {
+        let state = self.state.lock().unwrap();
+        let attachments = state
+            .attachments
+            .iter()
+            .filter(|attachment| attachment.spord_id == spord_id)
+            .cloned()
+            .collect();
+
+        Ok(attachments)
+    }
+
+    async fn attachment(&self, spord_id: i32, id: i64) -> Result<Option<AttachmentRecord>> {
+        let state = self.state.lock().unwrap();
+        let attachment = state
+            .attachments
+            .iter()
+            .find(|attachment| attachment.id == id && attachment.spord_id == spord_id)
+            .cloned();
+
+        Ok(attachment)
+    }
+
+    async fn add_attachment(&self, mut attachment: AttachmentRecord) -> Result<i64> {
+        let mut state = self.state.lock().unwrap();
+        attachment.id = state
+            .attachments
+            .iter()
+            .map(|attachment| attachment.id)
+            .max()
+            .unwrap_or_default()
+            + 1;
+        let id = attachment.id;
+        state.attachments.push(attachment);
+
+        Ok(id)
+    }
+
+    async fn delete_attachment(&self, id: i64) -> Result<bool> {
+        let mut state = self.state.lock().unwrap();
+        let count = state.attachments.len();
+        state.attachments.retain(|attachment| attachment.id != id);
+
+        Ok(state.attachments.len() < count)
+    }
+
+    async fn attachments_size(&self, spord_id: i32) -> Result<i64> {
+        let state = self.state.lock().unwrap();
+        let size = state
+            .attachments
+            .iter()
+            .filter(|attachment| attachment.spord_id == spord_id)
+            .map(|attachment| attachment.size)
+            .sum();
+
+        Ok(size)
+    }
+
+    async fn attachment_content_used(&self, sha256: &str) -> Result<bool> {
+        let state = self.state.lock().unwrap();
+
+        Ok(state
+            .attachments
+            .iter()
+            .any(|attachment| attachment.sha256 == sha256))
+    }
+
+    async fn transfer(&self, id: i32, location: i64, username: Option<String>) -> Result<bool> {
+        let mut state = self.state.lock().unwrap();
+        let Some(spord) = state.spords.get_mut(&id) else {
+            return Ok(false);
+        };
+        let old_location = spord.location.replace(location);
+
+        let event_id = state.events.len() as i64 + 1;
+        state.events.push(SpordEventRecord {
+            id: event_id,
+            spord_id: id,
+            kind: SpordEventKind::Transfer,
+            old_value: old_location,
+            new_value: Some(location),
+            username,
+            time: Utc::now(),
+        });
+
+        Ok(true)
+    }
+
+    async fn stats(&self, overdue_before: DateTime<Utc>) -> Result<SpordStats> {
+        let state = self.state.lock().unwrap();
+        let counts = state
+            .spords
+            .values()
+            .filter(|spord| spord.archived.is_none())
+            .map(|spord| (spord.state.clone(), 1))
+            .collect();
+        let overdue = state
+            .spords
+            .values()
+            .filter(|spord| spord.archived.is_none())
+            .filter(|spord| matches!(spord.state, SpordState::Pending | SpordState::Ordered))
+            .filter(|spord| spord.creation_date < overdue_before)
+            .count();
+
+        Ok(SpordStats {
+            by_state: sql::count_by_state(counts),
+            overdue: overdue as i64,
+        })
+    }
+
+    async fn dashboard(
+        &self,
+        location: Option<LocationFilter>,
+        created_since: DateTime<Utc>,
+        overdue_before: DateTime<Utc>,
+        oldest_limit: usize,
+    ) -> Result<Dashboard> {
+        let state = self.state.lock().unwrap();
+        let spords: Vec<&SpordRecord> = state
+            .spords
+            .values()
+            .filter(|spord| match location {
+                Some(LocationFilter(location)) => spord.location == location,
+                None => true,
+            })
+            .filter(|spord| spord.archived.is_none())
+            .collect();
+        let is_open = |spord: &&&SpordRecord| {
+            matches!(spord.state, SpordState::Pending | SpordState::Ordered)
+        };
+
+        let mut oldest_open: Vec<SpordRecord> = spords
+            .iter()
+            .filter(is_open)
+            .map(|&spord| spord.clone())
+            .collect();
+        oldest_open.sort_by_key(|spord| (spord.creation_date, spord.id));
+        oldest_open.truncate(oldest_limit);
+
+        Ok(Dashboard {
+            by_state: sql::count_by_state(
+                spords
+                    .iter()
+                    .map(|spord| (spord.state.clone(), 1))
+                    .collect(),
+            ),
+            created_since: spords
+                .iter()
+                .filter(|spord| spord.creation_date >= created_since)
+                .count() as i64,
+            awaiting_pickup: spords
+                .iter()
+                .filter(|spord| spord.state == SpordState::Received)
+                .count() as i64,
+            overdue: spords
+                .iter()
+                .filter(is_open)
+                .filter(|spord| spord.creation_date < overdue_before)
+                .count() as i64,
+            oldest_open,
+        })
+    }
+
+    async fn turnaround(
+        &self,
+        location: Option<LocationFilter>,
+        created_since: Option<DateTime<Utc>>,
+    ) -> Result<Vec<SpordTurnaround>> {
+        let state = self.state.lock().unwrap();
+        // the first change to a state in the history
+        let reached = |id: i32, reached: &SpordState| {
+            state
+                .events
+                .iter()
+                .filter(|event| event.spord_id == id && event.kind == SpordEventKind::State)
+                .filter(|event| event.new_value == Some(reached.as_sql() as i64))
+                .map(|event| event.time)
+                .min()
+        };
+
+        let mut turnaround: Vec<SpordTurnaround> = state
+            .spords
+            .values()
+            .filter(|spord| match location {
+                Some(LocationFilter(location)) => spord.location == location,
+                None => true,
+            })
+            .filter(|spord| created_since.is_none_or(|since| spord.creation_date >= since))
+            .map(|spord| SpordTurnaround {
+                vendor: spord.vendor.clone(),
+                part: spord.part.clone(),
+                creation_date: spord.creation_date,
+                received_date: spord
+                    .received_date
+                    .or_else(|| reached(spord.id, &SpordState::Received)),
+                picked_up_date: reached(spord.id, &SpordState::PickedUp),
+            })
+            .collect();
+        turnaround.sort_by_key(|spord| spord.creation_date);
+
+        Ok(turnaround)
+    }
+
+    async fn schema_version(&self) -> Result<(usize, usize)> {
+        Ok((0, 0))
+    }
+}
+
+#[async_trait]
+impl UserStore for MemoryStore {
+    async fn login(&self, username: &str, password: &str) -> Result<bool> {
+        let hash = {
+            let state = self.state.lock().unwrap();
+            match state.users.get(username) {
+                Some((hash, user)) if user.enabled => hash.clone(),
+                _ => return Ok(false),
+            }
+        };
+
+        Ok(bcrypt::verify(password, &hash)?)
+    }
+
+    async fn get(&self, username: &str) -> Result<Option<UserRecord>> {
+        let state = self.state.lock().unwrap();
+
+        Ok(state.users.get(username).map(|(_, user)| user.clone()))
+    }
+
+    async fn list(&self) -> Result<Vec<UserRecord>> {
+        let state = self.state.lock().unwrap();
+
+        Ok(state.users.values().map(|(_, user)| user.clone()).collect())
+    }
+
+    async fn create(&self, username: &str, password: &str) -> Result<()> {
+        self.insert_user(
+            UserRecord {
+                username: username.to_string(),
+                enabled: true,
+                location: None,
+                manager: false,
+            },
+            password,
+        )
+    }
+
+    async fn set_location(&self, username: &str, location: Option<i64>) -> Result<bool> {
+        let mut state = self.state.lock().unwrap();
+
+        Ok(match state.users.get_mut(username) {
+            Some((_, user)) => {
+                user.location = location;
+                true
+            }
+            None => false,
+        })
+    }
+
+    async fn set_manager(&self, username: &str, manager: bool) -> Result<bool> {
+        let mut state = self.state.lock().unwrap();
+
+        Ok(match state.users.get_mut(username) {
+            Some((_, user)) => {
+                user.manager = manager;
+                true
+            }
+            None => false,
+        })
+    }
+
+    async fn create_token(
+        &self,
+        name: &str,
+        username: Option<&str>,
+        scope: TokenScope,
+        expires: Option<DateTime<Utc>>,
+        location: Option<i64>,
+    ) -> Result<(i64, String)> {
+        let (token, token_hash) = sql::tokens::generate();
+        let mut state = self.state.lock().unwrap();
+        let id = state.tokens.last().map_or(0, |(_, token)| token.id) + 1;
+        state.tokens.push((
+            token_hash,
+            ApiTokenRecord {
+                id,
+                name: name.to_string(),
+                username: username.map(|username| username.to_string()),
+                scope,
+                created: Utc::now(),
+                expires,
+                last_used: None,
+                location: location.filter(|_| username.is_none()),
+            },
+        ));
+
+        Ok((id, token))
+    }
+
+    async fn authenticate_token(&self, token: &str) -> Result<Option<ApiTokenRecord>> {
+        let token_hash = sql::tokens::hash_token(token);
+        let now = Utc::now();
+        let mut state = self.state.lock().unwrap();
+        let record = state
+            .tokens
+            .iter_mut()
+            .find(|(hash, token)| *hash == token_hash && token.expires.is_none_or(|e| e > now))
+            .map(|(_, token)| {
+                token.last_used = Some(now);
+                token.clone()
+            });
+
+        Ok(record)
+    }
+
+    async fn tokens(&self) -> Result<Vec<ApiTokenRecord>> {
+        let state = self.state.lock().unwrap();
+
+        Ok(state
+            .tokens
+            .iter()
+            .map(|(_, token)| token.clone())
+            .collect())
+    }
+
+    async fn revoke_token(&self, id: i64, username: Option<&str>) -> Result<bool> {
+        let mut state = self.state.lock().unwrap();
+        let before = state.tokens.len();
+        state.tokens.retain(|(_, token)| {
+            token.id != id
+                || username.is_some_and(|username| token.username.as_deref() != Some(username))
+        });
+
+        Ok(state.tokens.len() < before)
+    }
+
+    async fn load_session(&self, key: &str) -> Result<Option<String>> {
+        let state = self.state.lock().unwrap();
+        let now = Utc::now();
+
+        Ok(state
+            .sessions
+            .iter()
+            .find(|(session_key, _, session)| session_key == key && session.expires > now)
+            .map(|(_, session_state, _)| session_state.clone()))
+    }
+
+    async fn save_session(
+        &self,
+        key: &str,
+        username: Option<String>,
+        session_state: String,
+        expires: DateTime<Utc>,
+    ) -> Result<()> {
+        let mut state = self.state.lock().unwrap();
+        let now = Utc::now();
+        match state
+            .sessions
+            .iter_mut()
+            .find(|(session_key, _, _)| session_key == key)
+        {
+            Some((_, stored, session)) => {
+                *stored = session_state;
+                session.username = username;
+                session.last_seen = now;
+                session.expires = expires;
+            }
+            None => {
+                let id = state
+                    .sessions
+                    .last()
+                    .map_or(0, |(_, _, session)| session.id)
+                    + 1;
+                state.sessions.push((
+                    key.to_string(),
+                    session_state,
+                    SessionRecord {
+                        id,
+                        username,
+                        created: now,
+                        last_seen: now,
+                        expires,
+                    },
+                ));
+            }
+        }
+        state
+            .sessions
+            .retain(|(_, _, session)| session.expires > now);
+
+        Ok(())
+    }
+
+    async fn set_session_expiry(&self, key: &str, expires: DateTime<Utc>) -> Result<()> {
+        let mut state = self.state.lock().unwrap();
+        if let Some((_, _, session)) = state
+            .sessions
+            .iter_mut()
+            .find(|(session_key, _, _)| session_key == key)
+        {
+            session.last_seen = Utc::now();
+            session.expires = expires;
+        }
+
+        Ok(())
+    }
+
+    async fn delete_session(&self, key: &str) -> Result<()> {
+        let mut state = self.state.lock().unwrap();
+        state
+            .sessions
+            .retain(|(session_key, _, _)| session_key != key);
+
+        Ok(())
+    }
+
+    async fn sessions(&self) -> Result<Vec<SessionRecord>> {
+        let state = self.state.lock().unwrap();
+        let now = Utc::now();
+        let mut sessions: Vec<SessionRecord> = state
+            .sessions
+            .iter()
+            .map(|(_, _, session)| session.clone())
+            .filter(|session| session.expires > now)
+            .collect();
+        sessions.sort_by_key(|session| std::cmp::Reverse(session.last_seen));
+
+        Ok(sessions)
+    }
+
+    async fn revoke_session(&self, id: i64) -> Result<bool> {
+        let mut state = self.state.lock().unwrap();
+        let before = state.sessions.len();
+        state.sessions.retain(|(_, _, session)| session.id != id);
+
+        Ok(state.sessions.len() < before)
+    }
+
+    async fn revoke_user_sessions(&self, username: &str) -> Result<usize> {
+        let mut state = self.state.lock().unwrap();
+        let before = state.sessions.len();
+        state
+            .sessions
+            .retain(|(_, _, session)| session.username.as_deref() != Some(username));
+
+        Ok(before - state.sessions.len())
+    }
+
+    async fn locked_until(&self, kind: AttemptKind, key: &str) -> Result<Option<DateTime<Utc>>> {
+        let state = self.state.lock().unwrap();
+        let now = Utc::now();
+
+        Ok(state
+            .login_attempts
+            .get(&(kind.as_sql(), key.to_string()))
+            .and_then(|(_, _, locked_until)| *locked_until)
+            .and_then(|timestamp| DateTime::from_timestamp(timestamp, 0))
+            .filter(|until| *until > now))
+    }
+
+    async fn record_login_failure(
+        &self,
+        config: &LoginConfig,
+        kind: AttemptKind,
+        key: &str,
+    ) -> Result<Option<DateTime<Utc>>> {
+        let mut state = self.state.lock().unwrap();
+        let now = Utc::now().timestamp();
+        let attempts_key = (kind.as_sql(), key.to_string());
+
+        let previous = state
+            .login_attempts
+            .get(&attempts_key)
+            .map(|(failures, last_failure, _)| (*failures, *last_failure));
+        let (failures, locked_until) = lockout::failed_again(config, kind, previous, now);
+        state
+            .login_attempts
+            .insert(attempts_key, (failures, now, locked_until));
+
+        Ok(locked_until.and_then(|timestamp| DateTime::from_timestamp(timestamp, 0)))
+    }
+
+    async fn clear_login_failures(&self, kind: AttemptKind, key: &str) -> Result<bool> {
+        let mut state = self.state.lock().unwrap();
+
+        Ok(state
+            .login_attempts
+            .remove(&(kind.as_sql(), key.to_string()))
+            .is_some())
+    }
+
+    async fn saved_filters(&self, username: &str) -> Result<Vec<SavedFilterRecord>> {
+        let state = self.state.lock().unwrap();
+        let mut filters: Vec<SavedFilterRecord> = state
+            .saved_filters
+            .iter()
+            .filter(|filter| filter.username == username)
+            .cloned()
+            .collect();
+        filters.sort_by(|a, b| a.name.cmp(&b.name));
+
+        Ok(filters)
+    }
+
+    async fn save_filter(
+        &self,
+        username: &str,
+        name: &str,
+        filter_state: Option<SpordState>,
+        tags: &[String],
+        search: Option<String>,
+    ) -> Result<i64> {
+        let mut state = self.state.lock().unwrap();
+        let id = match state
+            .saved_filters
+            .iter()
+            .position(|filter| filter.username == username && filter.name == name)
+        {
+            Some(i) => state.saved_filters.remove(i).id,
+            None => {
+                state
+                    .saved_filters
+                    .iter()
+                    .map(|filter| filter.id)
+                    .max()
+                    .unwrap_or(0)
+                    + 1
+            }
+        };
+        state.saved_filters.push(SavedFilterRecord {
+            id,
+            username: username.to_string(),
+            name: name.to_string(),
+            state: filter_state,
+            tags: tags.to_vec(),
+            search,
+            created: Utc::now(),
+        });
+
+        Ok(id)
+    }
+
+    async fn delete_saved_filter(&self, username: &str, id: i64) -> Result<bool> {
+        let mut state = self.state.lock().unwrap();
+        let before = state.saved_filters.len();
+        state
+            .saved_filters
+            .retain(|filter| filter.id != id || filter.username != username);
+
+        Ok(state.saved_filters.len() < before)
+    }
+}
+
+#[async_trait]
+impl LocationStore for MemoryStore {
+    async fn create(&self, name: &str) -> Result<i64> {
+        let mut state = self.state.lock().unwrap();
+        let id = state.locations.len() as i64 + 1;
+        state.locations.push(LocationRecord {
+            id,
+            name: name.to_string(),
+        });
+
+        Ok(id)
+    }
+
+    async fn list(&self) -> Result<Vec<LocationRecord>> {
+        let mut locations = self.state.lock().unwrap().locations.clone();
+        locations.sort_by(|a, b| a.name.cmp(&b.name));
+
+        Ok(locations)
+    }
+
+    async fn find(&self, name: &str) -> Result<Option<LocationRecord>> {
+        let state = self.state.lock().unwrap();
+
+        Ok(state
+            .locations
+            .iter()
+            .find(|location| location.name == name)
+            .cloned())
+    }
+
+    async fn get(&self, id: i64) -> Result<Option<LocationRecord>> {
+        let state = self.state.lock().unwrap();
+
+        Ok(state
+            .locations
+            .iter()
+            .find(|location| location.id == id)
+            .cloned())
+    }
+}
+
+#[async_trait]
+impl WebhookStore for MemoryStore {
+    async fn create(&self, url: &str, secret: &str, events: &[WebhookEvent]) -> Result<i64> {
+        let mut state = self.state.lock().unwrap();
+        let id = state.webhooks.len() as i64 + 1;
+        state.webhooks.push(WebhookRecord {
+            id,
+            url: url.to_string(),
+            secret: secret.to_string(),
+            events: events.to_vec(),
+            enabled: true,
+            created: Utc::now(),
+        });
+
+        Ok(id)
+    }
+
+    async fn list(&self) -> Result<Vec<WebhookRecord>> {
+        Ok(self.state.lock().unwrap().webhooks.clone())
+    }
+
+    async fn get(&self, id: i64) -> Result<Option<WebhookRecord>> {
+        let state = self.state.lock().unwrap();
+
+        Ok(state
+            .webhooks
+            .iter()
+            .find(|webhook| webhook.id == id)
+            .cloned())
+    }
+
+    async fn set_enabled(&self, id: i64, enabled: bool) -> Result<bool> {
+        let mut state = self.state.lock().unwrap();
+
+        Ok(
+            match state.webhooks.iter_mut().find(|webhook| webhook.id == id) {
+                Some(webhook) => {
+                    webhook.enabled = enabled;
+                    true
+                }
+                None => false,
+            },
+        )
+    }
+
+    async fn delete(&self, id: i64) -> Result<bool> {
+        let mut state = self.state.lock().unwrap();
+        state
+            .deliveries
+            .retain(|delivery| delivery.webhook_id != id);
+        let before = state.webhooks.len();
+        state.webhooks.retain(|webhook| webhook.id != id);
+
+        Ok(state.webhooks.len() < before)
+    }
+
+    async fn enqueue(
+        &self,
+        event: WebhookEvent,
+        payload: String,
+        only_webhook: Option<i64>,
+    ) -> Result<usize> {
+        let mut state = self.state.lock().unwrap();
+        let now = Utc::now();
+        let webhook_ids: Vec<i64> = state
+            .webhooks
+            .iter()
+            .filter(|webhook| webhook.enabled)
+            .filter(|webhook| match only_webhook {
+                Some(id) => webhook.id == id,
+                None => webhook.events.contains(&event),
+            })
+            .map(|webhook| webhook.id)
+            .collect();
+
+        for webhook_id in &webhook_ids {
+            let id = state.deliveries.len() as i64 + 1;
+            state.deliveries.push(WebhookDeliveryRecord {
+                id,
+                webhook_id: *webhook_id,
+                event: event.as_sql().to_string(),
+                payload: payload.clone(),
+                state: DeliveryState::Pending,
+                attempts: 0,
+                next_attempt: Some(now),
+                last_status: None,
+                last_error: None,
+                created: now,
+                updated: now,
+            });
+        }
+
+        Ok(webhook_ids.len())
+    }
+
+    async fn due(
+        &self,
+        limit: u32,
+        claim_until: DateTime<Utc>,
+    ) -> Result<Vec<(WebhookDeliveryRecord, WebhookRecord)>> {
+        let mut state = self.state.lock().unwrap();
+        let now = Utc::now();
+        let State {
+            webhooks,
+            deliveries,
+            ..
+        } = &mut *state;
+
+        let mut due: Vec<&mut WebhookDeliveryRecord> = deliveries
+            .iter_mut()
+            .filter(|delivery| {
+                delivery.state == DeliveryState::Pending
+                    && delivery.next_attempt.is_some_and(|next| next <= now)
+            })
+            .collect();
+        due.sort_by_key(|delivery| delivery.next_attempt);
+
+        let mut claimed = vec![];
+        for delivery in due {
+            if claimed.len() >= limit as usize {
+                break;
+            }
+            let Some(webhook) = webhooks
+                .iter()
+                .find(|webhook| webhook.id == delivery.webhook_id && webhook.enabled)
+            else {
+                continue;
+            };
+            claimed.push((delivery.clone(), webhook.clone()));
+            delivery.next_attempt = Some(claim_until);
+        }
+
+        Ok(claimed)
+    }
+
+    async fn record_attempt(
+        &self,
+        id: i64,
+        delivery_state: DeliveryState,
+        status: Option<u16>,
+        error: Option<String>,
+        next_attempt: Option<DateTime<Utc>>,
+    ) -> Result<()> {
+        let mut state = self.state.lock().unwrap();
+        if let Some(delivery) = state
+            .deliveries
+            .iter_mut()
+            .find(|delivery| delivery.id == id)
+        {
+            delivery.state = delivery_state;
+            delivery.attempts += 1;
+            delivery.next_attempt = next_attempt;
+            delivery.last_status = status;
+            delivery.last_error = error;
+            delivery.updated = Utc::now();
+        }
+
+        Ok(())
+    }
+
+    async fn prune_deliveries(&self, finished_before: DateTime<Utc>) -> Result<usize> {
+        let mut state = self.state.lock().unwrap();
+        let before = state.deliveries.len();
+        state.deliveries.retain(|delivery| {
+            delivery.state == DeliveryState::Pending || delivery.updated >= finished_before
+        });
+
+        Ok(before - state.deliveries.len())
+    }
+
+    async fn deliveries(&self, webhook_id: i64, limit: u32) -> Result<Vec<WebhookDeliveryRecord>> {
+        let state = self.state.lock().unwrap();
+
+        Ok(state
+            .deliveries
+            .iter()
+            .rev()
+            .filter(|delivery| delivery.webhook_id == webhook_id)
+            .take(limit as usize)
+            .cloned()
+            .collect())
+    }
+}
@@ -0,0 +1,466 @@
+use super::{LocationStore, Result, SpordStore, UserStore, WebhookStore};
+use crate::config::LoginConfig;
+use crate::metrics::SQL_DURATION;
+use crate::sql::lockout::AttemptKind;
+use crate::sql::models::{
+    ApiTokenRecord, AttachmentRecord, Dashboard, DeliveryState, LocationRecord, NoteRecord,
+    SavedFilterRecord, SessionRecord, SpordEventRecord, SpordRecord, SpordState, SpordStats,
+    SpordTurnaround, TokenScope, UserRecord, WebhookDeliveryRecord, WebhookEvent, WebhookRecord,
+};
+use crate::sql::{LocationFilter, SpordFilter};
+use async_trait::async_trait;
+use chrono::{DateTime, Utc};
+use std::future::Future;
+
+// Wraps a store and records how long each call takes in the
+// spord_tracker_sql_query_duration_seconds histogram
+pub struct Metered<S> {
+    inner: S,
+    backend: &'static str,
+}
+impl<S> Metered<S> {
+    pub fn new(inner: S, backend: &'static str) -> Metered<S> {
+        Metered { inner, backend }
+    }
+
+    async fn timed<T>(&self, query: &str, call: impl Future<Output = T>) -> T {
+        let _timer = SQL_DURATION
+            .with_label_values(&[self.backend, query])
+            .start_timer();
+        call.await
+    }
+}
+
+#[async_trait]
+impl<S: SpordStore> SpordStore for Metered<S> {
+    async fn create(&self, spord: SpordRecord) -> Result<i64> {
+        self.timed("spord_create", self.inner.create(spord)).await
+    }
+
+    async fn update(&self, spord: SpordRecord, username: Option<String>) -> Result<bool> {
+        self.timed("spord_update", self.inner.update(spord, username))
+            .await
+    }
+
+    async fn get(&self, id: i32) -> Result<Option<SpordRecord>> {
+        self.timed("spord_get", self.inner.get(id)).await
+    }
+
+    async fn list(&self, filter: SpordFilter) -> Result<Vec<SpordRecord>> {
+        self.timed("spord_list", self.inner.list(filter)).await
+    }
+
+    async fn delete(&self, id: i32) -> Result<bool> {
+        self.timed("spord_delete", self.inner.delete(id)).await
+    }
+
+    async fn archive(&self, id: i32, username: Option<String>) -> Result<bool> {
+        self.timed("spord_archive", self.inner.archive(id, username))
+            .await
+    }
+
+    async fn restore(&self, id: i32, username: Option<String>) -> Result<bool> {
+        self.timed("spord_restore", self.inner.restore(id, username))
+            .await
+    }
+
+    async fn archive_picked_up(&self, picked_up_before: DateTime<Utc>) -> Result<Vec<i32>> {
+        self.timed(
+            "spord_archive_picked_up",
+            self.inner.archive_picked_up(picked_up_before),
+        )
+        .await
+    }
+
+    async fn anonymize(&self, id: i32) -> Result<bool> {
+        self.timed("spord_anonymize", self.inner.anonymize(id))
+            .await
+    }
+
+    async fn tags(&self) -> Result<Vec<String>> {
+        self.timed("tags", self.inner.tags()).await
+    }
+
+    async fn events(&self, spord_id: i32) -> Result<Vec<SpordEventRecord>> {
+        self.timed("spord_events", self.inner.events(spord_id))
+            .await
+    }
+
+    async fn notes(&self, spord_id: i32) -> Result<Vec<NoteRecord>> {
+        self.timed("spord_notes", self.inner.notes(spord_id)).await
+    }
+
+    async fn add_note(&self, spord_id: i32, username: Option<String>, text: String) -> Result<i64> {
+        self.timed(
+            "spord_add_note",
+            self.inner.add_note(spord_id, username, text),
+        )
+        .await
+    }
+
+    async fn edit_note(
+        &self,
+        spord_id: i32,
+        id: i64,
+        username: Option<String>,
+        text: String,
+    ) -> Result<bool> {
+        self.timed(
+            "spord_edit_note",
+            self.inner.edit_note(spord_id, id, username, text),
+        )
+        .await
+    }
+
+    async fn attachments(&self, spord_id: i32) -> Result<Vec<AttachmentRecord>> {
+        self.timed("spord_attachments", self.inner.attachments(spord_id))
+            .await
+    }
+
+    async fn attachment(&self, spord_id: i32, id: i64) -> Result<Option<AttachmentRecord>> {
+        self.timed("spord_attachment", self.inner.attachment(spord_id, id))
+            .await
+    }
+
+    async fn add_attachment(&self, attachment: AttachmentRecord) -> Result<i64> {
+        self.timed(
+            "spord_add_attachment",
+            self.inner.add_attachment(attachment),
+        )
+        .await
+    }
+
+    async fn delete_attachment(&self, id: i64) -> Result<bool> {
+        self.timed("spord_delete_attachment", self.inner.delete_attachment(id))
+            .await
+    }
+
+    async fn attachments_size(&self, spord_id: i32) -> Result<i64> {
+        self.timed(
+            "spord_attachments_size",
+            self.inner.attachments_size(spord_id),
+        )
+        .await
+    }
+
+    async fn attachment_content_used(&self, sha256: &str) -> Result<bool> {
+        self.timed(
+            "spord_attachment_content_used",
+            self.inner.attachment_content_used(sha256),
+        )
+        .await
+    }
+
+    async fn transfer(&self, id: i32, location: i64, username: Option<String>) -> Result<bool> {
+        self.timed(
+            "spord_transfer",
+            self.inner.transfer(id, location, username),
+        )
+        .await
+    }
+
+    async fn dashboard(
+        &self,
+        location: Option<LocationFilter>,
+        created_since: DateTime<Utc>,
+        overdue_before: DateTime<Utc>,
+        oldest_limit: usize,
+    ) -> Result<Dashboard> {
+        self.timed(
+            "spord_dashboard",
+            self.inner
+                .dashboard(location, created_since, overdue_before, oldest_limit),
+        )
+        .await
+    }
+
+    async fn turnaround(
+        &self,
+        location: Option<LocationFilter>,
+        created_since: Option<DateTime<Utc>>,
+    ) -> Result<Vec<SpordTurnaround>> {
+        self.timed(
+            "spord_turnaround",
+            self.inner.turnaround(location, created_since),
+        )
+        .await
+    }
+
+    async fn schema_version(&self) -> Result<(usize, usize)> {
+        self.timed("schema_version", self.inner.schema_version())
+            .await
+    }
+
+    async fn stats(&self, overdue_before: DateTime<Utc>) -> Result<SpordStats> {
+        self.timed("spord_stats", self.inner.stats(overdue_before))
+            .await
+    }
+}
+
+#[async_trait]
+impl<S: UserStore> UserStore for Metered<S> {
+    // not timed, the bcrypt check would drown out the query itself
+    async fn login(&self, username: &str, password: &str) -> Result<bool> {
+        self.inner.login(username, password).await
+    }
+
+    async fn get(&self, username: &str) -> Result<Option<UserRecord>> {
+        self.timed("user_get", self.inner.get(username)).await
+    }
+
+    async fn list(&self) -> Result<Vec<UserRecord>> {
+        self.timed("user_list", self.inner.list()).await
+    }
+
+    async fn create(&self, username: &str, password: &str) -> Result<()> {
+        self.timed("user_create", self.inner.create(username, password))
+            .await
+    }
+
+    async fn set_location(&self, username: &str, location: Option<i64>) -> Result<bool> {
+        self.timed(
+            "user_set_location",
+            self.inner.set_location(username, location),
+        )
+        .await
+    }
+
+    async fn set_manager(&self, username: &str, manager: bool) -> Result<bool> {
+        self.timed(
+            "user_set_manager",
+            self.inner.set_manager(username, manager),
+        )
+        .await
+    }
+
+    async fn create_token(
+        &self,
+        name: &str,
+        username: Option<&str>,
+        scope: TokenScope,
+        expires: Option<DateTime<Utc>>,
+        location: Option<i64>,
+    ) -> Result<(i64, String)> {
+        self.timed(
+            "token_create",
+            self.inner
+                .create_token(name, username, scope, expires, location),
+        )
+        .await
+    }
+
+    async fn authenticate_token(&self, token: &str) -> Result<Option<ApiTokenRecord>> {
+        self.timed("token_authenticate", self.inner.authenticate_token(token))
+            .await
+    }
+
+    async fn tokens(&self) -> Result<Vec<ApiTokenRecord>> {
+        self.timed("token_list", self.inner.tokens()).await
+    }
+
+    async fn revoke_token(&self, id: i64, username: Option<&str>) -> Result<bool> {
+        self.timed("token_revoke", self.inner.revoke_token(id, username))
+            .await
+    }
+
+    async fn load_session(&self, key: &str) -> Result<Option<String>> {
+        self.timed("session_load", self.inner.load_session(key))
+            .await
+    }
+
+    async fn save_session(
+        &self,
+        key: &str,
+        username: Option<String>,
+        state: String,
+        expires: DateTime<Utc>,
+    ) -> Result<()> {
+        self.timed(
+            "session_save",
+            self.inner.save_session(key, username, state, expires),
+        )
+        .await
+    }
+
+    async fn set_session_expiry(&self, key: &str, expires: DateTime<Utc>) -> Result<()> {
+        self.timed(
+            "session_set_expiry",
+            self.inner.set_session_expiry(key, expires),
+        )
+        .await
+    }
+
+    async fn delete_session(&self, key: &str) -> Result<()> {
+        self.timed("session_delete", self.inner.delete_session(key))
+            .await
+    }
+
+    async fn sessions(&self) -> Result<Vec<SessionRecord>> {
+        self.timed("session_list", self.inner.sessions()).await
+    }
+
+    async fn revoke_session(&self, id: i64) -> Result<bool> {
+        self.timed("session_revoke", self.inner.revoke_session(id))
+            .await
+    }
+
+    async fn revoke_user_sessions(&self, username: &str) -> Result<usize> {
+        self.timed(
+            "session_revoke_user",
+            self.inner.revoke_user_sessions(username),
+        )
+        .await
+    }
+
+    async fn locked_until(&self, kind: AttemptKind, key: &str) -> Result<Option<DateTime<Utc>>> {
+        self.timed("login_locked_until", self.inner.locked_until(kind, key))
+            .await
+    }
+
+    async fn record_login_failure(
+        &self,
+        config: &LoginConfig,
+        kind: AttemptKind,
+        key: &str,
+    ) -> Result<Option<DateTime<Utc>>> {
+        self.timed(
+            "login_record_failure",
+            self.inner.record_login_failure(config, kind, key),
+        )
+        .await
+    }
+
+    async fn clear_login_failures(&self, kind: AttemptKind, key: &str) -> Result<bool> {
+        self.timed(
+            "login_clear_failures",
+            self.inner.clear_login_failures(kind, key),
+        )
+        .await
+    }
+
+    async fn saved_filters(&self, username: &str) -> Result<Vec<SavedFilterRecord>> {
+        self.timed("filter_list", self.inner.saved_filters(username))
+            .await
+    }
+
+    async fn save_filter(
+        &self,
+        username: &str,
+        name: &str,
+        state: Option<SpordState>,
+        tags: &[String],
+        search: Option<String>,
+    ) -> Result<i64> {
+        self.timed(
+            "filter_save",
+            self.inner.save_filter(username, name, state, tags, search),
+        )
+        .await
+    }
+
+    async fn delete_saved_filter(&self, username: &str, id: i64) -> Result<bool> {
+        self.timed(
+            "filter_delete",
+            self.inner.delete_saved_filter(username, id),
+        )
+        .await
+    }
+}
+
+#[async_trait]
+impl<S: LocationStore> LocationStore for Metered<S> {
+    async fn create(&self, name: &str) -> Result<i64> {
+        self.timed("location_create", self.inner.create(name)).await
+    }
+
+    async fn list(&self) -> Result<Vec<LocationRecord>> {
+        self.timed("location_list", self.inner.list()).await
+    }
+
+    async fn find(&self, name: &str) -> Result<Option<LocationRecord>> {
+        self.timed("location_find", self.inner.find(name)).await
+    }
+
+    async fn get(&self, id: i64) -> Result<Option<LocationRecord>> {
+        self.timed("location_get", self.inner.get(id)).await
+    }
+}
+
+#[async_trait]
+impl<S: WebhookStore> WebhookStore for Metered<S> {
+    async fn create(&self, url: &str, secret: &str, events: &[WebhookEvent]) -> Result<i64> {
+        self.timed("webhook_create", self.inner.create(url, secret, events))
+            .await
+    }
+
+    async fn list(&self) -> Result<Vec<WebhookRecord>> {
+        self.timed("webhook_list", self.inner.list()).await
+    }
+
+    async fn get(&self, id: i64) -> Result<Option<WebhookRecord>> {
+        self.timed("webhook_get", self.inner.get(id)).await
+    }
+
+    async fn set_enabled(&self, id: i64, enabled: bool) -> Result<bool> {
+        self.timed("webhook_set_enabled", self.inner.set_enabled(id, enabled))
+            .await
+    }
+
+    async fn delete(&self, id: i64) -> Result<bool> {
+        self.timed("webhook_delete", self.inner.delete(id)).await
+    }
+
+    async fn enqueue(
+        &self,
+        event: WebhookEvent,
+        payload: String,
+        only_webhook: Option<i64>,
+    ) -> Result<usize> {
+        self.timed(
+            "webhook_enqueue",
+            self.inner.enqueue(event, payload, only_webhook),
+        )
+        .await
+    }
+
+    async fn due(
+        &self,
+        limit: u32,
+        claim_until: DateTime<Utc>,
+    ) -> Result<Vec<(WebhookDeliveryRecord, WebhookRecord)>> {
+        self.timed("webhook_due", self.inner.due(limit, claim_until))
+            .await
+    }
+
+    async fn record_attempt(
+        &self,
+        id: i64,
+        state: DeliveryState,
+        status: Option<u16>,
+        error: Option<String>,
+        next_attempt: Option<DateTime<Utc>>,
+    ) -> Result<()> {
+        self.timed(
+            "webhook_record_attempt",
+            self.inner
+                .record_attempt(id, state, status, error, next_attempt),
+        )
+        .await
+    }
+
+    async fn prune_deliveries(&self, finished_before: DateTime<Utc>) -> Result<usize> {
+        self.timed(
+            "webhook_prune",
+            self.inner.prune_deliveries(finished_before),
+        )
+        .await
+    }
+
+    async fn deliveries(&self, webhook_id: i64, limit: u32) -> Result<Vec<WebhookDeliveryRecord>> {
+        self.timed(
+            "webhook_deliveries",
+            self.inner.deliveries(webhook_id, limit),
+        )
+        .await
+    }
+}
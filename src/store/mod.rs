@@ -0,0 +1,977 @@
+use crate::config::{LoginConfig, SqlDriver};
+use crate::sql::lockout::AttemptKind;
+use crate::sql::models::{
+    ApiTokenRecord, AttachmentRecord, Dashboard, DeliveryState, LocationRecord, NoteRecord,
+    SavedFilterRecord, SessionRecord, SpordEventRecord, SpordRecord, SpordState, SpordStats,
+    SpordTurnaround, TokenScope, UserRecord, WebhookDeliveryRecord, WebhookEvent, WebhookRecord,
+};
+use crate::sql::{LocationFilter, SpordFilter, SqlError};
+use crate::CONFIG;
+use async_trait::async_trait;
+use chrono::{DateTime, Utc};
+use std::sync::Arc;
+
+#[cfg(test)] // backend for handler tests
+pub mod memory;
+pub mod metered;
+pub mod postgres;
+pub mod sqlite;
+
+#[derive(Debug, Error)]
+pub enum StoreError {
+    #[error("Store(Sql({0}))")]
+    Sql(#[from] SqlError),
+
+    #[error("Store(Bcrypt({0:?}))")]
+    Bcrypt(#[from] bcrypt::BcryptError),
+
+    #[error("Store(Postgres({0}))")]
+    Postgres(#[from] deadpool_postgres::tokio_postgres::Error),
+
+    #[error("Store(PostgresPool({0}))")]
+    PostgresPool(#[from] deadpool_postgres::PoolError),
+
+    #[error("Store(PostgresConfig({0}))")]
+    PostgresConfig(#[from] deadpool_postgres::CreatePoolError),
+
+    #[error("Store(PostgresTls({0}))")]
+    PostgresTls(String),
+
+    #[error("Copy failed: {0}")]
+    Copy(String),
+}
+pub type Result<T> = std::result::Result<T, StoreError>;
+
+// Where spords and their history are kept. The web layer only sees this
+// trait, through `web::Data<dyn SpordStore>`
+#[async_trait]
+pub trait SpordStore: Send + Sync {
+    // Inserts a new spord, returning its id
+    async fn create(&self, spord: SpordRecord) -> Result<i64>;
+    // Returns false if there is no spord with this id. Leaves the location alone,
+    // replaces the tags and records a change of state in the history
+    async fn update(&self, spord: SpordRecord, username: Option<String>) -> Result<bool>;
+    async fn get(&self, id: i32) -> Result<Option<SpordRecord>>;
+    async fn list(&self, filter: SpordFilter) -> Result<Vec<SpordRecord>>;
+    // Deletes a spord with its history, notes and tags, returns false if there
+    // was none. Everything else archives instead
+    async fn delete(&self, id: i32) -> Result<bool>;
+    // Hides a spord from the listings and records it in the history. Returns
+    // false if there is no spord with this id that isn't archived yet
+    async fn archive(&self, id: i32, username: Option<String>) -> Result<bool>;
+    // Returns false if there is no archived spord with this id
+    async fn restore(&self, id: i32, username: Option<String>) -> Result<bool>;
+    // Archives spords picked up before picked_up_before and returns their ids.
+    // A restored spord counts from when it was restored instead
+    async fn archive_picked_up(&self, picked_up_before: DateTime<Utc>) -> Result<Vec<i32>>;
+    // Removes the customer details and notes of an archived spord, keeping
+    // the rest and its history. Returns false if there was nothing to remove
+    async fn anonymize(&self, id: i32) -> Result<bool>;
+    // Every tag some spord has, sorted
+    async fn tags(&self) -> Result<Vec<String>>;
+    async fn events(&self, spord_id: i32) -> Result<Vec<SpordEventRecord>>;
+    // Notes of a spord oldest first, each with its earlier texts
+    async fn notes(&self, spord_id: i32) -> Result<Vec<NoteRecord>>;
+    // Appends a note to a spord, returning its id
+    async fn add_note(&self, spord_id: i32, username: Option<String>, text: String) -> Result<i64>;
+    // Replaces the text of a note and keeps the old one in its revisions.
+    // Returns false if the spord has no such note
+    async fn edit_note(
+        &self,
+        spord_id: i32,
+        id: i64,
+        username: Option<String>,
+        text: String,
+    ) -> Result<bool>;
+    // Attachments of a spord oldest first. Only their details are kept here,
+    // the content is a file in attachments.dir
+    async fn attachments(&self, spord_id: i32) -> Result<Vec<AttachmentRecord>>;
+    // Only finds the attachment through the spord it belongs to
+    async fn attachment(&self, spord_id: i32, id: i64) -> Result<Option<AttachmentRecord>>;
+    // Keeps an attachment whose content is stored, returning its id
+    async fn add_attachment(&self, attachment: AttachmentRecord) -> Result<i64>;
+    // Returns false if there is no attachment with this id
+    async fn delete_attachment(&self, id: i64) -> Result<bool>;
+    // Bytes attached to a spord so far
+    async fn attachments_size(&self, spord_id: i32) -> Result<i64>;
+    // Whether any attachment of any spord still has this content
+    async fn attachment_content_used(&self, sha256: &str) -> Result<bool>;
+    // Moves a spord to another store and records it in the history
+    async fn transfer(&self, id: i32, location: i64, username: Option<String>) -> Result<bool>;
+    // Counts per state, and open spords created before overdue_before
+    async fn stats(&self, overdue_before: DateTime<Utc>) -> Result<SpordStats>;
+    // Numbers for the index page, for one store or all of them
+    async fn dashboard(
+        &self,
+        location: Option<LocationFilter>,
+        created_since: DateTime<Utc>,
+        overdue_before: DateTime<Utc>,
+        oldest_limit: usize,
+    ) -> Result<Dashboard>;
+    // Creation, received and picked up times for the turnaround reports, of
+    // spords created since created_since or all of them
+    async fn turnaround(
+        &self,
+        location: Option<LocationFilter>,
+        created_since: Option<DateTime<Utc>>,
+    ) -> Result<Vec<SpordTurnaround>>;
+    // Applied and expected migrations, fails when the database is unreachable
+    async fn schema_version(&self) -> Result<(usize, usize)>;
+}
+
+#[async_trait]
+pub trait UserStore: Send + Sync {
+    // True if the user exists, is enabled and the password matches
+    async fn login(&self, username: &str, password: &str) -> Result<bool>;
+    async fn get(&self, username: &str) -> Result<Option<UserRecord>>;
+    async fn list(&self) -> Result<Vec<UserRecord>>;
+    async fn create(&self, username: &str, password: &str) -> Result<()>;
+    // Both return false if there is no such user
+    async fn set_location(&self, username: &str, location: Option<i64>) -> Result<bool>;
+    async fn set_manager(&self, username: &str, manager: bool) -> Result<bool>;
+
+    // Creates an api token and returns its id and the plain token, which is
+    // not stored and can only be shown this once. username is None for
+    // service tokens, location only applies to those
+    async fn create_token(
+        &self,
+        name: &str,
+        username: Option<&str>,
+        scope: TokenScope,
+        expires: Option<DateTime<Utc>>,
+        location: Option<i64>,
+    ) -> Result<(i64, String)>;
+    // Looks up an unexpired token and marks it as used
+    async fn authenticate_token(&self, token: &str) -> Result<Option<ApiTokenRecord>>;
+    async fn tokens(&self) -> Result<Vec<ApiTokenRecord>>;
+    // Only revokes tokens of this user when one is given, returns false if
+    // there was no such token
+    async fn revoke_token(&self, id: i64, username: Option<&str>) -> Result<bool>;
+
+    // Sessions kept with session.database_store. The state is the serialized
+    // session, missing or expired sessions load as None
+    async fn load_session(&self, key: &str) -> Result<Option<String>>;
+    // Inserts or replaces a session and drops the expired ones
+    async fn save_session(
+        &self,
+        key: &str,
+        username: Option<String>,
+        state: String,
+        expires: DateTime<Utc>,
+    ) -> Result<()>;
+    async fn set_session_expiry(&self, key: &str, expires: DateTime<Utc>) -> Result<()>;
+    async fn delete_session(&self, key: &str) -> Result<()>;
+    // Unexpired sessions, the most recently used first
+    async fn sessions(&self) -> Result<Vec<SessionRecord>>;
+    // Returns false if there was no session with this id
+    async fn revoke_session(&self, id: i64) -> Result<bool>;
+    // Returns how many sessions were revoked
+    async fn revoke_user_sessions(&self, username: &str) -> Result<usize>;
+
+    // Failed logins per username and per client address, kept here so every
+    // server sharing the database counts the same failures. Returns when the
+    // lock on this username/ip runs out, if it is currently locked
+    async fn locked_until(&self, kind: AttemptKind, key: &str) -> Result<Option<DateTime<Utc>>>;
+    // Records a failed login and returns when the lock it caused runs out, see
+    // sql::lockout::failed_again
+    async fn record_login_failure(
+        &self,
+        config: &LoginConfig,
+        kind: AttemptKind,
+        key: &str,
+    ) -> Result<Option<DateTime<Utc>>>;
+    // Forgets all failures for this username/ip, returns false if there were none
+    async fn clear_login_failures(&self, kind: AttemptKind, key: &str) -> Result<bool>;
+
+    // List filters a user saved, by name
+    async fn saved_filters(&self, username: &str) -> Result<Vec<SavedFilterRecord>>;
+    // Saves a filter for a user, replacing one with the same name. Returns its id
+    async fn save_filter(
+        &self,
+        username: &str,
+        name: &str,
+        state: Option<SpordState>,
+        tags: &[String],
+        search: Option<String>,
+    ) -> Result<i64>;
+    // Only deletes filters of this user, returns false if there was none
+    async fn delete_saved_filter(&self, username: &str, id: i64) -> Result<bool>;
+}
+
+// The stores a spord can be in. They are kept next to the spords, so every
+// server sharing a database agrees on what a location id means
+#[async_trait]
+pub trait LocationStore: Send + Sync {
+    // Adds a store, returning its id
+    async fn create(&self, name: &str) -> Result<i64>;
+    // Sorted by name
+    async fn list(&self) -> Result<Vec<LocationRecord>>;
+    async fn find(&self, name: &str) -> Result<Option<LocationRecord>>;
+    async fn get(&self, id: i64) -> Result<Option<LocationRecord>>;
+}
+
+// Webhooks and their outbox. Every server sharing the database delivers from
+// the same outbox, so a change made on any of them reaches every webhook
+#[async_trait]
+pub trait WebhookStore: Send + Sync {
+    // Adds an enabled webhook, returning its id
+    async fn create(&self, url: &str, secret: &str, events: &[WebhookEvent]) -> Result<i64>;
+    async fn list(&self) -> Result<Vec<WebhookRecord>>;
+    async fn get(&self, id: i64) -> Result<Option<WebhookRecord>>;
+    // Returns false if there is no webhook with this id
+    async fn set_enabled(&self, id: i64, enabled: bool) -> Result<bool>;
+    // Deletes a webhook together with its delivery log
+    async fn delete(&self, id: i64) -> Result<bool>;
+    // Queues a delivery of this event for every enabled webhook subscribed to
+    // it, or only for the given webhook. Returns how many deliveries were queued
+    async fn enqueue(
+        &self,
+        event: WebhookEvent,
+        payload: String,
+        only_webhook: Option<i64>,
+    ) -> Result<usize>;
+    // Pending deliveries whose next attempt is due, oldest first. Deliveries
+    // of disabled webhooks wait until they are enabled again. The next attempt
+    // of the returned deliveries moves to claim_until, so other servers leave
+    // them alone until record_attempt, or until the claim runs out
+    async fn due(
+        &self,
+        limit: u32,
+        claim_until: DateTime<Utc>,
+    ) -> Result<Vec<(WebhookDeliveryRecord, WebhookRecord)>>;
+    // Records the outcome of one delivery attempt. next_attempt is None when
+    // the delivery succeeded or has been given up
+    async fn record_attempt(
+        &self,
+        id: i64,
+        state: DeliveryState,
+        status: Option<u16>,
+        error: Option<String>,
+        next_attempt: Option<DateTime<Utc>>,
+    ) -> Result<()>;
+    // Drops delivered and failed deliveries last updated before the given
+    // time, returns how many were dropped
+    async fn prune_deliveries(&self, finished_before: DateTime<Utc>) -> Result<usize>;
+    // Newest deliveries of one webhook
+    async fn deliveries(&self, webhook_id: i64, limit: u32) -> Result<Vec<WebhookDeliveryRecord>>;
+}
+
+pub type Stores = (
+    Arc<dyn SpordStore>,
+    Arc<dyn UserStore>,
+    Arc<dyn LocationStore>,
+    Arc<dyn WebhookStore>,
+);
+
+// Opens the stores for sql.driver. The sqlite pool is always opened, for
+// backups and the copy to postgres
+pub async fn open(pool: &crate::sql::Pool) -> Result<Stores> {
+    match CONFIG.sql.driver {
+        SqlDriver::Sqlite => {
+            let store = Arc::new(metered::Metered::new(
+                sqlite::SqliteStore::new(pool.clone()),
+                "sqlite",
+            ));
+            Ok((store.clone(), store.clone(), store.clone(), store))
+        }
+        SqlDriver::Postgres => {
+            let url = CONFIG.sql.postgres_url.as_deref().unwrap_or_default();
+            let store = postgres::PostgresStore::connect(
+                url,
+                CONFIG.sql.postgres_ca_file.as_deref(),
+                CONFIG.sql.pool_size,
+            )
+            .await?;
+            let store = Arc::new(metered::Metered::new(store, "postgres"));
+            Ok((store.clone(), store.clone(), store.clone(), store))
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::sql::models::{SpordEventKind, SpordState, TokenScope};
+    use crate::testing;
+    use chrono::Duration;
+
+    fn ids(spords: &[SpordRecord]) -> Vec<i32> {
+        spords.iter().map(|spord| spord.id).collect()
+    }
+
+    // The same checks for every backend, so switching sql.driver doesn't change
+    // what the pages show. north and south are store ids
+    async fn check_spords(store: &dyn SpordStore, north: i64, south: i64) {
+        let mut ann = testing::spord("Ann Example", "Brake pad");
+        ann.location = Some(north);
+        ann.tags = vec!["warranty".to_string(), "rush".to_string()];
+        let ann_id = store.create(ann).await.unwrap() as i32;
+        let mut bob = testing::spord("Bob Sample", "Chain");
+        bob.location = Some(south);
+        let bob_id = store.create(bob).await.unwrap() as i32;
+
+        let ann = store.get(ann_id).await.unwrap().unwrap();
+        assert_eq!(ann.customer_name, "Ann Example");
+        assert_eq!(ann.customer_phone.as_deref(), Some("555-0100"));
+        assert_eq!(ann.state, SpordState::Pending);
+        assert_eq!(ann.location, Some(north));
+        assert_eq!(ann.tags, ["rush", "warranty"]);
+        assert!(store.get(bob_id + 1).await.unwrap().is_none());
+
+        let list = |filter: SpordFilter| async move { ids(&store.list(filter).await.unwrap()) };
+        assert_eq!(list(SpordFilter::default()).await, [ann_id, bob_id]);
+        let in_north = SpordFilter {
+            location: Some(LocationFilter(Some(north))),
+            ..SpordFilter::default()
+        };
+        assert_eq!(list(in_north).await, [ann_id]);
+        let search = SpordFilter {
+            search: Some("BRAKE".to_string()),
+            ..SpordFilter::default()
+        };
+        assert_eq!(list(search).await, [ann_id]);
+        let tagged = SpordFilter {
+            tags: vec!["rush".to_string(), "warranty".to_string()],
+            ..SpordFilter::default()
+        };
+        assert_eq!(list(tagged).await, [ann_id]);
+        assert_eq!(store.tags().await.unwrap(), ["rush", "warranty"]);
+
+        let mut ordered = ann.clone();
+        ordered.state = SpordState::Ordered;
+        ordered.tags = vec!["rush".to_string()];
+        assert!(store
+            .update(ordered, Some("alice".to_string()))
+            .await
+            .unwrap());
+        let ann = store.get(ann_id).await.unwrap().unwrap();
+        assert_eq!(ann.state, SpordState::Ordered);
+        assert_eq!(ann.tags, ["rush"]);
+        let by_state = SpordFilter {
+            state: Some(SpordState::Ordered),
+            ..SpordFilter::default()
+        };
+        assert_eq!(list(by_state).await, [ann_id]);
+        let mut missing = ann.clone();
+        missing.id = bob_id + 1;
+        assert!(!store.update(missing, None).await.unwrap());
+
+        assert!(store
+            .transfer(ann_id, south, Some("alice".to_string()))
+            .await
+            .unwrap());
+        assert_eq!(
+            store.get(ann_id).await.unwrap().unwrap().location,
+            Some(south)
+        );
+        let events: Vec<_> = store
+            .events(ann_id)
+            .await
+            .unwrap()
+            .into_iter()
+            .map(|event| (event.kind, event.old_value, event.new_value, event.username))
+            .collect();
+        assert_eq!(
+            events,
+            [
+                (
+                    SpordEventKind::State,
+                    Some(SpordState::Pending.as_sql() as i64),
+                    Some(SpordState::Ordered.as_sql() as i64),
+                    Some("alice".to_string())
+                ),
+                (
+                    SpordEventKind::Transfer,
+                    Some(north),
+                    Some(south),
+                    Some("alice".to_string())
+                ),
+            ]
+        );
+
+        let note = store
+            .add_note(ann_id, Some("bob".to_string()), "Called".to_string())
+            .await
+            .unwrap();
+        assert!(store
+            .edit_note(
+                ann_id,
+                note,
+                Some("alice".to_string()),
+                "Called twice".to_string()
+            )
+            .await
+            .unwrap());
+        assert!(!store
+            .edit_note(bob_id, note, None, "Wrong spord".to_string())
+            .await
+            .unwrap());
+        let notes = store.notes(ann_id).await.unwrap();
+        assert_eq!(notes.len(), 1);
+        assert_eq!(notes[0].text, "Called twice");
+        assert_eq!(notes[0].username.as_deref(), Some("bob"));
+        assert_eq!(notes[0].edited_by.as_deref(), Some("alice"));
+        assert_eq!(notes[0].revisions.len(), 1);
+        assert_eq!(notes[0].revisions[0].text, "Called");
+        assert!(store.notes(bob_id).await.unwrap().is_empty());
+
+        assert!(store.archive(ann_id, None).await.unwrap());
+        assert!(!store.archive(ann_id, None).await.unwrap());
+        assert_eq!(list(SpordFilter::default()).await, [bob_id]);
+        let archived = SpordFilter {
+            archived: true,
+            ..SpordFilter::default()
+        };
+        assert_eq!(list(archived.clone()).await, [ann_id]);
+        assert!(store.restore(ann_id, None).await.unwrap());
+        assert!(!store.restore(ann_id, None).await.unwrap());
+        assert!(list(archived).await.is_empty());
+        let kinds: Vec<_> = store
+            .events(ann_id)
+            .await
+            .unwrap()
+            .into_iter()
+            .map(|event| event.kind)
+            .skip(2)
+            .collect();
+        assert_eq!(kinds, [SpordEventKind::Archive, SpordEventKind::Restore]);
+
+        assert!(store.delete(bob_id).await.unwrap());
+        assert!(!store.delete(bob_id).await.unwrap());
+        assert!(store.get(bob_id).await.unwrap().is_none());
+        assert_eq!(list(SpordFilter::default()).await, [ann_id]);
+    }
+
+    // A spord created and received that many days ago, without any history
+    async fn create_aged(
+        store: &dyn SpordStore,
+        state: SpordState,
+        created_days: i64,
+        received_days: Option<i64>,
+    ) -> i32 {
+        let mut spord = testing::spord("Ann Example", "Brake pad");
+        spord.customer_email = Some("ann@example.com".to_string());
+        spord.state = state;
+        spord.creation_date = Utc::now() - Duration::days(created_days);
+        spord.received_date = received_days.map(|days| Utc::now() - Duration::days(days));
+        store.create(spord).await.unwrap() as i32
+    }
+
+    // What the retention policy relies on: when a spord counts as picked up,
+    // and what anonymizing leaves behind
+    async fn check_retention(store: &dyn SpordStore) {
+        let days_ago = |days: i64| Utc::now() - Duration::days(days);
+        let picked_up = create_aged(store, SpordState::PickedUp, 120, Some(100)).await;
+        let never_received = create_aged(store, SpordState::PickedUp, 100, None).await;
+        let pending = create_aged(store, SpordState::Pending, 200, None).await;
+        // picked up just now, after being received long ago
+        let fresh = create_aged(store, SpordState::Received, 120, Some(100)).await;
+        let mut spord = store.get(fresh).await.unwrap().unwrap();
+        spord.state = SpordState::PickedUp;
+        store.update(spord, None).await.unwrap();
+        let by_hand = create_aged(store, SpordState::PickedUp, 120, Some(100)).await;
+        assert!(store
+            .archive(by_hand, Some("alice".to_string()))
+            .await
+            .unwrap());
+        // a restore counts as picked up again
+        let restored = create_aged(store, SpordState::PickedUp, 120, Some(100)).await;
+        store.archive(restored, None).await.unwrap();
+        store.restore(restored, None).await.unwrap();
+        store
+            .add_note(picked_up, Some("bob".to_string()), "Called".to_string())
+            .await
+            .unwrap();
+
+        assert!(store
+            .archive_picked_up(days_ago(110))
+            .await
+            .unwrap()
+            .is_empty());
+        assert_eq!(
+            store.archive_picked_up(days_ago(90)).await.unwrap(),
+            [picked_up, never_received]
+        );
+        assert_eq!(
+            store
+                .archive_picked_up(Utc::now() + Duration::days(1))
+                .await
+                .unwrap(),
+            [fresh, restored]
+        );
+        assert!(store
+            .archive_picked_up(Utc::now() + Duration::days(1))
+            .await
+            .unwrap()
+            .is_empty());
+        assert!(store
+            .get(pending)
+            .await
+            .unwrap()
+            .unwrap()
+            .archived
+            .is_none());
+        // archived by the policy, without a user, and only once
+        let events = store.events(picked_up).await.unwrap();
+        assert_eq!(events.len(), 1);
+        assert_eq!(events[0].kind, SpordEventKind::Archive);
+        assert_eq!(events[0].username, None);
+        let events = store.events(by_hand).await.unwrap();
+        assert_eq!(events.len(), 1);
+        assert_eq!(events[0].username.as_deref(), Some("alice"));
+
+        assert!(!store.anonymize(pending).await.unwrap());
+        assert!(!store.anonymize(by_hand + 100).await.unwrap());
+        assert!(store.anonymize(picked_up).await.unwrap());
+        assert!(!store.anonymize(picked_up).await.unwrap());
+        let spord = store.get(picked_up).await.unwrap().unwrap();
+        assert_eq!(spord.customer_name, "");
+        assert_eq!(spord.customer_phone, None);
+        assert_eq!(spord.customer_email, None);
+        assert_eq!(spord.part, "Brake pad");
+        assert!(spord.archived.is_some() && spord.anonymized.is_some());
+        assert!(store.notes(picked_up).await.unwrap().is_empty());
+        assert_eq!(store.events(picked_up).await.unwrap().len(), 1);
+        let pending = store.get(pending).await.unwrap().unwrap();
+        assert_eq!(pending.customer_name, "Ann Example");
+        assert!(pending.anonymized.is_none());
+    }
+
+    async fn check_users(store: &dyn UserStore, north: i64) {
+        store.create("dave", "hunter22").await.unwrap();
+        assert!(store.login("dave", "hunter22").await.unwrap());
+        assert!(!store.login("dave", "hunter2").await.unwrap());
+        assert!(!store.login("erin", "hunter22").await.unwrap());
+
+        let dave = store.get("dave").await.unwrap().unwrap();
+        assert!(dave.enabled);
+        assert_eq!(dave.location, None);
+        assert!(!dave.manager);
+        assert!(store.get("erin").await.unwrap().is_none());
+
+        assert!(store.set_location("dave", Some(north)).await.unwrap());
+        assert!(store.set_manager("dave", true).await.unwrap());
+        assert!(!store.set_location("erin", Some(north)).await.unwrap());
+        assert!(!store.set_manager("erin", true).await.unwrap());
+        let dave = store.get("dave").await.unwrap().unwrap();
+        assert_eq!(dave.location, Some(north));
+        assert!(dave.manager);
+
+        let users: Vec<_> = store
+            .list()
+            .await
+            .unwrap()
+            .into_iter()
+            .map(|user| user.username)
+            .collect();
+        assert!(users.contains(&"dave".to_string()));
+    }
+
+    // Creates north and south, returning their ids
+    async fn check_locations(store: &dyn LocationStore) -> (i64, i64) {
+        let south = store.create("South").await.unwrap();
+        let north = store.create("North").await.unwrap();
+        assert_ne!(north, south);
+
+        let names: Vec<_> = store
+            .list()
+            .await
+            .unwrap()
+            .into_iter()
+            .map(|location| (location.id, location.name))
+            .collect();
+        assert_eq!(
+            names,
+            [(north, "North".to_string()), (south, "South".to_string())]
+        );
+        assert_eq!(store.find("South").await.unwrap().unwrap().id, south);
+        assert!(store.find("East").await.unwrap().is_none());
+        assert_eq!(store.get(north).await.unwrap().unwrap().name, "North");
+        assert!(store.get(north + south).await.unwrap().is_none());
+
+        (north, south)
+    }
+
+    async fn check_tokens(store: &dyn UserStore, north: i64) {
+        let (dave_id, dave) = store
+            .create_token("laptop", Some("dave"), TokenScope::Read, None, Some(north))
+            .await
+            .unwrap();
+        let (service_id, service) = store
+            .create_token("till", None, TokenScope::Write, None, Some(north))
+            .await
+            .unwrap();
+        let (_, expired) = store
+            .create_token(
+                "old",
+                Some("dave"),
+                TokenScope::Read,
+                Some(Utc::now() - chrono::Duration::days(1)),
+                None,
+            )
+            .await
+            .unwrap();
+        assert_ne!(dave, service);
+
+        let token = store.authenticate_token(&dave).await.unwrap().unwrap();
+        assert_eq!(token.id, dave_id);
+        assert_eq!(token.username.as_deref(), Some("dave"));
+        assert_eq!(token.scope, TokenScope::Read);
+        // only service tokens are tied to a store
+        assert_eq!(token.location, None);
+        let token = store.authenticate_token(&service).await.unwrap().unwrap();
+        assert_eq!(token.location, Some(north));
+        assert!(store.authenticate_token(&expired).await.unwrap().is_none());
+        assert!(store
+            .authenticate_token("spt_nope")
+            .await
+            .unwrap()
+            .is_none());
+
+        let tokens = store.tokens().await.unwrap();
+        assert_eq!(tokens.len(), 3);
+        assert!(tokens
+            .iter()
+            .find(|token| token.id == dave_id)
+            .unwrap()
+            .last_used
+            .is_some());
+
+        assert!(!store.revoke_token(service_id, Some("dave")).await.unwrap());
+        assert!(store.revoke_token(dave_id, Some("dave")).await.unwrap());
+        assert!(store.revoke_token(service_id, None).await.unwrap());
+        assert!(!store.revoke_token(service_id, None).await.unwrap());
+        assert!(store.authenticate_token(&dave).await.unwrap().is_none());
+        assert_eq!(store.tokens().await.unwrap().len(), 1);
+    }
+
+    async fn check_attachments(store: &dyn SpordStore) {
+        let spord = store
+            .create(testing::spord("Ann Example", "Brake pad"))
+            .await
+            .unwrap() as i32;
+        let other = store
+            .create(testing::spord("Bob Sample", "Chain"))
+            .await
+            .unwrap() as i32;
+        let attachment = |spord_id, filename: &str, size, sha256: &str| AttachmentRecord {
+            id: 0,
+            spord_id,
+            filename: filename.to_string(),
+            mime_type: "text/plain".to_string(),
+            size,
+            sha256: sha256.to_string(),
+            username: Some("dave".to_string()),
+            created: Utc::now(),
+        };
+        let first = store
+            .add_attachment(attachment(spord, "a.txt", 10, "aa"))
+            .await
+            .unwrap();
+        let second = store
+            .add_attachment(attachment(spord, "b.txt", 5, "bb"))
+            .await
+            .unwrap();
+        store
+            .add_attachment(attachment(other, "c.txt", 7, "bb"))
+            .await
+            .unwrap();
+
+        let attachments = store.attachments(spord).await.unwrap();
+        assert_eq!(
+            attachments.iter().map(|a| a.id).collect::<Vec<_>>(),
+            [first, second]
+        );
+        assert_eq!(attachments[0].filename, "a.txt");
+        assert_eq!(attachments[0].username.as_deref(), Some("dave"));
+        assert_eq!(store.attachments_size(spord).await.unwrap(), 15);
+        assert_eq!(
+            store
+                .attachment(spord, second)
+                .await
+                .unwrap()
+                .unwrap()
+                .sha256,
+            "bb"
+        );
+        // only found through its own spord
+        assert!(store.attachment(other, second).await.unwrap().is_none());
+
+        assert!(store.delete_attachment(second).await.unwrap());
+        assert!(!store.delete_attachment(second).await.unwrap());
+        assert!(store.attachment_content_used("bb").await.unwrap());
+        assert!(store.delete_attachment(first).await.unwrap());
+        assert!(!store.attachment_content_used("aa").await.unwrap());
+        assert_eq!(store.attachments_size(spord).await.unwrap(), 0);
+    }
+
+    async fn check_sessions(store: &dyn UserStore) {
+        let expires = Utc::now() + chrono::Duration::hours(1);
+        store
+            .save_session("k1", Some("dave".to_string()), "{}".to_string(), expires)
+            .await
+            .unwrap();
+        store
+            .save_session("k2", Some("dave".to_string()), "{}".to_string(), expires)
+            .await
+            .unwrap();
+        store
+            .save_session("k3", None, "{\"a\":\"1\"}".to_string(), expires)
+            .await
+            .unwrap();
+        // saving again replaces the state
+        store
+            .save_session("k3", None, "{\"a\":\"2\"}".to_string(), expires)
+            .await
+            .unwrap();
+        assert_eq!(
+            store.load_session("k3").await.unwrap().as_deref(),
+            Some("{\"a\":\"2\"}")
+        );
+        assert!(store.load_session("k4").await.unwrap().is_none());
+        assert_eq!(store.sessions().await.unwrap().len(), 3);
+
+        store
+            .set_session_expiry("k3", Utc::now() - chrono::Duration::hours(1))
+            .await
+            .unwrap();
+        assert!(store.load_session("k3").await.unwrap().is_none());
+        let sessions = store.sessions().await.unwrap();
+        assert_eq!(sessions.len(), 2);
+
+        assert!(store.revoke_session(sessions[0].id).await.unwrap());
+        assert!(!store.revoke_session(sessions[0].id).await.unwrap());
+        store
+            .save_session("k5", Some("erin".to_string()), "{}".to_string(), expires)
+            .await
+            .unwrap();
+        assert_eq!(store.revoke_user_sessions("dave").await.unwrap(), 1);
+        store.delete_session("k5").await.unwrap();
+        assert!(store.sessions().await.unwrap().is_empty());
+    }
+
+    async fn check_lockout(store: &dyn UserStore) {
+        let config = LoginConfig {
+            max_user_failures: 2,
+            max_ip_failures: 3,
+            ..LoginConfig::default()
+        };
+        assert_eq!(
+            store
+                .record_login_failure(&config, AttemptKind::Username, "dave")
+                .await
+                .unwrap(),
+            None
+        );
+        let until = store
+            .record_login_failure(&config, AttemptKind::Username, "dave")
+            .await
+            .unwrap()
+            .unwrap();
+        assert!(until > Utc::now());
+        assert_eq!(
+            store
+                .locked_until(AttemptKind::Username, "dave")
+                .await
+                .unwrap(),
+            Some(until)
+        );
+        // the same key of the other kind is counted apart
+        assert_eq!(
+            store
+                .record_login_failure(&config, AttemptKind::Ip, "dave")
+                .await
+                .unwrap(),
+            None
+        );
+        assert!(store
+            .locked_until(AttemptKind::Ip, "dave")
+            .await
+            .unwrap()
+            .is_none());
+
+        assert!(store
+            .clear_login_failures(AttemptKind::Username, "dave")
+            .await
+            .unwrap());
+        assert!(!store
+            .clear_login_failures(AttemptKind::Username, "dave")
+            .await
+            .unwrap());
+        assert!(store
+            .locked_until(AttemptKind::Username, "dave")
+            .await
+            .unwrap()
+            .is_none());
+    }
+
+    async fn check_filters(store: &dyn UserStore) {
+        let tags = ["front".to_string(), "rush".to_string()];
+        let first = store
+            .save_filter("dave", "Rush", Some(SpordState::Ordered), &tags, None)
+            .await
+            .unwrap();
+        let second = store
+            .save_filter("dave", "Ann", None, &[], Some("ann".to_string()))
+            .await
+            .unwrap();
+        store
+            .save_filter("erin", "Rush", None, &[], None)
+            .await
+            .unwrap();
+        // saving under the same name replaces the filter
+        let replaced = store
+            .save_filter("dave", "Rush", Some(SpordState::Received), &tags, None)
+            .await
+            .unwrap();
+        assert_ne!(first, second);
+
+        let filters = store.saved_filters("dave").await.unwrap();
+        assert_eq!(filters.len(), 2);
+        let rush = filters.iter().find(|filter| filter.id == replaced).unwrap();
+        assert_eq!(rush.name, "Rush");
+        assert_eq!(rush.state, Some(SpordState::Received));
+        assert_eq!(rush.tags, tags);
+        let ann = filters.iter().find(|filter| filter.id == second).unwrap();
+        assert_eq!(ann.search.as_deref(), Some("ann"));
+        assert!(ann.tags.is_empty());
+
+        // only deleted by the user who saved it
+        assert!(!store.delete_saved_filter("erin", second).await.unwrap());
+        assert!(store.delete_saved_filter("dave", second).await.unwrap());
+        assert_eq!(store.saved_filters("dave").await.unwrap().len(), 1);
+        assert_eq!(store.saved_filters("erin").await.unwrap().len(), 1);
+    }
+
+    async fn check_webhooks(store: &dyn WebhookStore) {
+        let created = store
+            .create("https://a.example", "s1", &[WebhookEvent::Created])
+            .await
+            .unwrap();
+        let archived = store
+            .create("https://b.example", "s2", &[WebhookEvent::Archived])
+            .await
+            .unwrap();
+        let webhook = store.get(created).await.unwrap().unwrap();
+        assert_eq!(webhook.url, "https://a.example");
+        assert_eq!(webhook.secret, "s1");
+        assert_eq!(webhook.events, [WebhookEvent::Created]);
+        assert!(webhook.enabled);
+        assert_eq!(store.list().await.unwrap().len(), 2);
+
+        // only the subscribed and enabled webhooks get a delivery
+        assert_eq!(
+            store
+                .enqueue(WebhookEvent::Created, "{}".to_string(), None)
+                .await
+                .unwrap(),
+            1
+        );
+        assert!(store.set_enabled(archived, false).await.unwrap());
+        assert_eq!(
+            store
+                .enqueue(WebhookEvent::Archived, "{}".to_string(), None)
+                .await
+                .unwrap(),
+            0
+        );
+        assert!(store.set_enabled(archived, true).await.unwrap());
+        // a ping goes to its webhook whatever it subscribed to
+        assert_eq!(
+            store
+                .enqueue(WebhookEvent::Ping, "{}".to_string(), Some(archived))
+                .await
+                .unwrap(),
+            1
+        );
+
+        let claim_until = Utc::now() + Duration::minutes(5);
+        let due = store.due(10, claim_until).await.unwrap();
+        assert_eq!(due.len(), 2);
+        // claimed deliveries aren't due again until the claim runs out
+        assert!(store.due(10, claim_until).await.unwrap().is_empty());
+
+        let (delivery, webhook) = due
+            .into_iter()
+            .find(|(delivery, _)| delivery.webhook_id == created)
+            .unwrap();
+        assert_eq!(webhook.id, created);
+        assert_eq!(delivery.event, "created");
+        store
+            .record_attempt(delivery.id, DeliveryState::Delivered, Some(200), None, None)
+            .await
+            .unwrap();
+        let deliveries = store.deliveries(created, 10).await.unwrap();
+        assert_eq!(deliveries.len(), 1);
+        assert_eq!(deliveries[0].state, DeliveryState::Delivered);
+        assert_eq!(deliveries[0].attempts, 1);
+        assert_eq!(deliveries[0].last_status, Some(200));
+
+        // pending deliveries are kept
+        assert_eq!(
+            store
+                .prune_deliveries(Utc::now() + Duration::days(1))
+                .await
+                .unwrap(),
+            1
+        );
+        assert!(store.deliveries(created, 10).await.unwrap().is_empty());
+        assert_eq!(store.deliveries(archived, 10).await.unwrap().len(), 1);
+
+        assert!(store.delete(archived).await.unwrap());
+        assert!(!store.delete(archived).await.unwrap());
+        assert!(store.get(archived).await.unwrap().is_none());
+        assert_eq!(store.list().await.unwrap().len(), 1);
+    }
+
+    #[test]
+    async fn memory_store() {
+        let store = memory::MemoryStore::new();
+        let (north, south) = check_locations(&store).await;
+        check_spords(&store, north, south).await;
+        check_retention(&store).await;
+        check_users(&store, north).await;
+        check_tokens(&store, north).await;
+        check_sessions(&store).await;
+        check_attachments(&store).await;
+        check_lockout(&store).await;
+        check_filters(&store).await;
+        check_webhooks(&store).await;
+    }
+
+    #[test]
+    async fn sqlite_store() {
+        let (pool, _dir) = testing::pool().await;
+        let store = sqlite::SqliteStore::new(pool);
+        let (north, south) = check_locations(&store).await;
+        check_spords(&store, north, south).await;
+        check_retention(&store).await;
+        check_users(&store, north).await;
+        check_tokens(&store, north).await;
+        check_sessions(&store).await;
+        check_attachments(&store).await;
+        check_lockout(&store).await;
+        check_filters(&store).await;
+        check_webhooks(&store).await;
+    }
+
+    #[test]
+    #[ignore = "needs SPORD_TEST_POSTGRES_URL"]
+    async fn postgres_store() {
+        let schema = testing::PostgresSchema::create().await;
+        let store = postgres::PostgresStore::connect(&schema.url, None, 2)
+            .await
+            .unwrap();
+        let (north, south) = check_locations(&store).await;
+        check_spords(&store, north, south).await;
+        check_retention(&store).await;
+        check_users(&store, north).await;
+        check_tokens(&store, north).await;
+        check_sessions(&store).await;
+        check_attachments(&store).await;
+        check_lockout(&store).await;
+        check_filters(&store).await;
+        check_webhooks(&store).await;
+        schema.remove().await;
+    }
+}
@@ -0,0 +1,2277 @@
+use super::{LocationStore, Result, SpordStore, StoreError, UserStore, WebhookStore};
+use crate::config::LoginConfig;
+use crate::sql;
+use crate::sql::lockout::{self, AttemptKind};
+use crate::sql::models::{
+    self, ApiTokenRecord, AttachmentRecord, Dashboard, DeliveryState, LocationRecord, NoteRecord,
+    NoteRevision, SavedFilterRecord, SessionRecord, SpordEventKind, SpordEventRecord, SpordRecord,
+    SpordState, SpordStats, SpordTurnaround, TokenScope, UserRecord, WebhookDeliveryRecord,
+    WebhookEvent, WebhookRecord,
+};
+use crate::sql::{LocationFilter, SpordFilter};
+use async_trait::async_trait;
+use chrono::{DateTime, Utc};
+use deadpool_postgres::tokio_postgres::{Row, Transaction};
+use deadpool_postgres::{Config, Pool, PoolConfig, Runtime};
+use rustls::crypto::ring::default_provider;
+use rustls::pki_types::{pem::PemObject, CertificateDer};
+use rustls::{ClientConfig, RootCertStore};
+use std::sync::Arc;
+use tokio_postgres_rustls::MakeRustlsConnect;
+
+// Schema changes for postgres, applied in order. The number of applied
+// migrations is kept in schema_version.
+const MIGRATIONS: &[&str] = &[
+    // 1: the initial tables
+    "CREATE TABLE locations (
+        id BIGSERIAL PRIMARY KEY,
+        name TEXT NOT NULL UNIQUE
+    );
+    CREATE TABLE auth (
+        username TEXT PRIMARY KEY,
+        password TEXT NOT NULL,
+        enabled BOOLEAN NOT NULL,
+        lastlogin BIGINT,
+        location BIGINT REFERENCES locations(id),
+        manager BOOLEAN NOT NULL DEFAULT FALSE
+    );
+    CREATE TABLE spords (
+        id SERIAL PRIMARY KEY,
+        name TEXT NOT NULL,
+        phone TEXT,
+        email TEXT,
+        part TEXT,
+        vendor TEXT,
+        state INTEGER NOT NULL,
+        created BIGINT NOT NULL,
+        received BIGINT,
+        location BIGINT REFERENCES locations(id),
+        archived BIGINT,
+        anonymized BIGINT
+    );
+    CREATE INDEX spords_archived ON spords (archived);
+    CREATE TABLE spord_events (
+        id BIGSERIAL PRIMARY KEY,
+        spord INTEGER NOT NULL REFERENCES spords(id),
+        kind TEXT NOT NULL,
+        old_value BIGINT,
+        new_value BIGINT,
+        username TEXT,
+        time BIGINT NOT NULL
+    );
+    CREATE INDEX spord_events_spord ON spord_events (spord, kind);
+    CREATE TABLE spord_notes (
+        id BIGSERIAL PRIMARY KEY,
+        spord INTEGER NOT NULL REFERENCES spords(id),
+        username TEXT,
+        text TEXT NOT NULL,
+        created BIGINT NOT NULL,
+        edited BIGINT,
+        edited_by TEXT
+    );
+    CREATE INDEX spord_notes_spord ON spord_notes (spord);
+    CREATE TABLE spord_note_revisions (
+        id BIGSERIAL PRIMARY KEY,
+        note BIGINT NOT NULL REFERENCES spord_notes(id),
+        text TEXT NOT NULL,
+        username TEXT,
+        time BIGINT NOT NULL
+    );
+    CREATE INDEX spord_note_revisions_note ON spord_note_revisions (note);
+    CREATE TABLE tags (
+        id BIGSERIAL PRIMARY KEY,
+        name TEXT NOT NULL UNIQUE
+    );
+    CREATE TABLE spord_tags (
+        spord INTEGER NOT NULL REFERENCES spords(id),
+        tag BIGINT NOT NULL REFERENCES tags(id),
+        PRIMARY KEY (spord, tag)
+    );
+    CREATE INDEX spord_tags_tag ON spord_tags (tag);
+    CREATE TABLE attachments (
+        id BIGSERIAL PRIMARY KEY,
+        spord INTEGER NOT NULL REFERENCES spords(id),
+        filename TEXT NOT NULL,
+        mime_type TEXT NOT NULL,
+        size BIGINT NOT NULL,
+        sha256 TEXT NOT NULL,
+        username TEXT,
+        created BIGINT NOT NULL
+    );
+    CREATE INDEX attachments_spord ON attachments (spord);
+    CREATE INDEX attachments_sha256 ON attachments (sha256);
+    CREATE TABLE api_tokens (
+        id BIGSERIAL PRIMARY KEY,
+        name TEXT NOT NULL,
+        username TEXT,
+        token_hash TEXT NOT NULL UNIQUE,
+        scope TEXT NOT NULL,
+        created BIGINT NOT NULL,
+        expires BIGINT,
+        last_used BIGINT,
+        location BIGINT REFERENCES locations(id)
+    );
+    CREATE TABLE sessions (
+        id BIGSERIAL PRIMARY KEY,
+        key TEXT NOT NULL UNIQUE,
+        username TEXT,
+        state TEXT NOT NULL,
+        created BIGINT NOT NULL,
+        last_seen BIGINT NOT NULL,
+        expires BIGINT NOT NULL
+    );
+    CREATE INDEX sessions_username ON sessions (username);
+    CREATE TABLE login_attempts (
+        kind TEXT NOT NULL,
+        key TEXT NOT NULL,
+        failures INTEGER NOT NULL,
+        last_failure BIGINT NOT NULL,
+        locked_until BIGINT,
+        PRIMARY KEY (kind, key)
+    );
+    CREATE TABLE saved_filters (
+        id BIGSERIAL PRIMARY KEY,
+        username TEXT NOT NULL,
+        name TEXT NOT NULL,
+        state INTEGER,
+        tags TEXT NOT NULL,
+        search TEXT,
+        created BIGINT NOT NULL,
+        UNIQUE (username, name)
+    );
+    CREATE TABLE webhooks (
+        id BIGSERIAL PRIMARY KEY,
+        url TEXT NOT NULL,
+        secret TEXT NOT NULL,
+        events TEXT NOT NULL,
+        enabled BOOLEAN NOT NULL,
+        created BIGINT NOT NULL
+    );
+    CREATE TABLE webhook_deliveries (
+        id BIGSERIAL PRIMARY KEY,
+        webhook BIGINT NOT NULL REFERENCES webhooks(id),
+        event TEXT NOT NULL,
+        payload TEXT NOT NULL,
+        state TEXT NOT NULL,
+        attempts INTEGER NOT NULL,
+        next_attempt BIGINT,
+        last_status INTEGER,
+        last_error TEXT,
+        created BIGINT NOT NULL,
+        updated BIGINT NOT NULL
+    );
+    CREATE INDEX webhook_deliveries_due ON webhook_deliveries (state, next_attempt);",
+];
+
+const ATTACHMENT_COLUMNS: &str = "id, spord, filename, mime_type, size, sha256, username, created";
+
+const SPORD_COLUMNS: &str = "id, name, phone, email, part, state, created, received, location, \
+    vendor, ARRAY(SELECT tags.name FROM spord_tags JOIN tags ON tags.id = spord_tags.tag \
+        WHERE spord_tags.spord = spords.id ORDER BY tags.name), archived, anonymized";
+
+fn tls_connector(ca_file: Option<&str>) -> Result<MakeRustlsConnect> {
+    let mut roots = RootCertStore::from_iter(webpki_roots::TLS_SERVER_ROOTS.iter().cloned());
+    if let Some(ca_file) = ca_file {
+        let certs = CertificateDer::pem_file_iter(ca_file)
+            .and_then(|certs| certs.collect::<std::result::Result<Vec<_>, _>>())
+            .map_err(|e| {
+                StoreError::PostgresTls(format!("Could not read CA file {}: {}", ca_file, e))
+            })?;
+        if certs.is_empty() {
+            return Err(StoreError::PostgresTls(format!(
+                "CA file {} contains no certificates",
+                ca_file
+            )));
+        }
+        for cert in certs {
+            roots.add(cert).map_err(|e| {
+                StoreError::PostgresTls(format!("Bad certificate in {}: {}", ca_file, e))
+            })?;
+        }
+    }
+
+    let config = ClientConfig::builder_with_provider(Arc::new(default_provider()))
+        .with_safe_default_protocol_versions()
+        .map_err(|e| StoreError::PostgresTls(e.to_string()))?
+        .with_root_certificates(roots)
+        .with_no_client_auth();
+    Ok(MakeRustlsConnect::new(config))
+}
+
+// The stores backed by a postgres database, selected with sql.driver
+pub struct PostgresStore {
+    pool: Pool,
+}
+impl PostgresStore {
+    // Connects and brings the schema up to date. TLS follows sslmode in the url,
+    // the server certificate is checked against the public roots and ca_file
+    pub async fn connect(
+        url: &str,
+        ca_file: Option<&str>,
+        pool_size: usize,
+    ) -> Result<PostgresStore> {
+        let config = Config {
+            url: Some(url.to_string()),
+            pool: Some(PoolConfig::new(pool_size)),
+            ..Config::default()
+        };
+        let store = PostgresStore {
+            pool: config.create_pool(Some(Runtime::Tokio1), tls_connector(ca_file)?)?,
+        };
+        store.migrate().await?;
+
+        Ok(store)
+    }
+
+    async fn migrate(&self) -> Result<()> {
+        let mut client = self.pool.get().await?;
+        client
+            .batch_execute("CREATE TABLE IF NOT EXISTS schema_version (version INTEGER NOT NULL)")
+            .await?;
+
+        // the lock keeps two processes starting at once from both migrating
+        let tx = client.transaction().await?;
+        tx.batch_execute("LOCK TABLE schema_version IN EXCLUSIVE MODE")
+            .await?;
+        let version: i32 = tx
+            .query_opt("SELECT version FROM schema_version", &[])
+            .await?
+            .map(|row| row.try_get(0))
+            .transpose()?
+            .unwrap_or(0);
+
+        for (i, migration) in MIGRATIONS.iter().enumerate().skip(version as usize) {
+            info!("Applying postgres migration {}", i + 1);
+            tx.batch_execute(migration).await?;
+        }
+        tx.execute("DELETE FROM schema_version", &[]).await?;
+        tx.execute(
+            "INSERT INTO schema_version (version) VALUES ($1)",
+            &[&(MIGRATIONS.len() as i32)],
+        )
+        .await?;
+        tx.commit().await?;
+
+        Ok(())
+    }
+}
+
+fn spord_from_row(row: &Row) -> Result<SpordRecord> {
+    Ok(SpordRecord {
+        id: row.try_get(0)?,
+        customer_name: row.try_get(1)?,
+        customer_phone: row.try_get(2)?,
+        customer_email: row.try_get(3)?,
+        part: row.try_get(4)?,
+        state: SpordState::from_sql(row.try_get(5)?),
+        creation_date: DateTime::from_timestamp(row.try_get(6)?, 0).unwrap_or_default(),
+        received_date: row
+            .try_get::<_, Option<i64>>(7)?
+            .and_then(|received| DateTime::from_timestamp(received, 0)),
+        location: row.try_get(8)?,
+        vendor: row.try_get(9)?,
+        tags: row.try_get(10)?,
+        archived: row
+            .try_get::<_, Option<i64>>(11)?
+            .and_then(|archived| DateTime::from_timestamp(archived, 0)),
+        anonymized: row
+            .try_get::<_, Option<i64>>(12)?
+            .and_then(|anonymized| DateTime::from_timestamp(anonymized, 0)),
+    })
+}
+
+// Replaces the tags of a spord, creating new ones and dropping the ones no
+// spord uses anymore
+async fn set_tags(tx: &Transaction<'_>, spord_id: i32, tags: &[String]) -> Result<()> {
+    tx.execute("DELETE FROM spord_tags WHERE spord=$1", &[&spord_id])
+        .await?;
+    for tag in tags {
+        tx.execute(
+            "INSERT INTO tags (name) VALUES($1) ON CONFLICT (name) DO NOTHING",
+            &[tag],
+        )
+        .await?;
+        tx.execute(
+            "INSERT INTO spord_tags (spord, tag) SELECT $1, id FROM tags WHERE name=$2",
+            &[&spord_id, tag],
+        )
+        .await?;
+    }
+    tx.execute(
+        "DELETE FROM tags WHERE id NOT IN (SELECT tag FROM spord_tags)",
+        &[],
+    )
+    .await?;
+
+    Ok(())
+}
+
+// Sets or clears the archived time of a spord and records it in the history.
+// Returns false if the spord does not exist or is already in that state
+async fn set_archived(
+    tx: &Transaction<'_>,
+    id: i32,
+    archived: Option<i64>,
+    username: &Option<String>,
+) -> Result<bool> {
+    let (changed, kind) = match archived {
+        Some(archived) => (
+            tx.execute(
+                "UPDATE spords SET archived=$1 WHERE id=$2 AND archived IS NULL",
+                &[&archived, &id],
+            )
+            .await?,
+            SpordEventKind::Archive,
+        ),
+        None => (
+            tx.execute(
+                "UPDATE spords SET archived=NULL WHERE id=$1 AND archived IS NOT NULL",
+                &[&id],
+            )
+            .await?,
+            SpordEventKind::Restore,
+        ),
+    };
+    if changed == 0 {
+        return Ok(false);
+    }
+
+    tx.execute(
+        "INSERT INTO spord_events (spord, kind, username, time) VALUES($1, $2, $3, $4)",
+        &[&id, &kind.as_sql(), username, &Utc::now().timestamp()],
+    )
+    .await?;
+
+    Ok(true)
+}
+
+fn user_from_row(row: &Row) -> Result<UserRecord> {
+    Ok(UserRecord {
+        username: row.try_get(0)?,
+        enabled: row.try_get(1)?,
+        location: row.try_get(2)?,
+        manager: row.try_get(3)?,
+    })
+}
+
+const TOKEN_COLUMNS: &str = "id, name, username, scope, created, expires, last_used, location";
+
+fn token_from_row(row: &Row) -> Result<ApiTokenRecord> {
+    let timestamp =
+        |timestamp: Option<i64>| timestamp.and_then(|ts| DateTime::from_timestamp(ts, 0));
+
+    Ok(ApiTokenRecord {
+        id: row.try_get(0)?,
+        name: row.try_get(1)?,
+        username: row.try_get(2)?,
+        scope: TokenScope::from_sql(row.try_get(3)?),
+        created: DateTime::from_timestamp(row.try_get(4)?, 0).unwrap_or_default(),
+        expires: timestamp(row.try_get(5)?),
+        last_used: timestamp(row.try_get(6)?),
+        location: row.try_get(7)?,
+    })
+}
+
+#[async_trait]
+impl SpordStore for PostgresStore {
+    async fn create(&self, spord: SpordRecord) -> Result<i64> {
+        let mut client = self.pool.get().await?;
+        let tx = client.transaction().await?;
+        let row = tx
+            .query_one(
+                "INSERT INTO spords
+                    (name, phone, email, part, state, created, received, location, vendor)
+                    VALUES($1, $2, $3, $4, $5, $6, $7, $8, $9) RETURNING id",
+                &[
+                    &spord.customer_name,
+                    &spord.customer_phone,
+                    &spord.customer_email,
+                    &spord.part,
+                    &spord.state.as_sql(),
+                    &spord.creation_date.timestamp(),
+                    &spord.received_date_unix(),
+                    &spord.location,
+                    &spord.vendor,
+                ],
+            )
+            .await?;
+        let id: i32 = row.try_get(0)?;
+        set_tags(&tx, id, &spord.tags).await?;
+        tx.commit().await?;
+
+        Ok(id as i64)
+    }
+
+    async fn update(&self, spord: SpordRecord, username: Option<String>) -> Result<bool> {
+        let mut client = self.pool.get().await?;
+        let tx = client.transaction().await?;
+
+        let Some(row) = tx
+            .query_opt(
+                "SELECT state FROM spords WHERE id=$1 FOR UPDATE",
+                &[&spord.id],
+            )
+            .await?
+        else {
+            return Ok(false);
+        };
+        let old_state: i32 = row.try_get(0)?;
+
+        tx.execute(
+            "UPDATE spords SET name=$1, phone=$2, email=$3, part=$4, state=$5, created=$6,
+                received=$7, vendor=$8 WHERE id=$9",
+            &[
+                &spord.customer_name,
+                &spord.customer_phone,
+                &spord.customer_email,
+                &spord.part,
+                &spord.state.as_sql(),
+                &spord.creation_date.timestamp(),
+                &spord.received_date_unix(),
+                &spord.vendor,
+                &spord.id,
+            ],
+        )
+        .await?;
+        set_tags(&tx, spord.id, &spord.tags).await?;
+        if old_state != spord.state.as_sql() {
+            tx.execute(
+                "INSERT INTO spord_events (spord, kind, old_value, new_value, username, time)
+                    VALUES($1, $2, $3, $4, $5, $6)",
+                &[
+                    &spord.id,
+                    &SpordEventKind::State.as_sql(),
+                    &(old_state as i64),
+                    &(spord.state.as_sql() as i64),
+                    &username,
+                    &Utc::now().timestamp(),
+                ],
+            )
+            .await?;
+        }
+        tx.commit().await?;
+
+        Ok(true)
+    }
+
+    async fn get(&self, id: i32) -> Result<Option<SpordRecord>> {
+        let client = self.pool.get().await?;
+        let row = client
+            .query_opt(
+                &format!("SELECT {} FROM spords WHERE id=$1", SPORD_COLUMNS),
+                &[&id],
+            )
+            .await?;
+
+        row.as_ref().map(spord_from_row).transpose()
+    }
+
+    async fn list(&self, filter: SpordFilter) -> Result<Vec<SpordRecord>> {
+        let all_locations = filter.location.is_none();
+        let location = filter
+            .location
+            .and_then(|LocationFilter(location)| location);
+        let state = filter.state.map(|state| state.as_sql());
+        // ILIKE to match the case insensitive LIKE of sqlite
+        let search = filter.search.map(|search| {
+            let escaped = search
+                .replace('\\', "\\\\")
+                .replace('%', "\\%")
+                .replace('_', "\\_");
+            format!("%{}%", escaped)
+        });
+
+        let client = self.pool.get().await?;
+        let rows = client
+            .query(
+                &format!(
+                    "SELECT {} FROM spords WHERE ($1 OR location IS NOT DISTINCT FROM $2)
+                        AND ($3::INTEGER IS NULL OR state = $3)
+                        AND ($4::TEXT IS NULL OR name ILIKE $4 ESCAPE '\\'
+                            OR phone ILIKE $4 ESCAPE '\\' OR email ILIKE $4 ESCAPE '\\'
+                            OR part ILIKE $4 ESCAPE '\\')
+                        AND (SELECT COUNT(*) FROM spord_tags JOIN tags ON tags.id = spord_tags.tag
+                            WHERE spord_tags.spord = spords.id AND tags.name = ANY($5)) = $6
+                        AND (archived IS NOT NULL) = $7
+                        ORDER BY id",
+                    SPORD_COLUMNS
+                ),
+                &[
+                    &all_locations,
+                    &location,
+                    &state,
+                    &search,
+                    &filter.tags,
+                    &(filter.tags.len() as i64),
+                    &filter.archived,
+                ],
+            )
+            .await?;
+
+        let mut spords = vec![];
+        for row in &rows {
+            match spord_from_row(row) {
+                Ok(spord) => spords.push(spord),
+                Err(e) => warn!("Error fetch spord record: {:?}", e),
+            }
+        }
+
+        Ok(spords)
+    }
+
+    async fn delete(&self, id: i32) -> Result<bool> {
+        let mut client = self.pool.get().await?;
+        let tx = client.transaction().await?;
+        tx.execute("DELETE FROM spord_events WHERE spord=$1", &[&id])
+            .await?;
+        tx.execute(
+            "DELETE FROM spord_note_revisions
+                WHERE note IN (SELECT id FROM spord_notes WHERE spord=$1)",
+            &[&id],
+        )
+        .await?;
+        tx.execute("DELETE FROM spord_notes WHERE spord=$1", &[&id])
+            .await?;
+        set_tags(&tx, id, &[]).await?;
+        let deleted = tx.execute("DELETE FROM spords WHERE id=$1", &[&id]).await?;
+        tx.commit().await?;
+
+        Ok(deleted > 0)
+    }
+
+    async fn archive(&self, id: i32, username: Option<String>) -> Result<bool> {
+        let mut client = self.pool.get().await?;
+        let tx = client.transaction().await?;
+        let archived = set_archived(&tx, id, Some(Utc::now().timestamp()), &username).await?;
+        tx.commit().await?;
+
+        Ok(archived)
+    }
+
+    async fn restore(&self, id: i32, username: Option<String>) -> Result<bool> {
+        let mut client = self.pool.get().await?;
+        let tx = client.transaction().await?;
+        let restored = set_archived(&tx, id, None, &username).await?;
+        tx.commit().await?;
+
+        Ok(restored)
+    }
+
+    async fn archive_picked_up(&self, picked_up_before: DateTime<Utc>) -> Result<Vec<i32>> {
+        let mut client = self.pool.get().await?;
+        let tx = client.transaction().await?;
+
+        let rows = tx
+            .query(
+                "SELECT id FROM spords WHERE archived IS NULL AND state = $1
+                    AND GREATEST(COALESCE((SELECT MAX(time) FROM spord_events
+                            WHERE spord = spords.id AND kind = $2 AND new_value = $3),
+                            received, created),
+                        (SELECT MAX(time) FROM spord_events
+                            WHERE spord = spords.id AND kind = $4)) < $5
+                    ORDER BY id FOR UPDATE",
+                &[
+                    &SpordState::PickedUp.as_sql(),
+                    &SpordEventKind::State.as_sql(),
+                    &(SpordState::PickedUp.as_sql() as i64),
+                    &SpordEventKind::Restore.as_sql(),
+                    &picked_up_before.timestamp(),
+                ],
+            )
+            .await?;
+        let ids = rows
+            .iter()
+            .map(|row| Ok(row.try_get(0)?))
+            .collect::<Result<Vec<i32>>>()?;
+
+        let now = Utc::now().timestamp();
+        for id in &ids {
+            set_archived(&tx, *id, Some(now), &None).await?;
+        }
+        tx.commit().await?;
+
+        Ok(ids)
+    }
+
+    async fn anonymize(&self, id: i32) -> Result<bool> {
+        let mut client = self.pool.get().await?;
+        let tx = client.transaction().await?;
+
+        let changed = tx
+            .execute(
+                "UPDATE spords SET name='', phone=NULL, email=NULL, anonymized=$1
+                    WHERE id=$2 AND archived IS NOT NULL AND anonymized IS NULL",
+                &[&Utc::now().timestamp(), &id],
+            )
+            .await?;
+        if changed == 0 {
+            return Ok(false);
+        }
+        tx.execute(
+            "DELETE FROM spord_note_revisions
+                WHERE note IN (SELECT id FROM spord_notes WHERE spord=$1)",
+            &[&id],
+        )
+        .await?;
+        tx.execute("DELETE FROM spord_notes WHERE spord=$1", &[&id])
+            .await?;
+        tx.commit().await?;
+
+        Ok(true)
+    }
+
+    async fn tags(&self) -> Result<Vec<String>> {
+        let client = self.pool.get().await?;
+        let rows = client
+            .query(
+                "SELECT name FROM tags WHERE id IN (SELECT tag FROM spord_tags) ORDER BY name",
+                &[],
+            )
+            .await?;
+
+        rows.iter().map(|row| Ok(row.try_get(0)?)).collect()
+    }
+
+    async fn events(&self, spord_id: i32) -> Result<Vec<SpordEventRecord>> {
+        let client = self.pool.get().await?;
+        let rows = client
+            .query(
+                "SELECT id, spord, kind, old_value, new_value, username, time
+                    FROM spord_events WHERE spord=$1 ORDER BY time, id",
+                &[&spord_id],
+            )
+            .await?;
+
+        rows.iter()
+            .map(|row| {
+                Ok(SpordEventRecord {
+                    id: row.try_get(0)?,
+                    spord_id: row.try_get(1)?,
+                    kind: SpordEventKind::from_sql(row.try_get(2)?),
+                    old_value: row.try_get(3)?,
+                    new_value: row.try_get(4)?,
+                    username: row.try_get(5)?,
+                    time: DateTime::from_timestamp(row.try_get(6)?, 0).unwrap_or_default(),
+                })
+            })
+            .collect()
+    }
+
+    async fn notes(&self, spord_id: i32) -> Result<Vec<NoteRecord>> {
+        let client = self.pool.get().await?;
+        let rows = client
+            .query(
+                "SELECT id, spord, username, text, created, edited, edited_by
+                    FROM spord_notes WHERE spord=$1 ORDER BY created, id",
+                &[&spord_id],
+            )
+            .await?;
+        let mut notes = rows
+            .iter()
+            .map(|row| {
+                Ok(NoteRecord {
+                    id: row.try_get(0)?,
+                    spord_id: row.try_get(1)?,
+                    username: row.try_get(2)?,
+                    text: row.try_get(3)?,
+                    created: DateTime::from_timestamp(row.try_get(4)?, 0).unwrap_or_default(),
+                    edited: row
+                        .try_get::<_, Option<i64>>(5)?
+                        .and_then(|edited| DateTime::from_timestamp(edited, 0)),
+                    edited_by: row.try_get(6)?,
+                    revisions: vec![],
+                })
+            })
+            .collect::<Result<Vec<_>>>()?;
+
+        let rows = client
+            .query(
+                "SELECT spord_note_revisions.note, spord_note_revisions.text,
+                    spord_note_revisions.username, spord_note_revisions.time
+                    FROM spord_note_revisions
+                    JOIN spord_notes ON spord_notes.id = spord_note_revisions.note
+                    WHERE spord_notes.spord=$1 ORDER BY spord_note_revisions.id",
+                &[&spord_id],
+            )
+            .await?;
+        for row in &rows {
+            let note_id: i64 = row.try_get(0)?;
+            if let Some(note) = notes.iter_mut().find(|note| note.id == note_id) {
+                note.revisions.push(NoteRevision {
+                    text: row.try_get(1)?,
+                    username: row.try_get(2)?,
+                    time: DateTime::from_timestamp(row.try_get(3)?, 0).unwrap_or_default(),
+                });
+            }
+        }
+
+        Ok(notes)
+    }
+
+    async fn add_note(&self, spord_id: i32, username: Option<String>, text: String) -> Result<i64> {
+        let client = self.pool.get().await?;
+        let row = client
+            .query_one(
+                "INSERT INTO spord_notes (spord, username, text, created)
+                    VALUES($1, $2, $3, $4) RETURNING id",
+                &[&spord_id, &username, &text, &Utc::now().timestamp()],
+            )
+            .await?;
+
+        Ok(row.try_get(0)?)
+    }
+
+    async fn edit_note(
+        &self,
+        spord_id: i32,
+        id: i64,
+        username: Option<String>,
+        text: String,
+    ) -> Result<bool> {
+        let mut client = self.pool.get().await?;
+        let tx = client.transaction().await?;
+
+        let Some(row) = tx
+            .query_opt(
+                "SELECT text, CASE WHEN edited IS NULL THEN username ELSE edited_by END,
+                    COALESCE(edited, created) FROM spord_notes WHERE id=$1 AND spord=$2
+                    FOR UPDATE",
+                &[&id, &spord_id],
+            )
+            .await?
+        else {
+            return Ok(false);
+        };
+        let (old_text, old_username, old_time): (String, Option<String>, i64) =
+            (row.try_get(0)?, row.try_get(1)?, row.try_get(2)?);
+
+        tx.execute(
+            "INSERT INTO spord_note_revisions (note, text, username, time)
+                VALUES($1, $2, $3, $4)",
+            &[&id, &old_text, &old_username, &old_time],
+        )
+        .await?;
+        tx.execute(
+            "UPDATE spord_notes SET text=$1, edited=$2, edited_by=$3 WHERE id=$4",
+            &[&text, &Utc::now().timestamp(), &username, &id],
+        )
+        .await?;
+        tx.commit().await?;
+
+        Ok(true)
+    }
+
+    async fn attachments(&self, spord_id: i32) -> Result<Vec<AttachmentRecord>> {
+        let client = self.pool.get().await?;
+        let rows = client
+            .query(
+                &format!(
+                    "SELECT {} FROM attachments WHERE spord=$1 ORDER BY created, id",
+                    ATTACHMENT_COLUMNS
+                ),
+                &[&spord_id],
+            )
+            .await?;
+
+        rows.iter().map(attachment_from_row).collect()
+    }
+
+    async fn attachment(&self, spord_id: i32, id: i64) -> Result<Option<AttachmentRecord>> {
+        let client = self.pool.get().await?;
+        let row = client
+            .query_opt(
+                &format!(
+                    "SELECT {} FROM attachments WHERE id=$1 AND spord=$2",
+                    ATTACHMENT_COLUMNS
+                ),
+                &[&id, &spord_id],
+            )
+            .await?;
+
+        row.as_ref().map(attachment_from_row).transpose()
+    }
+
+    async fn add_attachment(&self, attachment: AttachmentRecord) -> Result<i64> {
+        let client = self.pool.get().await?;
+        let row = client
+            .query_one(
+                "INSERT INTO attachments
+                    (spord, filename, mime_type, size, sha256, username, created)
+                    VALUES($1, $2, $3, $4, $5, $6, $7) RETURNING id",
+                &[
+                    &attachment.spord_id,
+                    &attachment.filename,
+                    &attachment.mime_type,
+                    &attachment.size,
+                    &attachment.sha256,
+                    &attachment.username,
+                    &attachment.created.timestamp(),
+                ],
+            )
+            .await?;
+
+        Ok(row.try_get(0)?)
+    }
+
+    async fn delete_attachment(&self, id: i64) -> Result<bool> {
+        let client = self.pool.get().await?;
+        let deleted = client
+            .execute("DELETE FROM attachments WHERE id=$1", &[&id])
+            .await?;
+
+        Ok(deleted > 0)
+    }
+
+    async fn attachments_size(&self, spord_id: i32) -> Result<i64> {
+        let client = self.pool.get().await?;
+        let row = client
+            .query_one(
+                "SELECT COALESCE(SUM(size), 0)::BIGINT FROM attachments WHERE spord=$1",
+                &[&spord_id],
+            )
+            .await?;
+
+        Ok(row.try_get(0)?)
+    }
+
+    async fn attachment_content_used(&self, sha256: &str) -> Result<bool> {
+        let client = self.pool.get().await?;
+        let row = client
+            .query_one(
+                "SELECT EXISTS(SELECT 1 FROM attachments WHERE sha256=$1)",
+                &[&sha256],
+            )
+            .await?;
+
+        Ok(row.try_get(0)?)
+    }
+
+    async fn transfer(&self, id: i32, location: i64, username: Option<String>) -> Result<bool> {
+        let mut client = self.pool.get().await?;
+        let tx = client.transaction().await?;
+
+        let Some(row) = tx
+            .query_opt("SELECT location FROM spords WHERE id=$1 FOR UPDATE", &[&id])
+            .await?
+        else {
+            return Ok(false);
+        };
+        let old_location: Option<i64> = row.try_get(0)?;
+
+        tx.execute(
+            "UPDATE spords SET location=$1 WHERE id=$2",
+            &[&location, &id],
+        )
+        .await?;
+        tx.execute(
+            "INSERT INTO spord_events (spord, kind, old_value, new_value, username, time)
+                VALUES($1, $2, $3, $4, $5, $6)",
+            &[
+                &id,
+                &SpordEventKind::Transfer.as_sql(),
+                &old_location,
+                &location,
+                &username,
+                &Utc::now().timestamp(),
+            ],
+        )
+        .await?;
+        tx.commit().await?;
+
+        Ok(true)
+    }
+
+    async fn stats(&self, overdue_before: DateTime<Utc>) -> Result<SpordStats> {
+        let client = self.pool.get().await?;
+        let rows = client
+            .query(
+                "SELECT state, COUNT(*) FROM spords WHERE archived IS NULL GROUP BY state",
+                &[],
+            )
+            .await?;
+        let counts = rows
+            .iter()
+            .map(|row| Ok((SpordState::from_sql(row.try_get(0)?), row.try_get(1)?)))
+            .collect::<Result<Vec<_>>>()?;
+
+        let overdue: i64 = client
+            .query_one(
+                "SELECT COUNT(*) FROM spords
+                    WHERE archived IS NULL AND state IN ($1, $2) AND created < $3",
+                &[
+                    &SpordState::Pending.as_sql(),
+                    &SpordState::Ordered.as_sql(),
+                    &overdue_before.timestamp(),
+                ],
+            )
+            .await?
+            .try_get(0)?;
+
+        Ok(SpordStats {
+            by_state: sql::count_by_state(counts),
+            overdue,
+        })
+    }
+
+    async fn dashboard(
+        &self,
+        location: Option<LocationFilter>,
+        created_since: DateTime<Utc>,
+        overdue_before: DateTime<Utc>,
+        oldest_limit: usize,
+    ) -> Result<Dashboard> {
+        let all_locations = location.is_none();
+        let location = location.and_then(|LocationFilter(location)| location);
+        let (pending, ordered) = (SpordState::Pending.as_sql(), SpordState::Ordered.as_sql());
+        let client = self.pool.get().await?;
+
+        let rows = client
+            .query(
+                "SELECT state, COUNT(*) FROM spords
+                    WHERE ($1 OR location IS NOT DISTINCT FROM $2) AND archived IS NULL
+                    GROUP BY state",
+                &[&all_locations, &location],
+            )
+            .await?;
+        let counts = rows
+            .iter()
+            .map(|row| Ok((SpordState::from_sql(row.try_get(0)?), row.try_get(1)?)))
+            .collect::<Result<Vec<_>>>()?;
+
+        let row = client
+            .query_one(
+                "SELECT
+                    COUNT(*) FILTER (WHERE created >= $3),
+                    COUNT(*) FILTER (WHERE state = $4),
+                    COUNT(*) FILTER (WHERE state IN ($5, $6) AND created < $7)
+                    FROM spords WHERE ($1 OR location IS NOT DISTINCT FROM $2)
+                        AND archived IS NULL",
+                &[
+                    &all_locations,
+                    &location,
+                    &created_since.timestamp(),
+                    &SpordState::Received.as_sql(),
+                    &pending,
+                    &ordered,
+                    &overdue_before.timestamp(),
+                ],
+            )
+            .await?;
+
+        let rows = client
+            .query(
+                &format!(
+                    "SELECT {} FROM spords WHERE ($1 OR location IS NOT DISTINCT FROM $2)
+                        AND state IN ($3, $4) AND archived IS NULL
+                        ORDER BY created, id LIMIT $5",
+                    SPORD_COLUMNS
+                ),
+                &[
+                    &all_locations,
+                    &location,
+                    &pending,
+                    &ordered,
+                    &(oldest_limit as i64),
+                ],
+            )
+            .await?;
+
+        Ok(Dashboard {
+            by_state: sql::count_by_state(counts),
+            created_since: row.try_get(0)?,
+            awaiting_pickup: row.try_get(1)?,
+            overdue: row.try_get(2)?,
+            oldest_open: rows.iter().map(spord_from_row).collect::<Result<_>>()?,
+        })
+    }
+
+    async fn turnaround(
+        &self,
+        location: Option<LocationFilter>,
+        created_since: Option<DateTime<Utc>>,
+    ) -> Result<Vec<SpordTurnaround>> {
+        let all_locations = location.is_none();
+        let location = location.and_then(|LocationFilter(location)| location);
+        let created_since = created_since.map(|since| since.timestamp());
+        let client = self.pool.get().await?;
+
+        let rows = client
+            .query(
+                "SELECT vendor, part, created,
+                    COALESCE(received, (SELECT MIN(time) FROM spord_events
+                        WHERE spord = spords.id AND kind = $4 AND new_value = $5)),
+                    (SELECT MIN(time) FROM spord_events
+                        WHERE spord = spords.id AND kind = $4 AND new_value = $6)
+                    FROM spords WHERE ($1 OR location IS NOT DISTINCT FROM $2)
+                        AND ($3::BIGINT IS NULL OR created >= $3)
+                    ORDER BY created, id",
+                &[
+                    &all_locations,
+                    &location,
+                    &created_since,
+                    &SpordEventKind::State.as_sql(),
+                    &(SpordState::Received.as_sql() as i64),
+                    &(SpordState::PickedUp.as_sql() as i64),
+                ],
+            )
+            .await?;
+
+        rows.iter()
+            .map(|row| {
+                Ok(SpordTurnaround {
+                    vendor: row.try_get(0)?,
+                    part: row.try_get::<_, Option<String>>(1)?.unwrap_or_default(),
+                    creation_date: DateTime::from_timestamp(row.try_get(2)?, 0).unwrap_or_default(),
+                    received_date: row
+                        .try_get::<_, Option<i64>>(3)?
+                        .and_then(|received| DateTime::from_timestamp(received, 0)),
+                    picked_up_date: row
+                        .try_get::<_, Option<i64>>(4)?
+                        .and_then(|picked_up| DateTime::from_timestamp(picked_up, 0)),
+                })
+            })
+            .collect()
+    }
+
+    async fn schema_version(&self) -> Result<(usize, usize)> {
+        let client = self.pool.get().await?;
+        let version: i32 = client
+            .query_opt("SELECT version FROM schema_version", &[])
+            .await?
+            .map(|row| row.try_get(0))
+            .transpose()?
+            .unwrap_or(0);
+
+        Ok((version as usize, MIGRATIONS.len()))
+    }
+}
+
+#[async_trait]
+impl UserStore for PostgresStore {
+    async fn login(&self, username: &str, password: &str) -> Result<bool> {
+        let client = self.pool.get().await?;
+        let Some(row) = client
+            .query_opt(
+                "SELECT password FROM auth WHERE username=$1 AND enabled",
+                &[&username],
+            )
+            .await?
+        else {
+            return Ok(false);
+        };
+        let hash: String = row.try_get(0)?;
+
+        Ok(bcrypt::verify(password, &hash)?)
+    }
+
+    async fn get(&self, username: &str) -> Result<Option<UserRecord>> {
+        let client = self.pool.get().await?;
+        let row = client
+            .query_opt(
+                "SELECT username, enabled, location, manager FROM auth WHERE username=$1",
+                &[&username],
+            )
+            .await?;
+
+        row.as_ref().map(user_from_row).transpose()
+    }
+
+    async fn list(&self) -> Result<Vec<UserRecord>> {
+        let client = self.pool.get().await?;
+        let rows = client
+            .query(
+                "SELECT username, enabled, location, manager FROM auth ORDER BY username",
+                &[],
+            )
+            .await?;
+
+        rows.iter().map(user_from_row).collect()
+    }
+
+    async fn create(&self, username: &str, password: &str) -> Result<()> {
+        let hash = bcrypt::hash(password, bcrypt::DEFAULT_COST)?;
+        let client = self.pool.get().await?;
+        client
+            .execute(
+                "INSERT INTO auth (username, password, enabled) VALUES($1, $2, TRUE)",
+                &[&username, &hash],
+            )
+            .await?;
+
+        Ok(())
+    }
+
+    async fn set_location(&self, username: &str, location: Option<i64>) -> Result<bool> {
+        let client = self.pool.get().await?;
+        let updated = client
+            .execute(
+                "UPDATE auth SET location=$1 WHERE username=$2",
+                &[&location, &username],
+            )
+            .await?;
+
+        Ok(updated > 0)
+    }
+
+    async fn set_manager(&self, username: &str, manager: bool) -> Result<bool> {
+        let client = self.pool.get().await?;
+        let updated = client
+            .execute(
+                "UPDATE auth SET manager=$1 WHERE username=$2",
+                &[&manager, &username],
+            )
+            .await?;
+
+        Ok(updated > 0)
+    }
+
+    async fn create_token(
+        &self,
+        name: &str,
+        username: Option<&str>,
+        scope: TokenScope,
+        expires: Option<DateTime<Utc>>,
+        location: Option<i64>,
+    ) -> Result<(i64, String)> {
+        let (token, token_hash) = sql::tokens::generate();
+        let client = self.pool.get().await?;
+        let row = client
+            .query_one(
+                "INSERT INTO api_tokens
+                    (name, username, token_hash, scope, created, expires, location)
+                    VALUES($1, $2, $3, $4, $5, $6, $7) RETURNING id",
+                &[
+                    &name,
+                    &username,
+                    &token_hash,
+                    &scope.as_sql(),
+                    &Utc::now().timestamp(),
+                    &expires.map(|expires| expires.timestamp()),
+                    &location.filter(|_| username.is_none()),
+                ],
+            )
+            .await?;
+
+        Ok((row.try_get(0)?, token))
+    }
+
+    async fn authenticate_token(&self, token: &str) -> Result<Option<ApiTokenRecord>> {
+        let client = self.pool.get().await?;
+        let row = client
+            .query_opt(
+                &format!(
+                    "UPDATE api_tokens SET last_used=$2
+                        WHERE token_hash=$1 AND (expires IS NULL OR expires>$2)
+                        RETURNING {}",
+                    TOKEN_COLUMNS
+                ),
+                &[&sql::tokens::hash_token(token), &Utc::now().timestamp()],
+            )
+            .await?;
+
+        row.as_ref().map(token_from_row).transpose()
+    }
+
+    async fn tokens(&self) -> Result<Vec<ApiTokenRecord>> {
+        let client = self.pool.get().await?;
+        let rows = client
+            .query(
+                &format!("SELECT {} FROM api_tokens ORDER BY id", TOKEN_COLUMNS),
+                &[],
+            )
+            .await?;
+
+        rows.iter().map(token_from_row).collect()
+    }
+
+    async fn revoke_token(&self, id: i64, username: Option<&str>) -> Result<bool> {
+        let client = self.pool.get().await?;
+        let deleted = client
+            .execute(
+                "DELETE FROM api_tokens WHERE id=$1 AND ($2::TEXT IS NULL OR username=$2)",
+                &[&id, &username],
+            )
+            .await?;
+
+        Ok(deleted > 0)
+    }
+
+    async fn load_session(&self, key: &str) -> Result<Option<String>> {
+        let client = self.pool.get().await?;
+        let row = client
+            .query_opt(
+                "SELECT state FROM sessions WHERE key=$1 AND expires>$2",
+                &[&key, &Utc::now().timestamp()],
+            )
+            .await?;
+
+        Ok(row.map(|row| row.try_get(0)).transpose()?)
+    }
+
+    async fn save_session(
+        &self,
+        key: &str,
+        username: Option<String>,
+        state: String,
+        expires: DateTime<Utc>,
+    ) -> Result<()> {
+        let now = Utc::now().timestamp();
+        let client = self.pool.get().await?;
+        client
+            .execute(
+                "INSERT INTO sessions (key, username, state, created, last_seen, expires)
+                    VALUES($1, $2, $3, $4, $4, $5)
+                    ON CONFLICT (key) DO UPDATE SET username=excluded.username,
+                    state=excluded.state, last_seen=excluded.last_seen, expires=excluded.expires",
+                &[&key, &username, &state, &now, &expires.timestamp()],
+            )
+            .await?;
+        client
+            .execute("DELETE FROM sessions WHERE expires<=$1", &[&now])
+            .await?;
+
+        Ok(())
+    }
+
+    async fn set_session_expiry(&self, key: &str, expires: DateTime<Utc>) -> Result<()> {
+        let client = self.pool.get().await?;
+        client
+            .execute(
+                "UPDATE sessions SET last_seen=$1, expires=$2 WHERE key=$3",
+                &[&Utc::now().timestamp(), &expires.timestamp(), &key],
+            )
+            .await?;
+
+        Ok(())
+    }
+
+    async fn delete_session(&self, key: &str) -> Result<()> {
+        let client = self.pool.get().await?;
+        client
+            .execute("DELETE FROM sessions WHERE key=$1", &[&key])
+            .await?;
+
+        Ok(())
+    }
+
+    async fn sessions(&self) -> Result<Vec<SessionRecord>> {
+        let client = self.pool.get().await?;
+        let rows = client
+            .query(
+                "SELECT id, username, created, last_seen, expires FROM sessions
+                    WHERE expires>$1 ORDER BY last_seen DESC",
+                &[&Utc::now().timestamp()],
+            )
+            .await?;
+
+        rows.iter()
+            .map(|row| {
+                Ok(SessionRecord {
+                    id: row.try_get(0)?,
+                    username: row.try_get(1)?,
+                    created: DateTime::from_timestamp(row.try_get(2)?, 0).unwrap_or_default(),
+                    last_seen: DateTime::from_timestamp(row.try_get(3)?, 0).unwrap_or_default(),
+                    expires: DateTime::from_timestamp(row.try_get(4)?, 0).unwrap_or_default(),
+                })
+            })
+            .collect()
+    }
+
+    async fn revoke_session(&self, id: i64) -> Result<bool> {
+        let client = self.pool.get().await?;
+        let deleted = client
+            .execute("DELETE FROM sessions WHERE id=$1", &[&id])
+            .await?;
+
+        Ok(deleted > 0)
+    }
+
+    async fn revoke_user_sessions(&self, username: &str) -> Result<usize> {
+        let client = self.pool.get().await?;
+        let deleted = client
+            .execute("DELETE FROM sessions WHERE username=$1", &[&username])
+            .await?;
+
+        Ok(deleted as usize)
+    }
+
+    async fn locked_until(&self, kind: AttemptKind, key: &str) -> Result<Option<DateTime<Utc>>> {
+        let client = self.pool.get().await?;
+        let row = client
+            .query_opt(
+                "SELECT locked_until FROM login_attempts WHERE kind=$1 AND key=$2",
+                &[&kind.as_sql(), &key],
+            )
+            .await?;
+        let locked_until: Option<i64> = row.map(|row| row.try_get(0)).transpose()?.flatten();
+
+        let now = Utc::now();
+        Ok(locked_until
+            .and_then(|timestamp| DateTime::from_timestamp(timestamp, 0))
+            .filter(|until| *until > now))
+    }
+
+    async fn record_login_failure(
+        &self,
+        config: &LoginConfig,
+        kind: AttemptKind,
+        key: &str,
+    ) -> Result<Option<DateTime<Utc>>> {
+        let now = Utc::now().timestamp();
+        let window_start = now - config.failure_window_seconds;
+
+        // the row stays locked until commit, so servers failing the same
+        // login at once count every failure
+        let mut client = self.pool.get().await?;
+        let tx = client.transaction().await?;
+        tx.execute(
+            "INSERT INTO login_attempts (kind, key, failures, last_failure)
+                VALUES($1, $2, 0, 0) ON CONFLICT (kind, key) DO NOTHING",
+            &[&kind.as_sql(), &key],
+        )
+        .await?;
+        let row = tx
+            .query_one(
+                "SELECT failures, last_failure FROM login_attempts
+                    WHERE kind=$1 AND key=$2 FOR UPDATE",
+                &[&kind.as_sql(), &key],
+            )
+            .await?;
+        let previous = (row.try_get::<_, i32>(0)? as u32, row.try_get(1)?);
+        let (failures, locked_until) = lockout::failed_again(config, kind, Some(previous), now);
+
+        tx.execute(
+            "UPDATE login_attempts SET failures=$3, last_failure=$4, locked_until=$5
+                WHERE kind=$1 AND key=$2",
+            &[
+                &kind.as_sql(),
+                &key,
+                &(failures as i32),
+                &now,
+                &locked_until,
+            ],
+        )
+        .await?;
+        tx.execute(
+            "DELETE FROM login_attempts WHERE last_failure<$1
+                AND (locked_until IS NULL OR locked_until<=$2)",
+            &[&window_start, &now],
+        )
+        .await?;
+        tx.commit().await?;
+
+        Ok(locked_until.and_then(|timestamp| DateTime::from_timestamp(timestamp, 0)))
+    }
+
+    async fn clear_login_failures(&self, kind: AttemptKind, key: &str) -> Result<bool> {
+        let client = self.pool.get().await?;
+        let deleted = client
+            .execute(
+                "DELETE FROM login_attempts WHERE kind=$1 AND key=$2",
+                &[&kind.as_sql(), &key],
+            )
+            .await?;
+
+        Ok(deleted > 0)
+    }
+
+    async fn saved_filters(&self, username: &str) -> Result<Vec<SavedFilterRecord>> {
+        let client = self.pool.get().await?;
+        let rows = client
+            .query(
+                &format!(
+                    "SELECT {} FROM saved_filters WHERE username=$1 ORDER BY name",
+                    FILTER_COLUMNS
+                ),
+                &[&username],
+            )
+            .await?;
+
+        rows.iter().map(filter_from_row).collect()
+    }
+
+    async fn save_filter(
+        &self,
+        username: &str,
+        name: &str,
+        state: Option<SpordState>,
+        tags: &[String],
+        search: Option<String>,
+    ) -> Result<i64> {
+        let client = self.pool.get().await?;
+        let row = client
+            .query_one(
+                "INSERT INTO saved_filters (username, name, state, tags, search, created)
+                    VALUES($1, $2, $3, $4, $5, $6)
+                    ON CONFLICT (username, name) DO UPDATE SET state=excluded.state,
+                        tags=excluded.tags, search=excluded.search, created=excluded.created
+                    RETURNING id",
+                &[
+                    &username,
+                    &name,
+                    &state.map(|state| state.as_sql()),
+                    &tags.join(","),
+                    &search,
+                    &Utc::now().timestamp(),
+                ],
+            )
+            .await?;
+
+        Ok(row.try_get(0)?)
+    }
+
+    async fn delete_saved_filter(&self, username: &str, id: i64) -> Result<bool> {
+        let client = self.pool.get().await?;
+        let deleted = client
+            .execute(
+                "DELETE FROM saved_filters WHERE id=$1 AND username=$2",
+                &[&id, &username],
+            )
+            .await?;
+
+        Ok(deleted > 0)
+    }
+}
+
+const FILTER_COLUMNS: &str = "id, username, name, state, tags, search, created";
+
+fn filter_from_row(row: &Row) -> Result<SavedFilterRecord> {
+    Ok(SavedFilterRecord {
+        id: row.try_get(0)?,
+        username: row.try_get(1)?,
+        name: row.try_get(2)?,
+        state: row.try_get::<_, Option<i32>>(3)?.map(SpordState::from_sql),
+        tags: models::parse_tags(row.try_get(4)?),
+        search: row.try_get(5)?,
+        created: DateTime::from_timestamp(row.try_get(6)?, 0).unwrap_or_default(),
+    })
+}
+
+fn attachment_from_row(row: &Row) -> Result<AttachmentRecord> {
+    Ok(AttachmentRecord {
+        id: row.try_get(0)?,
+        spord_id: row.try_get(1)?,
+        filename: row.try_get(2)?,
+        mime_type: row.try_get(3)?,
+        size: row.try_get(4)?,
+        sha256: row.try_get(5)?,
+        username: row.try_get(6)?,
+        created: DateTime::from_timestamp(row.try_get(7)?, 0).unwrap_or_default(),
+    })
+}
+
+fn location_from_row(row: &Row) -> Result<LocationRecord> {
+    Ok(LocationRecord {
+        id: row.try_get(0)?,
+        name: row.try_get(1)?,
+    })
+}
+
+#[async_trait]
+impl LocationStore for PostgresStore {
+    async fn create(&self, name: &str) -> Result<i64> {
+        let client = self.pool.get().await?;
+        let row = client
+            .query_one(
+                "INSERT INTO locations (name) VALUES($1) RETURNING id",
+                &[&name],
+            )
+            .await?;
+
+        Ok(row.try_get(0)?)
+    }
+
+    async fn list(&self) -> Result<Vec<LocationRecord>> {
+        let client = self.pool.get().await?;
+        let rows = client
+            .query("SELECT id, name FROM locations ORDER BY name", &[])
+            .await?;
+
+        rows.iter().map(location_from_row).collect()
+    }
+
+    async fn find(&self, name: &str) -> Result<Option<LocationRecord>> {
+        let client = self.pool.get().await?;
+        let row = client
+            .query_opt("SELECT id, name FROM locations WHERE name=$1", &[&name])
+            .await?;
+
+        row.as_ref().map(location_from_row).transpose()
+    }
+
+    async fn get(&self, id: i64) -> Result<Option<LocationRecord>> {
+        let client = self.pool.get().await?;
+        let row = client
+            .query_opt("SELECT id, name FROM locations WHERE id=$1", &[&id])
+            .await?;
+
+        row.as_ref().map(location_from_row).transpose()
+    }
+}
+
+const WEBHOOK_COLUMNS: &str = "id, url, secret, events, enabled, created";
+const DELIVERY_COLUMNS: &str = "id, webhook, event, payload, state, attempts, next_attempt, \
+    last_status, last_error, created, updated";
+
+fn webhook_from_row(row: &Row) -> Result<WebhookRecord> {
+    Ok(WebhookRecord {
+        id: row.try_get(0)?,
+        url: row.try_get(1)?,
+        secret: row.try_get(2)?,
+        events: sql::webhooks::events_from_sql(row.try_get(3)?),
+        enabled: row.try_get(4)?,
+        created: DateTime::from_timestamp(row.try_get(5)?, 0).unwrap_or_default(),
+    })
+}
+
+fn delivery_from_row(row: &Row) -> Result<WebhookDeliveryRecord> {
+    let timestamp = |timestamp: i64| DateTime::from_timestamp(timestamp, 0).unwrap_or_default();
+
+    Ok(WebhookDeliveryRecord {
+        id: row.try_get(0)?,
+        webhook_id: row.try_get(1)?,
+        event: row.try_get(2)?,
+        payload: row.try_get(3)?,
+        state: DeliveryState::from_sql(row.try_get(4)?),
+        attempts: row.try_get::<_, i32>(5)? as u32,
+        next_attempt: row.try_get::<_, Option<i64>>(6)?.map(timestamp),
+        last_status: row
+            .try_get::<_, Option<i32>>(7)?
+            .map(|status| status as u16),
+        last_error: row.try_get(8)?,
+        created: timestamp(row.try_get(9)?),
+        updated: timestamp(row.try_get(10)?),
+    })
+}
+
+#[async_trait]
+impl WebhookStore for PostgresStore {
+    async fn create(&self, url: &str, secret: &str, events: &[WebhookEvent]) -> Result<i64> {
+        let client = self.pool.get().await?;
+        let row = client
+            .query_one(
+                "INSERT INTO webhooks (url, secret, events, enabled, created)
+                    VALUES($1, $2, $3, TRUE, $4) RETURNING id",
+                &[
+                    &url,
+                    &secret,
+                    &sql::webhooks::events_to_sql(events),
+                    &Utc::now().timestamp(),
+                ],
+            )
+            .await?;
+
+        Ok(row.try_get(0)?)
+    }
+
+    async fn list(&self) -> Result<Vec<WebhookRecord>> {
+        let client = self.pool.get().await?;
+        let rows = client
+            .query(
+                &format!("SELECT {} FROM webhooks ORDER BY id", WEBHOOK_COLUMNS),
+                &[],
+            )
+            .await?;
+
+        rows.iter().map(webhook_from_row).collect()
+    }
+
+    async fn get(&self, id: i64) -> Result<Option<WebhookRecord>> {
+        let client = self.pool.get().await?;
+        let row = client
+            .query_opt(
+                &format!("SELECT {} FROM webhooks WHERE id=$1", WEBHOOK_COLUMNS),
+                &[&id],
+            )
+            .await?;
+
+        row.as_ref().map(webhook_from_row).transpose()
+    }
+
+    async fn set_enabled(&self, id: i64, enabled: bool) -> Result<bool> {
+        let client = self.pool.get().await?;
+        let updated = client
+            .execute(
+                "UPDATE webhooks SET enabled=$1 WHERE id=$2",
+                &[&enabled, &id],
+            )
+            .await?;
+
+        Ok(updated > 0)
+    }
+
+    async fn delete(&self, id: i64) -> Result<bool> {
+        let mut client = self.pool.get().await?;
+        let tx = client.transaction().await?;
+        tx.execute("DELETE FROM webhook_deliveries WHERE webhook=$1", &[&id])
+            .await?;
+        let deleted = tx
+            .execute("DELETE FROM webhooks WHERE id=$1", &[&id])
+            .await?;
+        tx.commit().await?;
+
+        Ok(deleted > 0)
+    }
+
+    async fn enqueue(
+        &self,
+        event: WebhookEvent,
+        payload: String,
+        only_webhook: Option<i64>,
+    ) -> Result<usize> {
+        let now = Utc::now().timestamp();
+        let client = self.pool.get().await?;
+        let webhooks = client
+            .query(
+                &format!(
+                    "SELECT {} FROM webhooks WHERE enabled AND ($1::BIGINT IS NULL OR id=$1)",
+                    WEBHOOK_COLUMNS
+                ),
+                &[&only_webhook],
+            )
+            .await?;
+
+        let mut queued = 0;
+        for webhook in webhooks.iter().map(webhook_from_row) {
+            let webhook = webhook?;
+            if only_webhook.is_none() && !webhook.events.contains(&event) {
+                continue;
+            }
+            client
+                .execute(
+                    "INSERT INTO webhook_deliveries
+                        (webhook, event, payload, state, attempts, next_attempt, created, updated)
+                        VALUES($1, $2, $3, $4, 0, $5, $5, $5)",
+                    &[
+                        &webhook.id,
+                        &event.as_sql(),
+                        &payload,
+                        &DeliveryState::Pending.as_sql(),
+                        &now,
+                    ],
+                )
+                .await?;
+            queued += 1;
+        }
+
+        Ok(queued)
+    }
+
+    async fn due(
+        &self,
+        limit: u32,
+        claim_until: DateTime<Utc>,
+    ) -> Result<Vec<(WebhookDeliveryRecord, WebhookRecord)>> {
+        // SKIP LOCKED lets servers polling at the same time take different
+        // deliveries instead of waiting for each other
+        let mut client = self.pool.get().await?;
+        let tx = client.transaction().await?;
+        let rows = tx
+            .query(
+                &format!(
+                    "SELECT {} FROM webhook_deliveries
+                        WHERE state=$1 AND next_attempt<=$2
+                            AND webhook IN (SELECT id FROM webhooks WHERE enabled)
+                        ORDER BY next_attempt LIMIT $3 FOR UPDATE SKIP LOCKED",
+                    DELIVERY_COLUMNS
+                ),
+                &[
+                    &DeliveryState::Pending.as_sql(),
+                    &Utc::now().timestamp(),
+                    &(limit as i64),
+                ],
+            )
+            .await?;
+
+        let mut due = vec![];
+        for row in &rows {
+            let delivery = delivery_from_row(row)?;
+            tx.execute(
+                "UPDATE webhook_deliveries SET next_attempt=$1 WHERE id=$2",
+                &[&claim_until.timestamp(), &delivery.id],
+            )
+            .await?;
+            let webhook = tx
+                .query_opt(
+                    &format!("SELECT {} FROM webhooks WHERE id=$1", WEBHOOK_COLUMNS),
+                    &[&delivery.webhook_id],
+                )
+                .await?;
+            if let Some(webhook) = webhook {
+                due.push((delivery, webhook_from_row(&webhook)?));
+            }
+        }
+        tx.commit().await?;
+
+        Ok(due)
+    }
+
+    async fn record_attempt(
+        &self,
+        id: i64,
+        state: DeliveryState,
+        status: Option<u16>,
+        error: Option<String>,
+        next_attempt: Option<DateTime<Utc>>,
+    ) -> Result<()> {
+        let client = self.pool.get().await?;
+        client
+            .execute(
+                "UPDATE webhook_deliveries SET state=$1, attempts=attempts+1, next_attempt=$2,
+                    last_status=$3, last_error=$4, updated=$5 WHERE id=$6",
+                &[
+                    &state.as_sql(),
+                    &next_attempt.map(|next| next.timestamp()),
+                    &status.map(i32::from),
+                    &error,
+                    &Utc::now().timestamp(),
+                    &id,
+                ],
+            )
+            .await?;
+
+        Ok(())
+    }
+
+    async fn prune_deliveries(&self, finished_before: DateTime<Utc>) -> Result<usize> {
+        let client = self.pool.get().await?;
+        let deleted = client
+            .execute(
+                "DELETE FROM webhook_deliveries WHERE state!=$1 AND updated<$2",
+                &[
+                    &DeliveryState::Pending.as_sql(),
+                    &finished_before.timestamp(),
+                ],
+            )
+            .await?;
+
+        Ok(deleted as usize)
+    }
+
+    async fn deliveries(&self, webhook_id: i64, limit: u32) -> Result<Vec<WebhookDeliveryRecord>> {
+        let client = self.pool.get().await?;
+        let rows = client
+            .query(
+                &format!(
+                    "SELECT {} FROM webhook_deliveries WHERE webhook=$1 ORDER BY id DESC LIMIT $2",
+                    DELIVERY_COLUMNS
+                ),
+                &[&webhook_id, &(limit as i64)],
+            )
+            .await?;
+
+        rows.iter().map(delivery_from_row).collect()
+    }
+}
+
+// How many rows copy_from_sqlite moved over
+#[derive(Debug, Default)]
+pub struct CopySummary {
+    pub locations: usize,
+    pub users: usize,
+    pub tokens: usize,
+    pub sessions: usize,
+    pub spords: usize,
+    pub events: usize,
+    pub notes: usize,
+    pub attachments: usize,
+    pub filters: usize,
+    pub webhooks: usize,
+    pub deliveries: usize,
+}
+
+// Copies locations, users with their api tokens, sessions and saved filters,
+// spords, their history, notes and attachment details, and webhooks with their
+// deliveries from the sqlite database into an empty postgres database, keeping
+// ids, password hashes and webhook secrets. Failed logins start over, and the
+// attachment contents stay where they are in attachments.dir. Everything is
+// copied in one transaction, so a failed copy leaves the target untouched
+pub async fn copy_from_sqlite(
+    sqlite: &crate::sql::Pool,
+    target: &PostgresStore,
+) -> Result<CopySummary> {
+    let locations = sql::locations::list(sqlite).await?;
+    let users = sql::user_export(sqlite).await?;
+    let tokens = sql::tokens::export(sqlite).await?;
+    let sessions = sql::sessions::export(sqlite).await?;
+    let spords = sql::spord_export(sqlite).await?;
+    let mut events = Vec::new();
+    let mut notes = Vec::new();
+    let mut attachments = Vec::new();
+    for spord in &spords {
+        events.extend(sql::spord_events_get(sqlite, spord.id).await?);
+        notes.extend(sql::notes::list(sqlite, spord.id).await?);
+        attachments.extend(sql::attachments::list(sqlite, spord.id).await?);
+    }
+    let mut filters = Vec::new();
+    for (user, _, _) in &users {
+        filters.extend(sql::filters::list(sqlite, &user.username).await?);
+    }
+    let webhooks = sql::webhooks::list(sqlite).await?;
+    let mut deliveries = Vec::new();
+    for webhook in &webhooks {
+        deliveries.extend(sql::webhooks::deliveries(sqlite, webhook.id, u32::MAX).await?);
+    }
+
+    let mut client = target.pool.get().await?;
+    let tx = client.transaction().await?;
+
+    let existing: i64 = tx
+        .query_one(
+            "SELECT (SELECT COUNT(*) FROM locations) + (SELECT COUNT(*) FROM auth)
+                + (SELECT COUNT(*) FROM spords) + (SELECT COUNT(*) FROM webhooks)",
+            &[],
+        )
+        .await?
+        .try_get(0)?;
+    if existing > 0 {
+        return Err(StoreError::Copy(
+            "the postgres database already has locations, users, spords or webhooks".to_string(),
+        ));
+    }
+
+    for location in &locations {
+        tx.execute(
+            "INSERT INTO locations (id, name) VALUES($1, $2)",
+            &[&location.id, &location.name],
+        )
+        .await?;
+    }
+
+    for (user, hash, lastlogin) in &users {
+        tx.execute(
+            "INSERT INTO auth (username, password, enabled, lastlogin, location, manager)
+                VALUES($1, $2, $3, $4, $5, $6)",
+            &[
+                &user.username,
+                hash,
+                &user.enabled,
+                lastlogin,
+                &user.location,
+                &user.manager,
+            ],
+        )
+        .await?;
+    }
+
+    for (token, token_hash) in &tokens {
+        tx.execute(
+            "INSERT INTO api_tokens (id, name, username, token_hash, scope, created, expires,
+                last_used, location)
+                VALUES($1, $2, $3, $4, $5, $6, $7, $8, $9)",
+            &[
+                &token.id,
+                &token.name,
+                &token.username,
+                token_hash,
+                &token.scope.as_sql(),
+                &token.created.timestamp(),
+                &token.expires.map(|expires| expires.timestamp()),
+                &token.last_used.map(|last_used| last_used.timestamp()),
+                &token.location,
+            ],
+        )
+        .await?;
+    }
+
+    for (session, key, state) in &sessions {
+        tx.execute(
+            "INSERT INTO sessions (id, key, username, state, created, last_seen, expires)
+                VALUES($1, $2, $3, $4, $5, $6, $7)",
+            &[
+                &session.id,
+                key,
+                &session.username,
+                state,
+                &session.created.timestamp(),
+                &session.last_seen.timestamp(),
+                &session.expires.timestamp(),
+            ],
+        )
+        .await?;
+    }
+
+    for spord in &spords {
+        tx.execute(
+            "INSERT INTO spords (id, name, phone, email, part, state, created, received,
+                location, vendor, archived, anonymized)
+                VALUES($1, $2, $3, $4, $5, $6, $7, $8, $9, $10, $11, $12)",
+            &[
+                &spord.id,
+                &spord.customer_name,
+                &spord.customer_phone,
+                &spord.customer_email,
+                &spord.part,
+                &spord.state.as_sql(),
+                &spord.creation_date.timestamp(),
+                &spord.received_date_unix(),
+                &spord.location,
+                &spord.vendor,
+                &spord.archived.map(|archived| archived.timestamp()),
+                &spord.anonymized.map(|anonymized| anonymized.timestamp()),
+            ],
+        )
+        .await?;
+        set_tags(&tx, spord.id, &spord.tags).await?;
+    }
+
+    for event in &events {
+        tx.execute(
+            "INSERT INTO spord_events (id, spord, kind, old_value, new_value, username, time)
+                VALUES($1, $2, $3, $4, $5, $6, $7)",
+            &[
+                &event.id,
+                &event.spord_id,
+                &event.kind.as_sql(),
+                &event.old_value,
+                &event.new_value,
+                &event.username,
+                &event.time.timestamp(),
+            ],
+        )
+        .await?;
+    }
+
+    for note in &notes {
+        tx.execute(
+            "INSERT INTO spord_notes (id, spord, username, text, created, edited, edited_by)
+                VALUES($1, $2, $3, $4, $5, $6, $7)",
+            &[
+                &note.id,
+                &note.spord_id,
+                &note.username,
+                &note.text,
+                &note.created.timestamp(),
+                &note.edited.map(|edited| edited.timestamp()),
+                &note.edited_by,
+            ],
+        )
+        .await?;
+        for revision in &note.revisions {
+            tx.execute(
+                "INSERT INTO spord_note_revisions (note, text, username, time)
+                    VALUES($1, $2, $3, $4)",
+                &[
+                    &note.id,
+                    &revision.text,
+                    &revision.username,
+                    &revision.time.timestamp(),
+                ],
+            )
+            .await?;
+        }
+    }
+
+    for attachment in &attachments {
+        tx.execute(
+            "INSERT INTO attachments
+                (id, spord, filename, mime_type, size, sha256, username, created)
+                VALUES($1, $2, $3, $4, $5, $6, $7, $8)",
+            &[
+                &attachment.id,
+                &attachment.spord_id,
+                &attachment.filename,
+                &attachment.mime_type,
+                &attachment.size,
+                &attachment.sha256,
+                &attachment.username,
+                &attachment.created.timestamp(),
+            ],
+        )
+        .await?;
+    }
+
+    for filter in &filters {
+        tx.execute(
+            "INSERT INTO saved_filters (id, username, name, state, tags, search, created)
+                VALUES($1, $2, $3, $4, $5, $6, $7)",
+            &[
+                &filter.id,
+                &filter.username,
+                &filter.name,
+                &filter.state.as_ref().map(|state| state.as_sql()),
+                &filter.tags.join(","),
+                &filter.search,
+                &filter.created.timestamp(),
+            ],
+        )
+        .await?;
+    }
+
+    for webhook in &webhooks {
+        tx.execute(
+            "INSERT INTO webhooks (id, url, secret, events, enabled, created)
+                VALUES($1, $2, $3, $4, $5, $6)",
+            &[
+                &webhook.id,
+                &webhook.url,
+                &webhook.secret,
+                &sql::webhooks::events_to_sql(&webhook.events),
+                &webhook.enabled,
+                &webhook.created.timestamp(),
+            ],
+        )
+        .await?;
+    }
+
+    for delivery in &deliveries {
+        tx.execute(
+            "INSERT INTO webhook_deliveries (id, webhook, event, payload, state, attempts,
+                next_attempt, last_status, last_error, created, updated)
+                VALUES($1, $2, $3, $4, $5, $6, $7, $8, $9, $10, $11)",
+            &[
+                &delivery.id,
+                &delivery.webhook_id,
+                &delivery.event,
+                &delivery.payload,
+                &delivery.state.as_sql(),
+                &(delivery.attempts as i32),
+                &delivery.next_attempt.map(|next| next.timestamp()),
+                &delivery.last_status.map(i32::from),
+                &delivery.last_error,
+                &delivery.created.timestamp(),
+                &delivery.updated.timestamp(),
+            ],
+        )
+        .await?;
+    }
+
+    // explicit ids do not move the sequences, new rows would collide otherwise
+    for table in [
+        "locations",
+        "api_tokens",
+        "sessions",
+        "spords",
+        "spord_events",
+        "spord_notes",
+        "attachments",
+        "saved_filters",
+        "webhooks",
+        "webhook_deliveries",
+    ] {
+        tx.execute(
+            &format!(
+                "SELECT setval(pg_get_serial_sequence('{0}', 'id'),
+                    COALESCE(MAX(id), 1), MAX(id) IS NOT NULL) FROM {0}",
+                table
+            ),
+            &[],
+        )
+        .await?;
+    }
+    tx.commit().await?;
+
+    Ok(CopySummary {
+        locations: locations.len(),
+        users: users.len(),
+        tokens: tokens.len(),
+        sessions: sessions.len(),
+        spords: spords.len(),
+        events: events.len(),
+        notes: notes.len(),
+        attachments: attachments.len(),
+        filters: filters.len(),
+        webhooks: webhooks.len(),
+        deliveries: deliveries.len(),
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::store::sqlite::SqliteStore;
+    use crate::testing;
+
+    #[test]
+    #[ignore = "needs SPORD_TEST_POSTGRES_URL"]
+    async fn copies_everything_from_sqlite() {
+        let schema = testing::PostgresSchema::create().await;
+        let (pool, _dir) = testing::pool().await;
+        let north = sql::locations::create(&pool, "North").await.unwrap();
+        let source = SqliteStore::new(pool.clone());
+        UserStore::create(&source, "bob", "hunter22").await.unwrap();
+        source.set_location("bob", Some(north)).await.unwrap();
+
+        let mut open = testing::spord("Ann Example", "Brake pad");
+        open.tags = vec!["rush".to_string()];
+        let open = SpordStore::create(&source, open).await.unwrap() as i32;
+        let mut ordered = SpordStore::get(&source, open).await.unwrap().unwrap();
+        ordered.state = SpordState::Ordered;
+        source
+            .update(ordered, Some("bob".to_string()))
+            .await
+            .unwrap();
+        let note = source
+            .add_note(open, Some("bob".to_string()), "Called".to_string())
+            .await
+            .unwrap();
+        source
+            .edit_note(open, note, None, "Called twice".to_string())
+            .await
+            .unwrap();
+        let archived = SpordStore::create(&source, testing::spord("Bob Sample", "Chain"))
+            .await
+            .unwrap() as i32;
+        source.archive(archived, None).await.unwrap();
+        let (token_id, token) = source
+            .create_token("till", None, TokenScope::Write, None, Some(north))
+            .await
+            .unwrap();
+        let attachment = source
+            .add_attachment(AttachmentRecord {
+                id: 0,
+                spord_id: open,
+                filename: "quote.pdf".to_string(),
+                mime_type: "application/pdf".to_string(),
+                size: 3,
+                sha256: "abc".to_string(),
+                username: Some("bob".to_string()),
+                created: Utc::now(),
+            })
+            .await
+            .unwrap();
+        let expires = Utc::now() + chrono::Duration::hours(1);
+        source
+            .save_session("key", Some("bob".to_string()), "{}".to_string(), expires)
+            .await
+            .unwrap();
+        let filter = source
+            .save_filter("bob", "Rush", None, &["rush".to_string()], None)
+            .await
+            .unwrap();
+        let webhook = WebhookStore::create(
+            &source,
+            "https://hooks.example",
+            "secret",
+            &[WebhookEvent::Created],
+        )
+        .await
+        .unwrap();
+        source
+            .enqueue(WebhookEvent::Created, "{}".to_string(), None)
+            .await
+            .unwrap();
+
+        let target = PostgresStore::connect(&schema.url, None, 1).await.unwrap();
+        let copied = copy_from_sqlite(&pool, &target).await.unwrap();
+        assert_eq!(
+            (
+                copied.locations,
+                copied.users,
+                copied.tokens,
+                copied.sessions,
+                copied.spords,
+                copied.events,
+                copied.notes,
+                copied.attachments,
+                copied.filters,
+                copied.webhooks,
+                copied.deliveries
+            ),
+            (1, 1, 1, 1, 2, 2, 1, 1, 1, 1, 1)
+        );
+
+        let location = LocationStore::get(&target, north).await.unwrap().unwrap();
+        assert_eq!(location.name, "North");
+
+        assert!(target.login("bob", "hunter22").await.unwrap());
+        let bob = UserStore::get(&target, "bob").await.unwrap().unwrap();
+        assert_eq!(bob.location, Some(north));
+        let copied_token = target.authenticate_token(&token).await.unwrap().unwrap();
+        assert_eq!(copied_token.id, token_id);
+        assert_eq!(copied_token.location, Some(north));
+        assert_eq!(
+            target.load_session("key").await.unwrap().as_deref(),
+            Some("{}")
+        );
+
+        let spord = SpordStore::get(&target, open).await.unwrap().unwrap();
+        assert_eq!(spord.customer_name, "Ann Example");
+        assert_eq!(spord.state, SpordState::Ordered);
+        assert_eq!(spord.tags, ["rush"]);
+        assert!(SpordStore::get(&target, archived)
+            .await
+            .unwrap()
+            .unwrap()
+            .archived
+            .is_some());
+        let events = target.events(open).await.unwrap();
+        assert_eq!(events.len(), 1);
+        assert_eq!(events[0].kind, SpordEventKind::State);
+        assert_eq!(events[0].username.as_deref(), Some("bob"));
+        let notes = target.notes(open).await.unwrap();
+        assert_eq!(notes[0].id, note);
+        assert_eq!(notes[0].text, "Called twice");
+        assert_eq!(notes[0].revisions.len(), 1);
+        assert_eq!(notes[0].revisions[0].text, "Called");
+        let attachments = target.attachments(open).await.unwrap();
+        assert_eq!(attachments[0].id, attachment);
+        assert_eq!(attachments[0].filename, "quote.pdf");
+        assert_eq!(target.attachments_size(open).await.unwrap(), 3);
+        let filters = target.saved_filters("bob").await.unwrap();
+        assert_eq!(filters[0].id, filter);
+        assert_eq!(filters[0].tags, ["rush"]);
+        let copied_webhook = WebhookStore::get(&target, webhook).await.unwrap().unwrap();
+        assert_eq!(copied_webhook.secret, "secret");
+        assert_eq!(copied_webhook.events, [WebhookEvent::Created]);
+        assert_eq!(target.deliveries(webhook, 10).await.unwrap().len(), 1);
+
+        // the sequences moved past the copied ids
+        assert!(LocationStore::create(&target, "South").await.unwrap() > north);
+        let next = SpordStore::create(&target, testing::spord("Cleo New", "Bell"))
+            .await
+            .unwrap() as i32;
+        assert!(next > archived);
+        assert!(
+            target
+                .save_filter("bob", "Other", None, &[], None)
+                .await
+                .unwrap()
+                > filter
+        );
+        assert!(
+            target
+                .add_note(next, None, "New".to_string())
+                .await
+                .unwrap()
+                > note
+        );
+
+        // a second copy would clash with what is there now
+        assert!(matches!(
+            copy_from_sqlite(&pool, &target).await,
+            Err(StoreError::Copy(_))
+        ));
+
+        schema.remove().await;
+    }
+
+    #[test]
+    #[ignore = "needs SPORD_TEST_POSTGRES_URL"]
+    async fn copy_stops_at_an_unreadable_spord() {
+        let schema = testing::PostgresSchema::create().await;
+        let (pool, _dir) = testing::pool().await;
+        let source = SqliteStore::new(pool.clone());
+        SpordStore::create(&source, testing::spord("Ann Example", "Brake pad"))
+            .await
+            .unwrap();
+        pool.conn(|conn| {
+            conn.execute(
+                "INSERT INTO spords (name, part, state, created) VALUES('Bob', 'Chain', 'x', 0)",
+                [],
+            )
+        })
+        .await
+        .unwrap();
+
+        let target = PostgresStore::connect(&schema.url, None, 1).await.unwrap();
+        assert!(copy_from_sqlite(&pool, &target).await.is_err());
+        // nothing is copied, not even the readable spord
+        let copied = SpordStore::list(&target, SpordFilter::default())
+            .await
+            .unwrap();
+        assert!(copied.is_empty());
+
+        schema.remove().await;
+    }
+}
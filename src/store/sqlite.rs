@@ -0,0 +1,350 @@
+use super::{LocationStore, Result, SpordStore, UserStore, WebhookStore};
+use crate::config::LoginConfig;
+use crate::sql;
+use crate::sql::lockout::AttemptKind;
+use crate::sql::models::{
+    ApiTokenRecord, AttachmentRecord, Dashboard, DeliveryState, LocationRecord, NoteRecord,
+    SavedFilterRecord, SessionRecord, SpordEventRecord, SpordRecord, SpordState, SpordStats,
+    SpordTurnaround, TokenScope, UserRecord, WebhookDeliveryRecord, WebhookEvent, WebhookRecord,
+};
+use crate::sql::Pool;
+use crate::sql::{LocationFilter, SpordFilter};
+use async_trait::async_trait;
+use chrono::{DateTime, Utc};
+
+// The stores backed by the sql module
+pub struct SqliteStore {
+    pool: Pool,
+}
+impl SqliteStore {
+    pub fn new(pool: Pool) -> SqliteStore {
+        SqliteStore { pool }
+    }
+}
+
+#[async_trait]
+impl SpordStore for SqliteStore {
+    async fn create(&self, spord: SpordRecord) -> Result<i64> {
+        Ok(sql::spord_create(&self.pool, spord).await?)
+    }
+
+    async fn update(&self, spord: SpordRecord, username: Option<String>) -> Result<bool> {
+        Ok(sql::spord_update(&self.pool, spord, username).await?)
+    }
+
+    async fn get(&self, id: i32) -> Result<Option<SpordRecord>> {
+        Ok(sql::spord_get(&self.pool, id).await?)
+    }
+
+    async fn list(&self, filter: SpordFilter) -> Result<Vec<SpordRecord>> {
+        Ok(sql::spord_get_all(&self.pool, filter).await?)
+    }
+
+    async fn delete(&self, id: i32) -> Result<bool> {
+        Ok(sql::spord_delete(&self.pool, id).await?)
+    }
+
+    async fn archive(&self, id: i32, username: Option<String>) -> Result<bool> {
+        Ok(sql::spord_archive(&self.pool, id, username).await?)
+    }
+
+    async fn restore(&self, id: i32, username: Option<String>) -> Result<bool> {
+        Ok(sql::spord_restore(&self.pool, id, username).await?)
+    }
+
+    async fn archive_picked_up(&self, picked_up_before: DateTime<Utc>) -> Result<Vec<i32>> {
+        Ok(sql::spord_archive_picked_up(&self.pool, picked_up_before).await?)
+    }
+
+    async fn anonymize(&self, id: i32) -> Result<bool> {
+        Ok(sql::spord_anonymize(&self.pool, id).await?)
+    }
+
+    async fn tags(&self) -> Result<Vec<String>> {
+        Ok(sql::tags_get_all(&self.pool).await?)
+    }
+
+    async fn events(&self, spord_id: i32) -> Result<Vec<SpordEventRecord>> {
+        Ok(sql::spord_events_get(&self.pool, spord_id).await?)
+    }
+
+    async fn notes(&self, spord_id: i32) -> Result<Vec<NoteRecord>> {
+        Ok(sql::notes::list(&self.pool, spord_id).await?)
+    }
+
+    async fn add_note(&self, spord_id: i32, username: Option<String>, text: String) -> Result<i64> {
+        Ok(sql::notes::add(&self.pool, spord_id, username, text).await?)
+    }
+
+    async fn edit_note(
+        &self,
+        spord_id: i32,
+        id: i64,
+        username: Option<String>,
+        text: String,
+    ) -> Result<bool> {
+        Ok(sql::notes::edit(&self.pool, spord_id, id, username, text).await?)
+    }
+
+    async fn attachments(&self, spord_id: i32) -> Result<Vec<AttachmentRecord>> {
+        Ok(sql::attachments::list(&self.pool, spord_id).await?)
+    }
+
+    async fn attachment(&self, spord_id: i32, id: i64) -> Result<Option<AttachmentRecord>> {
+        Ok(sql::attachments::get(&self.pool, spord_id, id).await?)
+    }
+
+    async fn add_attachment(&self, attachment: AttachmentRecord) -> Result<i64> {
+        Ok(sql::attachments::create(&self.pool, attachment).await?)
+    }
+
+    async fn delete_attachment(&self, id: i64) -> Result<bool> {
+        Ok(sql::attachments::delete(&self.pool, id).await?)
+    }
+
+    async fn attachments_size(&self, spord_id: i32) -> Result<i64> {
+        Ok(sql::attachments::total_size(&self.pool, spord_id).await?)
+    }
+
+    async fn attachment_content_used(&self, sha256: &str) -> Result<bool> {
+        Ok(sql::attachments::content_used(&self.pool, sha256).await?)
+    }
+
+    async fn transfer(&self, id: i32, location: i64, username: Option<String>) -> Result<bool> {
+        Ok(sql::locations::transfer_spord(&self.pool, id, location, username).await?)
+    }
+
+    async fn stats(&self, overdue_before: DateTime<Utc>) -> Result<SpordStats> {
+        Ok(sql::spord_stats(&self.pool, overdue_before).await?)
+    }
+
+    async fn dashboard(
+        &self,
+        location: Option<LocationFilter>,
+        created_since: DateTime<Utc>,
+        overdue_before: DateTime<Utc>,
+        oldest_limit: usize,
+    ) -> Result<Dashboard> {
+        Ok(sql::spord_dashboard(
+            &self.pool,
+            location,
+            created_since,
+            overdue_before,
+            oldest_limit,
+        )
+        .await?)
+    }
+
+    async fn turnaround(
+        &self,
+        location: Option<LocationFilter>,
+        created_since: Option<DateTime<Utc>>,
+    ) -> Result<Vec<SpordTurnaround>> {
+        Ok(sql::spord_turnaround(&self.pool, location, created_since).await?)
+    }
+
+    async fn schema_version(&self) -> Result<(usize, usize)> {
+        Ok(sql::schema_version(&self.pool).await?)
+    }
+}
+
+#[async_trait]
+impl UserStore for SqliteStore {
+    async fn login(&self, username: &str, password: &str) -> Result<bool> {
+        Ok(sql::user_login(&self.pool, username, password).await?)
+    }
+
+    async fn get(&self, username: &str) -> Result<Option<UserRecord>> {
+        Ok(sql::user_get(&self.pool, username).await?)
+    }
+
+    async fn list(&self) -> Result<Vec<UserRecord>> {
+        Ok(sql::user_get_all(&self.pool).await?)
+    }
+
+    async fn create(&self, username: &str, password: &str) -> Result<()> {
+        Ok(sql::user_create(&self.pool, username, password).await?)
+    }
+
+    async fn set_location(&self, username: &str, location: Option<i64>) -> Result<bool> {
+        Ok(sql::locations::set_user_location(&self.pool, username, location).await?)
+    }
+
+    async fn set_manager(&self, username: &str, manager: bool) -> Result<bool> {
+        Ok(sql::locations::set_user_manager(&self.pool, username, manager).await?)
+    }
+
+    async fn create_token(
+        &self,
+        name: &str,
+        username: Option<&str>,
+        scope: TokenScope,
+        expires: Option<DateTime<Utc>>,
+        location: Option<i64>,
+    ) -> Result<(i64, String)> {
+        Ok(sql::tokens::create(&self.pool, name, username, scope, expires, location).await?)
+    }
+
+    async fn authenticate_token(&self, token: &str) -> Result<Option<ApiTokenRecord>> {
+        Ok(sql::tokens::authenticate(&self.pool, token).await?)
+    }
+
+    async fn tokens(&self) -> Result<Vec<ApiTokenRecord>> {
+        Ok(sql::tokens::list(&self.pool).await?)
+    }
+
+    async fn revoke_token(&self, id: i64, username: Option<&str>) -> Result<bool> {
+        Ok(sql::tokens::revoke(&self.pool, id, username).await?)
+    }
+
+    async fn load_session(&self, key: &str) -> Result<Option<String>> {
+        Ok(sql::sessions::load(&self.pool, key).await?)
+    }
+
+    async fn save_session(
+        &self,
+        key: &str,
+        username: Option<String>,
+        state: String,
+        expires: DateTime<Utc>,
+    ) -> Result<()> {
+        Ok(sql::sessions::save(&self.pool, key, username, state, expires).await?)
+    }
+
+    async fn set_session_expiry(&self, key: &str, expires: DateTime<Utc>) -> Result<()> {
+        Ok(sql::sessions::set_expiry(&self.pool, key, expires).await?)
+    }
+
+    async fn delete_session(&self, key: &str) -> Result<()> {
+        Ok(sql::sessions::delete(&self.pool, key).await?)
+    }
+
+    async fn sessions(&self) -> Result<Vec<SessionRecord>> {
+        Ok(sql::sessions::list(&self.pool).await?)
+    }
+
+    async fn revoke_session(&self, id: i64) -> Result<bool> {
+        Ok(sql::sessions::revoke(&self.pool, id).await?)
+    }
+
+    async fn revoke_user_sessions(&self, username: &str) -> Result<usize> {
+        Ok(sql::sessions::revoke_user(&self.pool, username).await?)
+    }
+
+    async fn locked_until(&self, kind: AttemptKind, key: &str) -> Result<Option<DateTime<Utc>>> {
+        Ok(sql::lockout::locked_until(&self.pool, kind, key).await?)
+    }
+
+    async fn record_login_failure(
+        &self,
+        config: &LoginConfig,
+        kind: AttemptKind,
+        key: &str,
+    ) -> Result<Option<DateTime<Utc>>> {
+        Ok(sql::lockout::record_failure(&self.pool, config, kind, key).await?)
+    }
+
+    async fn clear_login_failures(&self, kind: AttemptKind, key: &str) -> Result<bool> {
+        Ok(sql::lockout::clear(&self.pool, kind, key).await?)
+    }
+
+    async fn saved_filters(&self, username: &str) -> Result<Vec<SavedFilterRecord>> {
+        Ok(sql::filters::list(&self.pool, username).await?)
+    }
+
+    async fn save_filter(
+        &self,
+        username: &str,
+        name: &str,
+        state: Option<SpordState>,
+        tags: &[String],
+        search: Option<String>,
+    ) -> Result<i64> {
+        Ok(sql::filters::save(&self.pool, username, name, state, tags, search).await?)
+    }
+
+    async fn delete_saved_filter(&self, username: &str, id: i64) -> Result<bool> {
+        Ok(sql::filters::delete(&self.pool, username, id).await?)
+    }
+}
+
+#[async_trait]
+impl LocationStore for SqliteStore {
+    async fn create(&self, name: &str) -> Result<i64> {
+        Ok(sql::locations::create(&self.pool, name).await?)
+    }
+
+    async fn list(&self) -> Result<Vec<LocationRecord>> {
+        Ok(sql::locations::list(&self.pool).await?)
+    }
+
+    async fn find(&self, name: &str) -> Result<Option<LocationRecord>> {
+        Ok(sql::locations::find(&self.pool, name).await?)
+    }
+
+    async fn get(&self, id: i64) -> Result<Option<LocationRecord>> {
+        Ok(sql::locations::get(&self.pool, id).await?)
+    }
+}
+
+#[async_trait]
+impl WebhookStore for SqliteStore {
+    async fn create(&self, url: &str, secret: &str, events: &[WebhookEvent]) -> Result<i64> {
+        Ok(sql::webhooks::create(&self.pool, url, secret, events).await?)
+    }
+
+    async fn list(&self) -> Result<Vec<WebhookRecord>> {
+        Ok(sql::webhooks::list(&self.pool).await?)
+    }
+
+    async fn get(&self, id: i64) -> Result<Option<WebhookRecord>> {
+        Ok(sql::webhooks::get(&self.pool, id).await?)
+    }
+
+    async fn set_enabled(&self, id: i64, enabled: bool) -> Result<bool> {
+        Ok(sql::webhooks::set_enabled(&self.pool, id, enabled).await?)
+    }
+
+    async fn delete(&self, id: i64) -> Result<bool> {
+        Ok(sql::webhooks::delete(&self.pool, id).await?)
+    }
+
+    async fn enqueue(
+        &self,
+        event: WebhookEvent,
+        payload: String,
+        only_webhook: Option<i64>,
+    ) -> Result<usize> {
+        Ok(sql::webhooks::enqueue(&self.pool, event, payload, only_webhook).await?)
+    }
+
+    async fn due(
+        &self,
+        limit: u32,
+        claim_until: DateTime<Utc>,
+    ) -> Result<Vec<(WebhookDeliveryRecord, WebhookRecord)>> {
+        Ok(sql::webhooks::due(&self.pool, limit, claim_until).await?)
+    }
+
+    async fn record_attempt(
+        &self,
+        id: i64,
+        state: DeliveryState,
+        status: Option<u16>,
+        error: Option<String>,
+        next_attempt: Option<DateTime<Utc>>,
+    ) -> Result<()> {
+        Ok(
+            sql::webhooks::record_attempt(&self.pool, id, state, status, error, next_attempt)
+                .await?,
+        )
+    }
+
+    async fn prune_deliveries(&self, finished_before: DateTime<Utc>) -> Result<usize> {
+        Ok(sql::webhooks::prune(&self.pool, finished_before).await?)
+    }
+
+    async fn deliveries(&self, webhook_id: i64, limit: u32) -> Result<Vec<WebhookDeliveryRecord>> {
+        Ok(sql::webhooks::deliveries(&self.pool, webhook_id, limit).await?)
+    }
+}
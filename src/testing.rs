@@ -0,0 +1,96 @@
+// Shared setup for the tests: one config for the whole test binary, and a new
+// sqlite database for every test
+use crate::config::{self, Config};
+use crate::sql::models::{SpordRecord, SpordState};
+use crate::sql::{self, Pool};
+use chrono::Utc;
+use deadpool_postgres::tokio_postgres::{self, NoTls};
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::Once;
+use tempfile::TempDir;
+
+static CONFIG_SET: Once = Once::new();
+static POSTGRES_SCHEMAS: AtomicUsize = AtomicUsize::new(0);
+
+// The config written by --write-config, which the code reads through CONFIG
+pub fn config() -> &'static Config {
+    CONFIG_SET.call_once(|| {
+        let config: Config = toml::from_str(config::TOML_TEMPLATE).unwrap();
+        config::set(config);
+    });
+    config::get()
+}
+
+// A migrated database in a directory that is removed with the returned TempDir
+pub async fn pool() -> (Pool, TempDir) {
+    config();
+    let dir = tempfile::tempdir().unwrap();
+    let pool = sql::open(&dir.path().join("spord-tracker.db"))
+        .await
+        .unwrap();
+    (pool, dir)
+}
+
+// A pending spord in no store, for create
+pub fn spord(customer_name: &str, part: &str) -> SpordRecord {
+    SpordRecord {
+        id: 0,
+        customer_name: customer_name.to_string(),
+        customer_phone: Some("555-0100".to_string()),
+        customer_email: None,
+        part: part.to_string(),
+        vendor: None,
+        state: SpordState::Pending,
+        creation_date: Utc::now(),
+        received_date: None,
+        location: None,
+        tags: vec![],
+        archived: None,
+        anonymized: None,
+    }
+}
+
+// An empty schema in the database at SPORD_TEST_POSTGRES_URL, so tests running
+// at once don't see each other's rows
+pub struct PostgresSchema {
+    pub url: String,
+    name: String,
+    admin: tokio_postgres::Client,
+}
+impl PostgresSchema {
+    // The postgres tests are ignored by default, run them with
+    // cargo test -- --ignored once SPORD_TEST_POSTGRES_URL is set
+    pub async fn create() -> PostgresSchema {
+        config();
+        let url = std::env::var("SPORD_TEST_POSTGRES_URL")
+            .expect("SPORD_TEST_POSTGRES_URL must point at a postgres database");
+        let (admin, connection) = tokio_postgres::connect(&url, NoTls).await.unwrap();
+        tokio::spawn(connection);
+
+        let name = format!(
+            "spord_test_{}_{}",
+            std::process::id(),
+            POSTGRES_SCHEMAS.fetch_add(1, Ordering::Relaxed)
+        );
+        admin
+            .batch_execute(&format!(
+                "DROP SCHEMA IF EXISTS {0} CASCADE; CREATE SCHEMA {0}",
+                name
+            ))
+            .await
+            .unwrap();
+        let separator = if url.contains('?') { '&' } else { '?' };
+        let url = format!("{}{}options=-csearch_path%3D{}", url, separator, name);
+
+        PostgresSchema { url, name, admin }
+    }
+
+    // Not done on drop, that can't wait for the database. A failed test leaves
+    // its schema behind, the next run of the same process id replaces it
+    pub async fn remove(self) {
+        self.admin
+            .batch_execute(&format!("DROP SCHEMA {} CASCADE", self.name))
+            .await
+            .unwrap();
+    }
+}
@@ -0,0 +1,1228 @@
+use super::attachments;
+use super::user_logged_in;
+use crate::sql::models::{
+    self, ApiTokenRecord, AttachmentRecord, NoteRecord, SpordRecord, SpordState, TokenScope,
+    WebhookEvent,
+};
+use crate::sql::{LocationFilter, SpordFilter};
+use crate::store::{LocationStore, SpordStore, UserStore, WebhookStore};
+use crate::webhooks;
+use crate::{logging, CONFIG};
+use actix_identity::Identity;
+use actix_multipart::Multipart;
+use actix_web::{
+    dev::Payload,
+    error::{
+        ErrorBadRequest, ErrorConflict, ErrorForbidden, ErrorInternalServerError, ErrorNotFound,
+        ErrorUnauthorized,
+    },
+    http::header::AUTHORIZATION,
+    web, FromRequest, HttpRequest, HttpResponse,
+};
+use chrono::{DateTime, Utc};
+use serde::Deserialize;
+use serde_json::json;
+use std::future::Future;
+use std::pin::Pin;
+
+// Who is calling the api: a bearer token, or a logged in browser session which
+// gets full access
+#[derive(Debug)]
+pub struct ApiUser {
+    // None for service tokens
+    pub username: Option<String>,
+    pub scope: TokenScope,
+    // home store of the user, or the store a service token is limited to
+    pub location: Option<i64>,
+    // only users can be managers, service tokens never are
+    pub manager: bool,
+    // managers, and service tokens created without a store, see every store
+    pub all_locations: bool,
+}
+impl ApiUser {
+    pub(super) async fn load(
+        users: &dyn UserStore,
+        username: String,
+        scope: TokenScope,
+    ) -> actix_web::Result<ApiUser> {
+        let user = users
+            .get(&username)
+            .await
+            .map_err(ErrorInternalServerError)?
+            .filter(|user| user.enabled)
+            .ok_or_else(|| ErrorUnauthorized("Unknown or disabled user"))?;
+
+        Ok(ApiUser {
+            username: Some(username),
+            scope,
+            location: user.location,
+            manager: user.manager,
+            all_locations: user.manager,
+        })
+    }
+
+    // User tokens act as their user. Service tokens only get every store when
+    // they were not limited to one, and nothing else a manager may do
+    async fn from_token(
+        users: &dyn UserStore,
+        token: ApiTokenRecord,
+    ) -> actix_web::Result<ApiUser> {
+        match token.username {
+            Some(username) => ApiUser::load(users, username, token.scope).await,
+            None => Ok(ApiUser {
+                username: None,
+                scope: token.scope,
+                location: token.location,
+                manager: false,
+                all_locations: token.location.is_none(),
+            }),
+        }
+    }
+
+    // Which spords a listing shows. Defaults to the user's own store, only
+    // those seeing every store may ask for another store or "all"
+    fn location_filter(
+        &self,
+        requested: Option<&str>,
+    ) -> actix_web::Result<Option<LocationFilter>> {
+        let filter = match requested {
+            None if self.all_locations && self.username.is_none() => None,
+            None => Some(LocationFilter(self.location)),
+            Some("all") => None,
+            Some(location) => Some(LocationFilter(Some(
+                location.parse().map_err(ErrorBadRequest)?,
+            ))),
+        };
+
+        match filter {
+            Some(LocationFilter(location)) if location == self.location => Ok(filter),
+            _ if self.all_locations => Ok(filter),
+            _ => Err(ErrorForbidden("Only managers can see other stores")),
+        }
+    }
+
+    pub(super) fn can_see(&self, spord: &SpordRecord) -> bool {
+        self.all_locations || spord.location == self.location
+    }
+
+    // Managers and the author, when spords.edit_notes allows changing notes
+    pub(super) fn can_edit_note(&self, note: &NoteRecord) -> bool {
+        CONFIG.spords.edit_notes
+            && (self.manager || (self.username.is_some() && note.username == self.username))
+    }
+
+    pub(super) fn require_note_editable(&self, note: &NoteRecord) -> actix_web::Result<()> {
+        if !CONFIG.spords.edit_notes {
+            Err(ErrorForbidden("Notes can only be added"))
+        } else if !self.can_edit_note(note) {
+            Err(ErrorForbidden(
+                "Only managers and the author can change a note",
+            ))
+        } else {
+            Ok(())
+        }
+    }
+
+    // Managers and whoever uploaded it
+    pub(super) fn can_delete_attachment(&self, attachment: &AttachmentRecord) -> bool {
+        self.manager || (self.username.is_some() && attachment.username == self.username)
+    }
+
+    pub fn name(&self) -> &str {
+        self.username.as_deref().unwrap_or("<service token>")
+    }
+
+    pub(super) fn require_manager(&self) -> actix_web::Result<()> {
+        if self.manager {
+            Ok(())
+        } else {
+            Err(ErrorForbidden("Only managers can do this"))
+        }
+    }
+
+    pub(super) fn require_write(&self) -> actix_web::Result<()> {
+        if self.scope == TokenScope::Write {
+            Ok(())
+        } else {
+            Err(ErrorForbidden("Token is read only"))
+        }
+    }
+}
+
+// Returns the token of an `Authorization: Bearer` header, if there is one
+pub fn bearer_token(request: &HttpRequest) -> Option<String> {
+    request
+        .headers()
+        .get(AUTHORIZATION)?
+        .to_str()
+        .ok()?
+        .strip_prefix("Bearer ")
+        .map(|token| token.trim().to_string())
+}
+
+impl FromRequest for ApiUser {
+    type Error = actix_web::Error;
+    type Future = Pin<Box<dyn Future<Output = Result<Self, Self::Error>>>>;
+
+    fn from_request(req: &HttpRequest, payload: &mut Payload) -> Self::Future {
+        let token = bearer_token(req);
+        let identity = Identity::from_request(req, payload).into_inner().ok();
+        let users = req.app_data::<web::Data<dyn UserStore>>().cloned();
+
+        Box::pin(async move {
+            let users = users.ok_or_else(|| ErrorInternalServerError("No user store"))?;
+
+            // never fall back to the cookie when a token was given
+            if let Some(token) = token {
+                return match users
+                    .authenticate_token(&token)
+                    .await
+                    .map_err(ErrorInternalServerError)?
+                {
+                    Some(record) => ApiUser::from_token(users.get_ref(), record).await,
+                    None => Err(ErrorUnauthorized("Invalid or expired token")),
+                };
+            }
+
+            match user_logged_in(identity) {
+                Some(username) => ApiUser::load(users.get_ref(), username, TokenScope::Write).await,
+                None => Err(ErrorUnauthorized("Unauthorized")),
+            }
+        })
+    }
+}
+
+#[derive(Debug, Deserialize)]
+pub struct SpordInput {
+    pub customer_name: String,
+    pub customer_phone: Option<String>,
+    pub customer_email: Option<String>,
+    pub part: String,
+    pub vendor: Option<String>,
+    pub state: Option<SpordState>,
+    pub received_date: Option<DateTime<Utc>>,
+    // added to the notes of the spord
+    pub note: Option<String>,
+    // replaces the tags, which are left alone when missing
+    pub tags: Option<Vec<String>>,
+    // only used when creating, see /api/spords/{id}/transfer
+    pub location: Option<i64>,
+}
+impl SpordInput {
+    // A new spord in location, or the changes to an existing one. Whatever is
+    // left out keeps its value, an empty string clears an optional field, and
+    // received_date only defaults to now when the spord becomes received
+    fn into_record(self, existing: Option<&SpordRecord>, location: Option<i64>) -> SpordRecord {
+        let optional = |new: Option<String>, old: fn(&SpordRecord) -> &Option<String>| match new {
+            Some(new) if new.trim().is_empty() => None,
+            Some(new) => Some(new),
+            None => existing.and_then(|existing| old(existing).clone()),
+        };
+        let state = self
+            .state
+            .or_else(|| existing.map(|existing| existing.state.clone()))
+            .unwrap_or(SpordState::Pending);
+        let became_received = state == SpordState::Received
+            && existing.is_none_or(|existing| existing.state != SpordState::Received);
+        let received_date = self
+            .received_date
+            .or_else(|| existing.and_then(|existing| existing.received_date))
+            .or_else(|| became_received.then(Utc::now));
+
+        SpordRecord {
+            id: existing.map_or(0, |existing| existing.id),
+            customer_name: self.customer_name,
+            customer_phone: optional(self.customer_phone, |existing| &existing.customer_phone),
+            customer_email: optional(self.customer_email, |existing| &existing.customer_email),
+            part: self.part,
+            vendor: optional(self.vendor, |existing| &existing.vendor),
+            state,
+            creation_date: existing.map_or_else(Utc::now, |existing| existing.creation_date),
+            received_date,
+            location,
+            tags: match self.tags {
+                Some(new_tags) => models::clean_tags(new_tags.iter().map(String::as_str)),
+                None => existing.map_or_else(Vec::new, |existing| existing.tags.clone()),
+            },
+            archived: existing.and_then(|existing| existing.archived),
+            anonymized: existing.and_then(|existing| existing.anonymized),
+        }
+    }
+}
+
+// The text of a new or changed note, which may not be blank
+pub(super) fn note_text(text: &str) -> actix_web::Result<String> {
+    match text.trim() {
+        "" => Err(ErrorBadRequest("A note needs some text")),
+        text => Ok(text.to_string()),
+    }
+}
+
+// Fetches a spord the user is allowed to see
+pub(super) async fn visible_spord(
+    spords: &dyn SpordStore,
+    user: &ApiUser,
+    id: i32,
+) -> actix_web::Result<SpordRecord> {
+    spords
+        .get(id)
+        .await
+        .map_err(ErrorInternalServerError)?
+        .filter(|spord| user.can_see(spord))
+        .ok_or_else(|| ErrorNotFound("No such spord"))
+}
+
+#[derive(Debug, Deserialize)]
+pub struct SpordListQuery {
+    // a location id, or "all"
+    pub location: Option<String>,
+    pub state: Option<SpordState>,
+    // text to look for in customer details and part
+    pub q: Option<String>,
+    // comma separated, spords having all of them
+    pub tags: Option<String>,
+    // archived spords instead of the active ones
+    #[serde(default)]
+    pub archived: bool,
+}
+
+#[get("/api/spords")]
+pub async fn spords_list(
+    spords: web::Data<dyn SpordStore>,
+    user: ApiUser,
+    query: web::Query<SpordListQuery>,
+) -> actix_web::Result<HttpResponse> {
+    let query = query.into_inner();
+    let filter = SpordFilter {
+        location: user.location_filter(query.location.as_deref())?,
+        state: query.state,
+        search: query.q.filter(|q| !q.is_empty()),
+        tags: models::parse_tags(query.tags.as_deref().unwrap_or_default()),
+        archived: query.archived,
+    };
+    let spords = spords
+        .list(filter)
+        .await
+        .map_err(ErrorInternalServerError)?;
+
+    Ok(HttpResponse::Ok().json(spords))
+}
+
+#[get("/api/spords/{id}")]
+pub async fn spords_get(
+    spords: web::Data<dyn SpordStore>,
+    user: ApiUser,
+    id: web::Path<i32>,
+) -> actix_web::Result<HttpResponse> {
+    let spord = visible_spord(spords.get_ref(), &user, *id).await?;
+
+    Ok(HttpResponse::Ok().json(spord))
+}
+
+#[post("/api/spords")]
+pub async fn spords_create(
+    webhooks: web::Data<dyn WebhookStore>,
+    spords: web::Data<dyn SpordStore>,
+    user: ApiUser,
+    input: web::Json<SpordInput>,
+) -> actix_web::Result<HttpResponse> {
+    user.require_write()?;
+
+    let input = input.into_inner();
+    let location = if user.all_locations {
+        input.location.or(user.location)
+    } else {
+        user.location
+    };
+    let note = input.note.as_deref().map(note_text).transpose()?;
+
+    let mut spord = input.into_record(None, location);
+    let id = spords
+        .create(spord.clone())
+        .await
+        .map_err(ErrorInternalServerError)?;
+    spord.id = id as i32;
+    if let Some(note) = note {
+        spords
+            .add_note(spord.id, user.username.clone(), note)
+            .await
+            .map_err(ErrorInternalServerError)?;
+    }
+    info!(
+        username = user.name(), spord_id = spord.id;
+        "{} created spord {} via api",
+        user.name(),
+        spord.id
+    );
+    webhooks::notify(
+        webhooks.get_ref(),
+        WebhookEvent::Created,
+        &spord,
+        user.username.as_deref(),
+        None,
+    )
+    .await;
+
+    Ok(HttpResponse::Created().json(spord))
+}
+
+#[put("/api/spords/{id}")]
+pub async fn spords_update(
+    webhooks: web::Data<dyn WebhookStore>,
+    spords: web::Data<dyn SpordStore>,
+    user: ApiUser,
+    id: web::Path<i32>,
+    input: web::Json<SpordInput>,
+) -> actix_web::Result<HttpResponse> {
+    user.require_write()?;
+
+    let existing = visible_spord(spords.get_ref(), &user, *id).await?;
+    let note = input.note.as_deref().map(note_text).transpose()?;
+
+    let spord = input
+        .into_inner()
+        .into_record(Some(&existing), existing.location);
+    spords
+        .update(spord.clone(), user.username.clone())
+        .await
+        .map_err(ErrorInternalServerError)?;
+    if let Some(note) = note {
+        spords
+            .add_note(spord.id, user.username.clone(), note)
+            .await
+            .map_err(ErrorInternalServerError)?;
+    }
+    info!(
+        username = user.name(), spord_id = spord.id;
+        "{} updated spord {} via api",
+        user.name(),
+        spord.id
+    );
+    if spord.state != existing.state {
+        webhooks::notify(
+            webhooks.get_ref(),
+            WebhookEvent::StateChanged,
+            &spord,
+            user.username.as_deref(),
+            Some(json!({ "state": existing.state })),
+        )
+        .await;
+    }
+
+    Ok(HttpResponse::Ok().json(spord))
+}
+
+// Archives the spord, nothing is deleted. Managers can restore it
+#[delete("/api/spords/{id}")]
+pub async fn spords_archive(
+    webhooks: web::Data<dyn WebhookStore>,
+    spords: web::Data<dyn SpordStore>,
+    user: ApiUser,
+    id: web::Path<i32>,
+) -> actix_web::Result<HttpResponse> {
+    user.require_write()?;
+    visible_spord(spords.get_ref(), &user, *id).await?;
+
+    if !spords
+        .archive(*id, user.username.clone())
+        .await
+        .map_err(ErrorInternalServerError)?
+    {
+        return Err(ErrorConflict("Spord is already archived"));
+    }
+    info!(
+        username = user.name(), spord_id = *id;
+        "{} archived spord {}",
+        user.name(),
+        id
+    );
+
+    let spord = spords
+        .get(*id)
+        .await
+        .map_err(ErrorInternalServerError)?
+        .ok_or_else(|| ErrorNotFound("No such spord"))?;
+    webhooks::notify(
+        webhooks.get_ref(),
+        WebhookEvent::Archived,
+        &spord,
+        user.username.as_deref(),
+        None,
+    )
+    .await;
+
+    Ok(HttpResponse::NoContent().finish())
+}
+
+#[post("/api/spords/{id}/restore")]
+pub async fn spords_restore(
+    spords: web::Data<dyn SpordStore>,
+    user: ApiUser,
+    id: web::Path<i32>,
+) -> actix_web::Result<HttpResponse> {
+    user.require_write()?;
+    user.require_manager()?;
+    visible_spord(spords.get_ref(), &user, *id).await?;
+
+    if !spords
+        .restore(*id, user.username.clone())
+        .await
+        .map_err(ErrorInternalServerError)?
+    {
+        return Err(ErrorConflict("Spord is not archived"));
+    }
+    info!(
+        username = user.name(), spord_id = *id;
+        "{} restored spord {}",
+        user.name(),
+        id
+    );
+
+    Ok(HttpResponse::Ok().json(visible_spord(spords.get_ref(), &user, *id).await?))
+}
+
+#[derive(Debug, Deserialize)]
+pub struct TransferInput {
+    pub location: i64,
+}
+
+#[post("/api/spords/{id}/transfer")]
+pub async fn spords_transfer(
+    webhooks: web::Data<dyn WebhookStore>,
+    spords: web::Data<dyn SpordStore>,
+    locations: web::Data<dyn LocationStore>,
+    user: ApiUser,
+    id: web::Path<i32>,
+    input: web::Json<TransferInput>,
+) -> actix_web::Result<HttpResponse> {
+    user.require_write()?;
+    let existing = visible_spord(spords.get_ref(), &user, *id).await?;
+
+    let location = locations
+        .get(input.location)
+        .await
+        .map_err(ErrorInternalServerError)?
+        .ok_or_else(|| ErrorBadRequest("No such location"))?;
+
+    spords
+        .transfer(*id, location.id, user.username.clone())
+        .await
+        .map_err(ErrorInternalServerError)?;
+    info!(
+        username = user.name(), spord_id = *id;
+        "{} transferred spord {} to {}",
+        user.name(),
+        id,
+        location.name
+    );
+
+    let spord = spords
+        .get(*id)
+        .await
+        .map_err(ErrorInternalServerError)?
+        .ok_or_else(|| ErrorNotFound("No such spord"))?;
+    webhooks::notify(
+        webhooks.get_ref(),
+        WebhookEvent::Transferred,
+        &spord,
+        user.username.as_deref(),
+        Some(json!({ "location": existing.location })),
+    )
+    .await;
+
+    Ok(HttpResponse::Ok().json(spord))
+}
+
+#[get("/api/spords/{id}/events")]
+pub async fn spords_events(
+    spords: web::Data<dyn SpordStore>,
+    user: ApiUser,
+    id: web::Path<i32>,
+) -> actix_web::Result<HttpResponse> {
+    visible_spord(spords.get_ref(), &user, *id).await?;
+
+    let events = spords.events(*id).await.map_err(ErrorInternalServerError)?;
+
+    Ok(HttpResponse::Ok().json(events))
+}
+
+#[get("/api/spords/{id}/notes")]
+pub async fn notes_list(
+    spords: web::Data<dyn SpordStore>,
+    user: ApiUser,
+    id: web::Path<i32>,
+) -> actix_web::Result<HttpResponse> {
+    visible_spord(spords.get_ref(), &user, *id).await?;
+
+    let notes = spords.notes(*id).await.map_err(ErrorInternalServerError)?;
+
+    Ok(HttpResponse::Ok().json(notes))
+}
+
+#[derive(Debug, Deserialize)]
+pub struct NoteInput {
+    pub text: String,
+}
+
+// The note with this id, after it was added or changed
+pub(super) async fn find_note(
+    spords: &dyn SpordStore,
+    spord_id: i32,
+    id: i64,
+) -> actix_web::Result<NoteRecord> {
+    spords
+        .notes(spord_id)
+        .await
+        .map_err(ErrorInternalServerError)?
+        .into_iter()
+        .find(|note| note.id == id)
+        .ok_or_else(|| ErrorNotFound("No such note"))
+}
+
+#[post("/api/spords/{id}/notes")]
+pub async fn notes_add(
+    spords: web::Data<dyn SpordStore>,
+    user: ApiUser,
+    id: web::Path<i32>,
+    input: web::Json<NoteInput>,
+) -> actix_web::Result<HttpResponse> {
+    user.require_write()?;
+    visible_spord(spords.get_ref(), &user, *id).await?;
+    let text = note_text(&input.text)?;
+
+    let note_id = spords
+        .add_note(*id, user.username.clone(), text)
+        .await
+        .map_err(ErrorInternalServerError)?;
+    info!(
+        username = user.name(), spord_id = *id, note_id = note_id;
+        "{} added note {} to spord {}",
+        user.name(),
+        note_id,
+        id
+    );
+
+    Ok(HttpResponse::Created().json(find_note(spords.get_ref(), *id, note_id).await?))
+}
+
+#[put("/api/spords/{id}/notes/{note_id}")]
+pub async fn notes_edit(
+    spords: web::Data<dyn SpordStore>,
+    user: ApiUser,
+    path: web::Path<(i32, i64)>,
+    input: web::Json<NoteInput>,
+) -> actix_web::Result<HttpResponse> {
+    user.require_write()?;
+    let (id, note_id) = path.into_inner();
+    visible_spord(spords.get_ref(), &user, id).await?;
+    let note = find_note(spords.get_ref(), id, note_id).await?;
+    user.require_note_editable(&note)?;
+    let text = note_text(&input.text)?;
+
+    spords
+        .edit_note(id, note.id, user.username.clone(), text)
+        .await
+        .map_err(ErrorInternalServerError)?;
+    info!(
+        username = user.name(), spord_id = id, note_id = note.id;
+        "{} changed note {} of spord {}",
+        user.name(),
+        note.id,
+        id
+    );
+
+    Ok(HttpResponse::Ok().json(find_note(spords.get_ref(), id, note.id).await?))
+}
+
+#[get("/api/spords/{id}/attachments")]
+pub async fn attachments_list(
+    spords: web::Data<dyn SpordStore>,
+    user: ApiUser,
+    id: web::Path<i32>,
+) -> actix_web::Result<HttpResponse> {
+    visible_spord(spords.get_ref(), &user, *id).await?;
+
+    let attachments = spords
+        .attachments(*id)
+        .await
+        .map_err(ErrorInternalServerError)?;
+
+    Ok(HttpResponse::Ok().json(attachments))
+}
+
+// multipart/form-data, every file field becomes an attachment
+#[post("/api/spords/{id}/attachments")]
+pub async fn attachments_upload(
+    spords: web::Data<dyn SpordStore>,
+    user: ApiUser,
+    id: web::Path<i32>,
+    multipart: Multipart,
+) -> actix_web::Result<HttpResponse> {
+    user.require_write()?;
+    visible_spord(spords.get_ref(), &user, *id).await?;
+
+    let stored =
+        attachments::receive(spords.get_ref(), *id, multipart, user.username.clone()).await?;
+    for attachment in &stored {
+        info!(
+            username = user.name(), spord_id = *id, attachment_id = attachment.id;
+            "{} attached {} to spord {}",
+            user.name(),
+            attachment.filename,
+            id
+        );
+    }
+
+    Ok(HttpResponse::Created().json(stored))
+}
+
+async fn visible_attachment(
+    spords: &dyn SpordStore,
+    user: &ApiUser,
+    spord_id: i32,
+    attachment_id: i64,
+) -> actix_web::Result<AttachmentRecord> {
+    visible_spord(spords, user, spord_id).await?;
+
+    spords
+        .attachment(spord_id, attachment_id)
+        .await
+        .map_err(ErrorInternalServerError)?
+        .ok_or_else(|| ErrorNotFound("No such attachment"))
+}
+
+#[get("/api/spords/{id}/attachments/{attachment_id}")]
+pub async fn attachments_download(
+    spords: web::Data<dyn SpordStore>,
+    user: ApiUser,
+    path: web::Path<(i32, i64)>,
+) -> actix_web::Result<HttpResponse> {
+    let (id, attachment_id) = path.into_inner();
+    let attachment = visible_attachment(spords.get_ref(), &user, id, attachment_id).await?;
+
+    attachments::download(&attachment).await
+}
+
+#[delete("/api/spords/{id}/attachments/{attachment_id}")]
+pub async fn attachments_delete(
+    spords: web::Data<dyn SpordStore>,
+    user: ApiUser,
+    path: web::Path<(i32, i64)>,
+) -> actix_web::Result<HttpResponse> {
+    user.require_write()?;
+    let (id, attachment_id) = path.into_inner();
+    let attachment = visible_attachment(spords.get_ref(), &user, id, attachment_id).await?;
+    if !user.can_delete_attachment(&attachment) {
+        return Err(ErrorForbidden(
+            "Only managers and the uploader can delete an attachment",
+        ));
+    }
+
+    crate::attachments::delete(spords.get_ref(), &attachment)
+        .await
+        .map_err(ErrorInternalServerError)?;
+    info!(
+        username = user.name(), spord_id = id, attachment_id = attachment.id;
+        "{} deleted attachment {} of spord {}",
+        user.name(),
+        attachment.filename,
+        id
+    );
+
+    Ok(HttpResponse::NoContent().finish())
+}
+
+#[get("/api/tags")]
+pub async fn tags_list(
+    spords: web::Data<dyn SpordStore>,
+    _user: ApiUser,
+) -> actix_web::Result<HttpResponse> {
+    let tags = spords.tags().await.map_err(ErrorInternalServerError)?;
+
+    Ok(HttpResponse::Ok().json(tags))
+}
+
+#[get("/api/locations")]
+pub async fn locations_list(
+    locations: web::Data<dyn LocationStore>,
+    _user: ApiUser,
+) -> actix_web::Result<HttpResponse> {
+    let locations = locations.list().await.map_err(ErrorInternalServerError)?;
+
+    Ok(HttpResponse::Ok().json(locations))
+}
+
+#[derive(Debug, Deserialize)]
+pub struct LogSpecInput {
+    // e.g. "info, spord_tracker::sql=debug"
+    pub spec: String,
+}
+
+#[get("/api/admin/log")]
+pub async fn log_spec_get(user: ApiUser) -> actix_web::Result<HttpResponse> {
+    user.require_manager()?;
+
+    Ok(HttpResponse::Ok().json(json!({
+        "spec": logging::current_spec(),
+        "configured": CONFIG.log.level,
+    })))
+}
+
+// Changes the log level until the next restart
+#[put("/api/admin/log")]
+pub async fn log_spec_set(
+    user: ApiUser,
+    input: web::Json<LogSpecInput>,
+) -> actix_web::Result<HttpResponse> {
+    user.require_manager()?;
+    user.require_write()?;
+
+    logging::set_spec(&input.spec).map_err(ErrorBadRequest)?;
+    info!(
+        username = user.name();
+        "{} set the log spec to \"{}\"",
+        user.name(),
+        input.spec
+    );
+
+    Ok(HttpResponse::Ok().json(json!({ "spec": logging::current_spec() })))
+}
+
+// Goes back to log.level from the config
+#[delete("/api/admin/log")]
+pub async fn log_spec_reset(user: ApiUser) -> actix_web::Result<HttpResponse> {
+    user.require_manager()?;
+    user.require_write()?;
+
+    logging::set_spec(&CONFIG.log.level).map_err(ErrorInternalServerError)?;
+    info!(
+        username = user.name();
+        "{} reset the log spec to \"{}\"",
+        user.name(),
+        CONFIG.log.level
+    );
+
+    Ok(HttpResponse::Ok().json(json!({ "spec": logging::current_spec() })))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::super::session;
+    use super::*;
+    use crate::sql::models::UserRecord;
+    use crate::store::memory::MemoryStore;
+    use crate::testing;
+    use actix_web::cookie::Key;
+    use actix_web::http::StatusCode;
+    use actix_web::{test, App};
+    use std::sync::Arc;
+
+    // Spords, users, tokens, stores and webhooks in memory
+    struct Setup {
+        spords: web::Data<dyn SpordStore>,
+        users: web::Data<dyn UserStore>,
+        locations: web::Data<dyn LocationStore>,
+        webhooks: web::Data<dyn WebhookStore>,
+        north: i64,
+        south: i64,
+    }
+    impl Setup {
+        // alice manages every store, bob works in north and carol in south
+        async fn new() -> Setup {
+            testing::config();
+            let store = Arc::new(MemoryStore::new());
+            let north = LocationStore::create(store.as_ref(), "North")
+                .await
+                .unwrap();
+            let south = LocationStore::create(store.as_ref(), "South")
+                .await
+                .unwrap();
+            for (username, location, manager) in [
+                ("alice", None, true),
+                ("bob", Some(north), false),
+                ("carol", Some(south), false),
+            ] {
+                let user = UserRecord {
+                    username: username.to_string(),
+                    enabled: true,
+                    location,
+                    manager,
+                };
+                store.insert_user(user, "password").unwrap();
+            }
+
+            Setup {
+                spords: web::Data::from(store.clone() as Arc<dyn SpordStore>),
+                users: web::Data::from(store.clone() as Arc<dyn UserStore>),
+                locations: web::Data::from(store.clone() as Arc<dyn LocationStore>),
+                webhooks: web::Data::from(store as Arc<dyn WebhookStore>),
+                north,
+                south,
+            }
+        }
+
+        async fn token(&self, username: &str, scope: TokenScope) -> String {
+            self.users
+                .create_token("test", Some(username), scope, None, None)
+                .await
+                .unwrap()
+                .1
+        }
+    }
+
+    macro_rules! app {
+        ($setup:expr) => {
+            test::init_service(
+                App::new()
+                    .app_data($setup.spords.clone())
+                    .app_data($setup.users.clone())
+                    .app_data($setup.locations.clone())
+                    .app_data($setup.webhooks.clone())
+                    .wrap(session::identity_middleware())
+                    .wrap(session::session_middleware(
+                        Key::generate(),
+                        $setup.users.clone().into_inner(),
+                    ))
+                    .service(spords_list)
+                    .service(spords_get)
+                    .service(spords_create)
+                    .service(spords_update)
+                    .service(spords_archive)
+                    .service(spords_restore)
+                    .service(spords_transfer),
+            )
+            .await
+        };
+    }
+
+    fn request(method: &str, uri: &str, token: &str) -> test::TestRequest {
+        let request = match method {
+            "POST" => test::TestRequest::post(),
+            "PUT" => test::TestRequest::put(),
+            "DELETE" => test::TestRequest::delete(),
+            _ => test::TestRequest::get(),
+        };
+        request
+            .uri(uri)
+            .insert_header((AUTHORIZATION, format!("Bearer {}", token)))
+    }
+
+    fn ids(spords: &[SpordRecord]) -> Vec<i32> {
+        spords.iter().map(|spord| spord.id).collect()
+    }
+
+    #[actix_web::test]
+    async fn requires_a_valid_token() {
+        let setup = Setup::new().await;
+        let app = app!(setup);
+
+        let response = test::call_service(
+            &app,
+            test::TestRequest::get().uri("/api/spords").to_request(),
+        )
+        .await;
+        assert_eq!(response.status(), StatusCode::UNAUTHORIZED);
+
+        let response =
+            test::call_service(&app, request("GET", "/api/spords", "spt_nope").to_request()).await;
+        assert_eq!(response.status(), StatusCode::UNAUTHORIZED);
+    }
+
+    #[actix_web::test]
+    async fn read_tokens_cannot_create() {
+        let setup = Setup::new().await;
+        let app = app!(setup);
+        let token = setup.token("bob", TokenScope::Read).await;
+
+        let response = test::call_service(
+            &app,
+            request("POST", "/api/spords", &token)
+                .set_json(json!({"customer_name": "Ann", "part": "TRA123"}))
+                .to_request(),
+        )
+        .await;
+        assert_eq!(response.status(), StatusCode::FORBIDDEN);
+    }
+
+    #[actix_web::test]
+    async fn spords_stay_in_their_store() {
+        let setup = Setup::new().await;
+        let app = app!(setup);
+        let bob = setup.token("bob", TokenScope::Write).await;
+        let carol = setup.token("carol", TokenScope::Write).await;
+        let alice = setup.token("alice", TokenScope::Write).await;
+
+        // clerks create in their own store, whatever they ask for
+        let spord: SpordRecord = test::call_and_read_body_json(
+            &app,
+            request("POST", "/api/spords", &bob)
+                .set_json(json!({
+                    "customer_name": "Ann",
+                    "part": "TRA123",
+                    "location": setup.south,
+                }))
+                .to_request(),
+        )
+        .await;
+        assert_eq!(spord.location, Some(setup.north));
+        assert_eq!(spord.state, SpordState::Pending);
+
+        let uri = format!("/api/spords/{}", spord.id);
+        let response = test::call_service(&app, request("GET", &uri, &bob).to_request()).await;
+        assert_eq!(response.status(), StatusCode::OK);
+        let response = test::call_service(&app, request("GET", &uri, &carol).to_request()).await;
+        assert_eq!(response.status(), StatusCode::NOT_FOUND);
+
+        let listed: Vec<SpordRecord> =
+            test::call_and_read_body_json(&app, request("GET", "/api/spords", &carol).to_request())
+                .await;
+        assert!(listed.is_empty());
+        let response = test::call_service(
+            &app,
+            request(
+                "GET",
+                &format!("/api/spords?location={}", setup.north),
+                &carol,
+            )
+            .to_request(),
+        )
+        .await;
+        assert_eq!(response.status(), StatusCode::FORBIDDEN);
+
+        let listed: Vec<SpordRecord> = test::call_and_read_body_json(
+            &app,
+            request("GET", "/api/spords?location=all", &alice).to_request(),
+        )
+        .await;
+        assert_eq!(ids(&listed), [spord.id]);
+    }
+
+    #[actix_web::test]
+    async fn updates_keep_what_is_left_out() {
+        let setup = Setup::new().await;
+        let app = app!(setup);
+        let bob = setup.token("bob", TokenScope::Write).await;
+
+        let spord: SpordRecord = test::call_and_read_body_json(
+            &app,
+            request("POST", "/api/spords", &bob)
+                .set_json(json!({
+                    "customer_name": "Ann",
+                    "customer_phone": "555-0100",
+                    "customer_email": "ann@example.com",
+                    "part": "TRA123",
+                    "vendor": "Trek",
+                    "state": "Ordered"
+                }))
+                .to_request(),
+        )
+        .await;
+        let uri = format!("/api/spords/{}", spord.id);
+        let put = |body: serde_json::Value| request("PUT", &uri, &bob).set_json(body).to_request();
+
+        let updated: SpordRecord = test::call_and_read_body_json(
+            &app,
+            put(json!({"customer_name": "Ann", "part": "TRA124"})),
+        )
+        .await;
+        assert_eq!(updated.state, SpordState::Ordered);
+        assert_eq!(updated.part, "TRA124");
+        assert_eq!(updated.received_date, None);
+        assert_eq!(updated.customer_phone.as_deref(), Some("555-0100"));
+        assert_eq!(updated.customer_email.as_deref(), Some("ann@example.com"));
+        assert_eq!(updated.vendor.as_deref(), Some("Trek"));
+
+        // becoming received sets the date once
+        let received: SpordRecord = test::call_and_read_body_json(
+            &app,
+            put(json!({"customer_name": "Ann", "part": "TRA124", "state": "Received"})),
+        )
+        .await;
+        assert!(received.received_date.is_some());
+        for body in [
+            json!({"customer_name": "Ann", "part": "TRA124", "state": "Received"}),
+            json!({"customer_name": "Ann B", "part": "TRA124"}),
+        ] {
+            let updated: SpordRecord = test::call_and_read_body_json(&app, put(body)).await;
+            assert_eq!(updated.state, SpordState::Received);
+            assert_eq!(updated.received_date, received.received_date);
+        }
+        let stored = setup.spords.get(spord.id).await.unwrap().unwrap();
+        assert_eq!(stored.customer_name, "Ann B");
+        assert_eq!(stored.state, SpordState::Received);
+        assert_eq!(stored.received_date, received.received_date);
+        assert_eq!(stored.customer_phone.as_deref(), Some("555-0100"));
+        assert_eq!(stored.vendor.as_deref(), Some("Trek"));
+
+        // an empty string clears an optional field
+        let cleared: SpordRecord = test::call_and_read_body_json(
+            &app,
+            put(json!({"customer_name": "Ann B", "part": "TRA124", "vendor": ""})),
+        )
+        .await;
+        assert_eq!(cleared.vendor, None);
+        assert_eq!(cleared.customer_email.as_deref(), Some("ann@example.com"));
+
+        // only the one real change of state is in the history
+        let states: Vec<_> = setup
+            .spords
+            .events(spord.id)
+            .await
+            .unwrap()
+            .into_iter()
+            .map(|event| (event.old_value, event.new_value))
+            .collect();
+        assert_eq!(
+            states,
+            [(
+                Some(SpordState::Ordered.as_sql() as i64),
+                Some(SpordState::Received.as_sql() as i64)
+            )]
+        );
+    }
+
+    #[actix_web::test]
+    async fn service_tokens_limited_to_a_store() {
+        let setup = Setup::new().await;
+        let app = app!(setup);
+        let bob = setup.token("bob", TokenScope::Write).await;
+        let carol = setup.token("carol", TokenScope::Write).await;
+        let (_, south) = setup
+            .users
+            .create_token("south", None, TokenScope::Read, None, Some(setup.south))
+            .await
+            .unwrap();
+        let (_, every_store) = setup
+            .users
+            .create_token("all", None, TokenScope::Write, None, None)
+            .await
+            .unwrap();
+
+        let mut created = vec![];
+        for token in [&bob, &carol] {
+            let spord: SpordRecord = test::call_and_read_body_json(
+                &app,
+                request("POST", "/api/spords", token)
+                    .set_json(json!({"customer_name": "Ann", "part": "TRA123"}))
+                    .to_request(),
+            )
+            .await;
+            created.push(spord.id);
+        }
+
+        let listed: Vec<SpordRecord> =
+            test::call_and_read_body_json(&app, request("GET", "/api/spords", &south).to_request())
+                .await;
+        assert_eq!(ids(&listed), [created[1]]);
+        let response = test::call_service(
+            &app,
+            request("GET", "/api/spords?location=all", &south).to_request(),
+        )
+        .await;
+        assert_eq!(response.status(), StatusCode::FORBIDDEN);
+
+        let listed: Vec<SpordRecord> = test::call_and_read_body_json(
+            &app,
+            request("GET", "/api/spords", &every_store).to_request(),
+        )
+        .await;
+        assert_eq!(ids(&listed), created);
+
+        // seeing every store doesn't make it a manager
+        let uri = format!("/api/spords/{}", created[0]);
+        let response = test::call_service(&app, request("DELETE", &uri, &bob).to_request()).await;
+        assert_eq!(response.status(), StatusCode::NO_CONTENT);
+        let response = test::call_service(
+            &app,
+            request("POST", &format!("{}/restore", uri), &every_store).to_request(),
+        )
+        .await;
+        assert_eq!(response.status(), StatusCode::FORBIDDEN);
+    }
+
+    #[actix_web::test]
+    async fn archived_spords_are_hidden_until_restored() {
+        let setup = Setup::new().await;
+        let app = app!(setup);
+        let bob = setup.token("bob", TokenScope::Write).await;
+        let alice = setup.token("alice", TokenScope::Write).await;
+        let webhook_id = setup
+            .webhooks
+            .create(
+                "http://127.0.0.1:1/hook",
+                "s3cret",
+                &[WebhookEvent::Archived],
+            )
+            .await
+            .unwrap();
+
+        let spord: SpordRecord = test::call_and_read_body_json(
+            &app,
+            request("POST", "/api/spords", &bob)
+                .set_json(json!({"customer_name": "Ann", "part": "TRA123"}))
+                .to_request(),
+        )
+        .await;
+        let uri = format!("/api/spords/{}", spord.id);
+        let restore_uri = format!("/api/spords/{}/restore", spord.id);
+
+        let response = test::call_service(&app, request("DELETE", &uri, &bob).to_request()).await;
+        assert_eq!(response.status(), StatusCode::NO_CONTENT);
+        let response = test::call_service(&app, request("DELETE", &uri, &bob).to_request()).await;
+        assert_eq!(response.status(), StatusCode::CONFLICT);
+
+        let listed: Vec<SpordRecord> =
+            test::call_and_read_body_json(&app, request("GET", "/api/spords", &bob).to_request())
+                .await;
+        assert!(listed.is_empty());
+        let listed: Vec<SpordRecord> = test::call_and_read_body_json(
+            &app,
+            request("GET", "/api/spords?archived=true", &bob).to_request(),
+        )
+        .await;
+        assert_eq!(ids(&listed), [spord.id]);
+        assert!(listed[0].archived.is_some());
+
+        // only managers restore
+        let response =
+            test::call_service(&app, request("POST", &restore_uri, &bob).to_request()).await;
+        assert_eq!(response.status(), StatusCode::FORBIDDEN);
+        let restored: SpordRecord =
+            test::call_and_read_body_json(&app, request("POST", &restore_uri, &alice).to_request())
+                .await;
+        assert_eq!(restored.archived, None);
+        let response =
+            test::call_service(&app, request("POST", &restore_uri, &alice).to_request()).await;
+        assert_eq!(response.status(), StatusCode::CONFLICT);
+
+        let listed: Vec<SpordRecord> =
+            test::call_and_read_body_json(&app, request("GET", "/api/spords", &bob).to_request())
+                .await;
+        assert_eq!(ids(&listed), [spord.id]);
+
+        let kinds: Vec<_> = setup
+            .spords
+            .events(spord.id)
+            .await
+            .unwrap()
+            .into_iter()
+            .map(|event| (event.kind, event.username))
+            .collect();
+        assert_eq!(
+            kinds,
+            [
+                (models::SpordEventKind::Archive, Some("bob".to_string())),
+                (models::SpordEventKind::Restore, Some("alice".to_string())),
+            ]
+        );
+
+        // one archived event, the conflicting second archive sends nothing
+        let deliveries = setup.webhooks.deliveries(webhook_id, 10).await.unwrap();
+        assert_eq!(deliveries.len(), 1);
+        assert_eq!(deliveries[0].event, "archived");
+        let payload: serde_json::Value = serde_json::from_str(&deliveries[0].payload).unwrap();
+        assert_eq!(payload["username"], "bob");
+        assert_eq!(payload["spord"]["id"], spord.id);
+        assert!(!payload["spord"]["archived"].is_null());
+    }
+}
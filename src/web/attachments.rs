@@ -0,0 +1,101 @@
+use crate::attachments::{self, AttachmentError, IncomingFile};
+use crate::sql::models::AttachmentRecord;
+use crate::store::SpordStore;
+use actix_multipart::Multipart;
+use actix_web::{
+    error::{
+        ErrorBadRequest, ErrorInternalServerError, ErrorPayloadTooLarge, ErrorUnsupportedMediaType,
+    },
+    http::header::{ContentDisposition, DispositionParam, DispositionType},
+    HttpResponse,
+};
+use futures_util::TryStreamExt;
+
+fn upload_error(error: AttachmentError) -> actix_web::Error {
+    match error {
+        AttachmentError::TooLarge(_) | AttachmentError::SpordFull(_) => {
+            ErrorPayloadTooLarge(error.to_string())
+        }
+        AttachmentError::TypeNotAllowed(_) => ErrorUnsupportedMediaType(error.to_string()),
+        _ => ErrorInternalServerError(error),
+    }
+}
+
+// Attaches every file field of a multipart upload to the spord, other fields
+// are skipped. When one file fails the ones stored before it are removed again
+pub async fn receive(
+    spords: &dyn SpordStore,
+    spord_id: i32,
+    multipart: Multipart,
+    username: Option<String>,
+) -> actix_web::Result<Vec<AttachmentRecord>> {
+    let mut stored = vec![];
+    let result = receive_into(spords, spord_id, multipart, username, &mut stored).await;
+
+    if let Err(e) = result {
+        for attachment in &stored {
+            if let Err(e) = attachments::delete(spords, attachment).await {
+                error!("Could not remove attachment {}: {}", attachment.id, e);
+            }
+        }
+        return Err(e);
+    }
+    if stored.is_empty() {
+        return Err(ErrorBadRequest("No file in the upload"));
+    }
+    Ok(stored)
+}
+
+async fn receive_into(
+    spords: &dyn SpordStore,
+    spord_id: i32,
+    mut multipart: Multipart,
+    username: Option<String>,
+    stored: &mut Vec<AttachmentRecord>,
+) -> actix_web::Result<()> {
+    while let Some(mut field) = multipart.try_next().await? {
+        let filename = field
+            .content_disposition()
+            .and_then(|disposition| disposition.get_filename())
+            .map(|filename| filename.to_string());
+        let Some(filename) = filename else {
+            while field.try_next().await?.is_some() {}
+            continue;
+        };
+
+        let mut incoming = IncomingFile::create().await.map_err(upload_error)?;
+        while let Some(chunk) = field.try_next().await? {
+            incoming.write(&chunk).await.map_err(upload_error)?;
+        }
+        let attachment =
+            attachments::store(spords, spord_id, &filename, incoming, username.clone())
+                .await
+                .map_err(upload_error)?;
+        stored.push(attachment);
+    }
+
+    Ok(())
+}
+
+// Images and pdfs open in the browser, anything else is downloaded. The
+// sniffed type is sent with nosniff so browsers don't second guess it
+pub async fn download(attachment: &AttachmentRecord) -> actix_web::Result<HttpResponse> {
+    let content = attachments::read(attachment)
+        .await
+        .map_err(ErrorInternalServerError)?;
+    let inline =
+        attachment.mime_type.starts_with("image/") || attachment.mime_type == "application/pdf";
+
+    Ok(HttpResponse::Ok()
+        .content_type(attachment.mime_type.as_str())
+        .insert_header(ContentDisposition {
+            disposition: if inline {
+                DispositionType::Inline
+            } else {
+                DispositionType::Attachment
+            },
+            parameters: vec![DispositionParam::Filename(attachment.filename.clone())],
+        })
+        .insert_header(("x-content-type-options", "nosniff"))
+        .body(content))
+}
@@ -0,0 +1,187 @@
+use super::api::bearer_token;
+use actix_session::{Session, SessionExt};
+use actix_web::{
+    body::MessageBody,
+    dev::{ServiceRequest, ServiceResponse},
+    error::ErrorForbidden,
+    http::{header::CONTENT_TYPE, Method},
+    middleware::Next,
+    web::Bytes,
+    Error,
+};
+use rand::distributions::{Alphanumeric, DistString};
+use serde::Deserialize;
+
+const SESSION_KEY: &str = "csrf_token";
+pub const FIELD_NAME: &str = "csrf_token";
+pub const HEADER_NAME: &str = "X-CSRF-Token";
+
+// Returns the session's csrf token, creating one if the session has none yet
+pub fn session_token(session: &Session) -> String {
+    if let Ok(Some(token)) = session.get::<String>(SESSION_KEY) {
+        return token;
+    }
+
+    let token = Alphanumeric.sample_string(&mut rand::thread_rng(), 32);
+    if let Err(e) = session.insert(SESSION_KEY, &token) {
+        warn!("Could not store csrf token in session: {}", e);
+    }
+    token
+}
+
+#[derive(Debug, Deserialize)]
+struct CsrfField {
+    csrf_token: Option<String>,
+}
+
+pub fn tokens_match(expected: &str, given: &str) -> bool {
+    expected.len() == given.len()
+        && expected
+            .bytes()
+            .zip(given.bytes())
+            .fold(0, |diff, (a, b)| diff | (a ^ b))
+            == 0
+}
+
+// Rejects state changing requests that don't carry the session's csrf token,
+// either in the X-CSRF-Token header, a csrf_token form field or query parameter
+pub async fn check(
+    mut req: ServiceRequest,
+    next: Next<impl MessageBody>,
+) -> Result<ServiceResponse<impl MessageBody>, Error> {
+    if !matches!(
+        *req.method(),
+        Method::POST | Method::PUT | Method::PATCH | Method::DELETE
+    ) {
+        return next.call(req).await;
+    }
+
+    // token authenticated api calls carry no cookies a third party could ride on
+    if bearer_token(req.request()).is_some() {
+        return next.call(req).await;
+    }
+
+    let mut given = req
+        .headers()
+        .get(HEADER_NAME)
+        .and_then(|value| value.to_str().ok())
+        .map(|value| value.to_string());
+
+    if given.is_none() {
+        let is_form = req
+            .headers()
+            .get(CONTENT_TYPE)
+            .and_then(|value| value.to_str().ok())
+            .is_some_and(|value| value.starts_with("application/x-www-form-urlencoded"));
+
+        if is_form {
+            let body = req.extract::<Bytes>().await?;
+            given = serde_urlencoded::from_bytes::<CsrfField>(&body)
+                .ok()
+                .and_then(|field| field.csrf_token);
+            req.set_payload(body.into());
+        } else {
+            given = serde_urlencoded::from_str::<CsrfField>(req.query_string())
+                .ok()
+                .and_then(|field| field.csrf_token);
+        }
+    }
+
+    let expected = req.get_session().get::<String>(SESSION_KEY).ok().flatten();
+
+    match (expected, given) {
+        (Some(expected), Some(given)) if tokens_match(&expected, &given) => next.call(req).await,
+        _ => {
+            warn!(
+                "Rejected {} {} without a valid csrf token",
+                req.method(),
+                req.path()
+            );
+            Err(ErrorForbidden("Invalid CSRF token"))
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use actix_session::{storage::CookieSessionStore, SessionMiddleware};
+    use actix_web::cookie::{Cookie, Key};
+    use actix_web::http::StatusCode;
+    use actix_web::middleware::from_fn;
+    use actix_web::{test, App, HttpResponse};
+
+    #[get("/token")]
+    async fn issue_token(session: Session) -> HttpResponse {
+        HttpResponse::Ok().body(session_token(&session))
+    }
+
+    #[post("/change")]
+    async fn change(body: String) -> HttpResponse {
+        HttpResponse::Ok().body(body)
+    }
+
+    // The middleware rejects with an error rather than a response
+    fn status<B>(result: Result<ServiceResponse<B>, Error>) -> StatusCode {
+        match result {
+            Ok(response) => response.status(),
+            Err(e) => e.as_response_error().status_code(),
+        }
+    }
+
+    #[actix_web::test]
+    async fn rejects_changes_without_the_session_token() {
+        let app = test::init_service(
+            App::new()
+                .wrap(from_fn(check))
+                .wrap(SessionMiddleware::new(
+                    CookieSessionStore::default(),
+                    Key::generate(),
+                ))
+                .service(issue_token)
+                .service(change),
+        )
+        .await;
+
+        let response =
+            test::call_service(&app, test::TestRequest::get().uri("/token").to_request()).await;
+        let cookie: Cookie = response.response().cookies().next().unwrap().into_owned();
+        let token = String::from_utf8(test::read_body(response).await.to_vec()).unwrap();
+        let post = || {
+            test::TestRequest::post()
+                .uri("/change")
+                .cookie(cookie.clone())
+        };
+
+        let missing = test::try_call_service(&app, post().to_request()).await;
+        assert_eq!(status(missing), StatusCode::FORBIDDEN);
+        let wrong = post().insert_header((HEADER_NAME, "x".repeat(token.len())));
+        let wrong = test::try_call_service(&app, wrong.to_request()).await;
+        assert_eq!(status(wrong), StatusCode::FORBIDDEN);
+        // the token of one session is no good without its cookie
+        let other_session = test::TestRequest::post()
+            .uri("/change")
+            .insert_header((HEADER_NAME, token.as_str()));
+        let other_session = test::try_call_service(&app, other_session.to_request()).await;
+        assert_eq!(status(other_session), StatusCode::FORBIDDEN);
+
+        let header = post().insert_header((HEADER_NAME, token.as_str()));
+        let header = test::try_call_service(&app, header.to_request()).await;
+        assert_eq!(status(header), StatusCode::OK);
+        // the form is still there for the handler after the check read it
+        let form = post().set_form([("csrf_token", token.as_str()), ("name", "rush")]);
+        let response = test::call_service(&app, form.to_request()).await;
+        assert_eq!(response.status(), StatusCode::OK);
+        let body = test::read_body(response).await;
+        assert!(body.starts_with(b"csrf_token="));
+
+        // api tokens carry no cookie a third party could ride on
+        let bearer = test::TestRequest::post()
+            .uri("/change")
+            .insert_header(("Authorization", "Bearer abc"));
+        let bearer = test::try_call_service(&app, bearer.to_request()).await;
+        assert_eq!(status(bearer), StatusCode::OK);
+        let get = test::TestRequest::get().uri("/token").to_request();
+        assert_eq!(test::call_service(&app, get).await.status(), StatusCode::OK);
+    }
+}
@@ -0,0 +1,177 @@
+use crate::config::SqlDriver;
+use crate::sql;
+use crate::sql::Pool;
+use crate::store::SpordStore;
+use crate::CONFIG;
+use actix_web::{web, HttpResponse};
+use serde::Serialize;
+use serde_json::json;
+use std::collections::BTreeMap;
+
+const VERSION: &str = env!("CARGO_PKG_VERSION");
+
+#[derive(Debug, Serialize)]
+struct Component {
+    // "ok" or "error"
+    status: &'static str,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    error: Option<String>,
+    #[serde(flatten)]
+    details: serde_json::Value,
+}
+impl Component {
+    fn ok(details: serde_json::Value) -> Component {
+        Component {
+            status: "ok",
+            error: None,
+            details,
+        }
+    }
+
+    fn failed(error: impl ToString, details: serde_json::Value) -> Component {
+        Component {
+            status: "error",
+            error: Some(error.to_string()),
+            details,
+        }
+    }
+}
+
+fn schema_component(
+    version: Result<(usize, usize), impl ToString>,
+    details: serde_json::Value,
+) -> Component {
+    match version {
+        Ok((version, expected)) => {
+            let mut details = details;
+            details["schema_version"] = json!(version);
+            details["expected_version"] = json!(expected);
+            if version == expected {
+                Component::ok(details)
+            } else {
+                Component::failed("schema is not up to date", details)
+            }
+        }
+        Err(e) => Component::failed(e, details),
+    }
+}
+
+// Writes and removes a probe file, neither the logger nor an upload can report
+// a full disk in time
+fn writable_dir_component(dir: String) -> Component {
+    let probe = std::path::Path::new(&dir).join(".readyz-probe");
+    let details = json!({ "dir": dir });
+
+    match std::fs::write(&probe, b"ok").and_then(|_| std::fs::remove_file(&probe)) {
+        Ok(()) => Component::ok(details),
+        Err(e) => Component::failed(e, details),
+    }
+}
+
+// The process is up and answering, nothing else is checked
+#[get("/healthz")]
+pub async fn healthz() -> HttpResponse {
+    HttpResponse::Ok().json(json!({ "status": "ok", "version": VERSION }))
+}
+
+// Whether this instance can serve requests: the database of sql.driver answers
+// with an up to date schema, logs and attachments can be written. 503 when
+// anything is wrong
+#[get("/readyz")]
+pub async fn readyz(pool: web::Data<Pool>, spords: web::Data<dyn SpordStore>) -> HttpResponse {
+    let mut components = BTreeMap::new();
+    // the sqlite file is left unused with postgres
+    if CONFIG.sql.driver == SqlDriver::Sqlite {
+        components.insert(
+            "sqlite",
+            schema_component(
+                sql::schema_version(&pool).await,
+                json!({ "location": CONFIG.sql.location }),
+            ),
+        );
+    }
+    components.insert(
+        "store",
+        schema_component(
+            spords.schema_version().await,
+            json!({ "driver": CONFIG.sql.driver }),
+        ),
+    );
+    components.insert("log_dir", writable_dir_component(crate::logging::log_dir()));
+    // created with the first upload
+    if std::fs::exists(&CONFIG.attachments.dir).unwrap_or(true) {
+        components.insert(
+            "attachments_dir",
+            writable_dir_component(CONFIG.attachments.dir.clone()),
+        );
+    }
+
+    readiness(components)
+}
+
+// 200 when every component is fine, 503 naming the failed ones otherwise
+fn readiness(components: BTreeMap<&str, Component>) -> HttpResponse {
+    let ready = components
+        .values()
+        .all(|component| component.error.is_none());
+    for (name, component) in &components {
+        if let Some(ref error) = component.error {
+            warn!("Readiness check {} failed: {}", name, error);
+        }
+    }
+
+    let body = json!({
+        "status": if ready { "ok" } else { "unavailable" },
+        "version": VERSION,
+        "components": components,
+    });
+    if ready {
+        HttpResponse::Ok().json(body)
+    } else {
+        HttpResponse::ServiceUnavailable().json(body)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use actix_web::body::to_bytes;
+    use actix_web::http::StatusCode;
+
+    async fn body(response: HttpResponse) -> serde_json::Value {
+        serde_json::from_slice(&to_bytes(response.into_body()).await.unwrap()).unwrap()
+    }
+
+    #[actix_web::test]
+    async fn unavailable_when_a_component_fails() {
+        let dir = tempfile::tempdir().unwrap();
+        let writable = || writable_dir_component(dir.path().display().to_string());
+        let up_to_date = || schema_component(Ok::<_, String>((3, 3)), json!({}));
+
+        let components = BTreeMap::from([("store", up_to_date()), ("log_dir", writable())]);
+        let response = readiness(components);
+        assert_eq!(response.status(), StatusCode::OK);
+        assert_eq!(body(response).await["status"], "ok");
+
+        let missing = dir.path().join("missing").display().to_string();
+        for (name, failed) in [
+            (
+                "store",
+                schema_component(Ok::<_, String>((2, 3)), json!({})),
+            ),
+            (
+                "store",
+                schema_component(Err("connection refused"), json!({})),
+            ),
+            ("log_dir", writable_dir_component(missing)),
+        ] {
+            let mut components = BTreeMap::from([("store", up_to_date()), ("log_dir", writable())]);
+            components.insert(name, failed);
+            let response = readiness(components);
+            assert_eq!(response.status(), StatusCode::SERVICE_UNAVAILABLE);
+            let body = body(response).await;
+            assert_eq!(body["status"], "unavailable");
+            assert_eq!(body["components"][name]["status"], "error");
+        }
+    }
+}
@@ -1,23 +1,204 @@
+use super::api::{self, ApiUser};
+use super::attachments;
+use super::csrf;
 use super::template;
 use super::user_logged_in;
-use crate::sql;
+use crate::metrics::LOGINS;
+use crate::reports::{ReportGroup, ReportRow};
+use crate::sql::lockout::AttemptKind;
+use crate::sql::models::{
+    self, ApiTokenRecord, AttachmentRecord, SavedFilterRecord, SpordState, TokenScope, WebhookEvent,
+};
+use crate::sql::{LocationFilter, SpordFilter};
+use crate::store::{LocationStore, SpordStore, UserStore, WebhookStore};
+use crate::CONFIG;
 use actix_identity::Identity;
+use actix_multipart::Multipart;
+use actix_session::Session;
+use actix_web::error::{
+    ErrorBadRequest, ErrorForbidden, ErrorInternalServerError, ErrorNotFound, ErrorUnauthorized,
+};
 use actix_web::HttpMessage;
 use actix_web::HttpRequest;
 use actix_web::{get, web, HttpResponse, Responder};
+use chrono::{Datelike, Duration, Months, NaiveTime, Utc};
+use rand::distributions::{Alphanumeric, DistString};
 use serde::Deserialize;
+use std::collections::HashMap;
+use std::net::{IpAddr, Ipv6Addr, SocketAddr};
+
+// rows in the oldest open orders table on the dashboard
+const OLDEST_OPEN_SHOWN: usize = 10;
+
+// Which spords a user's pages cover: managers look after every store, everyone
+// else sees their own. Also returns the name of that scope and every store name
+async fn user_scope(
+    locations: &dyn LocationStore,
+    users: &dyn UserStore,
+    username: &str,
+) -> actix_web::Result<(Option<LocationFilter>, String, HashMap<i64, String>)> {
+    let user = users
+        .get(username)
+        .await
+        .map_err(ErrorInternalServerError)?
+        .ok_or_else(|| ErrorInternalServerError("User not found"))?;
+
+    let locations: HashMap<i64, String> = locations
+        .list()
+        .await
+        .map_err(ErrorInternalServerError)?
+        .into_iter()
+        .map(|location| (location.id, location.name))
+        .collect();
+
+    if user.manager {
+        return Ok((None, "All stores".to_string(), locations));
+    }
+    let scope = user
+        .location
+        .and_then(|location| locations.get(&location).cloned())
+        .unwrap_or_else(|| "No store".to_string());
+
+    Ok((Some(LocationFilter(user.location)), scope, locations))
+}
+
+// The saved filters shown in the navbar of every page
+async fn saved_filters(
+    users: &dyn UserStore,
+    username: &str,
+) -> actix_web::Result<Vec<SavedFilterRecord>> {
+    users
+        .saved_filters(username)
+        .await
+        .map_err(ErrorInternalServerError)
+}
 
 #[get("/")]
-pub async fn index(id: Option<Identity>) -> impl Responder {
-    if let Some(_username) = user_logged_in(id) {
-        let html = template::template_index();
+pub async fn index(
+    spords: web::Data<dyn SpordStore>,
+    users: web::Data<dyn UserStore>,
+    locations: web::Data<dyn LocationStore>,
+    id: Option<Identity>,
+) -> actix_web::Result<HttpResponse> {
+    let Some(username) = user_logged_in(id) else {
+        return Ok(HttpResponse::Found()
+            .insert_header(("location", "/login"))
+            .finish());
+    };
+    let (location, scope, locations) =
+        user_scope(locations.get_ref(), users.get_ref(), &username).await?;
 
-        HttpResponse::Ok().body(html)
-    } else {
-        HttpResponse::Found()
+    let now = Utc::now();
+    let week_start = (now.date_naive()
+        - Duration::days(now.weekday().num_days_from_monday() as i64))
+    .and_time(NaiveTime::MIN)
+    .and_utc();
+    let overdue_before = now - Duration::days(CONFIG.spords.overdue_days as i64);
+    let dashboard = spords
+        .dashboard(location, week_start, overdue_before, OLDEST_OPEN_SHOWN)
+        .await
+        .map_err(ErrorInternalServerError)?;
+
+    let filters = saved_filters(users.get_ref(), &username).await?;
+
+    Ok(HttpResponse::Ok().body(template::template_index(
+        &filters,
+        &scope,
+        dashboard,
+        &locations,
+        CONFIG.spords.overdue_days,
+        // managers have no store filter
+        location.is_none(),
+    )))
+}
+
+#[derive(Debug, Deserialize)]
+pub struct ReportQuery {
+    #[serde(default)]
+    pub group: ReportGroup,
+    // spords created this many months back, 0 for all of them
+    pub months: Option<u32>,
+}
+
+async fn turnaround_report(
+    spords: &dyn SpordStore,
+    location: Option<LocationFilter>,
+    group: ReportGroup,
+    months: u32,
+) -> actix_web::Result<Vec<ReportRow>> {
+    let created_since = match months {
+        0 => None,
+        months => Utc::now().checked_sub_months(Months::new(months)),
+    };
+    let turnaround = spords
+        .turnaround(location, created_since)
+        .await
+        .map_err(ErrorInternalServerError)?;
+
+    Ok(crate::reports::build(
+        &turnaround,
+        group,
+        CONFIG.reports.part_prefix_length,
+    ))
+}
+
+#[get("/reports")]
+pub async fn reports(
+    spords: web::Data<dyn SpordStore>,
+    users: web::Data<dyn UserStore>,
+    locations: web::Data<dyn LocationStore>,
+    id: Option<Identity>,
+    query: web::Query<ReportQuery>,
+) -> actix_web::Result<HttpResponse> {
+    let Some(username) = user_logged_in(id) else {
+        return Ok(HttpResponse::Found()
             .insert_header(("location", "/login"))
-            .finish()
-    }
+            .finish());
+    };
+    let (location, scope, _) = user_scope(locations.get_ref(), users.get_ref(), &username).await?;
+
+    let months = query.months.unwrap_or(CONFIG.reports.months);
+    let rows = turnaround_report(spords.get_ref(), location, query.group, months).await?;
+
+    let filters = saved_filters(users.get_ref(), &username).await?;
+
+    Ok(HttpResponse::Ok().body(template::template_reports(
+        &filters,
+        &scope,
+        query.group,
+        months,
+        rows,
+    )))
+}
+
+#[get("/reports.csv")]
+pub async fn reports_csv(
+    spords: web::Data<dyn SpordStore>,
+    users: web::Data<dyn UserStore>,
+    locations: web::Data<dyn LocationStore>,
+    id: Option<Identity>,
+    query: web::Query<ReportQuery>,
+) -> actix_web::Result<HttpResponse> {
+    let Some(username) = user_logged_in(id) else {
+        return Err(ErrorUnauthorized("Unauthorized"));
+    };
+    let (location, _, _) = user_scope(locations.get_ref(), users.get_ref(), &username).await?;
+
+    let months = query.months.unwrap_or(CONFIG.reports.months);
+    let rows = turnaround_report(spords.get_ref(), location, query.group, months).await?;
+    let mut csv = vec![];
+    crate::reports::write_csv(&mut csv, query.group, &rows).map_err(ErrorInternalServerError)?;
+
+    Ok(HttpResponse::Ok()
+        .content_type("text/csv; charset=utf-8")
+        .insert_header((
+            "content-disposition",
+            format!(
+                "attachment; filename=\"turnaround-{}.csv\"",
+                query.group.name()
+            ),
+        ))
+        .body(csv))
 }
 
 #[get("/js/{path}")]
@@ -30,11 +211,12 @@ pub async fn js_file(path: web::Path<String>, id: Option<Identity>) -> HttpRespo
 }
 
 #[get("/login")]
-pub async fn login(id: Option<Identity>) -> impl Responder {
+pub async fn login(id: Option<Identity>, session: Session) -> impl Responder {
     if let Some(_username) = user_logged_in(id) {
         HttpResponse::Ok().insert_header(("location", "/")).finish()
     } else {
-        HttpResponse::Ok().body(template::template_login())
+        let csrf_token = csrf::session_token(&session);
+        HttpResponse::Ok().body(template::template_login(&csrf_token))
     }
 }
 
@@ -43,29 +225,139 @@ pub struct LoginPostData {
     pub username: String,
     pub password: String,
 }
+// An address from a forwarding header, which may be quoted and carry a port.
+// IPv6 addresses are in brackets, with or without a port after them
+fn forwarded_addr(value: &str) -> Option<IpAddr> {
+    let value = value.trim().trim_matches('"');
+    if let Some(bracketed) = value.strip_prefix('[') {
+        let (ip, _port) = bracketed.split_once(']')?;
+        return ip.parse::<Ipv6Addr>().ok().map(IpAddr::V6);
+    }
+    value
+        .parse::<IpAddr>()
+        .or_else(|_| value.parse::<SocketAddr>().map(|addr| addr.ip()))
+        .ok()
+}
+
+// The addresses a request was forwarded for, the client first and each proxy
+// after it. Taken from Forwarded if it is set and X-Forwarded-For otherwise
+fn forwarded_chain(request: &HttpRequest) -> Vec<String> {
+    let headers = request.headers();
+    let forwarded: Vec<String> = headers
+        .get_all("forwarded")
+        .filter_map(|value| value.to_str().ok())
+        .flat_map(|value| value.split(','))
+        .filter_map(|element| {
+            element.split(';').find_map(|pair| {
+                let (name, value) = pair.trim().split_once('=')?;
+                name.eq_ignore_ascii_case("for")
+                    .then(|| value.trim().to_string())
+            })
+        })
+        .collect();
+    if !forwarded.is_empty() {
+        return forwarded;
+    }
+
+    headers
+        .get_all("x-forwarded-for")
+        .filter_map(|value| value.to_str().ok())
+        .flat_map(|value| value.split(','))
+        .map(|value| value.trim().to_string())
+        .filter(|value| !value.is_empty())
+        .collect()
+}
+
+// The address failed logins are counted for. The forwarding headers are only
+// believed on connections from one of the trusted proxies, and only as far back
+// as the trusted proxies go: the entries before that came from the client
+fn client_ip(request: &HttpRequest, trusted_proxies: &[IpAddr]) -> String {
+    let Some(mut client) = request.peer_addr().map(|addr| addr.ip()) else {
+        return String::new();
+    };
+
+    for entry in forwarded_chain(request).iter().rev() {
+        if !trusted_proxies.contains(&client) {
+            break;
+        }
+        match forwarded_addr(entry) {
+            Some(addr) => client = addr,
+            // an obfuscated or unknown address from a trusted proxy
+            None => return entry.trim_matches('"').to_string(),
+        }
+    }
+    client.to_string()
+}
+
 #[post("/login_post")]
 pub async fn login_post(
+    users: web::Data<dyn UserStore>,
     request: HttpRequest,
     id: Option<Identity>,
     params: web::Form<LoginPostData>,
 ) -> actix_web::Result<HttpResponse> {
     if let Some(_username) = user_logged_in(id) {
-        Ok(HttpResponse::Ok().insert_header(("location", "/")).finish())
-    } else {
-        if sql::user_login(None, &params.username, &params.password)
+        return Ok(HttpResponse::Ok().insert_header(("location", "/")).finish());
+    }
+
+    let ip = client_ip(&request, &CONFIG.web.trusted_proxies);
+
+    let user_lock = users
+        .locked_until(AttemptKind::Username, &params.username)
+        .await
+        .map_err(ErrorInternalServerError)?;
+    let ip_lock = users
+        .locked_until(AttemptKind::Ip, &ip)
+        .await
+        .map_err(ErrorInternalServerError)?;
+    if let Some(until) = user_lock.or(ip_lock) {
+        LOGINS.with_label_values(&["locked"]).inc();
+        warn!(
+            username = params.username.as_str(), ip = ip.as_str();
+            "Rejected login for {} from {}, locked until {}",
+            params.username, ip, until
+        );
+        return Ok(HttpResponse::Found()
+            .insert_header(("location", "/login"))
+            .finish());
+    }
+
+    if users
+        .login(&params.username, &params.password)
+        .await
+        .map_err(ErrorInternalServerError)?
+    {
+        LOGINS.with_label_values(&["success"]).inc();
+        users
+            .clear_login_failures(AttemptKind::Username, &params.username)
             .await
-            .unwrap()
-        {
-            Identity::login(&request.extensions(), format!("user:{}", &params.username))?;
-
-            Ok(HttpResponse::Found()
-                .insert_header(("location", "/"))
-                .finish())
-        } else {
-            Ok(HttpResponse::Found()
-                .insert_header(("location", "/login"))
-                .finish())
+            .map_err(ErrorInternalServerError)?;
+        Identity::login(&request.extensions(), format!("user:{}", &params.username))?;
+
+        Ok(HttpResponse::Found()
+            .insert_header(("location", "/"))
+            .finish())
+    } else {
+        LOGINS.with_label_values(&["failure"]).inc();
+        for (kind, key) in [
+            (AttemptKind::Username, params.username.as_str()),
+            (AttemptKind::Ip, ip.as_str()),
+        ] {
+            if let Some(until) = users
+                .record_login_failure(&CONFIG.login, kind, key)
+                .await
+                .map_err(ErrorInternalServerError)?
+            {
+                warn!(
+                    "Too many failed logins for {:?} {}, locked until {}",
+                    kind, key, until
+                );
+            }
         }
+
+        Ok(HttpResponse::Found()
+            .insert_header(("location", "/login"))
+            .finish())
     }
 }
 
@@ -77,3 +369,909 @@ pub async fn logout(id: Identity) -> impl Responder {
         .insert_header(("location", "/login"))
         .finish()
 }
+
+// Managers see every token, everyone else only their own
+async fn visible_tokens(
+    users: &dyn UserStore,
+    user: &ApiUser,
+) -> actix_web::Result<Vec<ApiTokenRecord>> {
+    Ok(users
+        .tokens()
+        .await
+        .map_err(ErrorInternalServerError)?
+        .into_iter()
+        .filter(|token| user.manager || token.username == user.username)
+        .collect())
+}
+
+#[get("/tokens")]
+pub async fn tokens(
+    users: web::Data<dyn UserStore>,
+    locations: web::Data<dyn LocationStore>,
+    id: Option<Identity>,
+    session: Session,
+) -> actix_web::Result<HttpResponse> {
+    let Some(username) = user_logged_in(id) else {
+        return Ok(HttpResponse::Found()
+            .insert_header(("location", "/login"))
+            .finish());
+    };
+
+    let filters = saved_filters(users.get_ref(), &username).await?;
+    let user = ApiUser::load(users.get_ref(), username, TokenScope::Write).await?;
+    let all_tokens = visible_tokens(users.get_ref(), &user).await?;
+    let locations = locations.list().await.map_err(ErrorInternalServerError)?;
+    let csrf_token = csrf::session_token(&session);
+
+    Ok(HttpResponse::Ok().body(template::template_tokens(
+        &filters,
+        &csrf_token,
+        all_tokens,
+        None,
+        user.manager,
+        locations,
+    )))
+}
+
+#[derive(Debug, Deserialize)]
+pub struct TokenCreateData {
+    pub name: String,
+    pub scope: TokenScope,
+    pub expires_days: Option<String>,
+    pub service: Option<String>,
+    // store a service token is limited to, empty for every store
+    pub location: Option<String>,
+}
+#[post("/tokens/create")]
+pub async fn tokens_create(
+    users: web::Data<dyn UserStore>,
+    locations: web::Data<dyn LocationStore>,
+    id: Option<Identity>,
+    session: Session,
+    params: web::Form<TokenCreateData>,
+) -> actix_web::Result<HttpResponse> {
+    let Some(username) = user_logged_in(id) else {
+        return Ok(HttpResponse::Found()
+            .insert_header(("location", "/login"))
+            .finish());
+    };
+    let filters = saved_filters(users.get_ref(), &username).await?;
+    let user = ApiUser::load(users.get_ref(), username, TokenScope::Write).await?;
+
+    let expires = match params.expires_days.as_deref().map(str::trim) {
+        None | Some("") => None,
+        Some(days) => {
+            let days: i64 = days.parse().map_err(ErrorBadRequest)?;
+            Some(Utc::now() + Duration::days(days))
+        }
+    };
+    // service tokens are not tied to a user, so only managers hand them out
+    let owner = if params.service.is_some() {
+        user.require_manager()?;
+        None
+    } else {
+        user.username.as_deref()
+    };
+    let location = match params.location.as_deref().map(str::trim) {
+        None | Some("") => None,
+        Some(location) => Some(location.parse().map_err(ErrorBadRequest)?),
+    };
+
+    let (token_id, token) = users
+        .create_token(&params.name, owner, params.scope, expires, location)
+        .await
+        .map_err(ErrorInternalServerError)?;
+    info!(
+        username = user.name();
+        "{} created api token {} ({})",
+        user.name(), token_id, params.name
+    );
+
+    let all_tokens = visible_tokens(users.get_ref(), &user).await?;
+    let locations = locations.list().await.map_err(ErrorInternalServerError)?;
+    let csrf_token = csrf::session_token(&session);
+
+    Ok(HttpResponse::Ok().body(template::template_tokens(
+        &filters,
+        &csrf_token,
+        all_tokens,
+        Some(token),
+        user.manager,
+        locations,
+    )))
+}
+
+#[post("/tokens/{token_id}/revoke")]
+pub async fn tokens_revoke(
+    users: web::Data<dyn UserStore>,
+    id: Option<Identity>,
+    token_id: web::Path<i64>,
+) -> actix_web::Result<HttpResponse> {
+    let Some(username) = user_logged_in(id) else {
+        return Ok(HttpResponse::Found()
+            .insert_header(("location", "/login"))
+            .finish());
+    };
+    let user = ApiUser::load(users.get_ref(), username, TokenScope::Write).await?;
+
+    // managers revoke any token, everyone else only their own
+    let owner = if user.manager {
+        None
+    } else {
+        user.username.as_deref()
+    };
+    if users
+        .revoke_token(*token_id, owner)
+        .await
+        .map_err(ErrorInternalServerError)?
+    {
+        info!(
+            username = user.name();
+            "{} revoked api token {}",
+            user.name(), token_id
+        );
+    } else {
+        return Err(ErrorNotFound("No such token"));
+    }
+
+    Ok(HttpResponse::Found()
+        .insert_header(("location", "/tokens"))
+        .finish())
+}
+
+fn redirect(location: &str) -> HttpResponse {
+    HttpResponse::Found()
+        .insert_header(("location", location))
+        .finish()
+}
+
+// A state name as used in the filter forms, empty for any state
+fn parse_state(state: Option<&str>) -> actix_web::Result<Option<SpordState>> {
+    match state.map(str::trim) {
+        None | Some("") => Ok(None),
+        Some(name) => SpordState::from_name(name)
+            .map(Some)
+            .ok_or_else(|| ErrorBadRequest("Unknown state")),
+    }
+}
+
+fn parse_search(search: Option<&str>) -> Option<String> {
+    search
+        .map(str::trim)
+        .filter(|search| !search.is_empty())
+        .map(str::to_string)
+}
+
+#[derive(Debug, Deserialize)]
+pub struct SpordListQuery {
+    // a state name like picked_up
+    pub state: Option<String>,
+    // comma separated, spords having all of them
+    pub tags: Option<String>,
+    // text to look for in customer details and part
+    pub q: Option<String>,
+    // archived spords instead of the active ones
+    #[serde(default)]
+    pub archived: bool,
+}
+
+#[get("/spords")]
+pub async fn spords_list(
+    spords: web::Data<dyn SpordStore>,
+    users: web::Data<dyn UserStore>,
+    locations: web::Data<dyn LocationStore>,
+    id: Option<Identity>,
+    session: Session,
+    query: web::Query<SpordListQuery>,
+) -> actix_web::Result<HttpResponse> {
+    let Some(username) = user_logged_in(id) else {
+        return Ok(redirect("/login"));
+    };
+    let (location, scope, locations) =
+        user_scope(locations.get_ref(), users.get_ref(), &username).await?;
+
+    let filter = SpordFilter {
+        location,
+        state: parse_state(query.state.as_deref())?,
+        search: parse_search(query.q.as_deref()),
+        tags: models::parse_tags(query.tags.as_deref().unwrap_or_default()),
+        archived: query.archived,
+    };
+    let list = spords
+        .list(filter.clone())
+        .await
+        .map_err(ErrorInternalServerError)?;
+    let all_tags = spords.tags().await.map_err(ErrorInternalServerError)?;
+    let filters = saved_filters(users.get_ref(), &username).await?;
+    let csrf_token = csrf::session_token(&session);
+
+    Ok(HttpResponse::Ok().body(template::template_spords(
+        &filters,
+        &csrf_token,
+        &scope,
+        &filter,
+        list,
+        all_tags,
+        &locations,
+    )))
+}
+
+#[derive(Debug, Deserialize)]
+pub struct FilterSaveData {
+    pub name: String,
+    pub state: Option<String>,
+    pub tags: Option<String>,
+    pub q: Option<String>,
+}
+
+// Saves the filter of the spord list under a name, replacing one with the same
+// name
+#[post("/filters/save")]
+pub async fn filters_save(
+    users: web::Data<dyn UserStore>,
+    id: Option<Identity>,
+    params: web::Form<FilterSaveData>,
+) -> actix_web::Result<HttpResponse> {
+    let Some(username) = user_logged_in(id) else {
+        return Ok(redirect("/login"));
+    };
+
+    let name = params.name.trim();
+    if name.is_empty() {
+        return Err(ErrorBadRequest("A saved filter needs a name"));
+    }
+    let mut filter = SavedFilterRecord {
+        id: 0,
+        username: username.clone(),
+        name: name.to_string(),
+        state: parse_state(params.state.as_deref())?,
+        tags: models::parse_tags(params.tags.as_deref().unwrap_or_default()),
+        search: parse_search(params.q.as_deref()),
+        created: Utc::now(),
+    };
+    filter.id = users
+        .save_filter(
+            &username,
+            &filter.name,
+            filter.state.clone(),
+            &filter.tags,
+            filter.search.clone(),
+        )
+        .await
+        .map_err(ErrorInternalServerError)?;
+    info!(
+        username = username.as_str();
+        "{} saved spord filter {} ({})",
+        username, filter.id, filter.name
+    );
+
+    Ok(redirect(&format!("/spords?{}", filter.query())))
+}
+
+#[post("/filters/{filter_id}/delete")]
+pub async fn filters_delete(
+    users: web::Data<dyn UserStore>,
+    id: Option<Identity>,
+    filter_id: web::Path<i64>,
+) -> actix_web::Result<HttpResponse> {
+    let Some(username) = user_logged_in(id) else {
+        return Ok(redirect("/login"));
+    };
+
+    if users
+        .delete_saved_filter(&username, *filter_id)
+        .await
+        .map_err(ErrorInternalServerError)?
+    {
+        info!(
+            username = username.as_str();
+            "{} deleted spord filter {}",
+            username, filter_id
+        );
+    }
+
+    Ok(redirect("/spords"))
+}
+
+#[get("/spords/{spord_id}")]
+pub async fn spords_detail(
+    spords: web::Data<dyn SpordStore>,
+    users: web::Data<dyn UserStore>,
+    locations: web::Data<dyn LocationStore>,
+    id: Option<Identity>,
+    session: Session,
+    spord_id: web::Path<i32>,
+) -> actix_web::Result<HttpResponse> {
+    let Some(username) = user_logged_in(id) else {
+        return Ok(redirect("/login"));
+    };
+    let filters = saved_filters(users.get_ref(), &username).await?;
+    let user = ApiUser::load(users.get_ref(), username, TokenScope::Write).await?;
+    let spord = api::visible_spord(spords.get_ref(), &user, *spord_id).await?;
+
+    let events = spords
+        .events(spord.id)
+        .await
+        .map_err(ErrorInternalServerError)?;
+    let notes = spords
+        .notes(spord.id)
+        .await
+        .map_err(ErrorInternalServerError)?
+        .into_iter()
+        .map(|note| {
+            let can_edit = user.can_edit_note(&note);
+            (note, can_edit)
+        })
+        .collect();
+    let attachments = spords
+        .attachments(spord.id)
+        .await
+        .map_err(ErrorInternalServerError)?
+        .into_iter()
+        .map(|attachment| {
+            let can_delete = user.can_delete_attachment(&attachment);
+            (attachment, can_delete)
+        })
+        .collect();
+    let locations: HashMap<i64, String> = locations
+        .list()
+        .await
+        .map_err(ErrorInternalServerError)?
+        .into_iter()
+        .map(|location| (location.id, location.name))
+        .collect();
+    let can_restore = user.manager;
+    let csrf_token = csrf::session_token(&session);
+
+    Ok(HttpResponse::Ok().body(template::template_spord(
+        &filters,
+        &csrf_token,
+        (spord, can_restore),
+        events,
+        notes,
+        attachments,
+        &locations,
+    )))
+}
+
+#[derive(Debug, Deserialize)]
+pub struct TagsData {
+    // comma separated
+    pub tags: String,
+}
+
+// Tag form of the detail page, replaces every tag of the spord
+#[post("/spords/{spord_id}/tags")]
+pub async fn spords_tags(
+    spords: web::Data<dyn SpordStore>,
+    users: web::Data<dyn UserStore>,
+    id: Option<Identity>,
+    spord_id: web::Path<i32>,
+    params: web::Form<TagsData>,
+) -> actix_web::Result<HttpResponse> {
+    let Some(username) = user_logged_in(id) else {
+        return Ok(redirect("/login"));
+    };
+    let user = ApiUser::load(users.get_ref(), username, TokenScope::Write).await?;
+    let mut spord = api::visible_spord(spords.get_ref(), &user, *spord_id).await?;
+
+    spord.tags = models::parse_tags(&params.tags);
+    spords
+        .update(spord.clone(), user.username.clone())
+        .await
+        .map_err(ErrorInternalServerError)?;
+    info!(
+        username = user.name(), spord_id = spord.id;
+        "{} set the tags of spord {} to \"{}\"",
+        user.name(),
+        spord.id,
+        spord.tags.join(", ")
+    );
+
+    Ok(redirect(&format!("/spords/{}", spord.id)))
+}
+
+// Archives instead of deleting, the spord is only hidden from the lists
+#[post("/spords/{spord_id}/archive")]
+pub async fn spords_archive(
+    webhook_store: web::Data<dyn WebhookStore>,
+    spords: web::Data<dyn SpordStore>,
+    users: web::Data<dyn UserStore>,
+    id: Option<Identity>,
+    spord_id: web::Path<i32>,
+) -> actix_web::Result<HttpResponse> {
+    let Some(username) = user_logged_in(id) else {
+        return Ok(redirect("/login"));
+    };
+    let user = ApiUser::load(users.get_ref(), username, TokenScope::Write).await?;
+    let spord = api::visible_spord(spords.get_ref(), &user, *spord_id).await?;
+
+    if spords
+        .archive(spord.id, user.username.clone())
+        .await
+        .map_err(ErrorInternalServerError)?
+    {
+        info!(
+            username = user.name(), spord_id = spord.id;
+            "{} archived spord {}",
+            user.name(),
+            spord.id
+        );
+        let archived = spords
+            .get(spord.id)
+            .await
+            .map_err(ErrorInternalServerError)?
+            .unwrap_or(spord);
+        crate::webhooks::notify(
+            webhook_store.get_ref(),
+            WebhookEvent::Archived,
+            &archived,
+            user.username.as_deref(),
+            None,
+        )
+        .await;
+    }
+
+    Ok(redirect("/spords"))
+}
+
+#[post("/spords/{spord_id}/restore")]
+pub async fn spords_restore(
+    spords: web::Data<dyn SpordStore>,
+    users: web::Data<dyn UserStore>,
+    id: Option<Identity>,
+    spord_id: web::Path<i32>,
+) -> actix_web::Result<HttpResponse> {
+    let Some(username) = user_logged_in(id) else {
+        return Ok(redirect("/login"));
+    };
+    let user = ApiUser::load(users.get_ref(), username, TokenScope::Write).await?;
+    user.require_manager()?;
+    let spord = api::visible_spord(spords.get_ref(), &user, *spord_id).await?;
+
+    if spords
+        .restore(spord.id, user.username.clone())
+        .await
+        .map_err(ErrorInternalServerError)?
+    {
+        info!(
+            username = user.name(), spord_id = spord.id;
+            "{} restored spord {}",
+            user.name(),
+            spord.id
+        );
+    }
+
+    Ok(redirect(&format!("/spords/{}", spord.id)))
+}
+
+#[post("/spords/{spord_id}/notes")]
+pub async fn spords_notes_add(
+    spords: web::Data<dyn SpordStore>,
+    users: web::Data<dyn UserStore>,
+    id: Option<Identity>,
+    spord_id: web::Path<i32>,
+    params: web::Form<api::NoteInput>,
+) -> actix_web::Result<HttpResponse> {
+    let Some(username) = user_logged_in(id) else {
+        return Ok(redirect("/login"));
+    };
+    let user = ApiUser::load(users.get_ref(), username, TokenScope::Write).await?;
+    let spord = api::visible_spord(spords.get_ref(), &user, *spord_id).await?;
+    let text = api::note_text(&params.text)?;
+
+    let note_id = spords
+        .add_note(spord.id, user.username.clone(), text)
+        .await
+        .map_err(ErrorInternalServerError)?;
+    info!(
+        username = user.name(), spord_id = spord.id, note_id = note_id;
+        "{} added note {} to spord {}",
+        user.name(),
+        note_id,
+        spord.id
+    );
+
+    Ok(redirect(&format!("/spords/{}#note-{}", spord.id, note_id)))
+}
+
+#[post("/spords/{spord_id}/notes/{note_id}/edit")]
+pub async fn spords_notes_edit(
+    spords: web::Data<dyn SpordStore>,
+    users: web::Data<dyn UserStore>,
+    id: Option<Identity>,
+    path: web::Path<(i32, i64)>,
+    params: web::Form<api::NoteInput>,
+) -> actix_web::Result<HttpResponse> {
+    let Some(username) = user_logged_in(id) else {
+        return Ok(redirect("/login"));
+    };
+    let user = ApiUser::load(users.get_ref(), username, TokenScope::Write).await?;
+    let (spord_id, note_id) = path.into_inner();
+    api::visible_spord(spords.get_ref(), &user, spord_id).await?;
+    let note = api::find_note(spords.get_ref(), spord_id, note_id).await?;
+    user.require_note_editable(&note)?;
+    let text = api::note_text(&params.text)?;
+
+    spords
+        .edit_note(spord_id, note.id, user.username.clone(), text)
+        .await
+        .map_err(ErrorInternalServerError)?;
+    info!(
+        username = user.name(), spord_id = spord_id, note_id = note.id;
+        "{} changed note {} of spord {}",
+        user.name(),
+        note.id,
+        spord_id
+    );
+
+    Ok(redirect(&format!("/spords/{}#note-{}", spord_id, note.id)))
+}
+
+// Upload form of the detail page, the csrf token comes in the query string
+#[post("/spords/{spord_id}/attachments")]
+pub async fn spords_attachments_upload(
+    spords: web::Data<dyn SpordStore>,
+    users: web::Data<dyn UserStore>,
+    id: Option<Identity>,
+    spord_id: web::Path<i32>,
+    multipart: Multipart,
+) -> actix_web::Result<HttpResponse> {
+    let Some(username) = user_logged_in(id) else {
+        return Ok(redirect("/login"));
+    };
+    let user = ApiUser::load(users.get_ref(), username, TokenScope::Write).await?;
+    let spord = api::visible_spord(spords.get_ref(), &user, *spord_id).await?;
+
+    let stored =
+        attachments::receive(spords.get_ref(), spord.id, multipart, user.username.clone()).await?;
+    for attachment in &stored {
+        info!(
+            username = user.name(), spord_id = spord.id, attachment_id = attachment.id;
+            "{} attached {} to spord {}",
+            user.name(),
+            attachment.filename,
+            spord.id
+        );
+    }
+
+    Ok(redirect(&format!("/spords/{}", spord.id)))
+}
+
+async fn visible_attachment(
+    spords: &dyn SpordStore,
+    user: &ApiUser,
+    spord_id: i32,
+    attachment_id: i64,
+) -> actix_web::Result<AttachmentRecord> {
+    api::visible_spord(spords, user, spord_id).await?;
+
+    spords
+        .attachment(spord_id, attachment_id)
+        .await
+        .map_err(ErrorInternalServerError)?
+        .ok_or_else(|| ErrorNotFound("No such attachment"))
+}
+
+#[get("/spords/{spord_id}/attachments/{attachment_id}")]
+pub async fn spords_attachments_download(
+    spords: web::Data<dyn SpordStore>,
+    users: web::Data<dyn UserStore>,
+    id: Option<Identity>,
+    path: web::Path<(i32, i64)>,
+) -> actix_web::Result<HttpResponse> {
+    let Some(username) = user_logged_in(id) else {
+        return Ok(redirect("/login"));
+    };
+    let user = ApiUser::load(users.get_ref(), username, TokenScope::Write).await?;
+    let (spord_id, attachment_id) = path.into_inner();
+    let attachment = visible_attachment(spords.get_ref(), &user, spord_id, attachment_id).await?;
+
+    attachments::download(&attachment).await
+}
+
+#[post("/spords/{spord_id}/attachments/{attachment_id}/delete")]
+pub async fn spords_attachments_delete(
+    spords: web::Data<dyn SpordStore>,
+    users: web::Data<dyn UserStore>,
+    id: Option<Identity>,
+    path: web::Path<(i32, i64)>,
+) -> actix_web::Result<HttpResponse> {
+    let Some(username) = user_logged_in(id) else {
+        return Ok(redirect("/login"));
+    };
+    let user = ApiUser::load(users.get_ref(), username, TokenScope::Write).await?;
+    let (spord_id, attachment_id) = path.into_inner();
+    let attachment = visible_attachment(spords.get_ref(), &user, spord_id, attachment_id).await?;
+    if !user.can_delete_attachment(&attachment) {
+        return Err(ErrorForbidden(
+            "Only managers and the uploader can delete an attachment",
+        ));
+    }
+
+    crate::attachments::delete(spords.get_ref(), &attachment)
+        .await
+        .map_err(ErrorInternalServerError)?;
+    info!(
+        username = user.name(), spord_id = spord_id, attachment_id = attachment.id;
+        "{} deleted attachment {} of spord {}",
+        user.name(),
+        attachment.filename,
+        spord_id
+    );
+
+    Ok(redirect(&format!("/spords/{}", spord_id)))
+}
+
+// Webhooks get every spord with its customer details, from every store, so
+// only managers may see or change them
+async fn webhook_manager(users: &dyn UserStore, username: &str) -> actix_web::Result<ApiUser> {
+    let user = ApiUser::load(users, username.to_string(), TokenScope::Write).await?;
+    user.require_manager()?;
+    Ok(user)
+}
+
+#[get("/webhooks")]
+pub async fn webhooks(
+    webhook_store: web::Data<dyn WebhookStore>,
+    users: web::Data<dyn UserStore>,
+    id: Option<Identity>,
+    session: Session,
+) -> actix_web::Result<HttpResponse> {
+    let Some(username) = user_logged_in(id) else {
+        return Ok(redirect("/login"));
+    };
+    webhook_manager(users.get_ref(), &username).await?;
+
+    let all_webhooks = webhook_store
+        .list()
+        .await
+        .map_err(ErrorInternalServerError)?;
+    let filters = saved_filters(users.get_ref(), &username).await?;
+    let csrf_token = csrf::session_token(&session);
+
+    Ok(HttpResponse::Ok().body(template::template_webhooks(
+        &filters,
+        &csrf_token,
+        all_webhooks,
+    )))
+}
+
+#[post("/webhooks/create")]
+pub async fn webhooks_create(
+    webhook_store: web::Data<dyn WebhookStore>,
+    users: web::Data<dyn UserStore>,
+    id: Option<Identity>,
+    session: Session,
+    params: web::Form<HashMap<String, String>>,
+) -> actix_web::Result<HttpResponse> {
+    let Some(username) = user_logged_in(id) else {
+        return Ok(redirect("/login"));
+    };
+    webhook_manager(users.get_ref(), &username).await?;
+
+    let url = params
+        .get("url")
+        .map(|url| url.trim())
+        .filter(|url| url.starts_with("http://") || url.starts_with("https://"))
+        .ok_or_else(|| ErrorBadRequest("URL must start with http:// or https://"))?;
+    let secret = match params.get("secret").map(|secret| secret.trim()) {
+        Some(secret) if !secret.is_empty() => secret.to_string(),
+        _ => Alphanumeric.sample_string(&mut rand::thread_rng(), 32),
+    };
+    let events: Vec<WebhookEvent> = WebhookEvent::SUBSCRIBABLE
+        .into_iter()
+        .filter(|event| params.contains_key(&format!("event_{}", event.as_sql())))
+        .collect();
+
+    let webhook_id = webhook_store
+        .create(url, &secret, &events)
+        .await
+        .map_err(ErrorInternalServerError)?;
+    info!(
+        username = username.as_str();
+        "{} created webhook {} for {}",
+        username, webhook_id, url
+    );
+
+    // the secret is only shown this once
+    let webhook = webhook_store
+        .get(webhook_id)
+        .await
+        .map_err(ErrorInternalServerError)?
+        .ok_or_else(|| ErrorNotFound("No such webhook"))?;
+    let filters = saved_filters(users.get_ref(), &username).await?;
+    let csrf_token = csrf::session_token(&session);
+
+    Ok(HttpResponse::Ok().body(template::template_webhook(
+        &filters,
+        &csrf_token,
+        webhook,
+        vec![],
+        Some(secret),
+    )))
+}
+
+#[get("/webhooks/{webhook_id}")]
+pub async fn webhooks_detail(
+    webhook_store: web::Data<dyn WebhookStore>,
+    users: web::Data<dyn UserStore>,
+    id: Option<Identity>,
+    session: Session,
+    webhook_id: web::Path<i64>,
+) -> actix_web::Result<HttpResponse> {
+    let Some(username) = user_logged_in(id) else {
+        return Ok(redirect("/login"));
+    };
+    webhook_manager(users.get_ref(), &username).await?;
+
+    let webhook = webhook_store
+        .get(*webhook_id)
+        .await
+        .map_err(ErrorInternalServerError)?
+        .ok_or_else(|| ErrorNotFound("No such webhook"))?;
+    let deliveries = webhook_store
+        .deliveries(*webhook_id, 100)
+        .await
+        .map_err(ErrorInternalServerError)?;
+    let filters = saved_filters(users.get_ref(), &username).await?;
+    let csrf_token = csrf::session_token(&session);
+
+    Ok(HttpResponse::Ok().body(template::template_webhook(
+        &filters,
+        &csrf_token,
+        webhook,
+        deliveries,
+        None,
+    )))
+}
+
+#[post("/webhooks/{webhook_id}/toggle")]
+pub async fn webhooks_toggle(
+    webhook_store: web::Data<dyn WebhookStore>,
+    users: web::Data<dyn UserStore>,
+    id: Option<Identity>,
+    webhook_id: web::Path<i64>,
+) -> actix_web::Result<HttpResponse> {
+    let Some(username) = user_logged_in(id) else {
+        return Ok(redirect("/login"));
+    };
+    webhook_manager(users.get_ref(), &username).await?;
+
+    let webhook = webhook_store
+        .get(*webhook_id)
+        .await
+        .map_err(ErrorInternalServerError)?
+        .ok_or_else(|| ErrorNotFound("No such webhook"))?;
+    webhook_store
+        .set_enabled(webhook.id, !webhook.enabled)
+        .await
+        .map_err(ErrorInternalServerError)?;
+    info!(
+        username = username.as_str();
+        "{} set webhook {} enabled={}",
+        username, webhook.id, !webhook.enabled
+    );
+
+    Ok(redirect(&format!("/webhooks/{}", webhook.id)))
+}
+
+#[post("/webhooks/{webhook_id}/test")]
+pub async fn webhooks_test(
+    webhook_store: web::Data<dyn WebhookStore>,
+    users: web::Data<dyn UserStore>,
+    id: Option<Identity>,
+    webhook_id: web::Path<i64>,
+) -> actix_web::Result<HttpResponse> {
+    let Some(username) = user_logged_in(id) else {
+        return Ok(redirect("/login"));
+    };
+    webhook_manager(users.get_ref(), &username).await?;
+
+    crate::webhooks::ping(webhook_store.get_ref(), *webhook_id, &username)
+        .await
+        .map_err(ErrorInternalServerError)?;
+
+    Ok(redirect(&format!("/webhooks/{}", webhook_id)))
+}
+
+#[post("/webhooks/{webhook_id}/delete")]
+pub async fn webhooks_delete(
+    webhook_store: web::Data<dyn WebhookStore>,
+    users: web::Data<dyn UserStore>,
+    id: Option<Identity>,
+    webhook_id: web::Path<i64>,
+) -> actix_web::Result<HttpResponse> {
+    let Some(username) = user_logged_in(id) else {
+        return Ok(redirect("/login"));
+    };
+    webhook_manager(users.get_ref(), &username).await?;
+
+    if webhook_store
+        .delete(*webhook_id)
+        .await
+        .map_err(ErrorInternalServerError)?
+    {
+        info!(
+            username = username.as_str();
+            "{} deleted webhook {}",
+            username, webhook_id
+        );
+    }
+
+    Ok(redirect("/webhooks"))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use actix_web::test::TestRequest;
+
+    #[test]
+    async fn forwarded_address_only_from_trusted_proxies() {
+        let proxy: IpAddr = "10.0.0.1".parse().unwrap();
+        let request = |peer: &str| {
+            TestRequest::default()
+                .peer_addr(peer.parse().unwrap())
+                .insert_header(("x-forwarded-for", "203.0.113.7"))
+                .to_http_request()
+        };
+
+        assert_eq!(client_ip(&request("10.0.0.1:4000"), &[]), "10.0.0.1");
+        assert_eq!(
+            client_ip(&request("10.0.0.1:4000"), &[proxy]),
+            "203.0.113.7"
+        );
+        // anyone else could send the header themselves
+        assert_eq!(client_ip(&request("192.0.2.5:4000"), &[proxy]), "192.0.2.5");
+
+        let without_header = TestRequest::default()
+            .peer_addr("10.0.0.1:4000".parse().unwrap())
+            .to_http_request();
+        assert_eq!(client_ip(&without_header, &[proxy]), "10.0.0.1");
+        let with_port = TestRequest::default()
+            .peer_addr("10.0.0.1:4000".parse().unwrap())
+            .insert_header(("forwarded", "for=\"[2001:db8::1]:8000\""))
+            .to_http_request();
+        assert_eq!(client_ip(&with_port, &[proxy]), "2001:db8::1");
+        let without_port = TestRequest::default()
+            .peer_addr("10.0.0.1:4000".parse().unwrap())
+            .insert_header(("forwarded", "for=\"[2001:db8::1]\""))
+            .to_http_request();
+        assert_eq!(client_ip(&without_port, &[proxy]), "2001:db8::1");
+
+        // proxies append, so only the entries from the right are theirs
+        let chain = |header: &str, value: &str| {
+            TestRequest::default()
+                .peer_addr("10.0.0.1:4000".parse().unwrap())
+                .insert_header((header, value))
+                .to_http_request()
+        };
+        assert_eq!(
+            client_ip(&chain("x-forwarded-for", "6.6.6.6, 203.0.113.7"), &[proxy]),
+            "203.0.113.7"
+        );
+        let inner: IpAddr = "10.0.0.2".parse().unwrap();
+        assert_eq!(
+            client_ip(
+                &chain("x-forwarded-for", "6.6.6.6, 203.0.113.7, 10.0.0.2"),
+                &[proxy, inner]
+            ),
+            "203.0.113.7"
+        );
+        assert_eq!(
+            client_ip(
+                &chain("x-forwarded-for", "6.6.6.6, 203.0.113.7, 10.0.0.2"),
+                &[proxy]
+            ),
+            "10.0.0.2"
+        );
+        assert_eq!(
+            client_ip(
+                &chain("forwarded", "for=6.6.6.6, for=203.0.113.7;proto=https"),
+                &[proxy]
+            ),
+            "203.0.113.7"
+        );
+    }
+}
@@ -0,0 +1,117 @@
+use super::api::bearer_token;
+use super::csrf::tokens_match;
+use crate::metrics::{HTTP_DURATION, HTTP_REQUESTS, OVERDUE, SPORDS};
+use crate::store::SpordStore;
+use crate::CONFIG;
+use actix_web::{
+    body::MessageBody,
+    dev::{ServiceRequest, ServiceResponse},
+    error::{ErrorInternalServerError, ErrorUnauthorized},
+    middleware::Next,
+    web, Error, HttpRequest, HttpResponse,
+};
+use chrono::{Duration, Utc};
+use std::time::Instant;
+
+// Counts every request and how long it took, labelled with the route pattern
+// so ids in paths don't create new series
+pub async fn track(
+    req: ServiceRequest,
+    next: Next<impl MessageBody>,
+) -> Result<ServiceResponse<impl MessageBody>, Error> {
+    let started = Instant::now();
+    let method = req.method().to_string();
+    let route = req
+        .match_pattern()
+        .unwrap_or_else(|| "unmatched".to_string());
+
+    let result = next.call(req).await;
+
+    let status = match &result {
+        Ok(response) => response.status(),
+        Err(e) => e.as_response_error().status_code(),
+    };
+    let labels = [method.as_str(), route.as_str(), status.as_str()];
+    HTTP_REQUESTS.with_label_values(&labels).inc();
+    HTTP_DURATION
+        .with_label_values(&labels)
+        .observe(started.elapsed().as_secs_f64());
+
+    result
+}
+
+#[get("/metrics")]
+pub async fn metrics(
+    spords: web::Data<dyn SpordStore>,
+    request: HttpRequest,
+) -> actix_web::Result<HttpResponse> {
+    serve(spords.get_ref(), &request, CONFIG.metrics.token.as_deref()).await
+}
+
+// The metrics, for requests with the bearer token when one is set
+async fn serve(
+    spords: &dyn SpordStore,
+    request: &HttpRequest,
+    token: Option<&str>,
+) -> actix_web::Result<HttpResponse> {
+    if let Some(expected) = token {
+        let given = bearer_token(request).unwrap_or_default();
+        if !tokens_match(expected, &given) {
+            return Err(ErrorUnauthorized("Unauthorized"));
+        }
+    }
+
+    // the gauges are read from the store on every scrape
+    let overdue_before = Utc::now() - Duration::days(CONFIG.spords.overdue_days as i64);
+    let stats = spords
+        .stats(overdue_before)
+        .await
+        .map_err(ErrorInternalServerError)?;
+    for (state, count) in stats.by_state {
+        SPORDS.with_label_values(&[state.name()]).set(count);
+    }
+    OVERDUE.set(stats.overdue);
+
+    Ok(HttpResponse::Ok()
+        .content_type("text/plain; version=0.0.4")
+        .body(crate::metrics::render()))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::store::memory::MemoryStore;
+    use crate::testing;
+    use actix_web::http::StatusCode;
+    use actix_web::test::TestRequest;
+
+    #[actix_web::test]
+    async fn token_guards_the_metrics() {
+        testing::config();
+        let spords = MemoryStore::new();
+        let status = |result: actix_web::Result<HttpResponse>| match result {
+            Ok(response) => response.status(),
+            Err(e) => e.as_response_error().status_code(),
+        };
+
+        let without = TestRequest::get().uri("/metrics").to_http_request();
+        let wrong = TestRequest::get()
+            .uri("/metrics")
+            .insert_header(("Authorization", "Bearer guess"))
+            .to_http_request();
+        let with = TestRequest::get()
+            .uri("/metrics")
+            .insert_header(("Authorization", "Bearer secret"))
+            .to_http_request();
+
+        let result = serve(&spords, &without, Some("secret")).await;
+        assert_eq!(status(result), StatusCode::UNAUTHORIZED);
+        let result = serve(&spords, &wrong, Some("secret")).await;
+        assert_eq!(status(result), StatusCode::UNAUTHORIZED);
+        let result = serve(&spords, &with, Some("secret")).await;
+        assert_eq!(status(result), StatusCode::OK);
+        // open to anyone without a token set
+        let result = serve(&spords, &without, None).await;
+        assert_eq!(status(result), StatusCode::OK);
+    }
+}
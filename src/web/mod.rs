@@ -1,35 +1,184 @@
+use crate::config::SqlDriver;
+use crate::sql::Pool;
+use crate::store::{LocationStore, SpordStore, UserStore, WebhookStore};
 use crate::CONFIG;
-use actix_identity::{Identity, IdentityMiddleware};
-use actix_session::{storage::CookieSessionStore, SessionMiddleware};
-use actix_web::{cookie::Key, App, HttpServer};
+use actix_identity::Identity;
+use actix_web::{middleware::from_fn, web, App, HttpServer};
+use std::io;
+use std::sync::Arc;
 
+mod api;
+mod attachments;
+mod csrf;
 mod files;
+mod health;
 mod html;
+mod metrics;
+mod request_id;
+mod session;
 mod template;
+mod tls;
 
-pub async fn start() -> std::io::Result<()> {
-    let secret_key = Key::generate();
+pub async fn start(
+    pool: Pool,
+    spords: Arc<dyn SpordStore>,
+    users: Arc<dyn UserStore>,
+    locations: Arc<dyn LocationStore>,
+    webhooks: Arc<dyn WebhookStore>,
+) -> io::Result<()> {
+    let secret_key = session::load_or_create_key(&CONFIG.session.key_file)?;
+
+    actix_web::rt::spawn(crate::webhooks::run_worker(webhooks.clone()));
+    if CONFIG.backup.interval_hours > 0 {
+        if CONFIG.sql.driver == SqlDriver::Sqlite {
+            actix_web::rt::spawn(crate::sql::backup::run_schedule(pool.clone()));
+        } else {
+            warn!("Scheduled backups only cover sql.driver sqlite, back up postgres with its own tools");
+        }
+    }
+    if CONFIG.retention.interval_hours > 0 {
+        actix_web::rt::spawn(crate::retention::run_schedule(
+            spords.clone(),
+            webhooks.clone(),
+        ));
+    }
+
+    let pool = web::Data::new(pool);
+    let spords: web::Data<dyn SpordStore> = web::Data::from(spords);
+    let users: web::Data<dyn UserStore> = web::Data::from(users);
+    let locations: web::Data<dyn LocationStore> = web::Data::from(locations);
+    let webhooks: web::Data<dyn WebhookStore> = web::Data::from(webhooks);
+
+    // with metrics.listen set /metrics is only served there
+    let metrics_on_main = CONFIG.metrics.enabled && CONFIG.metrics.listen.is_none();
+    if let (true, Some(address)) = (CONFIG.metrics.enabled, &CONFIG.metrics.listen) {
+        info!("Will serve metrics on http://{}/metrics", address);
+        let spords = spords.clone();
+        let metrics_server = HttpServer::new(move || {
+            App::new()
+                .app_data(spords.clone())
+                .service(metrics::metrics)
+        })
+        .workers(1)
+        .bind(address)?
+        .run();
+        actix_web::rt::spawn(async {
+            if let Err(e) = metrics_server.await {
+                error!("Metrics server stopped: {}", e);
+            }
+        });
+    }
 
     let server = HttpServer::new(move || {
         App::new()
-            .wrap(actix_web::middleware::Logger::default())
-            .wrap(IdentityMiddleware::default())
-            .wrap(SessionMiddleware::new(
-                CookieSessionStore::default(),
+            .app_data(pool.clone())
+            .app_data(spords.clone())
+            .app_data(users.clone())
+            .app_data(locations.clone())
+            .app_data(webhooks.clone())
+            .wrap(from_fn(csrf::check))
+            .wrap(request_id::access_logger())
+            .wrap(session::identity_middleware())
+            .wrap(session::session_middleware(
                 secret_key.clone(),
+                users.clone().into_inner(),
             ))
+            .wrap(from_fn(metrics::track))
+            .wrap(from_fn(request_id::assign))
+            .configure(|config| {
+                if metrics_on_main {
+                    config.service(metrics::metrics);
+                }
+            })
+            .service(health::healthz)
+            .service(health::readyz)
             .service(html::index)
             .service(html::login)
             .service(html::login_post)
+            .service(html::logout)
+            .service(html::reports)
+            .service(html::reports_csv)
             .service(html::js_file)
+            .service(html::spords_list)
+            .service(html::spords_detail)
+            .service(html::spords_tags)
+            .service(html::spords_archive)
+            .service(html::spords_restore)
+            .service(html::spords_notes_add)
+            .service(html::spords_notes_edit)
+            .service(html::spords_attachments_upload)
+            .service(html::spords_attachments_download)
+            .service(html::spords_attachments_delete)
+            .service(html::filters_save)
+            .service(html::filters_delete)
+            .service(html::tokens)
+            .service(html::tokens_create)
+            .service(html::tokens_revoke)
+            .service(html::webhooks)
+            .service(html::webhooks_create)
+            .service(html::webhooks_detail)
+            .service(html::webhooks_toggle)
+            .service(html::webhooks_test)
+            .service(html::webhooks_delete)
+            .service(api::spords_list)
+            .service(api::spords_get)
+            .service(api::spords_create)
+            .service(api::spords_update)
+            .service(api::spords_archive)
+            .service(api::spords_restore)
+            .service(api::spords_transfer)
+            .service(api::spords_events)
+            .service(api::notes_list)
+            .service(api::notes_add)
+            .service(api::notes_edit)
+            .service(api::attachments_list)
+            .service(api::attachments_upload)
+            .service(api::attachments_download)
+            .service(api::attachments_delete)
+            .service(api::tags_list)
+            .service(api::locations_list)
+            .service(api::log_spec_get)
+            .service(api::log_spec_set)
+            .service(api::log_spec_reset)
     });
 
     let listen_address = &CONFIG.web.listen;
     let https = CONFIG.web.https.unwrap_or(false);
 
     if https {
+        let cert_file = CONFIG.web.key_file.as_deref().ok_or_else(|| {
+            io::Error::new(
+                io::ErrorKind::InvalidInput,
+                "web.key_file is required for https",
+            )
+        })?;
+        let key_file = CONFIG.web.privkey_file.as_deref().ok_or_else(|| {
+            io::Error::new(
+                io::ErrorKind::InvalidInput,
+                "web.privkey_file is required for https",
+            )
+        })?;
+
+        let resolver = Arc::new(tls::CertResolver::new(cert_file, key_file)?);
+        actix_web::rt::spawn(tls::reload_on_sighup(resolver.clone()));
+
         info!("Will listen on https://{}/", listen_address);
-        unimplemented!("actix-web https");
+        let server = server
+            .bind_rustls_0_23(listen_address, tls::server_config(resolver))?
+            .run();
+
+        if let Some(ref redirect_address) = CONFIG.web.redirect_listen {
+            info!("Will redirect http://{}/ to https", redirect_address);
+            let redirect_server = HttpServer::new(|| {
+                App::new().default_service(actix_web::web::to(tls::redirect_to_https))
+            })
+            .bind(redirect_address)?
+            .run();
+
+            tokio::try_join!(server, redirect_server)?;
+        } else {
+            server.await?;
+        }
     } else {
         info!("Will listen on http://{}/", listen_address);
         server.bind(listen_address)?.run().await?;
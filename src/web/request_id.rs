@@ -0,0 +1,153 @@
+use crate::logging::REQUEST_ID;
+use actix_web::{
+    body::{BoxBody, MessageBody},
+    dev::{ServiceRequest, ServiceResponse},
+    error::InternalError,
+    http::header::{HeaderName, HeaderValue},
+    middleware::{Logger, Next},
+    Error, HttpMessage, HttpResponse,
+};
+use rand::distributions::{Alphanumeric, DistString};
+
+pub const HEADER_NAME: &str = "X-Request-Id";
+
+// The id given to a request, kept in its extensions for the access log
+#[derive(Clone)]
+pub struct RequestId(pub String);
+
+// Ids from proxies are kept as long as they are short and plain
+fn incoming_id(req: &ServiceRequest) -> Option<String> {
+    let id = req.headers().get(HEADER_NAME)?.to_str().ok()?;
+    let valid = !id.is_empty()
+        && id.len() <= 64
+        && id
+            .chars()
+            .all(|c| c.is_ascii_alphanumeric() || matches!(c, '-' | '_' | '.'));
+
+    valid.then(|| id.to_string())
+}
+
+// Error responses are plain text, staff can quote the id from the page
+fn error_response(error: &Error, request_id: &str) -> HttpResponse {
+    HttpResponse::build(error.as_response_error().status_code())
+        .content_type("text/plain; charset=utf-8")
+        .insert_header((HEADER_NAME, request_id))
+        .body(format!("{}\n\nRequest ID: {}", error, request_id))
+}
+
+// Gives every request an id, taken from X-Request-Id or generated. Log records
+// written while handling it carry the id, and it is sent back in the
+// X-Request-Id header and on error pages
+pub async fn assign(
+    req: ServiceRequest,
+    next: Next<impl MessageBody + 'static>,
+) -> Result<ServiceResponse<BoxBody>, Error> {
+    let request_id = incoming_id(&req)
+        .unwrap_or_else(|| Alphanumeric.sample_string(&mut rand::thread_rng(), 16));
+    let description = format!("{} {}", req.method(), req.path());
+    req.extensions_mut().insert(RequestId(request_id.clone()));
+
+    REQUEST_ID
+        .scope(request_id.clone(), async move {
+            let response = match next.call(req).await {
+                Ok(response) => match response.response().error() {
+                    Some(error) => {
+                        let replacement = error_response(error, &request_id);
+                        if replacement.status().is_server_error() {
+                            error!("{} failed: {}", description, error);
+                        }
+                        response.into_response(replacement)
+                    }
+                    None => response.map_into_boxed_body(),
+                },
+                // errors from other middleware, there is no request to
+                // build a response with any more
+                Err(error) => {
+                    let replacement = error_response(&error, &request_id);
+                    if replacement.status().is_server_error() {
+                        error!("{} failed: {}", description, error);
+                    }
+                    return Err(InternalError::from_response(error, replacement).into());
+                }
+            };
+
+            let mut response = response;
+            if let Ok(value) = HeaderValue::from_str(&request_id) {
+                response
+                    .headers_mut()
+                    .insert(HeaderName::from_static("x-request-id"), value);
+            }
+
+            Ok(response)
+        })
+        .await
+}
+
+// actix's default access line with the request id on the end. The line is
+// written once the body is sent, after assign has returned, so the id can't
+// come from the task local like other records
+pub fn access_logger() -> Logger {
+    Logger::new(r#"%a "%r" %s %b "%{Referer}i" "%{User-Agent}i" %T [request %{request_id}xi]"#)
+        .custom_request_replace("request_id", |req| {
+            req.extensions()
+                .get::<RequestId>()
+                .map(|id| id.0.clone())
+                .unwrap_or_else(|| "-".to_string())
+        })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use actix_web::{middleware::from_fn, test, web, App};
+    use log::{Level, LevelFilter, Log, Metadata, Record};
+    use std::sync::Mutex;
+
+    const TARGET: &str = "request_id_test";
+
+    // keeps the access lines written for TARGET
+    struct Capture(Mutex<Vec<String>>);
+    impl Log for Capture {
+        fn enabled(&self, metadata: &Metadata) -> bool {
+            metadata.target() == TARGET && metadata.level() <= Level::Info
+        }
+        fn log(&self, record: &Record) {
+            if self.enabled(record.metadata()) {
+                self.0.lock().unwrap().push(record.args().to_string());
+            }
+        }
+        fn flush(&self) {}
+    }
+    static CAPTURE: Capture = Capture(Mutex::new(vec![]));
+
+    #[actix_web::test]
+    async fn access_line_carries_the_request_id() {
+        // no other test installs a logger
+        log::set_logger(&CAPTURE).unwrap();
+        log::set_max_level(LevelFilter::Info);
+
+        let app = test::init_service(
+            App::new()
+                .wrap(access_logger().log_target(TARGET))
+                .wrap(from_fn(assign))
+                .route("/healthz", web::get().to(HttpResponse::Ok)),
+        )
+        .await;
+        let response = test::call_service(
+            &app,
+            test::TestRequest::get()
+                .uri("/healthz")
+                .insert_header((HEADER_NAME, "ghi789"))
+                .to_request(),
+        )
+        .await;
+        assert_eq!(response.headers().get(HEADER_NAME).unwrap(), "ghi789");
+        // the line is written when the body is dropped
+        drop(test::read_body(response).await);
+
+        let lines = CAPTURE.0.lock().unwrap();
+        assert_eq!(lines.len(), 1);
+        assert!(lines[0].contains("GET /healthz"), "{}", lines[0]);
+        assert!(lines[0].ends_with("[request ghi789]"), "{}", lines[0]);
+    }
+}
@@ -0,0 +1,193 @@
+use crate::store::UserStore;
+use crate::CONFIG;
+use actix_identity::IdentityMiddleware;
+use actix_session::{
+    config::BrowserSession,
+    storage::{CookieSessionStore, LoadError, SaveError, SessionKey, SessionStore, UpdateError},
+    SessionMiddleware,
+};
+use actix_web::cookie::{time::Duration, Key, SameSite};
+use chrono::Utc;
+use rand::distributions::{Alphanumeric, DistString};
+use std::collections::HashMap;
+use std::io::{self, Write};
+use std::sync::Arc;
+
+const IDENTITY_KEY: &str = "actix_identity.user_id";
+
+// Reads the cookie signing key, or creates it on first start so sessions
+// survive restarts
+pub fn load_or_create_key(path: &str) -> io::Result<Key> {
+    if std::fs::exists(path)? {
+        let bytes = std::fs::read(path)?;
+        Key::try_from(bytes.as_slice()).map_err(|e| {
+            io::Error::new(
+                io::ErrorKind::InvalidData,
+                format!("Session key file {} is invalid: {}", path, e),
+            )
+        })
+    } else {
+        let key = Key::generate();
+
+        let mut options = std::fs::OpenOptions::new();
+        options.write(true).create_new(true);
+        #[cfg(unix)]
+        {
+            use std::os::unix::fs::OpenOptionsExt;
+            options.mode(0o600);
+        }
+        options.open(path)?.write_all(key.master())?;
+        info!("Generated new session key in {}", path);
+
+        Ok(key)
+    }
+}
+
+pub fn identity_middleware() -> IdentityMiddleware {
+    // 0 turns a timeout off, toml has no way to write null
+    let to_duration = |seconds: Option<u64>| {
+        seconds
+            .filter(|seconds| *seconds > 0)
+            .map(std::time::Duration::from_secs)
+    };
+
+    IdentityMiddleware::builder()
+        .visit_deadline(to_duration(CONFIG.session.idle_timeout_seconds))
+        .login_deadline(to_duration(CONFIG.session.absolute_timeout_seconds))
+        .build()
+}
+
+pub fn session_middleware(
+    key: Key,
+    users: Arc<dyn UserStore>,
+) -> SessionMiddleware<AppSessionStore> {
+    let store = if CONFIG.session.database_store {
+        AppSessionStore::Stored(users)
+    } else {
+        AppSessionStore::Cookie(CookieSessionStore::default())
+    };
+
+    // stored sessions are dropped once they could no longer pass the login deadline
+    let ttl = CONFIG
+        .session
+        .absolute_timeout_seconds
+        .filter(|seconds| *seconds > 0)
+        .map(|seconds| Duration::seconds(seconds as i64))
+        .unwrap_or(Duration::days(1));
+
+    SessionMiddleware::builder(store, key)
+        .cookie_same_site(SameSite::Lax)
+        .session_lifecycle(BrowserSession::default().state_ttl(ttl))
+        .build()
+}
+
+pub enum AppSessionStore {
+    Cookie(CookieSessionStore),
+    // in the database of sql.driver, next to the users
+    Stored(Arc<dyn UserStore>),
+}
+
+fn session_username(state: &HashMap<String, String>) -> Option<String> {
+    let id: String = serde_json::from_str(state.get(IDENTITY_KEY)?).ok()?;
+    id.strip_prefix("user:")
+        .map(|username| username.to_string())
+}
+
+fn expires_at(ttl: &Duration) -> chrono::DateTime<Utc> {
+    Utc::now() + chrono::Duration::seconds(ttl.whole_seconds())
+}
+
+async fn stored_save(
+    users: &dyn UserStore,
+    key: &str,
+    state: HashMap<String, String>,
+    ttl: &Duration,
+) -> anyhow::Result<()> {
+    let username = session_username(&state);
+    let state = serde_json::to_string(&state)?;
+    users
+        .save_session(key, username, state, expires_at(ttl))
+        .await?;
+
+    Ok(())
+}
+
+impl SessionStore for AppSessionStore {
+    async fn load(
+        &self,
+        session_key: &SessionKey,
+    ) -> Result<Option<HashMap<String, String>>, LoadError> {
+        match self {
+            Self::Cookie(store) => store.load(session_key).await,
+            Self::Stored(users) => {
+                let state = users
+                    .load_session(session_key.as_ref())
+                    .await
+                    .map_err(|e| LoadError::Other(e.into()))?;
+
+                state
+                    .map(|state| serde_json::from_str(&state))
+                    .transpose()
+                    .map_err(|e| LoadError::Deserialization(e.into()))
+            }
+        }
+    }
+
+    async fn save(
+        &self,
+        session_state: HashMap<String, String>,
+        ttl: &Duration,
+    ) -> Result<SessionKey, SaveError> {
+        match self {
+            Self::Cookie(store) => store.save(session_state, ttl).await,
+            Self::Stored(users) => {
+                let key = Alphanumeric.sample_string(&mut rand::thread_rng(), 64);
+                stored_save(users.as_ref(), &key, session_state, ttl)
+                    .await
+                    .map_err(SaveError::Other)?;
+
+                SessionKey::try_from(key).map_err(|e| SaveError::Other(e.into()))
+            }
+        }
+    }
+
+    async fn update(
+        &self,
+        session_key: SessionKey,
+        session_state: HashMap<String, String>,
+        ttl: &Duration,
+    ) -> Result<SessionKey, UpdateError> {
+        match self {
+            Self::Cookie(store) => store.update(session_key, session_state, ttl).await,
+            Self::Stored(users) => {
+                stored_save(users.as_ref(), session_key.as_ref(), session_state, ttl)
+                    .await
+                    .map_err(UpdateError::Other)?;
+
+                Ok(session_key)
+            }
+        }
+    }
+
+    async fn update_ttl(&self, session_key: &SessionKey, ttl: &Duration) -> anyhow::Result<()> {
+        match self {
+            Self::Cookie(store) => store.update_ttl(session_key, ttl).await,
+            Self::Stored(users) => {
+                users
+                    .set_session_expiry(session_key.as_ref(), expires_at(ttl))
+                    .await?;
+                Ok(())
+            }
+        }
+    }
+
+    async fn delete(&self, session_key: &SessionKey) -> anyhow::Result<()> {
+        match self {
+            Self::Cookie(store) => store.delete(session_key).await,
+            Self::Stored(users) => {
+                users.delete_session(session_key.as_ref()).await?;
+                Ok(())
+            }
+        }
+    }
+}
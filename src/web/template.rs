@@ -1,7 +1,20 @@
-use std::collections::BTreeMap;
-
-use handlebars::Handlebars;
+use super::csrf;
+use crate::reports::{self, DurationStats, ReportGroup, ReportRow};
+use crate::sql::models::{
+    ApiTokenRecord, AttachmentRecord, Dashboard, LocationRecord, NoteRecord, SavedFilterRecord,
+    SpordEventKind, SpordEventRecord, SpordRecord, SpordState, WebhookDeliveryRecord, WebhookEvent,
+    WebhookRecord,
+};
+use crate::sql::SpordFilter;
+use crate::CONFIG;
+use chrono::Utc;
+use handlebars::{
+    Context, Handlebars, Helper, HelperResult, Output, RenderContext, RenderErrorReason,
+};
 use serde::Serialize;
+use std::collections::HashMap;
+
+const DATE_FORMAT: &str = "%Y-%m-%d %H:%M";
 
 lazy_static! {
     static ref HANDLEBARS: Handlebars<'static> = load_templates();
@@ -10,6 +23,8 @@ lazy_static! {
 pub fn load_templates() -> Handlebars<'static> {
     let mut handlebars = Handlebars::new();
 
+    handlebars.register_helper("csrf_field", Box::new(csrf_field_helper));
+
     handlebars
         .register_template_string("header", include_str!("../../web/html/header.html"))
         .unwrap();
@@ -22,35 +37,670 @@ pub fn load_templates() -> Handlebars<'static> {
     handlebars
         .register_template_string("login", include_str!("../../web/html/login.html"))
         .unwrap();
+    handlebars
+        .register_template_string("tokens", include_str!("../../web/html/tokens.html"))
+        .unwrap();
+    handlebars
+        .register_template_string("webhooks", include_str!("../../web/html/webhooks.html"))
+        .unwrap();
+    handlebars
+        .register_template_string("webhook", include_str!("../../web/html/webhook.html"))
+        .unwrap();
+    handlebars
+        .register_template_string("reports", include_str!("../../web/html/reports.html"))
+        .unwrap();
+    handlebars
+        .register_template_string("spord", include_str!("../../web/html/spord.html"))
+        .unwrap();
+    handlebars
+        .register_template_string("spords", include_str!("../../web/html/spords.html"))
+        .unwrap();
 
     handlebars
 }
 
-pub fn template_index() -> String {
-    let header = template_header("Index");
+#[derive(Debug, Serialize)]
+struct StateCount {
+    pub state: &'static str,
+    pub count: i64,
+}
+
+#[derive(Debug, Serialize)]
+struct OpenSpord {
+    pub id: i32,
+    pub customer_name: String,
+    pub part: String,
+    pub state: &'static str,
+    pub location: String,
+    pub created: String,
+    pub age_days: i64,
+}
+
+#[derive(Debug, Serialize)]
+struct IndexData {
+    // which store the numbers are for
+    pub scope: String,
+    pub by_state: Vec<StateCount>,
+    pub created_this_week: i64,
+    pub awaiting_pickup: i64,
+    pub overdue: i64,
+    pub overdue_days: u32,
+    pub oldest_open: Vec<OpenSpord>,
+    // only managers look after webhooks
+    pub manager: bool,
+}
+
+pub fn template_index(
+    filters: &[SavedFilterRecord],
+    scope: &str,
+    dashboard: Dashboard,
+    locations: &HashMap<i64, String>,
+    overdue_days: u32,
+    manager: bool,
+) -> String {
+    let header = template_header("Dashboard", filters);
+    let footer = template_footer();
+
+    let now = Utc::now();
+    let data = IndexData {
+        scope: scope.to_string(),
+        by_state: dashboard
+            .by_state
+            .into_iter()
+            .map(|(state, count)| StateCount {
+                state: state.name(),
+                count,
+            })
+            .collect(),
+        created_this_week: dashboard.created_since,
+        awaiting_pickup: dashboard.awaiting_pickup,
+        overdue: dashboard.overdue,
+        overdue_days,
+        oldest_open: dashboard
+            .oldest_open
+            .into_iter()
+            .map(|spord| OpenSpord {
+                id: spord.id,
+                customer_name: spord.customer_name,
+                part: spord.part,
+                state: spord.state.name(),
+                location: spord
+                    .location
+                    .and_then(|location| locations.get(&location).cloned())
+                    .unwrap_or_default(),
+                created: spord.creation_date.format(DATE_FORMAT).to_string(),
+                age_days: (now - spord.creation_date).num_days(),
+            })
+            .collect(),
+        manager,
+    };
+    let body = HANDLEBARS.render("index", &data).unwrap();
+
+    format!("{}{}{}", header, body, footer)
+}
+
+#[derive(Debug, Serialize)]
+struct ReportGroupTab {
+    pub name: &'static str,
+    pub title: &'static str,
+    pub active: bool,
+}
+
+#[derive(Debug, Serialize)]
+struct ReportDurations {
+    pub count: usize,
+    pub average: String,
+    pub median: String,
+    pub p90: String,
+}
+impl From<DurationStats> for ReportDurations {
+    fn from(stats: DurationStats) -> ReportDurations {
+        ReportDurations {
+            count: stats.count,
+            average: reports::format_days(stats.average),
+            median: reports::format_days(stats.median),
+            p90: reports::format_days(stats.p90),
+        }
+    }
+}
+
+#[derive(Debug, Serialize)]
+struct ReportTableRow {
+    pub group: String,
+    pub spords: usize,
+    pub created_to_received: ReportDurations,
+    pub received_to_picked_up: ReportDurations,
+}
+
+#[derive(Debug, Serialize)]
+struct ReportsData {
+    pub scope: String,
+    pub group: &'static str,
+    pub group_title: &'static str,
+    pub groups: Vec<ReportGroupTab>,
+    pub months: u32,
+    pub rows: Vec<ReportTableRow>,
+}
+
+pub fn template_reports(
+    filters: &[SavedFilterRecord],
+    scope: &str,
+    group: ReportGroup,
+    months: u32,
+    rows: Vec<ReportRow>,
+) -> String {
+    let header = template_header("Reports", filters);
+    let footer = template_footer();
+
+    let data = ReportsData {
+        scope: scope.to_string(),
+        group: group.name(),
+        group_title: group.title(),
+        groups: ReportGroup::ALL
+            .into_iter()
+            .map(|tab| ReportGroupTab {
+                name: tab.name(),
+                title: tab.title(),
+                active: tab == group,
+            })
+            .collect(),
+        months,
+        rows: rows
+            .into_iter()
+            .map(|row| ReportTableRow {
+                group: row.group,
+                spords: row.spords,
+                created_to_received: row.created_to_received.into(),
+                received_to_picked_up: row.received_to_picked_up.into(),
+            })
+            .collect(),
+    };
+    let body = HANDLEBARS.render("reports", &data).unwrap();
+
+    format!("{}{}{}", header, body, footer)
+}
+
+// Byte counts for people, e.g. 1.5 MiB
+fn format_size(bytes: u64) -> String {
+    const UNITS: [&str; 4] = ["KiB", "MiB", "GiB", "TiB"];
+    if bytes < 1024 {
+        return format!("{} B", bytes);
+    }
+
+    let mut size = bytes as f64 / 1024.0;
+    let mut unit = 0;
+    while size >= 1024.0 && unit < UNITS.len() - 1 {
+        size /= 1024.0;
+        unit += 1;
+    }
+    format!("{:.1} {}", size, UNITS[unit])
+}
+
+#[derive(Debug, Serialize)]
+struct StateOption {
+    pub name: &'static str,
+    pub selected: bool,
+}
+
+#[derive(Debug, Serialize)]
+struct SpordRow {
+    pub id: i32,
+    pub customer_name: String,
+    pub part: String,
+    pub vendor: String,
+    pub state: &'static str,
+    pub tags: Vec<String>,
+    pub location: String,
+    pub created: String,
+    pub archived: String,
+}
+
+#[derive(Debug, Serialize)]
+struct SpordsData<'a> {
+    pub csrf_token: &'a str,
+    pub scope: &'a str,
+    pub states: Vec<StateOption>,
+    // the filter as typed in the form
+    pub state: &'static str,
+    pub tags: String,
+    pub q: String,
+    pub archived: bool,
+    // saved filters only cover the active spords
+    pub filtered: bool,
+    pub all_tags: Vec<String>,
+    pub spords: Vec<SpordRow>,
+    pub saved_filters: Vec<NavFilter>,
+}
+
+pub fn template_spords(
+    filters: &[SavedFilterRecord],
+    csrf_token: &str,
+    scope: &str,
+    filter: &SpordFilter,
+    spords: Vec<SpordRecord>,
+    all_tags: Vec<String>,
+    locations: &HashMap<i64, String>,
+) -> String {
+    let header = template_header("Spords", filters);
+    let footer = template_footer();
+
+    let data = SpordsData {
+        csrf_token,
+        scope,
+        states: SpordState::ALL
+            .iter()
+            .map(|state| StateOption {
+                name: state.name(),
+                selected: filter.state.as_ref() == Some(state),
+            })
+            .collect(),
+        state: filter
+            .state
+            .as_ref()
+            .map(|state| state.name())
+            .unwrap_or_default(),
+        tags: filter.tags.join(", "),
+        q: filter.search.clone().unwrap_or_default(),
+        archived: filter.archived,
+        filtered: !filter.archived
+            && (filter.state.is_some() || filter.search.is_some() || !filter.tags.is_empty()),
+        all_tags,
+        spords: spords
+            .into_iter()
+            .map(|spord| SpordRow {
+                id: spord.id,
+                customer_name: spord.customer_name,
+                part: spord.part,
+                vendor: spord.vendor.unwrap_or_default(),
+                state: spord.state.name(),
+                tags: spord.tags,
+                location: spord
+                    .location
+                    .and_then(|location| locations.get(&location).cloned())
+                    .unwrap_or_default(),
+                created: spord.creation_date.format(DATE_FORMAT).to_string(),
+                archived: spord
+                    .archived
+                    .map(|date| date.format(DATE_FORMAT).to_string())
+                    .unwrap_or_default(),
+            })
+            .collect(),
+        saved_filters: nav_filters(filters),
+    };
+    let body = HANDLEBARS.render("spords", &data).unwrap();
+
+    format!("{}{}{}", header, body, footer)
+}
+
+#[derive(Debug, Serialize)]
+struct SpordDetails {
+    pub id: i32,
+    pub customer_name: String,
+    pub customer_phone: String,
+    pub customer_email: String,
+    pub part: String,
+    pub vendor: String,
+    pub state: &'static str,
+    pub location: String,
+    pub created: String,
+    pub received: String,
+    pub tags: Vec<String>,
+    // for the tag form
+    pub tags_text: String,
+    // empty unless archived
+    pub archived: String,
+    pub anonymized: String,
+}
+
+#[derive(Debug, Serialize)]
+struct EventRow {
+    pub time: String,
+    pub description: String,
+    pub username: String,
+}
+
+#[derive(Debug, Serialize)]
+struct RevisionRow {
+    pub text: String,
+    pub username: String,
+    pub time: String,
+}
+
+#[derive(Debug, Serialize)]
+struct NoteRow {
+    pub id: i64,
+    pub username: String,
+    pub text: String,
+    pub created: String,
+    // empty unless the note was changed
+    pub edited: String,
+    pub edited_by: String,
+    pub revisions: Vec<RevisionRow>,
+    pub can_edit: bool,
+}
+
+#[derive(Debug, Serialize)]
+struct AttachmentRow {
+    pub id: i64,
+    pub filename: String,
+    pub mime_type: String,
+    pub size: String,
+    pub username: String,
+    pub created: String,
+    pub can_delete: bool,
+}
+
+#[derive(Debug, Serialize)]
+struct SpordData<'a> {
+    pub csrf_token: &'a str,
+    pub spord: SpordDetails,
+    pub can_restore: bool,
+    pub events: Vec<EventRow>,
+    pub notes: Vec<NoteRow>,
+    pub attachments: Vec<AttachmentRow>,
+    pub max_file_size: String,
+}
+
+pub fn template_spord(
+    filters: &[SavedFilterRecord],
+    csrf_token: &str,
+    (spord, can_restore): (SpordRecord, bool),
+    events: Vec<SpordEventRecord>,
+    notes: Vec<(NoteRecord, bool)>,
+    attachments: Vec<(AttachmentRecord, bool)>,
+    locations: &HashMap<i64, String>,
+) -> String {
+    let header = template_header(&format!("Spord {}", spord.id), filters);
     let footer = template_footer();
 
-    let body = HANDLEBARS.render("index", &()).unwrap();
+    let location_name = |location: Option<i64>| {
+        location
+            .and_then(|location| locations.get(&location).cloned())
+            .unwrap_or_else(|| "no store".to_string())
+    };
+    let state_name = |state: Option<i64>| {
+        state
+            .map(|state| SpordState::from_sql(state as i32).name())
+            .unwrap_or_default()
+    };
+
+    let data = SpordData {
+        csrf_token,
+        spord: SpordDetails {
+            id: spord.id,
+            customer_name: spord.customer_name,
+            customer_phone: spord.customer_phone.unwrap_or_default(),
+            customer_email: spord.customer_email.unwrap_or_default(),
+            part: spord.part,
+            vendor: spord.vendor.unwrap_or_default(),
+            state: spord.state.name(),
+            location: location_name(spord.location),
+            created: spord.creation_date.format(DATE_FORMAT).to_string(),
+            received: spord
+                .received_date
+                .map(|date| date.format(DATE_FORMAT).to_string())
+                .unwrap_or_default(),
+            tags_text: spord.tags.join(", "),
+            tags: spord.tags,
+            archived: spord
+                .archived
+                .map(|date| date.format(DATE_FORMAT).to_string())
+                .unwrap_or_default(),
+            anonymized: spord
+                .anonymized
+                .map(|date| date.format(DATE_FORMAT).to_string())
+                .unwrap_or_default(),
+        },
+        can_restore,
+        events: events
+            .into_iter()
+            .map(|event| EventRow {
+                time: event.time.format(DATE_FORMAT).to_string(),
+                description: match event.kind {
+                    SpordEventKind::Transfer => format!(
+                        "Moved from {} to {}",
+                        location_name(event.old_value),
+                        location_name(event.new_value)
+                    ),
+                    SpordEventKind::State => format!(
+                        "State changed from {} to {}",
+                        state_name(event.old_value),
+                        state_name(event.new_value)
+                    ),
+                    SpordEventKind::Archive => "Archived".to_string(),
+                    SpordEventKind::Restore => "Restored".to_string(),
+                    SpordEventKind::Other => "Changed".to_string(),
+                },
+                username: event.username.unwrap_or_default(),
+            })
+            .collect(),
+        notes: notes
+            .into_iter()
+            .map(|(note, can_edit)| NoteRow {
+                id: note.id,
+                username: note.username.unwrap_or_else(|| "-".to_string()),
+                text: note.text,
+                created: note.created.format(DATE_FORMAT).to_string(),
+                edited: note
+                    .edited
+                    .map(|edited| edited.format(DATE_FORMAT).to_string())
+                    .unwrap_or_default(),
+                edited_by: note.edited_by.unwrap_or_else(|| "-".to_string()),
+                revisions: note
+                    .revisions
+                    .into_iter()
+                    .map(|revision| RevisionRow {
+                        text: revision.text,
+                        username: revision.username.unwrap_or_else(|| "-".to_string()),
+                        time: revision.time.format(DATE_FORMAT).to_string(),
+                    })
+                    .collect(),
+                can_edit,
+            })
+            .collect(),
+        attachments: attachments
+            .into_iter()
+            .map(|(attachment, can_delete)| AttachmentRow {
+                id: attachment.id,
+                filename: attachment.filename,
+                mime_type: attachment.mime_type,
+                size: format_size(attachment.size as u64),
+                username: attachment.username.unwrap_or_default(),
+                created: attachment.created.format(DATE_FORMAT).to_string(),
+                can_delete,
+            })
+            .collect(),
+        max_file_size: format_size(CONFIG.attachments.max_file_bytes),
+    };
+    let body = HANDLEBARS.render("spord", &data).unwrap();
 
     format!("{}{}{}", header, body, footer)
 }
 
-pub fn template_login() -> String {
-    let header = template_header("Login");
+// Renders a hidden input holding the csrf_token of the template data, use it
+// as {{csrf_field}} inside every form that posts
+fn csrf_field_helper(
+    _: &Helper,
+    _: &Handlebars,
+    ctx: &Context,
+    _: &mut RenderContext,
+    out: &mut dyn Output,
+) -> HelperResult {
+    let token = ctx
+        .data()
+        .get("csrf_token")
+        .and_then(|token| token.as_str())
+        .ok_or(RenderErrorReason::Other(
+            "csrf_field used without csrf_token".to_string(),
+        ))?;
+
+    out.write(&format!(
+        "<input type=\"hidden\" name=\"{}\" value=\"{}\">",
+        csrf::FIELD_NAME,
+        handlebars::html_escape(token)
+    ))?;
+    Ok(())
+}
+
+#[derive(Debug, Serialize)]
+struct FormData<'a> {
+    pub csrf_token: &'a str,
+}
+
+pub fn template_login(csrf_token: &str) -> String {
+    let header = template_header("Login", &[]);
     let footer = template_footer();
 
-    let body = HANDLEBARS.render("login", &()).unwrap();
+    let body = HANDLEBARS
+        .render("login", &FormData { csrf_token })
+        .unwrap();
 
     format!("{}{}{}", header, body, footer)
 }
 
+#[derive(Debug, Serialize)]
+struct TokenRow {
+    #[serde(flatten)]
+    pub token: ApiTokenRecord,
+    // what a service token is limited to, empty for user tokens
+    pub store: String,
+}
+
+#[derive(Debug, Serialize)]
+struct TokensData<'a> {
+    pub csrf_token: &'a str,
+    pub tokens: Vec<TokenRow>,
+    pub new_token: Option<String>,
+    // only managers create service tokens
+    pub manager: bool,
+    pub locations: Vec<LocationRecord>,
+}
+
+pub fn template_tokens(
+    filters: &[SavedFilterRecord],
+    csrf_token: &str,
+    tokens: Vec<ApiTokenRecord>,
+    new_token: Option<String>,
+    manager: bool,
+    locations: Vec<LocationRecord>,
+) -> String {
+    let header = template_header("API Tokens", filters);
+    let footer = template_footer();
+
+    let tokens = tokens
+        .into_iter()
+        .map(|token| {
+            let store = match (&token.username, token.location) {
+                (Some(_), _) => String::new(),
+                (None, None) => "All stores".to_string(),
+                (None, Some(id)) => locations
+                    .iter()
+                    .find(|location| location.id == id)
+                    .map(|location| location.name.clone())
+                    .unwrap_or_default(),
+            };
+            TokenRow { token, store }
+        })
+        .collect();
+
+    let data = TokensData {
+        csrf_token,
+        tokens,
+        new_token,
+        manager,
+        locations,
+    };
+    let body = HANDLEBARS.render("tokens", &data).unwrap();
+
+    format!("{}{}{}", header, body, footer)
+}
+
+#[derive(Debug, Serialize)]
+struct WebhooksData<'a> {
+    pub csrf_token: &'a str,
+    pub webhooks: Vec<WebhookRecord>,
+    pub events: Vec<&'static str>,
+}
+
+pub fn template_webhooks(
+    filters: &[SavedFilterRecord],
+    csrf_token: &str,
+    webhooks: Vec<WebhookRecord>,
+) -> String {
+    let header = template_header("Webhooks", filters);
+    let footer = template_footer();
+
+    let data = WebhooksData {
+        csrf_token,
+        webhooks,
+        events: WebhookEvent::SUBSCRIBABLE
+            .iter()
+            .map(|event| event.as_sql())
+            .collect(),
+    };
+    let body = HANDLEBARS.render("webhooks", &data).unwrap();
+
+    format!("{}{}{}", header, body, footer)
+}
+
+#[derive(Debug, Serialize)]
+struct WebhookData<'a> {
+    pub csrf_token: &'a str,
+    pub webhook: WebhookRecord,
+    pub deliveries: Vec<WebhookDeliveryRecord>,
+    // only right after creating the webhook
+    pub new_secret: Option<String>,
+}
+
+pub fn template_webhook(
+    filters: &[SavedFilterRecord],
+    csrf_token: &str,
+    webhook: WebhookRecord,
+    deliveries: Vec<WebhookDeliveryRecord>,
+    new_secret: Option<String>,
+) -> String {
+    let header = template_header("Webhook", filters);
+    let footer = template_footer();
+
+    let data = WebhookData {
+        csrf_token,
+        webhook,
+        deliveries,
+        new_secret,
+    };
+    let body = HANDLEBARS.render("webhook", &data).unwrap();
+
+    format!("{}{}{}", header, body, footer)
+}
+
+#[derive(Debug, Serialize)]
+struct NavFilter {
+    pub id: i64,
+    pub name: String,
+    pub query: String,
+}
+
+fn nav_filters(filters: &[SavedFilterRecord]) -> Vec<NavFilter> {
+    filters
+        .iter()
+        .map(|filter| NavFilter {
+            id: filter.id,
+            name: filter.name.clone(),
+            query: filter.query(),
+        })
+        .collect()
+}
+
 #[derive(Debug, Serialize)]
 struct HeaderData {
     pub title: String,
+    // saved filters of the user, empty on the login page
+    pub filters: Vec<NavFilter>,
 }
-pub fn template_header(title: &str) -> String {
+pub fn template_header(title: &str, filters: &[SavedFilterRecord]) -> String {
     let data = HeaderData {
         title: title.to_owned(),
+        filters: nav_filters(filters),
     };
     HANDLEBARS.render("header", &data).unwrap()
 }
@@ -0,0 +1,151 @@
+use crate::CONFIG;
+use actix_web::{HttpRequest, HttpResponse};
+use rustls::{
+    crypto::ring::default_provider,
+    pki_types::{pem::PemObject, CertificateDer, PrivateKeyDer},
+    server::{ClientHello, ResolvesServerCert},
+    sign::CertifiedKey,
+    ServerConfig,
+};
+use std::io;
+use std::sync::{Arc, RwLock};
+
+fn tls_error(message: String) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, message)
+}
+
+// Loads the PEM certificate chain and private key, checking they belong together
+pub fn load_certified_key(cert_file: &str, key_file: &str) -> io::Result<CertifiedKey> {
+    let certs = CertificateDer::pem_file_iter(cert_file)
+        .and_then(|certs| certs.collect::<Result<Vec<_>, _>>())
+        .map_err(|e| {
+            tls_error(format!(
+                "Could not read certificate file {}: {}",
+                cert_file, e
+            ))
+        })?;
+    if certs.is_empty() {
+        return Err(tls_error(format!(
+            "Certificate file {} contains no certificates",
+            cert_file
+        )));
+    }
+
+    let key = PrivateKeyDer::from_pem_file(key_file).map_err(|e| {
+        tls_error(format!(
+            "Could not read private key file {}: {}",
+            key_file, e
+        ))
+    })?;
+
+    CertifiedKey::from_der(certs, key, &default_provider()).map_err(|e| {
+        tls_error(format!(
+            "Private key {} does not fit certificate {}: {}",
+            key_file, cert_file, e
+        ))
+    })
+}
+
+// Hands out the current certificate, which can be swapped while running
+#[derive(Debug)]
+pub struct CertResolver {
+    cert_file: String,
+    key_file: String,
+    current: RwLock<Arc<CertifiedKey>>,
+}
+impl CertResolver {
+    pub fn new(cert_file: &str, key_file: &str) -> io::Result<CertResolver> {
+        let certified_key = load_certified_key(cert_file, key_file)?;
+
+        Ok(CertResolver {
+            cert_file: cert_file.to_owned(),
+            key_file: key_file.to_owned(),
+            current: RwLock::new(Arc::new(certified_key)),
+        })
+    }
+
+    // On failure the old certificate stays in use
+    pub fn reload(&self) -> io::Result<()> {
+        let certified_key = load_certified_key(&self.cert_file, &self.key_file)?;
+        *self.current.write().unwrap() = Arc::new(certified_key);
+
+        Ok(())
+    }
+}
+impl ResolvesServerCert for CertResolver {
+    fn resolve(&self, _client_hello: ClientHello<'_>) -> Option<Arc<CertifiedKey>> {
+        Some(self.current.read().unwrap().clone())
+    }
+}
+
+pub fn server_config(resolver: Arc<CertResolver>) -> ServerConfig {
+    ServerConfig::builder_with_provider(Arc::new(default_provider()))
+        .with_safe_default_protocol_versions()
+        .expect("ring supports the default protocol versions")
+        .with_no_client_auth()
+        .with_cert_resolver(resolver)
+}
+
+#[cfg(unix)]
+pub async fn reload_on_sighup(resolver: Arc<CertResolver>) {
+    use tokio::signal::unix::{signal, SignalKind};
+
+    let mut hangups = match signal(SignalKind::hangup()) {
+        Ok(hangups) => hangups,
+        Err(e) => {
+            error!(
+                "Could not listen for SIGHUP, certificate reload disabled: {}",
+                e
+            );
+            return;
+        }
+    };
+
+    while hangups.recv().await.is_some() {
+        match resolver.reload() {
+            Ok(()) => info!("Reloaded TLS certificate from {}", resolver.cert_file),
+            Err(e) => error!(
+                "Failed to reload TLS certificate, keeping the old one: {}",
+                e
+            ),
+        }
+    }
+}
+
+#[cfg(not(unix))]
+pub async fn reload_on_sighup(_resolver: Arc<CertResolver>) {}
+
+fn strip_port(host: &str) -> &str {
+    match host.rsplit_once(':') {
+        Some((host, port)) if !port.contains(']') => host,
+        _ => host,
+    }
+}
+
+// Sends plain http requests to the same path on the https listener
+pub async fn redirect_to_https(request: HttpRequest) -> HttpResponse {
+    let connection_info = request.connection_info();
+    let host = strip_port(connection_info.host());
+    let path = request
+        .uri()
+        .path_and_query()
+        .map(|path| path.as_str())
+        .unwrap_or("/");
+
+    let port = CONFIG
+        .web
+        .listen
+        .rsplit_once(':')
+        .and_then(|(_, port)| port.parse::<u16>().ok())
+        .unwrap_or(443);
+
+    let location = if port == 443 {
+        format!("https://{}{}", host, path)
+    } else {
+        format!("https://{}:{}{}", host, port, path)
+    };
+
+    HttpResponse::MovedPermanently()
+        .insert_header(("location", location))
+        .finish()
+}
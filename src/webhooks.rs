@@ -0,0 +1,453 @@
+use crate::sql::models::{
+    DeliveryState, SpordRecord, WebhookDeliveryRecord, WebhookEvent, WebhookRecord,
+};
+use crate::store::WebhookStore;
+use crate::CONFIG;
+use chrono::{Duration, Utc};
+use hmac::{Hmac, Mac};
+use serde::Serialize;
+use sha2::Sha256;
+
+pub const SIGNATURE_HEADER: &str = "X-Spord-Signature";
+pub const TIMESTAMP_HEADER: &str = "X-Spord-Timestamp";
+pub const EVENT_HEADER: &str = "X-Spord-Event";
+pub const DELIVERY_HEADER: &str = "X-Spord-Delivery";
+
+// how often the worker drops old deliveries from the log
+const PRUNE_INTERVAL: std::time::Duration = std::time::Duration::from_secs(3600);
+// deliveries the worker takes from the outbox at once
+const BATCH_SIZE: u32 = 50;
+
+#[derive(Debug, Serialize)]
+struct Payload<'a> {
+    event: WebhookEvent,
+    timestamp: chrono::DateTime<Utc>,
+    username: Option<&'a str>,
+    spord: Option<&'a SpordRecord>,
+    // state or location the spord had before this event
+    previous: Option<serde_json::Value>,
+}
+
+// Queues an event for every subscribed webhook. Failures are only logged, a
+// broken webhook setup must not fail the change that triggered it
+pub async fn notify(
+    webhooks: &dyn WebhookStore,
+    event: WebhookEvent,
+    spord: &SpordRecord,
+    username: Option<&str>,
+    previous: Option<serde_json::Value>,
+) {
+    let payload = Payload {
+        event,
+        timestamp: Utc::now(),
+        username,
+        spord: Some(spord),
+        previous,
+    };
+
+    if let Err(e) = enqueue(webhooks, event, &payload, None).await {
+        error!("Could not queue {} webhooks: {}", event.as_sql(), e);
+    }
+}
+
+// Queues a ping for a single webhook, to test the receiving end
+pub async fn ping(
+    webhooks: &dyn WebhookStore,
+    webhook_id: i64,
+    username: &str,
+) -> anyhow::Result<()> {
+    let payload = Payload {
+        event: WebhookEvent::Ping,
+        timestamp: Utc::now(),
+        username: Some(username),
+        spord: None,
+        previous: None,
+    };
+
+    enqueue(webhooks, WebhookEvent::Ping, &payload, Some(webhook_id)).await
+}
+
+async fn enqueue(
+    webhooks: &dyn WebhookStore,
+    event: WebhookEvent,
+    payload: &Payload<'_>,
+    only_webhook: Option<i64>,
+) -> anyhow::Result<()> {
+    let payload = serde_json::to_string(payload)?;
+    let queued = webhooks.enqueue(event, payload, only_webhook).await?;
+    if queued > 0 {
+        debug!("Queued {} {} webhook deliveries", queued, event.as_sql());
+    }
+
+    Ok(())
+}
+
+// Hex encoded HMAC-SHA256 of "<timestamp>.<body>", sent as "sha256=<hex>"
+// with the unix timestamp of the attempt in X-Spord-Timestamp. Receivers
+// should compare the signature in constant time and reject timestamps more
+// than 5 minutes away from their clock, so a captured delivery can't be
+// replayed later. Retries are signed again with a new timestamp and keep
+// their X-Spord-Delivery id, which receivers can use to drop duplicates
+pub fn sign(secret: &str, timestamp: i64, body: &[u8]) -> String {
+    let mut mac =
+        Hmac::<Sha256>::new_from_slice(secret.as_bytes()).expect("hmac accepts any key length");
+    mac.update(timestamp.to_string().as_bytes());
+    mac.update(b".");
+    mac.update(body);
+    format!("{:x}", mac.finalize().into_bytes())
+}
+
+// Delay before the next attempt, doubling after every failure
+fn retry_delay(attempts: u32) -> Duration {
+    let doublings = attempts.saturating_sub(1).min(16);
+    Duration::seconds(
+        CONFIG
+            .webhooks
+            .retry_seconds
+            .saturating_mul(1 << doublings)
+            .min(86400),
+    )
+}
+
+async fn deliver(
+    webhooks: &dyn WebhookStore,
+    client: &awc::Client,
+    delivery: &WebhookDeliveryRecord,
+    webhook: &WebhookRecord,
+) {
+    let timestamp = Utc::now().timestamp();
+    let result = client
+        .post(&webhook.url)
+        .insert_header(("content-type", "application/json"))
+        .insert_header((EVENT_HEADER, delivery.event.as_str()))
+        .insert_header((DELIVERY_HEADER, delivery.id.to_string()))
+        .insert_header((TIMESTAMP_HEADER, timestamp.to_string()))
+        .insert_header((
+            SIGNATURE_HEADER,
+            format!(
+                "sha256={}",
+                sign(&webhook.secret, timestamp, delivery.payload.as_bytes())
+            ),
+        ))
+        .send_body(delivery.payload.clone())
+        .await;
+
+    let (status, error) = match result {
+        Ok(response) if response.status().is_success() => (Some(response.status().as_u16()), None),
+        Ok(response) => (
+            Some(response.status().as_u16()),
+            Some(format!("Receiver answered {}", response.status())),
+        ),
+        Err(e) => (None, Some(e.to_string())),
+    };
+
+    let attempts = delivery.attempts + 1;
+    let (state, next_attempt) = match error {
+        None => (DeliveryState::Delivered, None),
+        Some(_) if attempts >= CONFIG.webhooks.max_attempts => (DeliveryState::Failed, None),
+        Some(_) => (
+            DeliveryState::Pending,
+            Some(Utc::now() + retry_delay(attempts)),
+        ),
+    };
+
+    match (&state, &error) {
+        (DeliveryState::Delivered, _) => {
+            debug!("Delivered webhook {} to {}", delivery.id, webhook.url)
+        }
+        (_, Some(error)) => warn!(
+            "Webhook delivery {} to {} failed (attempt {}): {}",
+            delivery.id, webhook.url, attempts, error
+        ),
+        _ => (),
+    }
+
+    if let Err(e) = webhooks
+        .record_attempt(delivery.id, state, status, error, next_attempt)
+        .await
+    {
+        error!("Could not record webhook delivery {}: {}", delivery.id, e);
+    }
+}
+
+// Drops deliveries finished more than webhooks.keep_days ago
+async fn prune(webhooks: &dyn WebhookStore) {
+    if CONFIG.webhooks.keep_days == 0 {
+        return;
+    }
+
+    let finished_before = Utc::now() - Duration::days(CONFIG.webhooks.keep_days as i64);
+    match webhooks.prune_deliveries(finished_before).await {
+        Ok(0) => (),
+        Ok(pruned) => debug!("Pruned {} old webhook deliveries", pruned),
+        Err(e) => error!("Could not prune webhook deliveries: {}", e),
+    }
+}
+
+// A batch of deliveries is claimed for as long as sending all of them may
+// take, other servers retry them after that if this one stops halfway
+fn claim_until() -> chrono::DateTime<Utc> {
+    let timeout = CONFIG.webhooks.timeout_seconds as i64;
+    Utc::now() + Duration::seconds(timeout.saturating_mul(BATCH_SIZE as i64) + 60)
+}
+
+// Sends due deliveries from the outbox until the server stops
+pub async fn run_worker(webhooks: std::sync::Arc<dyn WebhookStore>) {
+    let client = awc::Client::builder()
+        .timeout(std::time::Duration::from_secs(
+            CONFIG.webhooks.timeout_seconds,
+        ))
+        .finish();
+    let poll = std::time::Duration::from_secs(CONFIG.webhooks.poll_seconds.max(1));
+    let mut last_prune: Option<std::time::Instant> = None;
+
+    loop {
+        if last_prune.is_none_or(|last_prune| last_prune.elapsed() >= PRUNE_INTERVAL) {
+            prune(webhooks.as_ref()).await;
+            last_prune = Some(std::time::Instant::now());
+        }
+
+        match webhooks.due(BATCH_SIZE, claim_until()).await {
+            Ok(due) => {
+                for (delivery, webhook) in due {
+                    deliver(webhooks.as_ref(), &client, &delivery, &webhook).await;
+                }
+            }
+            Err(e) => error!("Could not fetch due webhook deliveries: {}", e),
+        }
+
+        actix_web::rt::time::sleep(poll).await;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::store::sqlite::SqliteStore;
+    use crate::testing;
+    use actix_web::{web, App, HttpRequest, HttpResponse, HttpServer};
+    use std::sync::atomic::{AtomicBool, Ordering};
+    use std::sync::Mutex;
+
+    // What a local receiver got: signature, timestamp and event headers and
+    // the body
+    #[derive(Default)]
+    struct Receiver {
+        received: Mutex<Vec<(String, String, String, String)>>,
+        failing: AtomicBool,
+    }
+
+    async fn receive(
+        receiver: web::Data<Receiver>,
+        request: HttpRequest,
+        body: String,
+    ) -> HttpResponse {
+        let header = |name: &str| {
+            request
+                .headers()
+                .get(name)
+                .and_then(|value| value.to_str().ok())
+                .unwrap_or_default()
+                .to_string()
+        };
+        receiver.received.lock().unwrap().push((
+            header(SIGNATURE_HEADER),
+            header(TIMESTAMP_HEADER),
+            header(EVENT_HEADER),
+            body,
+        ));
+
+        if receiver.failing.load(Ordering::SeqCst) {
+            HttpResponse::ServiceUnavailable().finish()
+        } else {
+            HttpResponse::Ok().finish()
+        }
+    }
+
+    // Starts a receiver on a free local port and returns its url
+    fn start_receiver(receiver: web::Data<Receiver>) -> String {
+        let server = HttpServer::new(move || {
+            App::new()
+                .app_data(receiver.clone())
+                .route("/hook", web::post().to(receive))
+        })
+        .workers(1)
+        .bind(("127.0.0.1", 0))
+        .unwrap();
+        let addr = server.addrs()[0];
+        actix_web::rt::spawn(server.run());
+
+        format!("http://{}/hook", addr)
+    }
+
+    // The outbox of a new sqlite database, removed with the returned TempDir
+    async fn store() -> (Box<dyn WebhookStore>, tempfile::TempDir) {
+        let (pool, dir) = testing::pool().await;
+        (Box::new(SqliteStore::new(pool)), dir)
+    }
+
+    async fn latest_delivery(
+        webhooks: &dyn WebhookStore,
+        webhook_id: i64,
+    ) -> WebhookDeliveryRecord {
+        webhooks.deliveries(webhook_id, 1).await.unwrap().remove(0)
+    }
+
+    #[actix_web::test]
+    async fn retry_delay_doubles_up_to_a_day() {
+        testing::config();
+        let retry = CONFIG.webhooks.retry_seconds;
+        assert_eq!(retry_delay(1), Duration::seconds(retry));
+        assert_eq!(retry_delay(2), Duration::seconds(retry * 2));
+        assert_eq!(retry_delay(3), Duration::seconds(retry * 4));
+        assert_eq!(retry_delay(40), Duration::seconds(86400));
+    }
+
+    #[actix_web::test]
+    async fn signed_delivery_is_retried_until_received() {
+        let (webhooks, _dir) = store().await;
+        let receiver = web::Data::new(Receiver::default());
+        let url = start_receiver(receiver.clone());
+        let webhook_id = webhooks
+            .create(&url, "s3cret", &[WebhookEvent::Created])
+            .await
+            .unwrap();
+
+        let spord = testing::spord("Ann", "TRA123");
+        notify(
+            webhooks.as_ref(),
+            WebhookEvent::Created,
+            &spord,
+            Some("alice"),
+            None,
+        )
+        .await;
+        // not subscribed to
+        notify(
+            webhooks.as_ref(),
+            WebhookEvent::Deleted,
+            &spord,
+            Some("alice"),
+            None,
+        )
+        .await;
+
+        let client = awc::Client::default();
+        let mut due = webhooks.due(50, claim_until()).await.unwrap();
+        assert_eq!(due.len(), 1);
+        let (delivery, webhook) = due.remove(0);
+
+        // the first attempt fails and is retried after webhooks.retry_seconds
+        receiver.failing.store(true, Ordering::SeqCst);
+        let before = Utc::now();
+        deliver(webhooks.as_ref(), &client, &delivery, &webhook).await;
+
+        let delivery = latest_delivery(webhooks.as_ref(), webhook_id).await;
+        assert_eq!(delivery.state, DeliveryState::Pending);
+        assert_eq!(delivery.attempts, 1);
+        assert_eq!(delivery.last_status, Some(503));
+        let next_attempt = delivery.next_attempt.unwrap();
+        assert!(next_attempt >= before + retry_delay(1) - Duration::seconds(1));
+        assert!(next_attempt <= Utc::now() + retry_delay(1));
+        assert!(webhooks.due(50, claim_until()).await.unwrap().is_empty());
+
+        receiver.failing.store(false, Ordering::SeqCst);
+        deliver(webhooks.as_ref(), &client, &delivery, &webhook).await;
+
+        let delivery = latest_delivery(webhooks.as_ref(), webhook_id).await;
+        assert_eq!(delivery.state, DeliveryState::Delivered);
+        assert_eq!(delivery.attempts, 2);
+        assert_eq!(delivery.last_status, Some(200));
+        assert_eq!(delivery.next_attempt, None);
+
+        let received = receiver.received.lock().unwrap();
+        assert_eq!(received.len(), 2);
+        for (signature, timestamp, event, body) in received.iter() {
+            let sent: i64 = timestamp.parse().unwrap();
+            assert!((before.timestamp() - 1..=Utc::now().timestamp()).contains(&sent));
+            let mut mac = Hmac::<Sha256>::new_from_slice(b"s3cret").unwrap();
+            mac.update(format!("{}.{}", timestamp, body).as_bytes());
+            assert_eq!(
+                *signature,
+                format!("sha256={:x}", mac.finalize().into_bytes())
+            );
+            assert_eq!(event, "created");
+
+            let payload: serde_json::Value = serde_json::from_str(body).unwrap();
+            assert_eq!(payload["event"], "created");
+            assert_eq!(payload["username"], "alice");
+            assert_eq!(payload["spord"]["part"], "TRA123");
+        }
+    }
+
+    #[actix_web::test]
+    async fn delivery_fails_after_max_attempts() {
+        let (webhooks, _dir) = store().await;
+        let receiver = web::Data::new(Receiver::default());
+        receiver.failing.store(true, Ordering::SeqCst);
+        let url = start_receiver(receiver.clone());
+        let webhook_id = webhooks.create(&url, "s3cret", &[]).await.unwrap();
+        ping(webhooks.as_ref(), webhook_id, "alice").await.unwrap();
+
+        let (mut delivery, webhook) = webhooks.due(50, claim_until()).await.unwrap().remove(0);
+        delivery.attempts = CONFIG.webhooks.max_attempts - 1;
+        deliver(
+            webhooks.as_ref(),
+            &awc::Client::default(),
+            &delivery,
+            &webhook,
+        )
+        .await;
+
+        let delivery = latest_delivery(webhooks.as_ref(), webhook_id).await;
+        assert_eq!(delivery.state, DeliveryState::Failed);
+        assert_eq!(delivery.next_attempt, None);
+    }
+
+    #[actix_web::test]
+    async fn disabled_webhooks_hold_their_deliveries() {
+        let (webhooks, _dir) = store().await;
+        let webhook_id = webhooks
+            .create("http://127.0.0.1:9/hook", "s3cret", &[])
+            .await
+            .unwrap();
+        ping(webhooks.as_ref(), webhook_id, "alice").await.unwrap();
+
+        webhooks.set_enabled(webhook_id, false).await.unwrap();
+        assert!(webhooks.due(50, claim_until()).await.unwrap().is_empty());
+
+        webhooks.set_enabled(webhook_id, true).await.unwrap();
+        assert_eq!(webhooks.due(50, claim_until()).await.unwrap().len(), 1);
+    }
+
+    #[actix_web::test]
+    async fn prune_keeps_pending_deliveries() {
+        let (webhooks, _dir) = store().await;
+        let webhook_id = webhooks
+            .create("http://127.0.0.1:9/hook", "s3cret", &[])
+            .await
+            .unwrap();
+        ping(webhooks.as_ref(), webhook_id, "alice").await.unwrap();
+        ping(webhooks.as_ref(), webhook_id, "alice").await.unwrap();
+        let delivered = latest_delivery(webhooks.as_ref(), webhook_id).await;
+        webhooks
+            .record_attempt(
+                delivered.id,
+                DeliveryState::Delivered,
+                Some(200),
+                None,
+                None,
+            )
+            .await
+            .unwrap();
+
+        let pruned = webhooks
+            .prune_deliveries(Utc::now() + Duration::seconds(1))
+            .await
+            .unwrap();
+        assert_eq!(pruned, 1);
+        let left = webhooks.deliveries(webhook_id, 10).await.unwrap();
+        assert_eq!(left.len(), 1);
+        assert_eq!(left[0].state, DeliveryState::Pending);
+    }
+}